@@ -46,6 +46,8 @@ fn ossl_bindings(header: &str, args: &[&str]) {
         .allowlist_item("EVP_.*")
         .allowlist_item("evp_.*")
         .allowlist_item("BN_.*")
+        .allowlist_item("EC_.*")
+        .allowlist_item("point_conversion_form_t")
         .allowlist_item("LN_aes.*")
         .generate()
         .expect("Unable to generate bindings")
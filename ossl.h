@@ -4,5 +4,6 @@
 #include "openssl/core_names.h"
 #include "openssl/params.h"
 #include "openssl/evp.h"
+#include "openssl/ec.h"
 #include "openssl/obj_mac.h"
 #include "openssl/kdf.h"
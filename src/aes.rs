@@ -159,6 +159,21 @@ impl SecretKeyFactory for AesKeyFactory {
         Ok(())
     }
 
+    /* pkcs11-spec-v3.1 6.7.2: the first three bytes of the ECB
+     * encryption of a block of zeros */
+    fn check_value(&self, obj: &Object) -> Result<Vec<u8>> {
+        let mech = CK_MECHANISM {
+            mechanism: CKM_AES_ECB,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut op = AesOperation::encrypt_new(&mech, obj)?;
+        let mut kcv = vec![0u8; AES_BLOCK_SIZE];
+        op.encrypt(&[0u8; AES_BLOCK_SIZE], kcv.as_mut_slice())?;
+        kcv.truncate(3);
+        Ok(kcv)
+    }
+
     fn recommend_key_size(&self, max: usize) -> Result<usize> {
         if max >= MAX_AES_SIZE_BYTES {
             Ok(MAX_AES_SIZE_BYTES)
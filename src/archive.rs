@@ -0,0 +1,111 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Portable token archives. A small clear text header describes how the
+ * wrapping key is obtained, it is followed by the token objects
+ * serialized as JSON and encrypted with AES Key Wrap with Padding.
+ * The header is repeated at the start of the encrypted data so that it
+ * can't be altered without detection */
+
+use super::err_rv;
+use super::error;
+use super::interface;
+
+use error::Result;
+use interface::*;
+
+const MAGIC: &[u8; 16] = b"KRYOPTIC ARCHIVE";
+const ARCHIVE_VERSION: u32 = 1;
+
+/* the archive leaves the token and is not protected by login counters,
+ * so passphrases get a much higher work factor than PINs */
+pub const ARCHIVE_KDF_ITER: u32 = 100000;
+
+/* the header is not authenticated until the key is derived, so bound
+ * the work an attacker supplied archive can make us do */
+pub const ARCHIVE_KDF_MAX_ITER: u32 = 10 * ARCHIVE_KDF_ITER;
+
+const KDF_KEY: u32 = 0;
+const KDF_PBKDF2: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveKdf {
+    /* wrapped directly under an AES key */
+    Key,
+    /* wrapped under a key derived from a passphrase via PBKDF2 */
+    Pbkdf2 { salt: String, iterations: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveHeader {
+    pub kdf: ArchiveKdf,
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32> {
+    if data.len() < *pos + 4 {
+        return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+    }
+    let mut val = [0u8; 4];
+    val.copy_from_slice(&data[*pos..*pos + 4]);
+    *pos += 4;
+    Ok(u32::from_be_bytes(val))
+}
+
+impl ArchiveHeader {
+    pub fn new(kdf: ArchiveKdf) -> ArchiveHeader {
+        ArchiveHeader { kdf: kdf }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&ARCHIVE_VERSION.to_be_bytes());
+        match &self.kdf {
+            ArchiveKdf::Key => {
+                data.extend_from_slice(&KDF_KEY.to_be_bytes());
+            }
+            ArchiveKdf::Pbkdf2 { salt, iterations } => {
+                data.extend_from_slice(&KDF_PBKDF2.to_be_bytes());
+                data.extend_from_slice(&iterations.to_be_bytes());
+                data.extend_from_slice(&(salt.len() as u32).to_be_bytes());
+                data.extend_from_slice(salt.as_bytes());
+            }
+        }
+        data
+    }
+
+    /* Returns the header and the length it occupies in data */
+    pub fn parse(data: &[u8]) -> Result<(ArchiveHeader, usize)> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+        }
+        let mut pos = MAGIC.len();
+        let version = read_u32(data, &mut pos)?;
+        if version != ARCHIVE_VERSION {
+            return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+        }
+        let kdf = match read_u32(data, &mut pos)? {
+            KDF_KEY => ArchiveKdf::Key,
+            KDF_PBKDF2 => {
+                let iterations = read_u32(data, &mut pos)?;
+                let len = read_u32(data, &mut pos)? as usize;
+                if iterations == 0
+                    || iterations > ARCHIVE_KDF_MAX_ITER
+                    || data.len() < pos + len
+                {
+                    return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+                }
+                let salt = match std::str::from_utf8(&data[pos..pos + len]) {
+                    Ok(s) => s.to_string(),
+                    Err(_) => return err_rv!(CKR_ENCRYPTED_DATA_INVALID),
+                };
+                pos += len;
+                ArchiveKdf::Pbkdf2 {
+                    salt: salt,
+                    iterations: iterations,
+                }
+            }
+            _ => return err_rv!(CKR_ENCRYPTED_DATA_INVALID),
+        };
+        Ok((ArchiveHeader::new(kdf), pos))
+    }
+}
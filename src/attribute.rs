@@ -39,7 +39,7 @@ macro_rules! attrmap_element {
     };
 }
 
-static ATTRMAP: [Attrmap<'_>; 147] = [
+static ATTRMAP: [Attrmap<'_>; 160] = [
     attrmap_element!(CKA_CLASS; as NumType),
     attrmap_element!(CKA_TOKEN; as BoolType),
     attrmap_element!(CKA_PRIVATE; as BoolType),
@@ -61,7 +61,7 @@ static ATTRMAP: [Attrmap<'_>; 147] = [
     attrmap_element!(CKA_HASH_OF_SUBJECT_PUBLIC_KEY; as BytesType),
     attrmap_element!(CKA_HASH_OF_ISSUER_PUBLIC_KEY; as BytesType),
     attrmap_element!(CKA_NAME_HASH_ALGORITHM; as NumType),
-    attrmap_element!(CKA_CHECK_VALUE; as IgnoreType),
+    attrmap_element!(CKA_CHECK_VALUE; as BytesType),
     attrmap_element!(CKA_KEY_TYPE; as NumType),
     attrmap_element!(CKA_SUBJECT; as BytesType),
     attrmap_element!(CKA_ID; as BytesType),
@@ -175,6 +175,14 @@ static ATTRMAP: [Attrmap<'_>; 147] = [
     attrmap_element!(KRA_MANUFACTURER_ID; as StringType),
     attrmap_element!(KRA_MODEL; as StringType),
     attrmap_element!(KRA_SERIAL_NUMBER; as StringType),
+    attrmap_element!(KRA_INTEGRITY_TAG; as BytesType),
+    attrmap_element!(KRA_EXPORT_POLICY; as NumType),
+    attrmap_element!(KRA_INTEGRITY_KEY; as BytesType),
+    attrmap_element!(KRA_KEY_DATA; as BytesType),
+    attrmap_element!(KRA_KEY_DATA_PASSPHRASE; as BytesType),
+    attrmap_element!(KRA_MECHANISM_PROFILES; as BytesType),
+    attrmap_element!(KRA_DENIED_OPERATIONS; as NumType),
+    attrmap_element!(KRA_MECHANISM_POLICY; as BytesType),
     attrmap_element!(CKA_VALIDATION_TYPE; as NumType),
     attrmap_element!(CKA_VALIDATION_VERSION; as BytesType),
     attrmap_element!(CKA_VALIDATION_LEVEL; as NumType),
@@ -187,6 +195,11 @@ static ATTRMAP: [Attrmap<'_>; 147] = [
     attrmap_element!(CKA_VALIDATION_VENDOR_URI; as StringType),
     attrmap_element!(CKA_VALIDATION_PROFILE; as StringType),
     attrmap_element!(CKA_VALIDATION_FLAGS; as NumType),
+    attrmap_element!(CKA_HASH_OF_CERTIFICATE; as BytesType),
+    attrmap_element!(CKA_PKCS_TRUST_SERVER_AUTH; as NumType),
+    attrmap_element!(CKA_PKCS_TRUST_CLIENT_AUTH; as NumType),
+    attrmap_element!(CKA_PKCS_TRUST_CODE_SIGNING; as NumType),
+    attrmap_element!(CKA_PKCS_TRUST_EMAIL_PROTECTION; as NumType),
 ];
 
 #[derive(Debug, Clone)]
@@ -213,7 +226,12 @@ impl Attribute {
         if self.ck_type != attr.type_ {
             return false;
         }
-        match attr.to_buf() {
+        let buf = if is_attr_array(attr.type_) {
+            attr.to_attr_array_buf()
+        } else {
+            attr.to_buf()
+        };
+        match buf {
             Ok(buf) => buf == self.value,
             Err(_) => false,
         }
@@ -276,11 +294,96 @@ impl Attribute {
         Ok(chars.iter().collect())
     }
 
+    pub fn to_attr_array(&self) -> Result<Vec<Attribute>> {
+        if !is_attr_array(self.ck_type) {
+            return err_rv!(CKR_ATTRIBUTE_TYPE_INVALID);
+        }
+        match attr_array_from_vec(&self.value) {
+            Ok(attrs) => Ok(attrs),
+            Err(e) => {
+                /* older versions stored the caller's CK_ATTRIBUTE array
+                 * as is, the values it pointed to are lost and were never
+                 * enforced, so it is read back as an empty template */
+                if is_raw_attr_array(&self.value) {
+                    Ok(Vec::new())
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
     pub fn zeroize(&mut self) {
         self.value.zeroize();
     }
 }
 
+/* Attributes holding an array of attributes, stored as a sequence of
+ * type, value length and value of each element */
+pub fn is_attr_array(t: CK_ATTRIBUTE_TYPE) -> bool {
+    match t {
+        CKA_WRAP_TEMPLATE | CKA_UNWRAP_TEMPLATE | CKA_DERIVE_TEMPLATE => true,
+        _ => false,
+    }
+}
+
+fn attr_array_to_vec(template: &[CK_ATTRIBUTE]) -> Result<Vec<u8>> {
+    let mut v = Vec::<u8>::new();
+    for ck_attr in template {
+        /* no nesting */
+        if is_attr_array(ck_attr.type_) {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let attr = ck_attr.to_attribute()?;
+        v.extend_from_slice(&attr.ck_type.to_ne_bytes());
+        v.extend_from_slice(
+            &CK_ULONG::try_from(attr.value.len())?.to_ne_bytes(),
+        );
+        v.extend_from_slice(&attr.value);
+    }
+    Ok(v)
+}
+
+fn attr_array_from_vec(v: &[u8]) -> Result<Vec<Attribute>> {
+    let ulsize = std::mem::size_of::<CK_ULONG>();
+    let mut attrs = Vec::<Attribute>::new();
+    let mut buf = v;
+    while buf.len() > 0 {
+        if buf.len() < 2 * ulsize {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let ck_type =
+            CK_ULONG::from_ne_bytes(buf[..ulsize].try_into().unwrap());
+        let len = usize::try_from(CK_ULONG::from_ne_bytes(
+            buf[ulsize..2 * ulsize].try_into().unwrap(),
+        ))?;
+        buf = &buf[2 * ulsize..];
+        if buf.len() < len {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        attrs.push(Attribute {
+            ck_type: ck_type,
+            attrtype: attr_id_to_attrtype(ck_type)?,
+            value: buf[..len].to_vec(),
+        });
+        buf = &buf[len..];
+    }
+    Ok(attrs)
+}
+
+/* Whether the value is a copy of a CK_ATTRIBUTE array of known types */
+fn is_raw_attr_array(v: &[u8]) -> bool {
+    let size = std::mem::size_of::<CK_ATTRIBUTE>();
+    let ulsize = std::mem::size_of::<CK_ULONG>();
+    if v.len() == 0 || v.len() % size != 0 {
+        return false;
+    }
+    v.chunks(size).all(|c| {
+        let ck_type = CK_ULONG::from_ne_bytes(c[..ulsize].try_into().unwrap());
+        attr_id_to_attrtype(ck_type).is_ok()
+    })
+}
+
 macro_rules! conversion_from_type {
     (make $fn1:ident; $fn2:ident; $fn3:ident; from $rtype:ty; as $atype:ident; via $conv:ident) => {
         #[allow(dead_code)]
@@ -360,16 +463,16 @@ pub fn string_from_sized(t: CK_ULONG, val: &[u8]) -> Attribute {
 }
 
 fn date_to_vec(val: CK_DATE) -> Vec<u8> {
-    let mut v = Vec::with_capacity(8);
-    v[0] = val.year[0];
-    v[1] = val.year[1];
-    v[2] = val.year[2];
-    v[3] = val.year[3];
-    v[4] = val.month[0];
-    v[5] = val.month[1];
-    v[6] = val.day[0];
-    v[7] = val.day[1];
-    v
+    vec![
+        val.year[0],
+        val.year[1],
+        val.year[2],
+        val.year[3],
+        val.month[0],
+        val.month[1],
+        val.day[0],
+        val.day[1],
+    ]
 }
 
 conversion_from_type! {make from_date; from_type_date; from_string_date; from CK_DATE; as DateType; via date_to_vec}
@@ -377,8 +480,8 @@ conversion_from_type! {make from_date; from_type_date; from_string_date; from CK
 fn vec_to_date(val: Vec<u8>) -> CK_DATE {
     CK_DATE {
         year: [val[0], val[1], val[2], val[3]],
-        month: [val[5], val[6]],
-        day: [val[8], val[9]],
+        month: [val[4], val[5]],
+        day: [val[6], val[7]],
     }
 }
 
@@ -406,15 +509,7 @@ pub fn string_to_ck_date(date: &str) -> Result<CK_DATE> {
     if s[4] != ASCII_DASH || s[7] != ASCII_DASH {
         return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
     }
-    let mut buf = Vec::with_capacity(8);
-    buf[0] = s[0];
-    buf[1] = s[1];
-    buf[2] = s[2];
-    buf[3] = s[3];
-    buf[4] = s[5];
-    buf[5] = s[6];
-    buf[6] = s[8];
-    buf[7] = s[9];
+    let buf = vec![s[0], s[1], s[2], s[3], s[5], s[6], s[8], s[9]];
     vec_to_date_validate(buf)
 }
 
@@ -493,6 +588,25 @@ impl CK_ATTRIBUTE {
     pub fn to_buf(&self) -> Result<Vec<u8>> {
         Ok(bytes_to_vec!(self.pValue, self.ulValueLen))
     }
+    fn to_attr_array_buf(&self) -> Result<Vec<u8>> {
+        let size = sizeof!(CK_ATTRIBUTE);
+        if self.ulValueLen % size != 0 {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let count = usize::try_from(self.ulValueLen / size)?;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if self.pValue.is_null() {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        attr_array_to_vec(unsafe {
+            std::slice::from_raw_parts(
+                self.pValue as *const CK_ATTRIBUTE,
+                count,
+            )
+        })
+    }
     pub fn to_date(&self) -> Result<CK_DATE> {
         if self.ulValueLen != 8 {
             return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
@@ -514,7 +628,13 @@ impl CK_ATTRIBUTE {
             AttrType::StringType => {
                 Ok(from_string(self.type_, self.to_string()?))
             }
-            AttrType::BytesType => Ok(from_bytes(self.type_, self.to_buf()?)),
+            AttrType::BytesType => {
+                if is_attr_array(self.type_) {
+                    Ok(from_bytes(self.type_, self.to_attr_array_buf()?))
+                } else {
+                    Ok(from_bytes(self.type_, self.to_buf()?))
+                }
+            }
             AttrType::DateType => Ok(from_date(self.type_, self.to_date()?)),
             AttrType::DenyType => err_rv!(CKR_ATTRIBUTE_TYPE_INVALID),
             AttrType::IgnoreType => Ok(from_ignore(self.type_, None)),
@@ -704,6 +824,22 @@ impl<'a> CkAttrs<'a> {
         }
     }
 
+    /* Adds the elements of an attribute array, any attribute already
+     * present must have the same value */
+    pub fn add_attr_array(&mut self, attrs: &[Attribute]) -> Result<()> {
+        for attr in attrs {
+            match self.p.as_ref().iter().find(|a| a.type_ == attr.ck_type) {
+                Some(a) => {
+                    if a.to_attribute()?.value != attr.value {
+                        return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+                    }
+                }
+                None => self.add_vec(attr.ck_type, attr.value.clone())?,
+            }
+        }
+        Ok(())
+    }
+
     pub fn len(&self) -> usize {
         self.p.as_ref().len()
     }
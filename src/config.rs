@@ -0,0 +1,158 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* The configuration file is a TOML document listing the slots exposed
+ * by the module, for example:
+ *
+ *   [[slots]]
+ *   slot = 1
+ *   label = "Signing keys"
+ *   dbtype = "sqlite"
+ *   dbpath = "/var/lib/kryoptic/signing.sql"
+ *
+ * Slots without a slot number get the first free one, slots without a
+ * dbtype use the backend matching the dbpath file name. A slot can
+ * also carry a policy table, see policy.rs.
+ *
+ * The system crypto-policies back-end applies to all slots, a different
+ * back-end file can be named with a top level key, an empty string
+ * disables it:
+ *
+ *   crypto_policies = "/etc/crypto-policies/back-ends/kryoptic.config"
+ *
+ * Without that key the KRYOPTIC_CRYPTO_POLICIES environment variable is
+ * used the same way, this also covers legacy init strings.
+ */
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::err_rv;
+use super::error;
+use super::interface;
+use super::policy;
+
+use error::Result;
+use interface::*;
+use policy::crypto_policies;
+use policy::{Policy, PolicyConfig};
+
+pub const CONFIG_FILE_NAME: &str = "kryoptic.conf";
+pub const CRYPTO_POLICIES_ENV: &str = "KRYOPTIC_CRYPTO_POLICIES";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Slot {
+    pub slot: Option<CK_SLOT_ID>,
+    pub label: Option<String>,
+    pub dbtype: Option<String>,
+    pub dbpath: Option<String>,
+    pub policy: Option<PolicyConfig>,
+}
+
+impl Slot {
+    pub fn get_dbpath(&self) -> String {
+        match &self.dbpath {
+            Some(p) => p.clone(),
+            None => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub crypto_policies: Option<String>,
+    #[serde(default)]
+    pub slots: Vec<Slot>,
+}
+
+impl Config {
+    fn check(&self) -> Result<()> {
+        if self.slots.len() == 0 {
+            return err_rv!(CKR_ARGUMENTS_BAD);
+        }
+        let mut ids = HashSet::<CK_SLOT_ID>::new();
+        for slot in &self.slots {
+            if let Some(id) = slot.slot {
+                if !ids.insert(id) {
+                    return err_rv!(CKR_ARGUMENTS_BAD);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* The policy configured system wide, if any */
+    pub fn get_system_policy(&self) -> Result<Option<Policy>> {
+        let path = match &self.crypto_policies {
+            Some(p) => p.clone(),
+            None => match std::env::var(CRYPTO_POLICIES_ENV) {
+                Ok(p) => p,
+                Err(_) => crypto_policies::DEFAULT_BACKEND_FILE.to_string(),
+            },
+        };
+        if path.len() == 0 {
+            return Ok(None);
+        }
+        crypto_policies::load(&path)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Config> {
+        let config: Config = match toml::from_str(text) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(error::Error::ck_rv_from_error(
+                    CKR_ARGUMENTS_BAD,
+                    e,
+                ))
+            }
+        };
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn from_file(filename: &str) -> Result<Config> {
+        match std::fs::read_to_string(filename) {
+            Ok(text) => Self::from_toml(&text),
+            Err(e) => Err(error::Error::ck_rv_from_error(CKR_ARGUMENTS_BAD, e)),
+        }
+    }
+
+    /* The original "<dbpath>[:<slot>]" format naming a single slot */
+    fn from_legacy_conf_string(conf: &str) -> Result<Config> {
+        let v: Vec<&str> = conf.split(':').collect();
+        let slot = if v.len() > 1 {
+            match v[1].parse::<CK_SLOT_ID>() {
+                Ok(n) => Some(n),
+                Err(_) => return err_rv!(CKR_ARGUMENTS_BAD),
+            }
+        } else {
+            None
+        };
+        Ok(Config {
+            crypto_policies: None,
+            slots: vec![Slot {
+                slot: slot,
+                label: None,
+                dbtype: None,
+                dbpath: Some(v[0].to_string()),
+                policy: None,
+            }],
+        })
+    }
+
+    /* Accepts either the path of a configuration file or a legacy
+     * single slot string, as found in pReserved or KRYOPTIC_CONF */
+    pub fn from_init_string(conf: &str) -> Result<Config> {
+        let path = Path::new(conf);
+        match path.extension() {
+            Some(ext) if ext == "conf" || ext == "toml" => {
+                Self::from_file(conf)
+            }
+            _ => Self::from_legacy_conf_string(conf),
+        }
+    }
+}
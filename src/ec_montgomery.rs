@@ -0,0 +1,299 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Montgomery curve keys (X25519 and X448) can be created, imported
+ * from PKCS#8 and SubjectPublicKeyInfo data and unwrapped. No
+ * mechanism operates on them yet. */
+
+use super::attribute;
+use super::error;
+use super::interface;
+use super::kasn1;
+use super::mechanism;
+use super::object;
+use super::{attr_element, bytes_attr_not_empty, err_rv};
+
+use attribute::{from_bool, from_bytes, CkAttrs};
+use error::Result;
+use interface::*;
+use mechanism::Mechanisms;
+use object::{
+    CommonKeyFactory, OAFlags, Object, ObjectAttr, ObjectFactories,
+    ObjectFactory, ObjectType, PrivKeyFactory, PubKeyFactory,
+};
+
+use once_cell::sync::Lazy;
+use std::fmt::Debug;
+
+use crate::ecc_misc::*;
+
+const BITS_X25519: usize = 255;
+const BITS_X448: usize = 448;
+
+// ASN.1 encoding of the curve name
+const STRING_CURVE25519: &[u8] = &[
+    0x13, 0x0a, 0x63, 0x75, 0x72, 0x76, 0x65, 0x32, 0x35, 0x35, 0x31, 0x39,
+];
+const STRING_CURVE448: &[u8] =
+    &[0x13, 0x08, 0x63, 0x75, 0x72, 0x76, 0x65, 0x34, 0x34, 0x38];
+
+fn oid_to_bits(oid: asn1::ObjectIdentifier) -> Result<usize> {
+    match oid {
+        OID_X25519 => Ok(BITS_X25519),
+        OID_X448 => Ok(BITS_X448),
+        _ => err_rv!(CKR_GENERAL_ERROR),
+    }
+}
+
+fn curve_name_to_bits(name: asn1::PrintableString) -> Result<usize> {
+    let asn1_name = match asn1::write_single(&name) {
+        Ok(r) => r,
+        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    };
+    match asn1_name.as_slice() {
+        STRING_CURVE25519 => Ok(BITS_X25519),
+        STRING_CURVE448 => Ok(BITS_X448),
+        _ => err_rv!(CKR_GENERAL_ERROR),
+    }
+}
+
+/* The public key of both public and private key objects */
+fn set_public_key_info(key: &mut Object) -> Result<()> {
+    let oid =
+        match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits)? {
+            BITS_X25519 => OID_X25519,
+            BITS_X448 => OID_X448,
+            _ => return err_rv!(CKR_GENERAL_ERROR),
+        };
+    set_ec_public_key_info(key, oid, None)
+}
+
+#[derive(Debug)]
+pub struct ECMontgomeryPubFactory {
+    attributes: Vec<ObjectAttr>,
+}
+
+impl ECMontgomeryPubFactory {
+    pub fn new() -> ECMontgomeryPubFactory {
+        let mut data: ECMontgomeryPubFactory = ECMontgomeryPubFactory {
+            attributes: Vec::new(),
+        };
+        data.attributes.append(&mut data.init_common_object_attrs());
+        data.attributes
+            .append(&mut data.init_common_storage_attrs());
+        data.attributes.append(&mut data.init_common_key_attrs());
+        data.attributes
+            .append(&mut data.init_common_public_key_attrs());
+        data.attributes.push(attr_element!(CKA_EC_PARAMS; OAFlags::AlwaysRequired | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_EC_POINT; OAFlags::RequiredOnCreate | OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data
+    }
+}
+
+impl ObjectFactory for ECMontgomeryPubFactory {
+    fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        let mut obj = self.default_object_create(template)?;
+
+        bytes_attr_not_empty!(obj; CKA_EC_PARAMS);
+        bytes_attr_not_empty!(obj; CKA_EC_POINT);
+        if make_bits_from_ec_params(&obj, oid_to_bits, curve_name_to_bits)
+            .is_err()
+        {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        set_public_key_info(&mut obj)?;
+
+        Ok(obj)
+    }
+
+    fn get_attributes(&self) -> &Vec<ObjectAttr> {
+        &self.attributes
+    }
+
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        PubKeyFactory::import_from_public_key_info(self, data, template)
+    }
+}
+
+impl CommonKeyFactory for ECMontgomeryPubFactory {}
+
+impl PubKeyFactory for ECMontgomeryPubFactory {
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let spki = match asn1::parse_single::<kasn1::SubjectPublicKeyInfo>(data)
+        {
+            Ok(k) => k,
+            Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        let params = match spki.get_oid() {
+            &OID_X25519 | &OID_X448 => {
+                match asn1::write_single(spki.get_oid()) {
+                    Ok(p) => p,
+                    Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+                }
+            }
+            _ => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        /* CKA_EC_POINT is the DER encoded octet string */
+        let point = match asn1::write_single(&spki.get_public_key()) {
+            Ok(p) => p,
+            Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+        };
+        let mut tmpl = CkAttrs::from(template);
+        tmpl.add_vec(CKA_EC_PARAMS, params)?;
+        tmpl.add_vec(CKA_EC_POINT, point)?;
+        self.create(tmpl.as_slice())
+    }
+}
+
+#[derive(Debug)]
+pub struct ECMontgomeryPrivFactory {
+    attributes: Vec<ObjectAttr>,
+}
+
+impl ECMontgomeryPrivFactory {
+    pub fn new() -> ECMontgomeryPrivFactory {
+        let mut data: ECMontgomeryPrivFactory = ECMontgomeryPrivFactory {
+            attributes: Vec::new(),
+        };
+        data.attributes.append(&mut data.init_common_object_attrs());
+        data.attributes
+            .append(&mut data.init_common_storage_attrs());
+        data.attributes.append(&mut data.init_common_key_attrs());
+        data.attributes
+            .append(&mut data.init_common_private_key_attrs());
+        data.attributes.push(attr_element!(CKA_EC_PARAMS; OAFlags::RequiredOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_EC_POINT; OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_VALUE; OAFlags::Sensitive | OAFlags::RequiredOnCreate | OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+
+        /* default to private */
+        let private = attr_element!(CKA_PRIVATE; OAFlags::Defval | OAFlags::ChangeOnCopy; from_bool; val true);
+        match data
+            .attributes
+            .iter()
+            .position(|x| x.get_type() == CKA_PRIVATE)
+        {
+            Some(idx) => data.attributes[idx] = private,
+            None => data.attributes.push(private),
+        }
+
+        data
+    }
+}
+
+impl ObjectFactory for ECMontgomeryPrivFactory {
+    fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        let mut obj = self.default_object_create(template)?;
+
+        ec_montgomery_import(&mut obj)?;
+        set_public_key_info(&mut obj)?;
+
+        Ok(obj)
+    }
+
+    fn get_attributes(&self) -> &Vec<ObjectAttr> {
+        &self.attributes
+    }
+
+    fn import_from_wrapped(
+        &self,
+        data: Vec<u8>,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        PrivKeyFactory::import_from_wrapped(self, data, template)
+    }
+}
+
+impl CommonKeyFactory for ECMontgomeryPrivFactory {}
+
+impl PrivKeyFactory for ECMontgomeryPrivFactory {
+    fn import_from_wrapped(
+        &self,
+        data: Vec<u8>,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let mut key = self.default_object_unwrap(template)?;
+
+        if !key.check_or_set_attr(attribute::from_ulong(
+            CKA_CLASS,
+            CKO_PRIVATE_KEY,
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+        if !key.check_or_set_attr(attribute::from_ulong(
+            CKA_KEY_TYPE,
+            CKK_EC_MONTGOMERY,
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+
+        let (tlv, extra) = match asn1::strip_tlv(&data) {
+            Ok(x) => x,
+            Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        };
+        /* Some Key Wrapping algorithms may 0 pad to match block size */
+        if !extra.iter().all(|b| *b == 0) {
+            return err_rv!(CKR_WRAPPED_KEY_INVALID);
+        }
+        let pkeyinfo = kasn1::PrivateKeyInfo::parse_wrapped(tlv.full_data())?;
+        let oid_encoded = match pkeyinfo.get_oid() {
+            &OID_X25519 | &OID_X448 => {
+                match asn1::write_single(pkeyinfo.get_oid()) {
+                    Ok(b) => b,
+                    Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+                }
+            }
+            _ => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        };
+        if !key.check_or_set_attr(attribute::from_bytes(
+            CKA_EC_PARAMS,
+            oid_encoded,
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+
+        /* RFC 8410 7: CurvePrivateKey ::= OCTET STRING */
+        let value =
+            match asn1::parse_single::<&[u8]>(pkeyinfo.get_private_key()) {
+                Ok(v) => v,
+                Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+            };
+        if !key.check_or_set_attr(attribute::from_bytes(
+            CKA_VALUE,
+            value.to_vec(),
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+
+        ec_montgomery_import(&mut key)?;
+        set_public_key_info(&mut key)?;
+
+        Ok(key)
+    }
+}
+
+static PUBLIC_KEY_FACTORY: Lazy<Box<dyn ObjectFactory>> =
+    Lazy::new(|| Box::new(ECMontgomeryPubFactory::new()));
+
+static PRIVATE_KEY_FACTORY: Lazy<Box<dyn ObjectFactory>> =
+    Lazy::new(|| Box::new(ECMontgomeryPrivFactory::new()));
+
+pub fn register(_: &mut Mechanisms, ot: &mut ObjectFactories) {
+    ot.add_factory(
+        ObjectType::new(CKO_PUBLIC_KEY, CKK_EC_MONTGOMERY),
+        &PUBLIC_KEY_FACTORY,
+    );
+    ot.add_factory(
+        ObjectType::new(CKO_PRIVATE_KEY, CKK_EC_MONTGOMERY),
+        &PRIVATE_KEY_FACTORY,
+    );
+}
+
+include!("ossl/ec_montgomery.rs");
@@ -26,6 +26,9 @@ use crate::ecc_misc::*;
 pub const MIN_EC_SIZE_BITS: usize = 256;
 pub const MAX_EC_SIZE_BITS: usize = 521;
 
+pub const OID_EC_PUBLIC_KEY: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 10045, 2, 1);
+
 // ASN.1 encoding of the OID
 const OID_SECP256R1: asn1::ObjectIdentifier =
     asn1::oid!(1, 2, 840, 10045, 3, 1, 7);
@@ -117,7 +120,15 @@ fn curve_name_to_oid(
     })
 }
 
-#[cfg(feature = "fips")]
+/* The public key of both public and private key objects */
+fn set_public_key_info(key: &mut Object) -> Result<()> {
+    let params = match asn1::write_single(&get_oid_from_obj(key)?) {
+        Ok(p) => p,
+        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    };
+    set_ec_public_key_info(key, OID_EC_PUBLIC_KEY, Some(params.as_slice()))
+}
+
 pub fn ec_key_curve_size(key: &Object) -> Result<usize> {
     let x = match key.get_attr_as_bytes(CKA_EC_PARAMS) {
         Ok(b) => b,
@@ -157,10 +168,11 @@ impl ECCPubFactory {
 
 impl ObjectFactory for ECCPubFactory {
     fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
-        let obj = self.default_object_create(template)?;
+        let mut obj = self.default_object_create(template)?;
 
         bytes_attr_not_empty!(obj; CKA_EC_PARAMS);
         bytes_attr_not_empty!(obj; CKA_EC_POINT);
+        set_public_key_info(&mut obj)?;
 
         Ok(obj)
     }
@@ -168,11 +180,47 @@ impl ObjectFactory for ECCPubFactory {
     fn get_attributes(&self) -> &Vec<ObjectAttr> {
         &self.attributes
     }
+
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        PubKeyFactory::import_from_public_key_info(self, data, template)
+    }
 }
 
 impl CommonKeyFactory for ECCPubFactory {}
 
-impl PubKeyFactory for ECCPubFactory {}
+impl PubKeyFactory for ECCPubFactory {
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let spki = match asn1::parse_single::<kasn1::SubjectPublicKeyInfo>(data)
+        {
+            Ok(k) => k,
+            Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        if spki.get_oid() != &OID_EC_PUBLIC_KEY {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let params = match spki.get_parameters() {
+            Some(p) => p,
+            None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        /* CKA_EC_POINT is the DER encoded octet string */
+        let point = match asn1::write_single(&spki.get_public_key()) {
+            Ok(p) => p,
+            Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+        };
+        let mut tmpl = CkAttrs::from(template);
+        tmpl.add_owned_slice(CKA_EC_PARAMS, params)?;
+        tmpl.add_vec(CKA_EC_POINT, point)?;
+        self.create(tmpl.as_slice())
+    }
+}
 
 #[derive(Debug)]
 pub struct ECCPrivFactory {
@@ -191,6 +239,7 @@ impl ECCPrivFactory {
         data.attributes
             .append(&mut data.init_common_private_key_attrs());
         data.attributes.push(attr_element!(CKA_EC_PARAMS; OAFlags::RequiredOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_EC_POINT; OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
         data.attributes.push(attr_element!(CKA_VALUE; OAFlags::Sensitive | OAFlags::RequiredOnCreate | OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
 
         /* default to private */
@@ -213,6 +262,7 @@ impl ObjectFactory for ECCPrivFactory {
         let mut obj = self.default_object_create(template)?;
 
         ecc_import(&mut obj)?;
+        set_public_key_info(&mut obj)?;
 
         Ok(obj)
     }
@@ -259,14 +309,21 @@ impl PrivKeyFactory for ECCPrivFactory {
             Ok(o) => o,
             _ => return err_rv!(CKR_GENERAL_ERROR),
         };
+        let params = match asn1::write_single(&oid) {
+            Ok(p) => p,
+            _ => return err_rv!(CKR_GENERAL_ERROR),
+        };
         let ecpkey_asn1 = match asn1::write_single(&ECPrivateKey::new_owned(
             key.get_attr_as_bytes(CKA_VALUE)?,
         )?) {
             Ok(p) => p,
             _ => return err_rv!(CKR_GENERAL_ERROR),
         };
-        let pkeyinfo =
-            kasn1::PrivateKeyInfo::new(&ecpkey_asn1.as_slice(), oid)?;
+        let pkeyinfo = kasn1::PrivateKeyInfo::new(
+            &ecpkey_asn1.as_slice(),
+            OID_EC_PUBLIC_KEY,
+            Some(params.as_slice()),
+        )?;
 
         match asn1::write_single(&pkeyinfo) {
             Ok(x) => Ok(x),
@@ -301,15 +358,22 @@ impl PrivKeyFactory for ECCPrivFactory {
         if !extra.iter().all(|b| *b == 0) {
             return err_rv!(CKR_WRAPPED_KEY_INVALID);
         }
-        let pkeyinfo = match tlv.parse::<kasn1::PrivateKeyInfo>() {
-            Ok(k) => k,
-            Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        let pkeyinfo = kasn1::PrivateKeyInfo::parse_wrapped(tlv.full_data())?;
+        if pkeyinfo.get_oid() != &OID_EC_PUBLIC_KEY {
+            return err_rv!(CKR_WRAPPED_KEY_INVALID);
+        }
+        let curve = match pkeyinfo.get_parameters() {
+            Some(p) => match asn1::parse_single::<asn1::ObjectIdentifier>(p) {
+                Ok(o) => o,
+                Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+            },
+            None => return err_rv!(CKR_WRAPPED_KEY_INVALID),
         };
         /* filter out unknown OIDs */
-        let oid = match pkeyinfo.get_oid() {
-            &OID_SECP521R1 => OID_SECP256R1,
-            &OID_SECP384R1 => OID_SECP384R1,
-            &OID_SECP256R1 => OID_SECP521R1,
+        let oid = match curve {
+            OID_SECP256R1 => OID_SECP256R1,
+            OID_SECP384R1 => OID_SECP384R1,
+            OID_SECP521R1 => OID_SECP521R1,
             _ => return err_rv!(CKR_WRAPPED_KEY_INVALID),
         };
         let oid_encoded = match asn1::write_single(&oid) {
@@ -338,6 +402,27 @@ impl PrivKeyFactory for ECCPrivFactory {
             return err_rv!(CKR_TEMPLATE_INCONSISTENT);
         }
 
+        /* use the public point if it was stored alongside the key */
+        let public_key = match ecpkey.get_public_key() {
+            Some(p) => Some(p),
+            None => pkeyinfo.get_public_key(),
+        };
+        if let Some(point) = public_key {
+            let point_encoded = match asn1::write_single(&point) {
+                Ok(b) => b,
+                Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+            };
+            if !key.check_or_set_attr(attribute::from_bytes(
+                CKA_EC_POINT,
+                point_encoded,
+            ))? {
+                return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+            }
+        }
+
+        ecc_import(&mut key)?;
+        set_public_key_info(&mut key)?;
+
         Ok(key)
     }
 }
@@ -449,6 +534,10 @@ impl Mechanism for EccMechanism {
         }
 
         EccOperation::generate_keypair(&mut pubkey, &mut privkey)?;
+        let ec_point = pubkey.get_attr_as_bytes(CKA_EC_POINT)?.clone();
+        privkey.set_attr(attribute::from_bytes(CKA_EC_POINT, ec_point))?;
+        set_public_key_info(&mut pubkey)?;
+        set_public_key_info(&mut privkey)?;
         object::default_key_attributes(&mut privkey, mech.mechanism)?;
         object::default_key_attributes(&mut pubkey, mech.mechanism)?;
 
@@ -1,11 +1,21 @@
 // Copyright 2023 - 2024 Simo Sorce, Jakub Jelen
 // See LICENSE.txt file for terms
 
+use super::attribute::from_bytes;
 use super::error::Result;
+use super::interface::*;
 use super::kasn1;
+use super::object::{self, Object};
+use super::{bytes_attr_not_empty, err_rv};
 
 type Version = u64;
 
+// ASN.1 encoding of the OIDs of RFC 8410 keys
+pub const OID_X25519: asn1::ObjectIdentifier = asn1::oid!(1, 3, 101, 110);
+pub const OID_X448: asn1::ObjectIdentifier = asn1::oid!(1, 3, 101, 111);
+pub const OID_ED25519: asn1::ObjectIdentifier = asn1::oid!(1, 3, 101, 112);
+pub const OID_ED448: asn1::ObjectIdentifier = asn1::oid!(1, 3, 101, 113);
+
 #[derive(asn1::Asn1Read, asn1::Asn1Write)]
 pub enum ECParameters<'a> {
     // ecParametdders   ECParameters,
@@ -34,4 +44,69 @@ impl ECPrivateKey<'_> {
             public_key: None,
         })
     }
+
+    pub fn get_public_key(&self) -> Option<&[u8]> {
+        match &self.public_key {
+            Some(p) => Some(p.as_bytes()),
+            None => None,
+        }
+    }
+}
+
+/// Get the size in bits of the curve named in CKA_EC_PARAMS, the
+/// callers map the curve OIDs and names they support
+pub fn make_bits_from_ec_params(
+    key: &Object,
+    oid_to_bits: fn(asn1::ObjectIdentifier) -> Result<usize>,
+    curve_name_to_bits: fn(asn1::PrintableString) -> Result<usize>,
+) -> Result<usize> {
+    let x = match key.get_attr_as_bytes(CKA_EC_PARAMS) {
+        Ok(b) => b,
+        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    };
+    match asn1::parse_single::<ECParameters>(x) {
+        Ok(ECParameters::OId(o)) => oid_to_bits(o),
+        Ok(ECParameters::CurveName(c)) => curve_name_to_bits(c),
+        _ => err_rv!(CKR_GENERAL_ERROR),
+    }
+}
+
+/// DER encode an EC point, as stored in CKA_EC_POINT
+pub fn ec_point_to_der(point: &[u8]) -> Result<Vec<u8>> {
+    match asn1::write_single(&point) {
+        Ok(b) => Ok(b),
+        Err(_) => err_rv!(CKR_GENERAL_ERROR),
+    }
+}
+
+/// Check an imported private key and fill its CKA_EC_POINT, when
+/// missing, with the point computed from the private value
+pub fn ec_key_import(
+    obj: &mut Object,
+    public_point: fn(&Object) -> Result<Vec<u8>>,
+) -> Result<()> {
+    bytes_attr_not_empty!(obj; CKA_EC_PARAMS);
+    bytes_attr_not_empty!(obj; CKA_VALUE);
+    if obj.get_attr(CKA_EC_POINT).is_none() {
+        let point = ec_point_to_der(&public_point(obj)?)?;
+        obj.set_attr(from_bytes(CKA_EC_POINT, point))?;
+    }
+    Ok(())
+}
+
+/// Set the public key info of both public and private key objects,
+/// points that are not DER encoded are left without public key info
+pub fn set_ec_public_key_info(
+    key: &mut Object,
+    algorithm: asn1::ObjectIdentifier,
+    parameters: Option<&[u8]>,
+) -> Result<()> {
+    let point = match key.get_attr_as_bytes(CKA_EC_POINT) {
+        Ok(p) => match asn1::parse_single::<&[u8]>(p) {
+            Ok(o) => o.to_vec(),
+            Err(_) => return Ok(()),
+        },
+        Err(_) => return Ok(()),
+    };
+    object::set_public_key_info(key, algorithm, parameters, &point)
 }
@@ -4,10 +4,11 @@
 use super::attribute;
 use super::error;
 use super::interface;
+use super::kasn1;
 use super::object;
 use super::{attr_element, bytes_attr_not_empty, bytes_to_vec, err_rv};
 
-use attribute::{from_bool, from_bytes};
+use attribute::{from_bool, from_bytes, CkAttrs};
 use error::Result;
 use interface::*;
 use object::{
@@ -26,10 +27,6 @@ const BITS_ED448: usize = 448;
 pub const MIN_EDDSA_SIZE_BITS: usize = BITS_ED25519;
 pub const MAX_EDDSA_SIZE_BITS: usize = BITS_ED448;
 
-// ASN.1 encoding of the OID
-const OID_ED25519: asn1::ObjectIdentifier = asn1::oid!(1, 3, 101, 112);
-const OID_ED448: asn1::ObjectIdentifier = asn1::oid!(1, 3, 101, 113);
-
 // ASN.1 encoding of the curve name
 const STRING_ED25519: &[u8] = &[
     0x13, 0x0c, 0x65, 0x64, 0x77, 0x61, 0x72, 0x64, 0x73, 0x32, 0x35, 0x35,
@@ -59,6 +56,17 @@ fn curve_name_to_bits(name: asn1::PrintableString) -> Result<usize> {
     }
 }
 
+/* The public key of both public and private key objects */
+fn set_public_key_info(key: &mut Object) -> Result<()> {
+    let oid =
+        match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits)? {
+            BITS_ED25519 => OID_ED25519,
+            BITS_ED448 => OID_ED448,
+            _ => return err_rv!(CKR_GENERAL_ERROR),
+        };
+    set_ec_public_key_info(key, oid, None)
+}
+
 #[derive(Debug)]
 pub struct EDDSAPubFactory {
     attributes: Vec<ObjectAttr>,
@@ -83,10 +91,11 @@ impl EDDSAPubFactory {
 
 impl ObjectFactory for EDDSAPubFactory {
     fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
-        let obj = self.default_object_create(template)?;
+        let mut obj = self.default_object_create(template)?;
 
         bytes_attr_not_empty!(obj; CKA_EC_PARAMS);
         bytes_attr_not_empty!(obj; CKA_EC_POINT);
+        set_public_key_info(&mut obj)?;
 
         Ok(obj)
     }
@@ -94,11 +103,49 @@ impl ObjectFactory for EDDSAPubFactory {
     fn get_attributes(&self) -> &Vec<ObjectAttr> {
         &self.attributes
     }
+
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        PubKeyFactory::import_from_public_key_info(self, data, template)
+    }
 }
 
 impl CommonKeyFactory for EDDSAPubFactory {}
 
-impl PubKeyFactory for EDDSAPubFactory {}
+impl PubKeyFactory for EDDSAPubFactory {
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let spki = match asn1::parse_single::<kasn1::SubjectPublicKeyInfo>(data)
+        {
+            Ok(k) => k,
+            Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        let params = match spki.get_oid() {
+            &OID_ED25519 | &OID_ED448 => {
+                match asn1::write_single(spki.get_oid()) {
+                    Ok(p) => p,
+                    Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+                }
+            }
+            _ => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        /* CKA_EC_POINT is the DER encoded octet string */
+        let point = match asn1::write_single(&spki.get_public_key()) {
+            Ok(p) => p,
+            Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+        };
+        let mut tmpl = CkAttrs::from(template);
+        tmpl.add_vec(CKA_EC_PARAMS, params)?;
+        tmpl.add_vec(CKA_EC_POINT, point)?;
+        self.create(tmpl.as_slice())
+    }
+}
 
 #[derive(Debug)]
 pub struct EDDSAPrivFactory {
@@ -117,6 +164,7 @@ impl EDDSAPrivFactory {
         data.attributes
             .append(&mut data.init_common_private_key_attrs());
         data.attributes.push(attr_element!(CKA_EC_PARAMS; OAFlags::RequiredOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_EC_POINT; OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
         data.attributes.push(attr_element!(CKA_VALUE; OAFlags::Sensitive | OAFlags::RequiredOnCreate | OAFlags::SettableOnlyOnCreate | OAFlags::Unchangeable; from_bytes; val Vec::new()));
 
         /* default to private */
@@ -139,6 +187,7 @@ impl ObjectFactory for EDDSAPrivFactory {
         let mut obj = self.default_object_create(template)?;
 
         eddsa_import(&mut obj)?;
+        set_public_key_info(&mut obj)?;
 
         Ok(obj)
     }
@@ -162,7 +211,71 @@ impl ObjectFactory for EDDSAPrivFactory {
 
 impl CommonKeyFactory for EDDSAPrivFactory {}
 
-impl PrivKeyFactory for EDDSAPrivFactory {}
+impl PrivKeyFactory for EDDSAPrivFactory {
+    fn import_from_wrapped(
+        &self,
+        data: Vec<u8>,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let mut key = self.default_object_unwrap(template)?;
+
+        if !key.check_or_set_attr(attribute::from_ulong(
+            CKA_CLASS,
+            CKO_PRIVATE_KEY,
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+        if !key.check_or_set_attr(attribute::from_ulong(
+            CKA_KEY_TYPE,
+            CKK_EC_EDWARDS,
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+
+        let (tlv, extra) = match asn1::strip_tlv(&data) {
+            Ok(x) => x,
+            Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        };
+        /* Some Key Wrapping algorithms may 0 pad to match block size */
+        if !extra.iter().all(|b| *b == 0) {
+            return err_rv!(CKR_WRAPPED_KEY_INVALID);
+        }
+        let pkeyinfo = kasn1::PrivateKeyInfo::parse_wrapped(tlv.full_data())?;
+        let oid_encoded = match pkeyinfo.get_oid() {
+            &OID_ED25519 | &OID_ED448 => {
+                match asn1::write_single(pkeyinfo.get_oid()) {
+                    Ok(b) => b,
+                    Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+                }
+            }
+            _ => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        };
+        if !key.check_or_set_attr(attribute::from_bytes(
+            CKA_EC_PARAMS,
+            oid_encoded,
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+
+        /* RFC 8410 7: CurvePrivateKey ::= OCTET STRING */
+        let value =
+            match asn1::parse_single::<&[u8]>(pkeyinfo.get_private_key()) {
+                Ok(v) => v,
+                Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+            };
+        if !key.check_or_set_attr(attribute::from_bytes(
+            CKA_VALUE,
+            value.to_vec(),
+        ))? {
+            return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+        }
+
+        eddsa_import(&mut key)?;
+        set_public_key_info(&mut key)?;
+
+        Ok(key)
+    }
+}
 
 static PUBLIC_KEY_FACTORY: Lazy<Box<dyn ObjectFactory>> =
     Lazy::new(|| Box::new(EDDSAPubFactory::new()));
@@ -259,6 +372,10 @@ impl Mechanism for EddsaMechanism {
         }
 
         EddsaOperation::generate_keypair(&mut pubkey, &mut privkey)?;
+        let ec_point = pubkey.get_attr_as_bytes(CKA_EC_POINT)?.clone();
+        privkey.set_attr(attribute::from_bytes(CKA_EC_POINT, ec_point))?;
+        set_public_key_info(&mut pubkey)?;
+        set_public_key_info(&mut privkey)?;
         object::default_key_attributes(&mut privkey, mech.mechanism)?;
         object::default_key_attributes(&mut pubkey, mech.mechanism)?;
 
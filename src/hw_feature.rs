@@ -0,0 +1,113 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* CKO_HW_FEATURE objects: the token clock and a monotonic counter.
+ *
+ * Both are synthesized in memory and their CKA_VALUE is computed when
+ * it is read. The counter value itself is kept in storage, where it
+ * survives C_InitToken unless the SO has set CKA_RESET_ON_INIT. */
+
+use super::attribute;
+use super::error;
+use super::interface;
+use super::mechanism;
+use super::object;
+use super::{attr_element, err_rv};
+
+use attribute::{from_bool, from_bytes, from_string, from_ulong};
+use error::Result;
+use interface::*;
+use mechanism::Mechanisms;
+use object::{
+    OAFlags, Object, ObjectAttr, ObjectFactories, ObjectFactory, ObjectType,
+    SynthesizedFactory,
+};
+
+use once_cell::sync::Lazy;
+
+const COUNTER_SIZE: usize = 8;
+
+fn hw_feature_object(feature: CK_HW_FEATURE_TYPE) -> Result<Object> {
+    let mut obj = object::synthesized_object(CKO_HW_FEATURE)?;
+    obj.set_attr(from_ulong(CKA_HW_FEATURE_TYPE, feature))?;
+    Ok(obj)
+}
+
+/* The value is filled in when read */
+pub fn clock_object() -> Result<Object> {
+    let mut obj = hw_feature_object(CKH_CLOCK)?;
+    obj.set_attr(from_bytes(CKA_VALUE, vec![b'0'; 16]))?;
+    Ok(obj)
+}
+
+/* The value and flags are filled in from storage when read */
+pub fn counter_object() -> Result<Object> {
+    let mut obj = hw_feature_object(CKH_MONOTONIC_COUNTER)?;
+    obj.set_attr(from_bytes(CKA_VALUE, vec![0u8; COUNTER_SIZE]))?;
+    obj.set_attr(from_bool(CKA_RESET_ON_INIT, false))?;
+    obj.set_attr(from_bool(CKA_HAS_RESET, false))?;
+    Ok(obj)
+}
+
+/* The persistent counter as kept in storage */
+pub fn new_stored_counter(uid: &str) -> Result<Object> {
+    let mut obj = Object::new();
+    obj.set_attr(from_string(CKA_UNIQUE_ID, uid.to_string()))?;
+    obj.set_attr(from_bool(CKA_TOKEN, true))?;
+    obj.set_attr(from_ulong(CKA_CLASS, CKO_HW_FEATURE))?;
+    obj.set_attr(from_ulong(CKA_HW_FEATURE_TYPE, CKH_MONOTONIC_COUNTER))?;
+    obj.set_attr(from_bytes(CKA_VALUE, vec![0u8; COUNTER_SIZE]))?;
+    obj.set_attr(from_bool(CKA_RESET_ON_INIT, false))?;
+    obj.set_attr(from_bool(CKA_HAS_RESET, false))?;
+    Ok(obj)
+}
+
+pub fn increment_counter(obj: &mut Object) -> Result<()> {
+    let value = obj.get_attr_as_bytes(CKA_VALUE)?;
+    if value.len() != COUNTER_SIZE {
+        return err_rv!(CKR_DEVICE_ERROR);
+    }
+    let mut counter = [0u8; COUNTER_SIZE];
+    counter.copy_from_slice(value.as_slice());
+    let next = match u64::from_be_bytes(counter).checked_add(1) {
+        Some(n) => n,
+        None => return err_rv!(CKR_DEVICE_ERROR),
+    };
+    obj.set_attr(from_bytes(CKA_VALUE, next.to_be_bytes().to_vec()))
+}
+
+/* Applies a C_InitToken to the stored counter, the counter is reset
+ * only if requested, and the request is honored only once */
+pub fn init_counter(obj: &mut Object) -> Result<()> {
+    if obj.get_attr_as_bool(CKA_RESET_ON_INIT)? {
+        obj.set_attr(from_bytes(CKA_VALUE, vec![0u8; COUNTER_SIZE]))?;
+        obj.set_attr(from_bool(CKA_HAS_RESET, true))?;
+        obj.set_attr(from_bool(CKA_RESET_ON_INIT, false))?;
+    }
+    Ok(())
+}
+
+static HW_FEATURE_FACTORY: Lazy<Box<dyn ObjectFactory>> = Lazy::new(|| {
+    Box::new(SynthesizedFactory::new(vec![
+        attr_element!(
+            CKA_HW_FEATURE_TYPE;
+            OAFlags::AlwaysRequired | OAFlags::NeverSettable
+            | OAFlags::Unchangeable; from_ulong; val 0),
+        attr_element!(
+            CKA_VALUE;
+            OAFlags::NeverSettable | OAFlags::Unchangeable;
+            from_bytes; val Vec::new()),
+        attr_element!(
+            CKA_RESET_ON_INIT;
+            OAFlags::NeverSettable | OAFlags::Unchangeable;
+            from_bool; val false),
+        attr_element!(
+            CKA_HAS_RESET;
+            OAFlags::NeverSettable | OAFlags::Unchangeable;
+            from_bool; val false),
+    ]))
+});
+
+pub fn register(_: &mut Mechanisms, ot: &mut ObjectFactories) {
+    ot.add_factory(ObjectType::new(CKO_HW_FEATURE, 0), &HW_FEATURE_FACTORY);
+}
@@ -2,11 +2,16 @@
 // See LICENSE.txt file for terms
 
 // Helper routines to use with rust/asn1
+use super::ecc;
+use super::ecc_misc;
 use super::err_rv;
 use super::error;
 use super::interface;
+use super::rsa;
 
 use asn1;
+use data_encoding::BASE64;
+use ecc_misc::{OID_ED25519, OID_ED448, OID_X25519, OID_X448};
 use error::Result;
 use interface::*;
 use std::borrow::Cow;
@@ -146,33 +151,462 @@ struct Attribute<'a> {
 
 type Attributes<'a> = asn1::SetOf<'a, Attribute<'a>>;
 
+/* RFC 5280 4.1.1.2 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct AlgorithmIdentifier<'a> {
+    algorithm: asn1::ObjectIdentifier,
+    parameters: Option<asn1::Tlv<'a>>,
+}
+
+impl<'a> AlgorithmIdentifier<'a> {
+    /* The parameters, if any, are passed DER encoded */
+    fn new(
+        oid: asn1::ObjectIdentifier,
+        parameters: Option<&'a [u8]>,
+    ) -> Result<AlgorithmIdentifier<'a>> {
+        let parameters = match parameters {
+            Some(p) => match asn1::parse_single::<asn1::Tlv>(p) {
+                Ok(tlv) => Some(tlv),
+                Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+            },
+            None => None,
+        };
+        Ok(AlgorithmIdentifier {
+            algorithm: oid,
+            parameters: parameters,
+        })
+    }
+
+    fn get_parameters(&self) -> Option<&'a [u8]> {
+        match &self.parameters {
+            Some(p) => Some(p.full_data()),
+            None => None,
+        }
+    }
+}
+
+/* RFC 5958 2 */
 #[derive(asn1::Asn1Read, asn1::Asn1Write)]
 pub struct PrivateKeyInfo<'a> {
     version: Version,
-    private_key_algorithm: asn1::ObjectIdentifier,
+    private_key_algorithm: AlgorithmIdentifier<'a>,
     private_key: DerEncOctetString<'a>,
-    #[explicit(1)]
+    #[implicit(0)]
     attributes: Option<Attributes<'a>>,
+    #[implicit(1)]
+    public_key: Option<asn1::BitString<'a>>,
 }
 
-impl PrivateKeyInfo<'_> {
-    pub fn new<'a>(
+impl<'a> PrivateKeyInfo<'a> {
+    pub fn new(
         private_key_asn1: &'a [u8],
         oid: asn1::ObjectIdentifier,
+        parameters: Option<&'a [u8]>,
     ) -> Result<PrivateKeyInfo<'a>> {
         Ok(PrivateKeyInfo {
             version: 0,
-            private_key_algorithm: oid,
+            private_key_algorithm: AlgorithmIdentifier::new(oid, parameters)?,
             private_key: DerEncOctetString::new(private_key_asn1)?,
             attributes: None,
+            public_key: None,
         })
     }
 
     pub fn get_oid(&self) -> &asn1::ObjectIdentifier {
-        &self.private_key_algorithm
+        &self.private_key_algorithm.algorithm
+    }
+
+    pub fn get_parameters(&self) -> Option<&'a [u8]> {
+        self.private_key_algorithm.get_parameters()
     }
 
     pub fn get_private_key(&self) -> &[u8] {
         &self.private_key.as_bytes()
     }
+
+    pub fn get_public_key(&self) -> Option<&[u8]> {
+        match &self.public_key {
+            Some(p) => Some(p.as_bytes()),
+            None => None,
+        }
+    }
+
+    /* Parses a private key unwrapped by C_UnwrapKey, accepting also the
+     * layout of keys wrapped by older versions of this token */
+    pub fn parse_wrapped(data: &'a [u8]) -> Result<PrivateKeyInfo<'a>> {
+        if let Ok(pki) = asn1::parse_single::<PrivateKeyInfo>(data) {
+            return Ok(pki);
+        }
+        let legacy = match asn1::parse_single::<LegacyPrivateKeyInfo>(data) {
+            Ok(l) => l,
+            Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        };
+        let oid = match legacy.private_key_algorithm.parse() {
+            Ok(o) => o,
+            Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
+        };
+        let algorithm = match oid {
+            rsa::OID_RSA_ENCRYPTION => AlgorithmIdentifier {
+                algorithm: oid,
+                parameters: None,
+            },
+            /* EC keys stored the curve in place of the algorithm */
+            _ => AlgorithmIdentifier {
+                algorithm: ecc::OID_EC_PUBLIC_KEY,
+                parameters: Some(legacy.private_key_algorithm),
+            },
+        };
+        Ok(PrivateKeyInfo {
+            version: legacy.version,
+            private_key_algorithm: algorithm,
+            private_key: legacy.private_key,
+            attributes: legacy.attributes,
+            public_key: None,
+        })
+    }
+}
+
+/* Up to the introduction of key data imports, wrapped private keys were
+ * exported with a bare OID in place of the AlgorithmIdentifier and with
+ * the attributes tagged [1] EXPLICIT. This is not RFC 5958 and other
+ * implementations can't unwrap these keys; the layout is only accepted
+ * as input so that keys wrapped by older versions can still be
+ * unwrapped, nothing is exported in this format anymore */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct LegacyPrivateKeyInfo<'a> {
+    version: Version,
+    private_key_algorithm: asn1::Tlv<'a>,
+    private_key: DerEncOctetString<'a>,
+    #[explicit(1)]
+    attributes: Option<Attributes<'a>>,
+}
+
+/* RFC 5958 3 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+pub struct EncryptedPrivateKeyInfo<'a> {
+    encryption_algorithm: AlgorithmIdentifier<'a>,
+    encrypted_data: &'a [u8],
+}
+
+/* RFC 8018 A.2 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct Pbkdf2Params<'a> {
+    salt: &'a [u8],
+    iteration_count: u64,
+    key_length: Option<u64>,
+    prf: Option<AlgorithmIdentifier<'a>>,
+}
+
+/* RFC 8018 A.4 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct Pbes2Params<'a> {
+    key_derivation_func: AlgorithmIdentifier<'a>,
+    encryption_scheme: AlgorithmIdentifier<'a>,
+}
+
+const OID_PBES2: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 1, 5, 13);
+const OID_PBKDF2: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 1, 5, 12);
+const OID_HMAC_WITH_SHA1: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 7);
+const OID_HMAC_WITH_SHA224: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 8);
+const OID_HMAC_WITH_SHA256: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 9);
+const OID_HMAC_WITH_SHA384: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 10);
+const OID_HMAC_WITH_SHA512: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 11);
+const OID_AES128_CBC: asn1::ObjectIdentifier =
+    asn1::oid!(2, 16, 840, 1, 101, 3, 4, 1, 2);
+const OID_AES192_CBC: asn1::ObjectIdentifier =
+    asn1::oid!(2, 16, 840, 1, 101, 3, 4, 1, 22);
+const OID_AES256_CBC: asn1::ObjectIdentifier =
+    asn1::oid!(2, 16, 840, 1, 101, 3, 4, 1, 42);
+
+/* The PBES2 parameters, limited to PBKDF2 and AES-CBC */
+pub struct Pbes2<'a> {
+    pub salt: &'a [u8],
+    pub iterations: u64,
+    pub prf: CK_ULONG,
+    pub key_len: usize,
+    pub iv: &'a [u8],
+}
+
+fn parse_parameters<'a, T: asn1::Asn1Readable<'a>>(
+    algorithm: &AlgorithmIdentifier<'a>,
+) -> Result<T> {
+    match algorithm.get_parameters() {
+        Some(p) => match asn1::parse_single::<T>(p) {
+            Ok(t) => Ok(t),
+            Err(_) => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        },
+        None => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+    }
+}
+
+impl<'a> EncryptedPrivateKeyInfo<'a> {
+    pub fn get_pbes2(&self) -> Result<Pbes2<'a>> {
+        if self.encryption_algorithm.algorithm != OID_PBES2 {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let pbes2: Pbes2Params = parse_parameters(&self.encryption_algorithm)?;
+        if pbes2.key_derivation_func.algorithm != OID_PBKDF2 {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let kdf: Pbkdf2Params = parse_parameters(&pbes2.key_derivation_func)?;
+        let prf = match &kdf.prf {
+            None => CKP_PKCS5_PBKD2_HMAC_SHA1,
+            Some(a) => match a.algorithm {
+                OID_HMAC_WITH_SHA1 => CKP_PKCS5_PBKD2_HMAC_SHA1,
+                OID_HMAC_WITH_SHA224 => CKP_PKCS5_PBKD2_HMAC_SHA224,
+                OID_HMAC_WITH_SHA256 => CKP_PKCS5_PBKD2_HMAC_SHA256,
+                OID_HMAC_WITH_SHA384 => CKP_PKCS5_PBKD2_HMAC_SHA384,
+                OID_HMAC_WITH_SHA512 => CKP_PKCS5_PBKD2_HMAC_SHA512,
+                _ => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+            },
+        };
+        let key_len = match pbes2.encryption_scheme.algorithm {
+            OID_AES128_CBC => 16,
+            OID_AES192_CBC => 24,
+            OID_AES256_CBC => 32,
+            _ => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        match kdf.key_length {
+            Some(l) if l != key_len as u64 => {
+                return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID)
+            }
+            _ => (),
+        }
+        let iv: &[u8] = parse_parameters(&pbes2.encryption_scheme)?;
+        Ok(Pbes2 {
+            salt: kdf.salt,
+            iterations: kdf.iteration_count,
+            prf: prf,
+            key_len: key_len,
+            iv: iv,
+        })
+    }
+
+    pub fn get_encrypted_data(&self) -> &'a [u8] {
+        self.encrypted_data
+    }
+}
+
+fn pem_blocks(data: &[u8], max: usize) -> Result<Vec<Vec<u8>>> {
+    let text = match std::str::from_utf8(data) {
+        Ok(t) if t.contains("-----BEGIN ") => t,
+        _ => return Ok(vec![data.to_vec()]),
+    };
+    let mut blocks = Vec::<Vec<u8>>::new();
+    let mut b64 = String::new();
+    let mut inside = false;
+    for line in text.lines() {
+        let line = line.trim();
+        if !inside {
+            inside = line.starts_with("-----BEGIN ");
+            continue;
+        }
+        if line.starts_with("-----END ") {
+            let der = BASE64.decode(b64.as_bytes());
+            b64.zeroize();
+            match der {
+                Ok(d) => blocks.push(d),
+                Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+            }
+            if blocks.len() == max {
+                return Ok(blocks);
+            }
+            inside = false;
+            continue;
+        }
+        b64.push_str(line);
+    }
+    b64.zeroize();
+    if inside || blocks.len() == 0 {
+        return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+    }
+    Ok(blocks)
+}
+
+/* Returns the content of the first PEM block in data, or the data
+ * itself when it is not PEM encoded */
+pub fn pem_to_der(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(pem_blocks(data, 1)?.swap_remove(0))
+}
+
+/* Returns the content of all the PEM blocks in data, or the data
+ * itself when it is not PEM encoded */
+pub fn pem_to_der_all(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    pem_blocks(data, usize::MAX)
+}
+
+/* The key type of PKCS#8 and SubjectPublicKeyInfo algorithms */
+pub fn key_type(oid: &asn1::ObjectIdentifier) -> Result<CK_KEY_TYPE> {
+    match *oid {
+        rsa::OID_RSA_ENCRYPTION => Ok(CKK_RSA),
+        ecc::OID_EC_PUBLIC_KEY => Ok(CKK_EC),
+        OID_ED25519 | OID_ED448 => Ok(CKK_EC_EDWARDS),
+        OID_X25519 | OID_X448 => Ok(CKK_EC_MONTGOMERY),
+        _ => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+    }
+}
+
+/* RFC 5280 4.1 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+pub struct SubjectPublicKeyInfo<'a> {
+    algorithm: AlgorithmIdentifier<'a>,
+    subject_public_key: asn1::BitString<'a>,
+}
+
+impl<'a> SubjectPublicKeyInfo<'a> {
+    /* The parameters, if any, are passed DER encoded */
+    pub fn new(
+        oid: asn1::ObjectIdentifier,
+        parameters: Option<&'a [u8]>,
+        public_key: &'a [u8],
+    ) -> Result<SubjectPublicKeyInfo<'a>> {
+        let subject_public_key = match asn1::BitString::new(public_key, 0) {
+            Some(b) => b,
+            None => return err_rv!(CKR_GENERAL_ERROR),
+        };
+        Ok(SubjectPublicKeyInfo {
+            algorithm: AlgorithmIdentifier::new(oid, parameters)?,
+            subject_public_key: subject_public_key,
+        })
+    }
+
+    pub fn get_oid(&self) -> &asn1::ObjectIdentifier {
+        &self.algorithm.algorithm
+    }
+
+    pub fn get_parameters(&self) -> Option<&'a [u8]> {
+        self.algorithm.get_parameters()
+    }
+
+    pub fn get_public_key(&self) -> &'a [u8] {
+        self.subject_public_key.as_bytes()
+    }
+}
+
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct TbsCertificate<'a> {
+    #[explicit(0)]
+    version: Option<Version>,
+    serial_number: asn1::Tlv<'a>,
+    signature: asn1::Tlv<'a>,
+    issuer: asn1::Tlv<'a>,
+    validity: asn1::Tlv<'a>,
+    subject: asn1::Tlv<'a>,
+    subject_public_key_info: asn1::Tlv<'a>,
+    #[implicit(1)]
+    issuer_unique_id: Option<asn1::BitString<'a>>,
+    #[implicit(2)]
+    subject_unique_id: Option<asn1::BitString<'a>>,
+    #[explicit(3)]
+    extensions: Option<asn1::Tlv<'a>>,
+}
+
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+pub struct Certificate<'a> {
+    tbs_certificate: TbsCertificate<'a>,
+    signature_algorithm: asn1::Tlv<'a>,
+    signature_value: asn1::BitString<'a>,
+}
+
+impl Certificate<'_> {
+    pub fn parse<'a>(der: &'a [u8]) -> Result<Certificate<'a>> {
+        match asn1::parse_single::<Certificate>(der) {
+            Ok(c) => Ok(c),
+            Err(_) => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        }
+    }
+
+    /* The following return the full DER encoding, as PKCS#11 stores
+     * names and serial numbers */
+    pub fn get_serial_number(&self) -> &[u8] {
+        self.tbs_certificate.serial_number.full_data()
+    }
+
+    pub fn get_issuer(&self) -> &[u8] {
+        self.tbs_certificate.issuer.full_data()
+    }
+
+    pub fn get_subject(&self) -> &[u8] {
+        self.tbs_certificate.subject.full_data()
+    }
+
+    pub fn get_public_key_info(&self) -> &[u8] {
+        self.tbs_certificate.subject_public_key_info.full_data()
+    }
+
+    /* The content of the subjectPublicKey BIT STRING */
+    pub fn get_public_key(&self) -> Result<&[u8]> {
+        match self
+            .tbs_certificate
+            .subject_public_key_info
+            .parse::<SubjectPublicKeyInfo>()
+        {
+            Ok(spki) => Ok(spki.get_public_key()),
+            Err(_) => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        }
+    }
+}
+
+/* RFC 5755 4.1 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct AttributeCertificateInfo<'a> {
+    version: Version,
+    holder: asn1::Tlv<'a>,
+    issuer: asn1::Tlv<'a>,
+    signature: asn1::Tlv<'a>,
+    serial_number: asn1::Tlv<'a>,
+    attr_cert_validity_period: asn1::Tlv<'a>,
+    attributes: asn1::SequenceOf<'a, Attribute<'a>>,
+    issuer_unique_id: Option<asn1::BitString<'a>>,
+    extensions: Option<asn1::Tlv<'a>>,
+}
+
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+pub struct AttributeCertificate<'a> {
+    acinfo: AttributeCertificateInfo<'a>,
+    signature_algorithm: asn1::Tlv<'a>,
+    signature_value: asn1::BitString<'a>,
+}
+
+impl AttributeCertificate<'_> {
+    pub fn parse<'a>(der: &'a [u8]) -> Result<AttributeCertificate<'a>> {
+        match asn1::parse_single::<AttributeCertificate>(der) {
+            Ok(c) => Ok(c),
+            Err(_) => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        }
+    }
+
+    pub fn get_holder(&self) -> &[u8] {
+        self.acinfo.holder.full_data()
+    }
+
+    pub fn get_issuer(&self) -> &[u8] {
+        self.acinfo.issuer.full_data()
+    }
+
+    pub fn get_serial_number(&self) -> &[u8] {
+        self.acinfo.serial_number.full_data()
+    }
+
+    /* The DER encoded SEQUENCE OF the attribute types, as stored in
+     * CKA_ATTR_TYPES */
+    pub fn get_attr_types(&self) -> Result<Vec<u8>> {
+        let types = self
+            .acinfo
+            .attributes
+            .clone()
+            .map(|a| a.attribute_type)
+            .collect::<Vec<asn1::ObjectIdentifier>>();
+        match asn1::write_single(&asn1::SequenceOfWriter::new(types)) {
+            Ok(der) => Ok(der),
+            Err(_) => err_rv!(CKR_GENERAL_ERROR),
+        }
+    }
 }
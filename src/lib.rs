@@ -6,12 +6,11 @@ use std::collections::HashMap;
 use std::env;
 use std::ffi::CStr;
 use std::path::Path;
-use std::str::FromStr;
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use once_cell::sync::Lazy;
 
-mod interface {
+pub mod interface {
     #![allow(non_upper_case_globals)]
     #![allow(non_camel_case_types)]
     #![allow(non_snake_case)]
@@ -19,19 +18,28 @@ mod interface {
     include!("pkcs11/interface.rs");
 }
 
+mod archive;
 mod attribute;
+mod config;
 mod error;
 mod mechanism;
+pub mod migration;
 mod object;
+mod policy;
+mod profile;
 mod rng;
 mod session;
 mod slot;
 mod storage;
 mod token;
+mod trust;
 
+use attribute::CkAttrs;
+use config::{Config, CONFIG_FILE_NAME};
 use error::Result;
 use interface::*;
 use mechanism::Operation;
+use policy::Policy;
 use rng::RNG;
 use session::Session;
 use slot::Slot;
@@ -46,6 +54,8 @@ mod ossl;
 
 mod aes;
 mod drbg;
+#[cfg(not(feature = "fips"))]
+mod ec_montgomery;
 mod ecc;
 mod ecc_misc;
 #[cfg(not(feature = "fips"))]
@@ -53,6 +63,7 @@ mod eddsa;
 mod hash;
 mod hkdf;
 mod hmac;
+mod hw_feature;
 mod pbkdf2;
 mod rsa;
 mod sp800_108;
@@ -60,7 +71,7 @@ mod sshkdf;
 mod tlskdf;
 
 /* Helper code */
-mod kasn1;
+pub mod kasn1;
 mod misc;
 
 macro_rules! ret_to_rv {
@@ -186,6 +197,21 @@ impl State {
         CKR_OK
     }
 
+    /* Drops all slots except the listed ones */
+    fn remove_other_slots(&mut self, keep: &[CK_SLOT_ID]) {
+        let others: Vec<CK_SLOT_ID> = self
+            .slots
+            .keys()
+            .filter(|id| !keep.contains(id))
+            .cloned()
+            .collect();
+        for id in others {
+            if let Some(mut slot) = self.slots.remove(&id) {
+                let _ = slot.finalize();
+            }
+        }
+    }
+
     fn get_session(
         &self,
         handle: CK_SESSION_HANDLE,
@@ -374,6 +400,22 @@ fn find_conf() -> Result<String> {
         Ok(var) => return Ok(var),
         Err(_) => (),
     }
+    /* Then look for a configuration file in the Freedesktop config
+     * dir, falling back to $HOME/.config */
+    let conffile = match env::var("XDG_CONFIG_HOME") {
+        Ok(xdg) => Some(format!("{}/kryoptic/{}", xdg, CONFIG_FILE_NAME)),
+        Err(_) => match env::var("HOME") {
+            Ok(home) => {
+                Some(format!("{}/.config/kryoptic/{}", home, CONFIG_FILE_NAME))
+            }
+            Err(_) => None,
+        },
+    };
+    if let Some(conf) = conffile {
+        if Path::new(&conf).is_file() {
+            return Ok(conf);
+        }
+    }
     /* Freedesktop specification for data dirs first
      * then fallback to use $HOME/.local/share, if that is also not
      * available see if we have access to a system store */
@@ -393,8 +435,59 @@ fn find_conf() -> Result<String> {
     }
 }
 
+fn add_configured_slot(
+    state: &mut State,
+    conf: &config::Slot,
+    system_policy: Option<&Policy>,
+) -> CK_RV {
+    let filename = conf.get_dbpath();
+    let slotnum = match conf.slot {
+        Some(n) => n,
+        None => {
+            /* if a slot num was not specified, pick the first free one,
+             * but ensure that the same file is not already open on an
+             * existing slot */
+            let mut slot_high: i64 = -1;
+            let slots = state.get_slots_ids();
+            for s in &slots {
+                let sn = cast_or_ret!(i64 from *s);
+                if sn > slot_high {
+                    slot_high = sn;
+                }
+                match state.get_token_from_slot(*s) {
+                    Ok(t) => {
+                        if filename.eq(t.get_filename()) {
+                            return CKR_CRYPTOKI_ALREADY_INITIALIZED;
+                        }
+                    }
+                    Err(_) => return CKR_GENERAL_ERROR,
+                }
+            }
+            cast_or_ret!(CK_SLOT_ID from slot_high + 1)
+        }
+    };
+
+    /* check that this slot was not already initialized with a different db */
+    match state.get_token_from_slot(slotnum) {
+        Ok(token) => {
+            if filename.eq(token.get_filename()) {
+                return CKR_CRYPTOKI_ALREADY_INITIALIZED;
+            } else {
+                return CKR_ARGUMENTS_BAD;
+            }
+        }
+        Err(e) => match e.rv() {
+            CKR_SLOT_ID_INVALID => (),
+            CKR_CRYPTOKI_NOT_INITIALIZED => (),
+            x => return x,
+        },
+    }
+
+    /* will initialize a memory only token if filename is empty */
+    state.add_slot(slotnum, res_or_ret!(Slot::new(conf, system_policy)))
+}
+
 extern "C" fn fn_initialize(_init_args: CK_VOID_PTR) -> CK_RV {
-    let mut slotnum: CK_SLOT_ID = 0;
     let conf: String;
 
     if _init_args.is_null() {
@@ -414,61 +507,30 @@ extern "C" fn fn_initialize(_init_args: CK_VOID_PTR) -> CK_RV {
         }
     }
 
-    let v: Vec<&str> = conf.split(':').collect();
-    if v.len() > 1 {
-        slotnum = match CK_SLOT_ID::from_str(v[1]) {
-            Ok(n) => n,
-            Err(_) => return CKR_ARGUMENTS_BAD,
-        };
-    }
-    let filename = v[0].to_string();
+    let config = res_or_ret!(Config::from_init_string(&conf));
+    let system_policy = res_or_ret!(config.get_system_policy());
 
     let mut wstate = global_wlock!(noinitcheck STATE);
-    if !wstate.is_initialized() {
+    let was_initialized = wstate.is_initialized();
+    if !was_initialized {
         wstate.initialize();
     }
 
-    /* if a slot num was not specified, pick the first free one,
-     * but ensure that the same file is not already open on an
-     * existing slot */
-    if v.len() == 1 {
-        let mut slot_high: i64 = -1;
-        let slots = wstate.get_slots_ids();
-        for s in &slots {
-            let sn = cast_or_ret!(i64 from *s);
-            if sn > slot_high {
-                slot_high = sn;
-            }
-            match wstate.get_token_from_slot(*s) {
-                Ok(t) => {
-                    if filename.eq(t.get_filename()) {
-                        return CKR_CRYPTOKI_ALREADY_INITIALIZED;
-                    }
-                }
-                Err(_) => return CKR_GENERAL_ERROR,
-            }
-        }
-        slotnum = cast_or_ret!(CK_SLOT_ID from slot_high + 1);
-    }
-
-    /* check that this slot was not already initialized with a different db */
-    match wstate.get_token_from_slot(slotnum) {
-        Ok(token) => {
-            if filename.eq(token.get_filename()) {
-                return CKR_CRYPTOKI_ALREADY_INITIALIZED;
+    /* either all the configured slots are added or none is */
+    let existing = wstate.get_slots_ids();
+    for slot in &config.slots {
+        let ret =
+            add_configured_slot(&mut wstate, slot, system_policy.as_ref());
+        if ret != CKR_OK {
+            if was_initialized {
+                wstate.remove_other_slots(&existing);
             } else {
-                return CKR_ARGUMENTS_BAD;
+                let _ = wstate.finalize();
             }
+            return ret;
         }
-        Err(e) => match e.rv() {
-            CKR_SLOT_ID_INVALID => (),
-            CKR_CRYPTOKI_NOT_INITIALIZED => (),
-            x => return x,
-        },
     }
-
-    /* will initialize a memory only token if filename is empty */
-    wstate.add_slot(slotnum, res_or_ret!(Slot::new(filename)))
+    CKR_OK
 }
 extern "C" fn fn_finalize(_reserved: CK_VOID_PTR) -> CK_RV {
     global_wlock!(STATE).finalize()
@@ -885,13 +947,15 @@ extern "C" fn fn_find_objects(
     }
     let rstate = global_rlock!(STATE);
     let mut session = res_or_ret!(rstate.get_session_mut(s_handle));
+    let slot_id = session.get_slot_id();
+    let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let operation = match res_or_ret!(session.get_operation_mut()) {
         Operation::Search(op) => op,
         Operation::Empty => return CKR_OPERATION_NOT_INITIALIZED,
         _ => return CKR_OPERATION_ACTIVE,
     };
     let moc = cast_or_ret!(usize from max_object_count => CKR_ARGUMENTS_BAD);
-    let handles = res_or_ret!(operation.results(moc));
+    let handles = res_or_ret!(operation.results(&mut token, moc));
     let hlen = handles.len();
     if hlen > 0 {
         let mut idx = 0;
@@ -939,7 +1003,45 @@ macro_rules! check_op_empty_or_fail {
     };
 }
 
-fn check_allowed_mechs(mech: &CK_MECHANISM, key: &object::Object) -> CK_RV {
+/* Additional derived keys are created from their own templates, so the
+ * derive template is applied on top of them. Boolean attributes are
+ * overridden, any other difference is a conflict */
+fn apply_derive_template(
+    obj: &mut object::Object,
+    derive_template: &[attribute::Attribute],
+    template: &[CK_ATTRIBUTE],
+) -> Result<()> {
+    for attr in derive_template {
+        /* values explicitly requested must not be contradicted */
+        for ck_attr in template {
+            if ck_attr.type_ == attr.get_type() && !attr.match_ck_attr(ck_attr)
+            {
+                return err_rv!(CKR_TEMPLATE_INCONSISTENT);
+            }
+        }
+        match obj.get_attr(attr.get_type()) {
+            Some(a) if a.get_value() == attr.get_value() => (),
+            Some(a) if a.get_attrtype() != attribute::AttrType::BoolType => {
+                return err_rv!(CKR_TEMPLATE_INCONSISTENT)
+            }
+            _ => obj.set_attr(attr.clone())?,
+        }
+    }
+    Ok(())
+}
+
+fn check_allowed_mechs(
+    mech: &CK_MECHANISM,
+    key: &object::Object,
+    token: &Token,
+    op: CK_FLAGS,
+) -> CK_RV {
+    /* the token policy applies regardless of the key restrictions */
+    let policy = token.get_policy();
+    ok_or_ret!(ret_to_rv!(policy.check_mechanism(mech, op)));
+    ok_or_ret!(ret_to_rv!(policy.check_key(key)));
+    ok_or_ret!(ret_to_rv!(policy.check_key_dates(key, op)));
+
     let allowed = match key.get_attr(CKA_ALLOWED_MECHANISMS) {
         Some(attr) => attr,
         None => return CKR_OK,
@@ -981,7 +1083,7 @@ extern "C" fn fn_encrypt_init(
     let slot_id = session.get_slot_id();
     let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let key = res_or_ret!(token.get_object_by_handle(key_handle));
-    ok_or_ret!(check_allowed_mechs(mechanism, &key));
+    ok_or_ret!(check_allowed_mechs(mechanism, &key, &token, CKF_ENCRYPT));
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
     if mech.info().flags & CKF_ENCRYPT == CKF_ENCRYPT {
         let operation = res_or_ret!(mech.encryption_new(mechanism, &key));
@@ -1118,7 +1220,7 @@ extern "C" fn fn_decrypt_init(
     let slot_id = session.get_slot_id();
     let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let key = res_or_ret!(token.get_object_by_handle(key_handle));
-    ok_or_ret!(check_allowed_mechs(mechanism, &key));
+    ok_or_ret!(check_allowed_mechs(mechanism, &key, &token, CKF_DECRYPT));
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
     if mech.info().flags & CKF_DECRYPT == CKF_DECRYPT {
         let operation = res_or_ret!(mech.decryption_new(mechanism, &key));
@@ -1407,7 +1509,7 @@ extern "C" fn fn_sign_init(
     let slot_id = session.get_slot_id();
     let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let key = res_or_ret!(token.get_object_by_handle(key_handle));
-    ok_or_ret!(check_allowed_mechs(mechanism, &key));
+    ok_or_ret!(check_allowed_mechs(mechanism, &key, &token, CKF_SIGN));
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
     if mech.info().flags & CKF_SIGN == CKF_SIGN {
         let operation = res_or_ret!(mech.sign_new(mechanism, &key));
@@ -1551,7 +1653,7 @@ extern "C" fn fn_verify_init(
     let slot_id = session.get_slot_id();
     let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let key = res_or_ret!(token.get_object_by_handle(key_handle));
-    ok_or_ret!(check_allowed_mechs(mechanism, &key));
+    ok_or_ret!(check_allowed_mechs(mechanism, &key, &token, CKF_VERIFY));
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
     if mech.info().flags & CKF_VERIFY == CKF_VERIFY {
         let operation = res_or_ret!(mech.verify_new(mechanism, &key));
@@ -1785,20 +1887,14 @@ extern "C" fn fn_generate_key_pair(
     let result = mech.generate_keypair(data, pubtmpl, pritmpl);
     match result {
         Ok((pubkey, privkey)) => {
-            let pubh = res_or_ret!(token.insert_object(s_handle, pubkey));
-            match token.insert_object(s_handle, privkey) {
-                Ok(privh) => {
-                    unsafe {
-                        core::ptr::write(public_key as *mut _, pubh);
-                        core::ptr::write(private_key as *mut _, privh);
-                    }
-                    CKR_OK
-                }
-                Err(e) => {
-                    let _ = token.destroy_object(pubh);
-                    e.rv()
-                }
+            let handles = res_or_ret!(
+                token.insert_objects(s_handle, vec![pubkey, privkey])
+            );
+            unsafe {
+                core::ptr::write(public_key as *mut _, handles[0]);
+                core::ptr::write(private_key as *mut _, handles[1]);
             }
+            CKR_OK
         }
         Err(e) => e.rv(),
     }
@@ -1820,7 +1916,7 @@ extern "C" fn fn_wrap_key(
     let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let key = res_or_ret!(token.get_object_by_handle(key_handle));
     let wkey = res_or_ret!(token.get_object_by_handle(wrapping_key_handle));
-    ok_or_ret!(check_allowed_mechs(mechanism, &wkey));
+    ok_or_ret!(check_allowed_mechs(mechanism, &wkey, &token, CKF_WRAP));
     let factories = token.get_object_factories();
     let factory = res_or_ret!(factories.get_object_factory(&key));
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
@@ -1839,6 +1935,11 @@ extern "C" fn fn_wrap_key(
             return CKR_WRAPPING_KEY_HANDLE_INVALID;
         }
     }
+    if let Some(attr) = wkey.get_attr(CKA_WRAP_TEMPLATE) {
+        if !key.match_attributes(&res_or_ret!(attr.to_attr_array())) {
+            return CKR_KEY_NOT_WRAPPABLE;
+        }
+    }
 
     let pwraplen = unsafe { *pul_wrapped_key_len as CK_ULONG };
     let wraplen = cast_or_ret!(usize from pwraplen => CKR_ARGUMENTS_BAD);
@@ -1874,10 +1975,18 @@ extern "C" fn fn_unwrap_key(
     let slot_id = session.get_slot_id();
     let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
     let key = res_or_ret!(token.get_object_by_handle(unwrapping_key_handle));
-    ok_or_ret!(check_allowed_mechs(mechanism, &key));
+    ok_or_ret!(check_allowed_mechs(mechanism, &key, &token, CKF_UNWRAP));
+    let mut utmpl = CkAttrs::from(&*tmpl);
+    if let Some(attr) = key.get_attr(CKA_UNWRAP_TEMPLATE) {
+        res_or_ret!(utmpl.add_attr_array(&res_or_ret!(attr.to_attr_array())));
+        if !session.is_writable() {
+            fail_if_cka_token_true!(utmpl.as_slice());
+        }
+    }
     let factories = token.get_object_factories();
-    let factory =
-        res_or_ret!(factories.get_obj_factory_from_key_template(tmpl));
+    let factory = res_or_ret!(
+        factories.get_obj_factory_from_key_template(utmpl.as_slice())
+    );
     let wklen = cast_or_ret!(usize from wrapped_key_len);
     let data: &[u8] = unsafe { std::slice::from_raw_parts(wrapped_key, wklen) };
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
@@ -1890,7 +1999,8 @@ extern "C" fn fn_unwrap_key(
         return CKR_WRAPPING_KEY_HANDLE_INVALID;
     }
 
-    let result = mech.unwrap_key(mechanism, &key, data, tmpl, factory);
+    let result =
+        mech.unwrap_key(mechanism, &key, data, utmpl.as_slice(), factory);
     match result {
         Ok(obj) => {
             let kh = res_or_ret!(token.insert_object(s_handle, obj));
@@ -1929,7 +2039,20 @@ extern "C" fn fn_derive_key(
     if !res_or_ret!(key.get_attr_as_bool(CKA_DERIVE)) {
         return CKR_KEY_FUNCTION_NOT_PERMITTED;
     }
-    ok_or_ret!(check_allowed_mechs(mechanism, &key));
+    ok_or_ret!(check_allowed_mechs(mechanism, &key, &token, CKF_DERIVE));
+
+    /* the derive template applies to all the keys derived */
+    let derive_template = match key.get_attr(CKA_DERIVE_TEMPLATE) {
+        Some(attr) => res_or_ret!(attr.to_attr_array()),
+        None => Vec::new(),
+    };
+    let mut dtmpl = CkAttrs::from(&*tmpl);
+    if derive_template.len() > 0 {
+        res_or_ret!(dtmpl.add_attr_array(&derive_template));
+        if !session.is_writable() {
+            fail_if_cka_token_true!(dtmpl.as_slice());
+        }
+    }
 
     let mech = res_or_ret!(token.get_mechanisms().get(mechanism.mechanism));
     if mech.info().flags & CKF_DERIVE != CKF_DERIVE {
@@ -1963,7 +2086,7 @@ extern "C" fn fn_derive_key(
 
     let mut result = res_or_ret!(operation.derive(
         &key,
-        tmpl,
+        dtmpl.as_slice(),
         token.get_mechanisms(),
         token.get_object_factories(),
     ));
@@ -2002,9 +2125,7 @@ extern "C" fn fn_derive_key(
         CKM_SP800_108_COUNTER_KDF
         | CKM_SP800_108_FEEDBACK_KDF
         | CKM_SP800_108_DOUBLE_PIPELINE_KDF => {
-            let kh =
-                res_or_ret!(token.insert_object(s_handle, result.remove(0)));
-            if result.len() > 0 {
+            if result.len() > 1 {
                 let adk = match mechanism.mechanism {
                     CKM_SP800_108_COUNTER_KDF => {
                         let params = cast_params!(raw_err mechanism, CK_SP800_108_KDF_PARAMS);
@@ -2024,34 +2145,36 @@ extern "C" fn fn_derive_key(
                     }
                     _ => return CKR_MECHANISM_INVALID,
                 };
-                if adk.len() != result.len() {
+                if adk.len() != result.len() - 1 {
                     return CKR_GENERAL_ERROR;
                 }
-                let mut rv = CKR_OK;
-                let mut ah = Vec::<CK_OBJECT_HANDLE>::with_capacity(adk.len());
-                while result.len() > 0 {
-                    match token.insert_object(s_handle, result.remove(0)) {
-                        Ok(h) => ah.push(h),
-                        Err(e) => rv = e.rv(),
-                    }
-                    if rv != CKR_OK {
-                        break;
-                    }
-                }
-                if rv != CKR_OK {
-                    for h in ah {
-                        let _ = token.destroy_object(h);
-                    }
-                    let _ = token.destroy_object(kh);
-                    return rv;
+                for i in 0..adk.len() {
+                    let cnt = cast_or_ret!(usize from adk[i].ulAttributeCount);
+                    let tmpl: &[CK_ATTRIBUTE] = unsafe {
+                        std::slice::from_raw_parts(adk[i].pTemplate, cnt)
+                    };
+                    res_or_ret!(apply_derive_template(
+                        &mut result[i + 1],
+                        &derive_template,
+                        tmpl
+                    ));
                 }
+                let mut ah =
+                    res_or_ret!(token.insert_objects(s_handle, result));
+                let kh = ah.remove(0);
                 for i in 0..adk.len() {
                     unsafe {
                         core::ptr::write(adk[i].phKey, ah[i]);
                     }
                 }
+                unsafe {
+                    core::ptr::write(key_handle, kh);
+                }
+                return CKR_OK;
             }
 
+            let kh =
+                res_or_ret!(token.insert_object(s_handle, result.remove(0)));
             unsafe {
                 core::ptr::write(key_handle, kh);
             }
@@ -2066,18 +2189,17 @@ extern "C" fn fn_derive_key(
                 2 | 4 => (),
                 _ => return CKR_GENERAL_ERROR,
             }
-            let mut ah = Vec::<CK_OBJECT_HANDLE>::with_capacity(result.len());
-            while result.len() > 0 {
-                match token.insert_object(s_handle, result.remove(0)) {
-                    Ok(h) => ah.push(h),
-                    Err(e) => {
-                        for h in ah {
-                            let _ = token.destroy_object(h);
-                        }
-                        return e.rv();
-                    }
+            /* the MAC keys are not created from the caller template */
+            if result.len() == 4 {
+                for obj in &mut result[0..2] {
+                    res_or_ret!(apply_derive_template(
+                        obj,
+                        &derive_template,
+                        &[]
+                    ));
                 }
             }
+            let mut ah = res_or_ret!(token.insert_objects(s_handle, result));
             if ah.len() == 4 {
                 unsafe {
                     (*mat_out).hClientMacSecret = ah.remove(0);
@@ -2603,6 +2725,134 @@ static INTERFACE_300: CK_INTERFACE = CK_INTERFACE {
     flags: 0,
 };
 
+extern "C" fn fn_rotate_kek(
+    s_handle: CK_SESSION_HANDLE,
+    pin: CK_UTF8CHAR_PTR,
+    pin_len: CK_ULONG,
+) -> CK_RV {
+    let rstate = global_rlock!(STATE);
+    let session = res_or_ret!(rstate.get_session(s_handle));
+    if !session.is_writable() {
+        return CKR_SESSION_READ_ONLY;
+    }
+    let vpin: Vec<u8> = bytes_to_vec!(pin, pin_len);
+
+    let slot_id = session.get_slot_id();
+    let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
+    ret_to_rv!(token.rotate_kek(&vpin))
+}
+
+extern "C" fn fn_set_export_policy(
+    s_handle: CK_SESSION_HANDLE,
+    flags: CK_FLAGS,
+) -> CK_RV {
+    let rstate = global_rlock!(STATE);
+    let session = res_or_ret!(rstate.get_session(s_handle));
+    if !session.is_writable() {
+        return CKR_SESSION_READ_ONLY;
+    }
+    let slot_id = session.get_slot_id();
+    let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
+    ret_to_rv!(token.set_export_policy(flags))
+}
+
+/* The archive is protected either by the AES key referenced by
+ * key_handle or by a passphrase, never both */
+fn archive_key(
+    token: &mut Token,
+    key_handle: CK_OBJECT_HANDLE,
+    passphrase: &Vec<u8>,
+) -> Result<Option<object::Object>> {
+    if key_handle == CK_INVALID_HANDLE {
+        if passphrase.len() == 0 {
+            return err_rv!(CKR_ARGUMENTS_BAD);
+        }
+        return Ok(None);
+    }
+    if passphrase.len() != 0 {
+        return err_rv!(CKR_ARGUMENTS_BAD);
+    }
+    Ok(Some(token.get_object_by_handle(key_handle)?))
+}
+
+extern "C" fn fn_export_token(
+    s_handle: CK_SESSION_HANDLE,
+    key_handle: CK_OBJECT_HANDLE,
+    passphrase: CK_UTF8CHAR_PTR,
+    passphrase_len: CK_ULONG,
+    archive: CK_BYTE_PTR,
+    pul_archive_len: CK_ULONG_PTR,
+) -> CK_RV {
+    if pul_archive_len.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    let rstate = global_rlock!(STATE);
+    let session = res_or_ret!(rstate.get_session(s_handle));
+    let vpass: Vec<u8> = bytes_to_vec!(passphrase, passphrase_len);
+
+    let slot_id = session.get_slot_id();
+    let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
+    let key = res_or_ret!(archive_key(&mut token, key_handle, &vpass));
+    let data = res_or_ret!(token.export_archive(key.as_ref(), &vpass));
+    let data_len = cast_or_ret!(CK_ULONG from data.len());
+    if archive.is_null() {
+        unsafe {
+            *pul_archive_len = data_len;
+        }
+        return CKR_OK;
+    }
+    unsafe {
+        if *pul_archive_len < data_len {
+            *pul_archive_len = data_len;
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), archive, data.len());
+        *pul_archive_len = data_len;
+    }
+    CKR_OK
+}
+
+extern "C" fn fn_import_token(
+    s_handle: CK_SESSION_HANDLE,
+    key_handle: CK_OBJECT_HANDLE,
+    passphrase: CK_UTF8CHAR_PTR,
+    passphrase_len: CK_ULONG,
+    archive: CK_BYTE_PTR,
+    archive_len: CK_ULONG,
+) -> CK_RV {
+    if archive.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    let rstate = global_rlock!(STATE);
+    let session = res_or_ret!(rstate.get_session(s_handle));
+    if !session.is_writable() {
+        return CKR_SESSION_READ_ONLY;
+    }
+    let vpass: Vec<u8> = bytes_to_vec!(passphrase, passphrase_len);
+    let alen = cast_or_ret!(usize from archive_len);
+    let data: &[u8] = unsafe { std::slice::from_raw_parts(archive, alen) };
+
+    let slot_id = session.get_slot_id();
+    let mut token = res_or_ret!(rstate.get_token_from_slot_mut(slot_id));
+    let key = res_or_ret!(archive_key(&mut token, key_handle, &vpass));
+    ret_to_rv!(token.import_archive(key.as_ref(), &vpass, data))
+}
+
+pub static FNLIST_KRY: KR_FUNCTION_LIST = KR_FUNCTION_LIST {
+    version: CK_VERSION { major: 1, minor: 0 },
+    KR_RotateKEK: Some(fn_rotate_kek),
+    KR_SetExportPolicy: Some(fn_set_export_policy),
+    KR_ExportToken: Some(fn_export_token),
+    KR_ImportToken: Some(fn_import_token),
+};
+
+static INTERFACE_NAME_KRY_NUL: &str = "Kryoptic Vendor v1\0";
+static INTERFACE_KRY: CK_INTERFACE = CK_INTERFACE {
+    pInterfaceName: INTERFACE_NAME_KRY_NUL.as_ptr() as *mut u8,
+    pFunctionList: &FNLIST_KRY as *const _ as *const ::std::os::raw::c_void,
+    flags: 0,
+};
+
 #[cfg(feature = "fips")]
 include!("fips/interface.rs");
 
@@ -2615,7 +2865,7 @@ unsafe impl Sync for CK_INTERFACE {}
 unsafe impl Sync for InterfaceData {}
 
 #[cfg(feature = "fips")]
-static INTERFACE_SET: [InterfaceData; 3] = [
+static INTERFACE_SET: [InterfaceData; 4] = [
     InterfaceData {
         interface: std::ptr::addr_of!(INTERFACE_300),
         version: FNLIST_300.version,
@@ -2624,13 +2874,17 @@ static INTERFACE_SET: [InterfaceData; 3] = [
         interface: std::ptr::addr_of!(INTERFACE_240),
         version: FNLIST_240.version,
     },
+    InterfaceData {
+        interface: std::ptr::addr_of!(INTERFACE_KRY),
+        version: FNLIST_KRY.version,
+    },
     InterfaceData {
         interface: std::ptr::addr_of!(INTERFACE_VAL),
         version: FNLIST_VAL.version,
     },
 ];
 #[cfg(not(feature = "fips"))]
-static INTERFACE_SET: [InterfaceData; 2] = [
+static INTERFACE_SET: [InterfaceData; 3] = [
     InterfaceData {
         interface: std::ptr::addr_of!(INTERFACE_300),
         version: FNLIST_300.version,
@@ -2639,6 +2893,10 @@ static INTERFACE_SET: [InterfaceData; 2] = [
         interface: std::ptr::addr_of!(INTERFACE_240),
         version: FNLIST_240.version,
     },
+    InterfaceData {
+        interface: std::ptr::addr_of!(INTERFACE_KRY),
+        version: FNLIST_KRY.version,
+    },
 ];
 
 #[no_mangle]
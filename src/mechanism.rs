@@ -3,14 +3,22 @@
 
 use std::collections::BTreeMap;
 
-use super::err_rv;
+use super::attribute;
 use super::error;
 use super::interface;
 use super::object;
+use super::policy;
+use super::token;
+use super::{attr_element, err_rv};
+use attribute::{from_bytes, from_ulong};
 use error::Result;
 use interface::*;
-use object::{Object, ObjectFactories, ObjectFactory};
+use object::{
+    OAFlags, Object, ObjectAttr, ObjectFactories, ObjectFactory, ObjectType,
+    SynthesizedFactory,
+};
 
+use once_cell::sync::Lazy;
 use std::fmt::Debug;
 
 pub trait Mechanism: Debug + Send + Sync {
@@ -121,6 +129,22 @@ impl Mechanisms {
         self.tree.insert(typ, info);
     }
 
+    pub fn remove(&mut self, typ: CK_MECHANISM_TYPE) {
+        self.tree.remove(&typ);
+    }
+
+    /* replaces the advertised info, used to apply token policies */
+    pub fn restrict(
+        &mut self,
+        typ: CK_MECHANISM_TYPE,
+        info: CK_MECHANISM_INFO,
+    ) {
+        if let Some(mech) = self.tree.remove(&typ) {
+            self.tree
+                .insert(typ, Box::new(policy::Restricted::new(mech, info)));
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.tree.len()
     }
@@ -210,7 +234,11 @@ pub trait Decryption: MechOperation {
 
 pub trait SearchOperation: Debug + Send + Sync {
     fn finalized(&self) -> bool;
-    fn results(&mut self, _max: usize) -> Result<Vec<CK_OBJECT_HANDLE>> {
+    fn results(
+        &mut self,
+        _token: &mut token::Token,
+        _max: usize,
+    ) -> Result<Vec<CK_OBJECT_HANDLE>> {
         err_rv!(CKR_GENERAL_ERROR)
     }
 }
@@ -331,3 +359,59 @@ pub trait DRBG: Debug + Send + Sync {
         err_rv!(CKR_GENERAL_ERROR)
     }
 }
+
+/* Synthesizes the CKO_MECHANISM object of a registered mechanism,
+ * profiles lists the available profiles that require it and denied
+ * holds the operations the SO denied for it */
+pub fn mechanism_object(
+    typ: CK_MECHANISM_TYPE,
+    profiles: &[CK_PROFILE_ID],
+    denied: CK_FLAGS,
+) -> Result<Object> {
+    let mut obj = object::synthesized_object(CKO_MECHANISM)?;
+    obj.set_attr(from_ulong(CKA_MECHANISM_TYPE, typ))?;
+    update_mechanism_object(&mut obj, profiles, denied)?;
+    Ok(obj)
+}
+
+/* Refreshes the attributes that follow the token policy, in place so
+ * that the object keeps its handle */
+pub fn update_mechanism_object(
+    obj: &mut Object,
+    profiles: &[CK_PROFILE_ID],
+    denied: CK_FLAGS,
+) -> Result<()> {
+    let mut value = Vec::<u8>::with_capacity(
+        profiles.len() * std::mem::size_of::<CK_PROFILE_ID>(),
+    );
+    for p in profiles {
+        value.extend_from_slice(&p.to_ne_bytes());
+    }
+    obj.set_attr(from_bytes(KRA_MECHANISM_PROFILES, value))?;
+    obj.set_attr(from_ulong(KRA_DENIED_OPERATIONS, denied))
+}
+
+static MECHANISM_OBJECT_FACTORY: Lazy<Box<dyn ObjectFactory>> =
+    Lazy::new(|| {
+        Box::new(SynthesizedFactory::new(vec![
+            attr_element!(
+                CKA_MECHANISM_TYPE;
+                OAFlags::AlwaysRequired | OAFlags::NeverSettable
+                | OAFlags::Unchangeable; from_ulong;
+                val CK_UNAVAILABLE_INFORMATION),
+            attr_element!(
+                KRA_MECHANISM_PROFILES;
+                OAFlags::NeverSettable | OAFlags::Unchangeable;
+                from_bytes; val Vec::new()),
+            attr_element!(
+                KRA_DENIED_OPERATIONS;
+                OAFlags::NeverSettable; from_ulong; val 0),
+        ]))
+    });
+
+pub fn register(_: &mut Mechanisms, ot: &mut ObjectFactories) {
+    ot.add_factory(
+        ObjectType::new(CKO_MECHANISM, 0),
+        &MECHANISM_OBJECT_FACTORY,
+    );
+}
@@ -0,0 +1,53 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Copies the objects of a SoftHSMv2 token or of a NSS database into an
+ * initialized kryoptic token */
+use std::env;
+
+use kryoptic_pkcs11::migration::{migrate, Source};
+
+#[path = "../util/secret.rs"]
+mod secret;
+use secret::read_secret;
+
+type Result<T> = std::result::Result<T, String>;
+
+fn usage(name: &str) -> ! {
+    eprintln!("usage: {} <softhsm|nss> <source> <target>", name);
+    eprintln!("  softhsm <source>   a SoftHSMv2 token directory");
+    eprintln!("  nss <source>       a NSS database directory");
+    eprintln!("  <target>           the kryoptic token storage file");
+    std::process::exit(1);
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let source = match args[1].as_str() {
+        "softhsm" => Source::SoftHsm,
+        "nss" => Source::Nss,
+        _ => usage(&args[0]),
+    };
+    let source_pin = read_secret("Source PIN")?;
+    let target_pin = read_secret("Target User PIN")?;
+    let report = migrate(source, &args[2], &source_pin, &args[3], &target_pin)?;
+    for p in &report.problems {
+        eprintln!("{}", p);
+    }
+    println!(
+        "{} objects imported, {} problems reported",
+        report.imported,
+        report.problems.len()
+    );
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        usage(&args[0]);
+    }
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
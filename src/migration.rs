@@ -0,0 +1,302 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Migration of objects from other software tokens. The source databases
+ * are read directly, private values are decrypted with the source PIN and
+ * the objects are recreated through the object factories of the target
+ * token, so that they are validated exactly as newly created objects */
+
+use super::attribute;
+use super::error;
+use super::interface;
+use super::mechanism;
+use super::object;
+use super::token;
+
+use super::{err_rv, sizeof, void_ptr};
+use attribute::{AttrType, CkAttrs};
+use error::Result;
+use interface::*;
+use mechanism::Mechanisms;
+use object::{OAFlags, Object, ObjectFactories};
+use token::Token;
+
+mod nss;
+mod softhsm;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    SoftHsm,
+    Nss,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub imported: usize,
+    pub problems: Vec<String>,
+}
+
+/* An object as read from a source database, values are in the
+ * PKCS#11 native encoding (CK_ULONG and CK_BBOOL in host format) */
+struct SourceObject {
+    id: String,
+    attrs: Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)>,
+}
+
+/* Access to the target token crypto for the source decryption */
+struct Crypto<'a> {
+    mechanisms: &'a Mechanisms,
+    factories: &'a ObjectFactories,
+}
+
+impl Crypto<'_> {
+    fn digest(
+        &self,
+        mech: CK_MECHANISM_TYPE,
+        parts: &[&[u8]],
+    ) -> Result<Vec<u8>> {
+        let mut op = self.mechanisms.get(mech)?.digest_new(&CK_MECHANISM {
+            mechanism: mech,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        })?;
+        for p in parts {
+            op.digest_update(p)?;
+        }
+        let mut out = vec![0u8; op.digest_len()?];
+        op.digest_final(&mut out)?;
+        Ok(out)
+    }
+
+    fn aes_key(&self, value: &[u8]) -> Result<Object> {
+        let mut key = Object::new();
+        key.set_zeroize();
+        key.set_attr(attribute::from_ulong(CKA_CLASS, CKO_SECRET_KEY))?;
+        key.set_attr(attribute::from_ulong(CKA_KEY_TYPE, CKK_AES))?;
+        key.set_attr(attribute::from_bytes(CKA_VALUE, value.to_vec()))?;
+        key.set_attr(attribute::from_ulong(
+            CKA_VALUE_LEN,
+            value.len() as CK_ULONG,
+        ))?;
+        key.set_attr(attribute::from_bool(CKA_DECRYPT, true))?;
+        Ok(key)
+    }
+
+    fn pbkdf2_aes_key(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        iterations: usize,
+        prf: CK_PKCS5_PBKD2_PSEUDO_RANDOM_FUNCTION_TYPE,
+        keylen: usize,
+    ) -> Result<Object> {
+        let params = CK_PKCS5_PBKD2_PARAMS2 {
+            saltSource: CKZ_DATA_SPECIFIED,
+            pSaltSourceData: void_ptr!(salt.as_ptr()),
+            ulSaltSourceDataLen: salt.len() as CK_ULONG,
+            iterations: iterations as CK_ULONG,
+            prf: prf,
+            pPrfData: std::ptr::null_mut(),
+            ulPrfDataLen: 0,
+            pPassword: password.as_ptr() as *const _ as *mut _,
+            ulPasswordLen: password.len() as CK_ULONG,
+        };
+        let class = CKO_SECRET_KEY;
+        let keytyp = CKK_AES;
+        let keylen = keylen as CK_ULONG;
+        let truebool: CK_BBOOL = CK_TRUE;
+        let mut template = CkAttrs::with_capacity(4);
+        template.add_ulong(CKA_CLASS, &class);
+        template.add_ulong(CKA_KEY_TYPE, &keytyp);
+        template.add_ulong(CKA_VALUE_LEN, &keylen);
+        template.add_bool(CKA_DECRYPT, &truebool);
+        self.mechanisms.get(CKM_PKCS5_PBKD2)?.generate_key(
+            &CK_MECHANISM {
+                mechanism: CKM_PKCS5_PBKD2,
+                pParameter: void_ptr!(&params),
+                ulParameterLen: sizeof!(CK_PKCS5_PBKD2_PARAMS2),
+            },
+            template.as_slice(),
+            self.mechanisms,
+            self.factories,
+        )
+    }
+
+    fn aes_cbc_decrypt(
+        &self,
+        key: &Object,
+        iv: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        if iv.len() != 16 || data.len() == 0 || data.len() % 16 != 0 {
+            return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+        }
+        let mech = CK_MECHANISM {
+            mechanism: CKM_AES_CBC_PAD,
+            pParameter: void_ptr!(iv.as_ptr()),
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let mut op = self
+            .mechanisms
+            .get(CKM_AES_CBC_PAD)?
+            .decryption_new(&mech, key)?;
+        let mut plain = vec![0u8; op.decryption_len(data.len(), false)?];
+        let len = op.decrypt(data, &mut plain)?;
+        plain.resize(len, 0);
+        Ok(plain)
+    }
+}
+
+fn read_objects(
+    source: Source,
+    path: &str,
+    pin: &[u8],
+    crypto: &Crypto,
+    report: &mut Report,
+) -> Result<Vec<SourceObject>> {
+    match source {
+        Source::SoftHsm => softhsm::read_objects(path, pin, crypto, report),
+        Source::Nss => nss::read_objects(path, pin, crypto, report),
+    }
+}
+
+fn convert_object(
+    factories: &ObjectFactories,
+    src: &SourceObject,
+    report: &mut Report,
+) -> Result<Object> {
+    let mut attrs = Vec::<attribute::Attribute>::with_capacity(src.attrs.len());
+    let mut probe = Object::new();
+    for (typ, val) in &src.attrs {
+        /* source tokens store unset dates as empty values */
+        if val.len() == 0
+            && matches!(
+                attribute::attr_id_to_attrtype(*typ),
+                Ok(AttrType::DateType)
+            )
+        {
+            continue;
+        }
+        let ck_attr = CK_ATTRIBUTE {
+            type_: *typ,
+            pValue: void_ptr!(val.as_ptr()),
+            ulValueLen: val.len() as CK_ULONG,
+        };
+        match ck_attr.to_attribute() {
+            Ok(a) => {
+                if *typ == CKA_CLASS
+                    || *typ == CKA_KEY_TYPE
+                    || *typ == CKA_CERTIFICATE_TYPE
+                {
+                    probe.set_attr(a.clone())?;
+                }
+                attrs.push(a);
+            }
+            Err(_) => {
+                if val.len() > 0 {
+                    report.problems.push(format!(
+                        "{}: unsupported attribute 0x{:x} dropped",
+                        src.id, typ
+                    ));
+                }
+            }
+        }
+    }
+    let factory = factories.get_object_factory(&probe)?;
+
+    let mut template = CkAttrs::with_capacity(attrs.len() + 1);
+    template.zeroize = true;
+    /* attributes a user can't set are restored after creation */
+    let mut preserved = Vec::<attribute::Attribute>::new();
+    for a in attrs {
+        let typ = a.get_type();
+        /* the target token assigns its own unique ids */
+        if typ == CKA_UNIQUE_ID || typ == CKA_TOKEN {
+            continue;
+        }
+        match factory
+            .get_attributes()
+            .iter()
+            .find(|o| o.get_type() == typ)
+        {
+            Some(oattr) => {
+                if oattr.is(OAFlags::NeverSettable) {
+                    preserved.push(a);
+                } else {
+                    template.add_vec(typ, a.get_value().clone())?;
+                }
+            }
+            None => {
+                if a.get_value().len() > 0 {
+                    report.problems.push(format!(
+                        "{}: attribute {} not valid for this object, dropped",
+                        src.id,
+                        a.name()
+                    ));
+                }
+            }
+        }
+    }
+    template.add_owned_bool(CKA_TOKEN, CK_TRUE)?;
+
+    let mut obj = factory.create(template.as_slice())?;
+    for a in preserved {
+        obj.set_attr(a)?;
+    }
+    Ok(obj)
+}
+
+/* Copies all objects found in the source database in the target token.
+ * Objects that can't be converted are skipped and reported, the other
+ * objects are stored in a single transaction */
+pub fn migrate(
+    source: Source,
+    path: &str,
+    source_pin: &[u8],
+    target: &str,
+    target_pin: &[u8],
+) -> std::result::Result<Report, String> {
+    let mut token = Token::new(target.to_string()).map_err(|e| {
+        format!("Failed to open the target token {}: {}", target, e)
+    })?;
+    if !token.is_initialized() {
+        return Err(format!("Target token {} is not initialized", target));
+    }
+    let rv = token.login(CKU_USER, &target_pin.to_vec());
+    if rv != CKR_OK {
+        return Err(format!("Target token login failed: 0x{:x}", rv));
+    }
+    let ret = migrate_objects(&mut token, source, path, source_pin);
+    let _ = token.logout();
+    ret.map_err(|e| e.to_string())
+}
+
+fn migrate_objects(
+    token: &mut Token,
+    source: Source,
+    path: &str,
+    source_pin: &[u8],
+) -> Result<Report> {
+    let mut report = Report::default();
+    let mut objects = Vec::<Object>::new();
+    {
+        let crypto = Crypto {
+            mechanisms: token.get_mechanisms(),
+            factories: token.get_object_factories(),
+        };
+        let srcobjs =
+            read_objects(source, path, source_pin, &crypto, &mut report)?;
+        for src in &srcobjs {
+            match convert_object(crypto.factories, src, &mut report) {
+                Ok(obj) => objects.push(obj),
+                Err(e) => report
+                    .problems
+                    .push(format!("{}: object not imported: {}", src.id, e)),
+            }
+        }
+    }
+    report.imported = objects.len();
+    token.insert_objects(CK_INVALID_HANDLE, objects)?;
+    token.save()?;
+    Ok(report)
+}
@@ -0,0 +1,308 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* NSS softokn sqlite databases. Certificates and public objects live in
+ * cert9.db, keys in key4.db, each object is a row with one "a<type>"
+ * column per attribute. Private attribute values are encrypted with
+ * PBES2 under a password derived from the database global salt */
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+
+use super::super::attribute;
+use super::super::err_rv;
+use super::super::error;
+use super::super::interface;
+
+use super::{Crypto, Report, SourceObject};
+
+use attribute::AttrType;
+use error::Result;
+use interface::*;
+use zeroize::Zeroize;
+
+const OID_PBES2: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 1, 5, 13);
+const OID_PBKDF2: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 1, 5, 12);
+const OID_HMAC_SHA1: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 7);
+const OID_HMAC_SHA256: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 9);
+const OID_HMAC_SHA384: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 10);
+const OID_HMAC_SHA512: asn1::ObjectIdentifier =
+    asn1::oid!(1, 2, 840, 113549, 2, 11);
+const OID_AES128_CBC: asn1::ObjectIdentifier =
+    asn1::oid!(2, 16, 840, 1, 101, 3, 4, 1, 2);
+const OID_AES192_CBC: asn1::ObjectIdentifier =
+    asn1::oid!(2, 16, 840, 1, 101, 3, 4, 1, 22);
+const OID_AES256_CBC: asn1::ObjectIdentifier =
+    asn1::oid!(2, 16, 840, 1, 101, 3, 4, 1, 42);
+
+/* NSS marks attributes explicitly set to an empty value this way */
+const EXPLICIT_NULL: &[u8; 3] = b"\xa5\x00\xa5";
+const PASSWORD_CHECK: &[u8] = b"password-check";
+
+const PRIVATE_ATTRS: [CK_ATTRIBUTE_TYPE; 7] = [
+    CKA_VALUE,
+    CKA_PRIVATE_EXPONENT,
+    CKA_PRIME_1,
+    CKA_PRIME_2,
+    CKA_EXPONENT_1,
+    CKA_EXPONENT_2,
+    CKA_COEFFICIENT,
+];
+
+#[derive(asn1::Asn1Read)]
+struct AlgorithmIdentifier<'a> {
+    algorithm: asn1::ObjectIdentifier,
+    parameters: Option<asn1::Tlv<'a>>,
+}
+
+#[derive(asn1::Asn1Read)]
+struct EncryptedData<'a> {
+    algorithm: AlgorithmIdentifier<'a>,
+    data: &'a [u8],
+}
+
+#[derive(asn1::Asn1Read)]
+struct Pbes2Params<'a> {
+    kdf: AlgorithmIdentifier<'a>,
+    cipher: AlgorithmIdentifier<'a>,
+}
+
+#[derive(asn1::Asn1Read)]
+struct Pbkdf2Params<'a> {
+    salt: &'a [u8],
+    iterations: u64,
+    key_length: Option<u64>,
+    prf: Option<AlgorithmIdentifier<'a>>,
+}
+
+fn bad_db<E: std::error::Error + 'static>(error: E) -> error::Error {
+    error::Error::ck_rv_from_error(CKR_TOKEN_NOT_RECOGNIZED, error)
+}
+
+fn params<'a, T: asn1::Asn1Readable<'a>>(
+    alg: &AlgorithmIdentifier<'a>,
+) -> Result<T> {
+    match &alg.parameters {
+        Some(p) => match p.parse::<T>() {
+            Ok(t) => Ok(t),
+            Err(_) => err_rv!(CKR_ENCRYPTED_DATA_INVALID),
+        },
+        None => err_rv!(CKR_ENCRYPTED_DATA_INVALID),
+    }
+}
+
+/* Only PBES2 with PBKDF2 and AES-CBC is supported, older databases
+ * using PKCS#12 PBE with 3DES need to be upgraded with NSS first */
+fn decrypt(password: &[u8], data: &[u8], crypto: &Crypto) -> Result<Vec<u8>> {
+    let enc = match asn1::parse_single::<EncryptedData>(data) {
+        Ok(e) => e,
+        Err(_) => return err_rv!(CKR_ENCRYPTED_DATA_INVALID),
+    };
+    if enc.algorithm.algorithm != OID_PBES2 {
+        return err_rv!(CKR_MECHANISM_INVALID);
+    }
+    let pbes2 = params::<Pbes2Params>(&enc.algorithm)?;
+    if pbes2.kdf.algorithm != OID_PBKDF2 {
+        return err_rv!(CKR_MECHANISM_INVALID);
+    }
+    let kdf = params::<Pbkdf2Params>(&pbes2.kdf)?;
+    let prf = match &kdf.prf {
+        None => CKP_PKCS5_PBKD2_HMAC_SHA1,
+        Some(a) => match &a.algorithm {
+            o if *o == OID_HMAC_SHA1 => CKP_PKCS5_PBKD2_HMAC_SHA1,
+            o if *o == OID_HMAC_SHA256 => CKP_PKCS5_PBKD2_HMAC_SHA256,
+            o if *o == OID_HMAC_SHA384 => CKP_PKCS5_PBKD2_HMAC_SHA384,
+            o if *o == OID_HMAC_SHA512 => CKP_PKCS5_PBKD2_HMAC_SHA512,
+            _ => return err_rv!(CKR_MECHANISM_INVALID),
+        },
+    };
+    let keylen = match &pbes2.cipher.algorithm {
+        o if *o == OID_AES128_CBC => 16,
+        o if *o == OID_AES192_CBC => 24,
+        o if *o == OID_AES256_CBC => 32,
+        _ => return err_rv!(CKR_MECHANISM_INVALID),
+    };
+    if let Some(l) = kdf.key_length {
+        if l != keylen as u64 {
+            return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+        }
+    }
+    let iv = params::<&[u8]>(&pbes2.cipher)?;
+    /* NSS stores a truncated IV, the DER header of the original octet
+     * string takes the place of the first two bytes */
+    let iv = match iv.len() {
+        14 => [&[0x04u8, 0x0e][..], iv].concat(),
+        _ => iv.to_vec(),
+    };
+    let iterations = usize::try_from(kdf.iterations)?;
+    let key =
+        crypto.pbkdf2_aes_key(password, kdf.salt, iterations, prf, keylen)?;
+    crypto.aes_cbc_decrypt(&key, &iv, enc.data)
+}
+
+fn check_password(
+    conn: &Connection,
+    pin: &[u8],
+    crypto: &Crypto,
+) -> Result<Vec<u8>> {
+    let row = conn
+        .query_row(
+            "SELECT item1, item2 FROM metaData WHERE id = 'password'",
+            [],
+            |r| Ok((r.get::<_, Vec<u8>>(0)?, r.get::<_, Vec<u8>>(1)?)),
+        )
+        .optional()
+        .map_err(bad_db)?;
+    let (salt, check) = match row {
+        Some(r) => r,
+        None => return err_rv!(CKR_TOKEN_NOT_RECOGNIZED),
+    };
+    let mut password = crypto.digest(CKM_SHA_1, &[salt.as_slice(), pin])?;
+    let rv = match decrypt(&password, &check, crypto) {
+        Ok(mut c) => {
+            let ok = c.as_slice() == PASSWORD_CHECK;
+            c.zeroize();
+            if ok {
+                return Ok(password);
+            }
+            CKR_PIN_INCORRECT
+        }
+        Err(e) => match e.rv() {
+            CKR_MECHANISM_INVALID => CKR_MECHANISM_INVALID,
+            _ => CKR_PIN_INCORRECT,
+        },
+    };
+    password.zeroize();
+    err_rv!(rv)
+}
+
+/* Numbers are stored as 4 bytes big endian values */
+fn convert_value(typ: CK_ATTRIBUTE_TYPE, mut value: Vec<u8>) -> Vec<u8> {
+    if value.as_slice() == EXPLICIT_NULL {
+        return Vec::new();
+    }
+    match attribute::attr_id_to_attrtype(typ) {
+        Ok(AttrType::NumType) if value.len() == 4 => {
+            let mut val = [0u8; 4];
+            val.copy_from_slice(&value);
+            value.zeroize();
+            (u32::from_be_bytes(val) as CK_ULONG).to_ne_bytes().to_vec()
+        }
+        _ => value,
+    }
+}
+
+fn read_table(
+    dbfile: &str,
+    table: &str,
+    path: &std::path::Path,
+    pin: &[u8],
+    crypto: &Crypto,
+    report: &mut Report,
+) -> Result<Vec<SourceObject>> {
+    let conn = Connection::open_with_flags(
+        path.join(dbfile),
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )
+    .map_err(bad_db)?;
+    let mut password = match table {
+        "nssPrivate" => Some(check_password(&conn, pin, crypto)?),
+        _ => None,
+    };
+    let mut stmt = conn
+        .prepare(&format!("SELECT * FROM {}", table))
+        .map_err(bad_db)?;
+    let mut columns = Vec::<(usize, CK_ATTRIBUTE_TYPE)>::new();
+    let mut idcol = None;
+    for (i, name) in stmt.column_names().iter().enumerate() {
+        if *name == "id" {
+            idcol = Some(i);
+        } else if let Some(hex) = name.strip_prefix('a') {
+            if let Ok(typ) = CK_ATTRIBUTE_TYPE::from_str_radix(hex, 16) {
+                columns.push((i, typ));
+            }
+        }
+    }
+    let idcol = match idcol {
+        Some(i) => i,
+        None => return err_rv!(CKR_TOKEN_NOT_RECOGNIZED),
+    };
+
+    let mut objects = Vec::<SourceObject>::new();
+    let mut rows = stmt.query([]).map_err(bad_db)?;
+    while let Some(row) = rows.next().map_err(bad_db)? {
+        let id =
+            format!("{}:{}", dbfile, row.get::<_, i64>(idcol).map_err(bad_db)?);
+        let mut obj = SourceObject {
+            id: id,
+            attrs: Vec::new(),
+        };
+        let mut failed = false;
+        for (i, typ) in &columns {
+            let value = match row.get::<_, Option<Vec<u8>>>(*i) {
+                Ok(Some(v)) => v,
+                Ok(None) => continue,
+                Err(e) => {
+                    report.problems.push(format!(
+                        "{}: attribute 0x{:x} can't be read: {}",
+                        obj.id, typ, e
+                    ));
+                    continue;
+                }
+            };
+            let value = match &password {
+                Some(p) if PRIVATE_ATTRS.contains(typ) => {
+                    match decrypt(p, &value, crypto) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            report.problems.push(format!(
+                                "{}: failed to decrypt attribute 0x{:x}: {}",
+                                obj.id, typ, e
+                            ));
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+                _ => value,
+            };
+            obj.attrs.push((*typ, convert_value(*typ, value)));
+        }
+        if failed {
+            report
+                .problems
+                .push(format!("{}: object not imported", obj.id));
+            continue;
+        }
+        objects.push(obj);
+    }
+    if let Some(p) = &mut password {
+        p.zeroize();
+    }
+    Ok(objects)
+}
+
+pub(super) fn read_objects(
+    path: &str,
+    pin: &[u8],
+    crypto: &Crypto,
+    report: &mut Report,
+) -> Result<Vec<SourceObject>> {
+    /* accept NSS style "sql:" database specifications */
+    let dir = std::path::Path::new(path.strip_prefix("sql:").unwrap_or(path));
+    let mut objects =
+        read_table("key4.db", "nssPrivate", dir, pin, crypto, report)?;
+    objects.extend(read_table(
+        "cert9.db",
+        "nssPublic",
+        dir,
+        pin,
+        crypto,
+        report,
+    )?);
+    Ok(objects)
+}
@@ -0,0 +1,243 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* SoftHSMv2 file backend, each token is a directory holding a
+ * token.object file with the token metadata and one <uuid>.object file
+ * per token object */
+
+use super::super::err_rv;
+use super::super::error;
+use super::super::interface;
+
+use super::{Crypto, Report, SourceObject};
+
+use error::Result;
+use interface::*;
+use zeroize::Zeroize;
+
+/* SoftHSMv2 OSAttributes.h */
+const CKA_VENDOR_SOFTHSM: CK_ULONG = CKA_VENDOR_DEFINED + 0x5348;
+const CKA_OS_USERPIN: CK_ULONG = CKA_VENDOR_SOFTHSM + 5;
+
+const KIND_BOOL: CK_ULONG = 1;
+const KIND_ULONG: CK_ULONG = 2;
+const KIND_BYTES: CK_ULONG = 3;
+const KIND_ATTRMAP: CK_ULONG = 4;
+const KIND_MECHSET: CK_ULONG = 5;
+
+const PBE_ITERATION_BASE_COUNT: usize = 1500;
+const KEY_MAGIC: &[u8; 3] = b"RJR";
+const AES_BLOCK: usize = 16;
+
+enum Value {
+    Bool(bool),
+    Ulong(CK_ULONG),
+    Bytes(Vec<u8>),
+    AttrMap,
+    MechSet(Vec<CK_ULONG>),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        if self.data.len() - self.pos < len {
+            return err_rv!(CKR_DATA_INVALID);
+        }
+        let s = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(s)
+    }
+
+    fn ulong(&mut self) -> Result<CK_ULONG> {
+        let mut val = [0u8; 8];
+        val.copy_from_slice(self.bytes(8)?);
+        Ok(CK_ULONG::from_be_bytes(val))
+    }
+
+    fn done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/* Files start with a generation counter followed by a sequence of
+ * (type, kind, value) records, all integers are 64 bit big endian */
+fn parse_file(data: &[u8]) -> Result<Vec<(CK_ULONG, Value)>> {
+    let mut r = Reader { data: data, pos: 0 };
+    let _generation = r.ulong()?;
+    let mut attrs = Vec::<(CK_ULONG, Value)>::new();
+    while !r.done() {
+        let typ = r.ulong()?;
+        let val = match r.ulong()? {
+            KIND_BOOL => Value::Bool(r.bytes(1)?[0] != 0),
+            KIND_ULONG => Value::Ulong(r.ulong()?),
+            KIND_BYTES => {
+                let len = usize::try_from(r.ulong()?)?;
+                Value::Bytes(r.bytes(len)?.to_vec())
+            }
+            KIND_ATTRMAP => {
+                let len = usize::try_from(r.ulong()?)?;
+                r.bytes(len)?;
+                Value::AttrMap
+            }
+            KIND_MECHSET => {
+                let count = usize::try_from(r.ulong()?)?;
+                let mut mechs = Vec::<CK_ULONG>::with_capacity(count);
+                for _ in 0..count {
+                    mechs.push(r.ulong()?);
+                }
+                Value::MechSet(mechs)
+            }
+            _ => return err_rv!(CKR_DATA_INVALID),
+        };
+        attrs.push((typ, val));
+    }
+    Ok(attrs)
+}
+
+fn read_file(path: &std::path::Path) -> Result<Vec<(CK_ULONG, Value)>> {
+    match std::fs::read(path) {
+        Ok(data) => parse_file(&data),
+        Err(_) => err_rv!(CKR_TOKEN_NOT_RECOGNIZED),
+    }
+}
+
+/* The user PIN blob is salt(8) || IV(16) || AES-256-CBC(magic || key),
+ * the PBE key is an iterated SHA-256 over salt and PIN as in RFC 4880 */
+fn unwrap_token_key(
+    blob: &[u8],
+    pin: &[u8],
+    crypto: &Crypto,
+) -> Result<Vec<u8>> {
+    if blob.len() < 8 + AES_BLOCK {
+        return err_rv!(CKR_DATA_INVALID);
+    }
+    let salt = &blob[..8];
+    let iv = &blob[8..8 + AES_BLOCK];
+    let mut iter = PBE_ITERATION_BASE_COUNT + salt[7] as usize;
+    let mut hash = crypto.digest(CKM_SHA256, &[salt, pin])?;
+    while iter > 1 {
+        let next = crypto.digest(CKM_SHA256, &[hash.as_slice()])?;
+        hash.zeroize();
+        hash = next;
+        iter -= 1;
+    }
+    let pbekey = crypto.aes_key(&hash)?;
+    hash.zeroize();
+    let mut plain =
+        match crypto.aes_cbc_decrypt(&pbekey, iv, &blob[8 + AES_BLOCK..]) {
+            Ok(p) => p,
+            Err(_) => return err_rv!(CKR_PIN_INCORRECT),
+        };
+    if plain.len() != KEY_MAGIC.len() + 32 || &plain[..3] != KEY_MAGIC {
+        plain.zeroize();
+        return err_rv!(CKR_PIN_INCORRECT);
+    }
+    let key = plain[3..].to_vec();
+    plain.zeroize();
+    Ok(key)
+}
+
+fn convert(
+    id: &str,
+    attrs: Vec<(CK_ULONG, Value)>,
+    key: &[u8],
+    crypto: &Crypto,
+    report: &mut Report,
+) -> Result<SourceObject> {
+    let private = attrs
+        .iter()
+        .any(|(t, v)| *t == CKA_PRIVATE && matches!(v, Value::Bool(true)));
+    let datakey = crypto.aes_key(key)?;
+    let mut obj = SourceObject {
+        id: id.to_string(),
+        attrs: Vec::with_capacity(attrs.len()),
+    };
+    for (typ, val) in attrs {
+        let value = match val {
+            Value::Bool(b) => vec![if b { CK_TRUE } else { CK_FALSE }],
+            Value::Ulong(u) => u.to_ne_bytes().to_vec(),
+            /* private objects have all their byte strings encrypted
+             * with the token key as IV || AES-256-CBC(value) */
+            Value::Bytes(b) => {
+                if private && b.len() > 0 {
+                    if b.len() < AES_BLOCK {
+                        return err_rv!(CKR_DATA_INVALID);
+                    }
+                    crypto.aes_cbc_decrypt(
+                        &datakey,
+                        &b[..AES_BLOCK],
+                        &b[AES_BLOCK..],
+                    )?
+                } else {
+                    b
+                }
+            }
+            Value::MechSet(m) => {
+                let mut v = Vec::<u8>::with_capacity(m.len() * 8);
+                for mech in m {
+                    v.extend_from_slice(&mech.to_ne_bytes());
+                }
+                v
+            }
+            Value::AttrMap => {
+                report.problems.push(format!(
+                    "{}: attribute 0x{:x} holds a template, dropped",
+                    id, typ
+                ));
+                continue;
+            }
+        };
+        obj.attrs.push((typ, value));
+    }
+    Ok(obj)
+}
+
+pub(super) fn read_objects(
+    path: &str,
+    pin: &[u8],
+    crypto: &Crypto,
+    report: &mut Report,
+) -> Result<Vec<SourceObject>> {
+    let dir = std::path::Path::new(path);
+    let token = read_file(&dir.join("token.object"))?;
+    let blob = match token.iter().find(|(t, _)| *t == CKA_OS_USERPIN) {
+        Some((_, Value::Bytes(b))) if b.len() > 0 => b,
+        _ => return err_rv!(KRR_TOKEN_NOT_INITIALIZED),
+    };
+    let mut key = unwrap_token_key(blob, pin, crypto)?;
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return err_rv!(CKR_TOKEN_NOT_RECOGNIZED),
+    };
+    let mut names = Vec::<String>::new();
+    for entry in entries {
+        let name = match entry {
+            Ok(e) => e.file_name().to_string_lossy().to_string(),
+            Err(_) => return err_rv!(CKR_TOKEN_NOT_RECOGNIZED),
+        };
+        if name.ends_with(".object") && name != "token.object" {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    let mut objects = Vec::<SourceObject>::with_capacity(names.len());
+    for name in names {
+        let id = name.trim_end_matches(".object");
+        let ret = read_file(&dir.join(&name))
+            .and_then(|attrs| convert(id, attrs, &key, crypto, report));
+        match ret {
+            Ok(obj) => objects.push(obj),
+            Err(e) => report
+                .problems
+                .push(format!("{}: object can't be read: {}", id, e)),
+        }
+    }
+    key.zeroize();
+    Ok(objects)
+}
@@ -12,8 +12,51 @@ use attribute::{from_ulong, CkAttrs};
 use error::Result;
 use interface::*;
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub const CK_ULONG_SIZE: usize = std::mem::size_of::<interface::CK_ULONG>();
 
+/* Current UTC time in the YYYYMMDDhhmmss00 format of CK_TOKEN_INFO, the
+ * conversion from days since the epoch is the civil_from_days algorithm
+ * by H. Hinnant */
+pub fn utc_time() -> Result<[u8; 16]> {
+    let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    };
+    let z = i64::try_from(secs / 86400)? + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let tod = secs % 86400;
+    let mut time = [0u8; 16];
+    time.copy_from_slice(
+        format!(
+            "{:04}{:02}{:02}{:02}{:02}{:02}00",
+            year,
+            month,
+            day,
+            tod / 3600,
+            (tod / 60) % 60,
+            tod % 60
+        )
+        .as_bytes(),
+    );
+    Ok(time)
+}
+
+/* Current UTC date in the CK_DATE YYYYMMDD format */
+pub fn utc_date() -> Result<[u8; 8]> {
+    let mut date = [0u8; 8];
+    date.copy_from_slice(&utc_time()?[..8]);
+    Ok(date)
+}
+
 #[macro_export]
 macro_rules! map_err {
     ($map:expr, $err:tt) => {{
@@ -6,12 +6,14 @@ use std::collections::HashMap;
 
 use super::attribute;
 use super::error;
+use super::hash;
 use super::interface;
+use super::kasn1;
 use super::mechanism;
-use super::{err_not_found, err_rv};
+use super::{err_not_found, err_rv, sizeof};
 use attribute::{
-    from_bool, from_bytes, from_date_bytes, from_ignore, from_string,
-    from_ulong, AttrType, Attribute,
+    from_bool, from_bytes, from_date_bytes, from_string, from_ulong,
+    is_attr_array, AttrType, Attribute,
 };
 use error::{Error, Result};
 use interface::*;
@@ -194,6 +196,20 @@ impl Object {
         true
     }
 
+    pub fn match_attributes(&self, attrs: &[Attribute]) -> bool {
+        for attr in attrs {
+            match self.get_attr(attr.get_type()) {
+                Some(a) => {
+                    if a.get_value() != attr.get_value() {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
     pub fn check_key_ops(
         &self,
         class: CK_OBJECT_CLASS,
@@ -407,8 +423,6 @@ pub trait ObjectFactory: Debug + Send + Sync {
         template: &[CK_ATTRIBUTE],
         origin: &Object,
     ) -> Result<Object> {
-        /* FIXME: handle CKA_DERIVE_TEMPLATE */
-
         let mut obj = self.internal_object_create(
             template,
             OAFlags::SettableOnlyOnCreate,
@@ -576,6 +590,14 @@ pub trait ObjectFactory: Debug + Send + Sync {
         return err_rv!(CKR_GENERAL_ERROR);
     }
 
+    fn import_from_public_key_info(
+        &self,
+        _data: &[u8],
+        _template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        return err_rv!(CKR_GENERAL_ERROR);
+    }
+
     fn as_secret_key_factory(&self) -> Result<&dyn SecretKeyFactory> {
         err_rv!(CKR_GENERAL_ERROR)
     }
@@ -620,6 +642,51 @@ impl ObjectFactory for DataFactory {
     }
 }
 
+/* Builds the read-only session objects the token synthesizes itself,
+ * like profiles, hardware features and mechanisms, the caller adds
+ * the class specific attributes */
+pub fn synthesized_object(class: CK_OBJECT_CLASS) -> Result<Object> {
+    let mut obj = Object::new();
+    obj.set_attr(from_bool(CKA_TOKEN, false))?;
+    obj.set_attr(from_bool(CKA_DESTROYABLE, false))?;
+    obj.set_attr(from_bool(CKA_MODIFIABLE, false))?;
+    obj.set_attr(from_bool(CKA_COPYABLE, false))?;
+    obj.set_attr(from_bool(CKA_PRIVATE, false))?;
+    obj.set_attr(from_ulong(CKA_CLASS, class))?;
+    obj.generate_unique();
+    Ok(obj)
+}
+
+/* The factory of synthesized objects, which applications can not
+ * create, only the class specific attributes differ */
+#[derive(Debug)]
+pub struct SynthesizedFactory {
+    attributes: Vec<ObjectAttr>,
+}
+
+impl SynthesizedFactory {
+    pub fn new(mut attributes: Vec<ObjectAttr>) -> SynthesizedFactory {
+        let mut data: SynthesizedFactory = SynthesizedFactory {
+            attributes: Vec::new(),
+        };
+        data.attributes.append(&mut data.init_common_object_attrs());
+        data.attributes
+            .append(&mut data.init_common_storage_attrs());
+        data.attributes.append(&mut attributes);
+        data
+    }
+}
+
+impl ObjectFactory for SynthesizedFactory {
+    fn create(&self, _template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        err_rv!(CKR_ACTION_PROHIBITED)
+    }
+
+    fn get_attributes(&self) -> &Vec<ObjectAttr> {
+        &self.attributes
+    }
+}
+
 /* pkcs11-spec-v3.1 4.6 Certificate objects */
 pub trait CertFactory {
     fn init_common_certificate_attrs(&self) -> Vec<ObjectAttr> {
@@ -627,7 +694,7 @@ pub trait CertFactory {
             attr_element!(CKA_CERTIFICATE_TYPE; OAFlags::AlwaysRequired; from_ulong; val 0),
             attr_element!(CKA_TRUSTED; OAFlags::Defval; from_bool; val false),
             attr_element!(CKA_CERTIFICATE_CATEGORY; OAFlags::Defval; from_ulong; val CK_CERTIFICATE_CATEGORY_UNSPECIFIED),
-            attr_element!(CKA_CHECK_VALUE; OAFlags::Ignored; from_ignore; val None),
+            attr_element!(CKA_CHECK_VALUE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
             attr_element!(CKA_START_DATE; OAFlags::empty(); from_date_bytes; val Vec::new()),
             attr_element!(CKA_END_DATE; OAFlags::empty(); from_date_bytes; val Vec::new()),
             attr_element!(CKA_PUBLIC_KEY_INFO; OAFlags::empty(); from_bytes; val Vec::new()),
@@ -675,22 +742,99 @@ impl X509Factory {
             .append(&mut data.init_common_storage_attrs());
         data.attributes
             .append(&mut data.init_common_certificate_attrs());
-        data.attributes.push(attr_element!(CKA_SUBJECT; OAFlags::AlwaysRequired; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_SUBJECT; OAFlags::Unchangeable; from_bytes; val Vec::new()));
         data.attributes.push(
             attr_element!(CKA_ID; OAFlags::Defval; from_bytes; val Vec::new()),
         );
         data.attributes.push(attr_element!(CKA_ISSUER; OAFlags::Defval; from_bytes; val Vec::new()));
         data.attributes.push(attr_element!(CKA_SERIAL_NUMBER; OAFlags::Defval; from_bytes; val Vec::new()));
-        data.attributes.push(attr_element!(CKA_VALUE; OAFlags::AlwaysRequired; from_bytes; val Vec::new()));
-        data.attributes.push(attr_element!(CKA_URL; OAFlags::empty(); from_string; val String::new()));
-        data.attributes.push(attr_element!(CKA_HASH_OF_SUBJECT_PUBLIC_KEY; OAFlags::Defval; from_bytes; val Vec::new()));
-        data.attributes.push(attr_element!(CKA_HASH_OF_ISSUER_PUBLIC_KEY; OAFlags::Defval; from_bytes; val Vec::new()));
-        data.attributes.push(attr_element!(CKA_JAVA_MIDP_SECURITY_DOMAIN; OAFlags::Defval; from_ulong; val CK_SECURITY_DOMAIN_UNSPECIFIED));
-        data.attributes.push(attr_element!(CKA_NAME_HASH_ALGORITHM; OAFlags::empty(); from_ulong; val CKM_SHA_1));
+        data.attributes.push(attr_element!(CKA_VALUE; OAFlags::AlwaysRequired | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_URL; OAFlags::Unchangeable; from_string; val String::new()));
+        data.attributes.push(attr_element!(CKA_HASH_OF_SUBJECT_PUBLIC_KEY; OAFlags::Defval | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_HASH_OF_ISSUER_PUBLIC_KEY; OAFlags::Defval | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_JAVA_MIDP_SECURITY_DOMAIN; OAFlags::Defval | OAFlags::Unchangeable; from_ulong; val CK_SECURITY_DOMAIN_UNSPECIFIED));
+        data.attributes.push(attr_element!(CKA_NAME_HASH_ALGORITHM; OAFlags::Unchangeable; from_ulong; val CKM_SHA_1));
         data
     }
 }
 
+/* Uses the value found in the certificate, or checks that the one
+ * provided by the application matches it */
+pub fn set_or_check_bytes(
+    obj: &mut Object,
+    t: CK_ULONG,
+    val: &[u8],
+) -> Result<()> {
+    match obj.get_attr_as_bytes(t) {
+        Ok(v) if v.len() > 0 => {
+            if v.as_slice() != val {
+                return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+            }
+            Ok(())
+        }
+        _ => obj.set_attr(from_bytes(t, val.to_vec())),
+    }
+}
+
+/* Sets or checks CKA_PUBLIC_KEY_INFO with the SubjectPublicKeyInfo
+ * built from the algorithm and the encoded public key */
+pub fn set_public_key_info(
+    key: &mut Object,
+    algorithm: asn1::ObjectIdentifier,
+    parameters: Option<&[u8]>,
+    public_key: &[u8],
+) -> Result<()> {
+    let spki = match asn1::write_single(&kasn1::SubjectPublicKeyInfo::new(
+        algorithm, parameters, public_key,
+    )?) {
+        Ok(s) => s,
+        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    };
+    set_or_check_bytes(key, CKA_PUBLIC_KEY_INFO, &spki)
+}
+
+fn digest(mech: CK_MECHANISM_TYPE, data: &[u8]) -> Result<Vec<u8>> {
+    let mut op = match hash::internal_hash_op(mech) {
+        Ok(op) => op,
+        Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+    };
+    let mut digest = vec![0u8; op.digest_len()?];
+    op.digest(data, digest.as_mut_slice())?;
+    Ok(digest)
+}
+
+impl X509Factory {
+    fn certificate_attrs(&self, obj: &mut Object, value: &[u8]) -> Result<()> {
+        let cert = kasn1::Certificate::parse(value)?;
+        set_or_check_bytes(obj, CKA_SUBJECT, cert.get_subject())?;
+        set_or_check_bytes(obj, CKA_ISSUER, cert.get_issuer())?;
+        set_or_check_bytes(obj, CKA_SERIAL_NUMBER, cert.get_serial_number())?;
+        set_or_check_bytes(
+            obj,
+            CKA_PUBLIC_KEY_INFO,
+            cert.get_public_key_info(),
+        )?;
+
+        let hash = match obj.get_attr_as_ulong(CKA_NAME_HASH_ALGORITHM) {
+            Ok(h) => h,
+            Err(_) => CKM_SHA_1,
+        };
+        set_or_check_bytes(
+            obj,
+            CKA_HASH_OF_SUBJECT_PUBLIC_KEY,
+            &digest(hash, cert.get_public_key()?)?,
+        )?;
+
+        /* pkcs11-spec-v3.1 4.6.3: the first three bytes of the SHA-1
+         * hash of the certificate value */
+        set_or_check_bytes(
+            obj,
+            CKA_CHECK_VALUE,
+            &digest(CKM_SHA_1, value)?[..3],
+        )
+    }
+}
+
 impl ObjectFactory for X509Factory {
     fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
         let mut obj = self.default_object_create(template)?;
@@ -701,7 +845,7 @@ impl ObjectFactory for X509Factory {
         }
 
         let value = match obj.get_attr_as_bytes(CKA_VALUE) {
-            Ok(v) => v,
+            Ok(v) => v.clone(),
             Err(_) => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
         };
         let url = match obj.get_attr_as_string(CKA_URL) {
@@ -729,6 +873,14 @@ impl ObjectFactory for X509Factory {
                 Err(_) => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
             }
         }
+        if value.len() > 0 {
+            self.certificate_attrs(&mut obj, &value)?;
+        } else {
+            match obj.get_attr_as_bytes(CKA_SUBJECT) {
+                Ok(_) => (),
+                Err(_) => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
+            }
+        }
         match obj.get_attr_as_ulong(CKA_JAVA_MIDP_SECURITY_DOMAIN) {
             Ok(sd) => match sd {
                 CK_SECURITY_DOMAIN_UNSPECIFIED => (),
@@ -739,7 +891,6 @@ impl ObjectFactory for X509Factory {
             },
             Err(_) => (),
         }
-        /* TODO: should we check if CKA_NAME_HASH_ALGORITHM? */
 
         Ok(obj)
     }
@@ -751,6 +902,159 @@ impl ObjectFactory for X509Factory {
 
 impl CertFactory for X509Factory {}
 
+/* pkcs11-spec-v3.1 4.6.4 WTLS public key certificate objects */
+#[derive(Debug)]
+struct WTLSFactory {
+    attributes: Vec<ObjectAttr>,
+}
+
+impl WTLSFactory {
+    fn new() -> WTLSFactory {
+        let mut data: WTLSFactory = WTLSFactory {
+            attributes: Vec::new(),
+        };
+        data.attributes.append(&mut data.init_common_object_attrs());
+        data.attributes
+            .append(&mut data.init_common_storage_attrs());
+        data.attributes
+            .append(&mut data.init_common_certificate_attrs());
+        data.attributes.push(attr_element!(CKA_SUBJECT; OAFlags::AlwaysRequired | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_ISSUER; OAFlags::Defval; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_VALUE; OAFlags::AlwaysRequired | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_URL; OAFlags::Unchangeable; from_string; val String::new()));
+        data.attributes.push(attr_element!(CKA_HASH_OF_SUBJECT_PUBLIC_KEY; OAFlags::Defval | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_HASH_OF_ISSUER_PUBLIC_KEY; OAFlags::Defval | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_NAME_HASH_ALGORITHM; OAFlags::Unchangeable; from_ulong; val CKM_SHA_1));
+        data
+    }
+}
+
+impl ObjectFactory for WTLSFactory {
+    fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        let mut obj = self.default_object_create(template)?;
+
+        let ret = self.basic_cert_object_create_checks(&mut obj);
+        if ret != CKR_OK {
+            return err_rv!(ret);
+        }
+
+        match obj.get_attr_as_bytes(CKA_SUBJECT) {
+            Ok(s) => {
+                if s.len() == 0 {
+                    return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+                }
+            }
+            Err(_) => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
+        }
+        let value = match obj.get_attr_as_bytes(CKA_VALUE) {
+            Ok(v) => v.clone(),
+            Err(_) => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
+        };
+        let url = match obj.get_attr_as_string(CKA_URL) {
+            Ok(u) => u,
+            Err(_) => String::new(),
+        };
+        if value.len() == 0 {
+            /* the certificate must then be retrievable from the URL,
+             * and the key hash is needed to look it up */
+            if url.len() == 0 {
+                return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+            }
+            match obj.get_attr_as_bytes(CKA_HASH_OF_SUBJECT_PUBLIC_KEY) {
+                Ok(h) => {
+                    if h.len() == 0 {
+                        return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+                    }
+                }
+                Err(_) => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
+            }
+        } else {
+            set_or_check_bytes(
+                &mut obj,
+                CKA_CHECK_VALUE,
+                &digest(CKM_SHA_1, &value)?[..3],
+            )?;
+        }
+
+        Ok(obj)
+    }
+
+    fn get_attributes(&self) -> &Vec<ObjectAttr> {
+        &self.attributes
+    }
+}
+
+impl CertFactory for WTLSFactory {}
+
+/* pkcs11-spec-v3.1 4.6.5 X.509 attribute certificate objects */
+#[derive(Debug)]
+struct X509AttrCertFactory {
+    attributes: Vec<ObjectAttr>,
+}
+
+impl X509AttrCertFactory {
+    fn new() -> X509AttrCertFactory {
+        let mut data: X509AttrCertFactory = X509AttrCertFactory {
+            attributes: Vec::new(),
+        };
+        data.attributes.append(&mut data.init_common_object_attrs());
+        data.attributes
+            .append(&mut data.init_common_storage_attrs());
+        data.attributes
+            .append(&mut data.init_common_certificate_attrs());
+        data.attributes.push(attr_element!(CKA_OWNER; OAFlags::Defval | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_AC_ISSUER; OAFlags::Defval; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_SERIAL_NUMBER; OAFlags::Defval; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_ATTR_TYPES; OAFlags::Defval; from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(CKA_VALUE; OAFlags::AlwaysRequired | OAFlags::Unchangeable; from_bytes; val Vec::new()));
+        data
+    }
+
+    /* RFC 5755 4.1: the owner is the DER encoded Holder, the issuer
+     * the AttCertIssuer and the types the SEQUENCE OF the attribute
+     * type OIDs found in the certificate */
+    fn certificate_attrs(&self, obj: &mut Object, value: &[u8]) -> Result<()> {
+        let cert = kasn1::AttributeCertificate::parse(value)?;
+        set_or_check_bytes(obj, CKA_OWNER, cert.get_holder())?;
+        set_or_check_bytes(obj, CKA_AC_ISSUER, cert.get_issuer())?;
+        set_or_check_bytes(obj, CKA_SERIAL_NUMBER, cert.get_serial_number())?;
+        set_or_check_bytes(obj, CKA_ATTR_TYPES, &cert.get_attr_types()?)?;
+
+        set_or_check_bytes(
+            obj,
+            CKA_CHECK_VALUE,
+            &digest(CKM_SHA_1, value)?[..3],
+        )
+    }
+}
+
+impl ObjectFactory for X509AttrCertFactory {
+    fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        let mut obj = self.default_object_create(template)?;
+
+        let ret = self.basic_cert_object_create_checks(&mut obj);
+        if ret != CKR_OK {
+            return err_rv!(ret);
+        }
+
+        /* there is no URL alternative so the value must always be
+         * present, the other attributes are derived from it */
+        let value = obj.get_attr_as_bytes(CKA_VALUE)?.clone();
+        if value.len() == 0 {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        self.certificate_attrs(&mut obj, &value)?;
+
+        Ok(obj)
+    }
+
+    fn get_attributes(&self) -> &Vec<ObjectAttr> {
+        &self.attributes
+    }
+}
+
+impl CertFactory for X509AttrCertFactory {}
+
 /* pkcs11-spec-v3.1 4.7 Key objects */
 pub trait CommonKeyFactory {
     fn init_common_key_attrs(&self) -> Vec<ObjectAttr> {
@@ -779,10 +1083,19 @@ pub trait PubKeyFactory {
             attr_element!(CKA_VERIFY_RECOVER; OAFlags::Defval; from_bool; val false),
             attr_element!(CKA_WRAP; OAFlags::Defval; from_bool; val false),
             attr_element!(CKA_TRUSTED; OAFlags::NeverSettable | OAFlags::Defval; from_bool; val false),
-            attr_element!(CKA_WRAP_TEMPLATE; OAFlags::empty(); from_bytes; val Vec::new()),
+            attr_element!(CKA_WRAP_TEMPLATE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
             attr_element!(CKA_PUBLIC_KEY_INFO; OAFlags::empty(); from_bytes; val Vec::new()),
         ]
     }
+
+    /* Creates the key from a DER encoded SubjectPublicKeyInfo */
+    fn import_from_public_key_info(
+        &self,
+        _data: &[u8],
+        _template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        return err_rv!(CKR_GENERAL_ERROR);
+    }
 }
 
 /* pkcs11-spec-v3.1 4.9 Private key objects */
@@ -799,10 +1112,10 @@ pub trait PrivKeyFactory {
             attr_element!(CKA_ALWAYS_SENSITIVE; OAFlags::NeverSettable; from_bool; val false),
             attr_element!(CKA_NEVER_EXTRACTABLE; OAFlags::NeverSettable; from_bool; val false),
             attr_element!(CKA_WRAP_WITH_TRUSTED; OAFlags::Defval | OAFlags::ChangeToTrue; from_bool; val false),
-            attr_element!(CKA_UNWRAP_TEMPLATE; OAFlags::empty(); from_bytes; val Vec::new()),
+            attr_element!(CKA_UNWRAP_TEMPLATE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
             attr_element!(CKA_ALWAYS_AUTHENTICATE; OAFlags::Defval; from_bool; val false),
             attr_element!(CKA_PUBLIC_KEY_INFO; OAFlags::empty(); from_bytes; val Vec::new()),
-            attr_element!(CKA_DERIVE_TEMPLATE; OAFlags::empty(); from_bytes; val Vec::new()),
+            attr_element!(CKA_DERIVE_TEMPLATE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
         ]
     }
 
@@ -845,12 +1158,12 @@ pub trait SecretKeyFactory {
             attr_element!(CKA_EXTRACTABLE; OAFlags::ChangeToFalse | OAFlags::Defval; from_bool; val false),
             attr_element!(CKA_ALWAYS_SENSITIVE; OAFlags::NeverSettable; from_bool; val false),
             attr_element!(CKA_NEVER_EXTRACTABLE; OAFlags::NeverSettable; from_bool; val false),
-            attr_element!(CKA_CHECK_VALUE; OAFlags::Ignored; from_ignore; val None),
+            attr_element!(CKA_CHECK_VALUE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
             attr_element!(CKA_WRAP_WITH_TRUSTED; OAFlags::Defval | OAFlags::ChangeToTrue; from_bool; val false),
             attr_element!(CKA_TRUSTED; OAFlags::NeverSettable | OAFlags::Defval; from_bool; val false),
-            attr_element!(CKA_WRAP_TEMPLATE; OAFlags::empty(); from_bytes; val Vec::new()),
-            attr_element!(CKA_UNWRAP_TEMPLATE; OAFlags::empty(); from_bytes; val Vec::new()),
-            attr_element!(CKA_DERIVE_TEMPLATE; OAFlags::empty(); from_bytes; val Vec::new()),
+            attr_element!(CKA_WRAP_TEMPLATE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
+            attr_element!(CKA_UNWRAP_TEMPLATE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
+            attr_element!(CKA_DERIVE_TEMPLATE; OAFlags::Unchangeable; from_bytes; val Vec::new()),
         ]
     }
 
@@ -929,6 +1242,19 @@ pub trait SecretKeyFactory {
     fn recommend_key_size(&self, _: usize) -> Result<usize> {
         return err_rv!(CKR_GENERAL_ERROR);
     }
+
+    /* pkcs11-spec-v3.1 6.8.2: the first three bytes of the SHA-1 hash
+     * of the key value, key types can define their own */
+    fn check_value(&self, obj: &Object) -> Result<Vec<u8>> {
+        let mut kcv = digest(CKM_SHA_1, obj.get_attr_as_bytes(CKA_VALUE)?)?;
+        kcv.truncate(3);
+        Ok(kcv)
+    }
+
+    fn set_check_value(&self, obj: &mut Object) -> Result<()> {
+        let kcv = self.check_value(obj)?;
+        set_or_check_bytes(obj, CKA_CHECK_VALUE, &kcv)
+    }
 }
 
 /* pkcs11-spec-v3.1 6.8 Generic secret key */
@@ -1175,7 +1501,7 @@ impl ObjectFactories {
             None => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
         };
         let type_ = match class {
-            CKO_DATA => 0,
+            CKO_DATA | CKO_TRUST => 0,
             CKO_CERTIFICATE => {
                 match template.iter().find(|a| a.type_ == CKA_CERTIFICATE_TYPE)
                 {
@@ -1201,10 +1527,9 @@ impl ObjectFactories {
                     None => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
                 }
             }
+            CKO_HW_FEATURE | CKO_MECHANISM | CKO_PROFILE => 0,
             /* TODO:
-             *  CKO_HW_FEATURE, CKO_DOMAIN_PARAMETERS,
-             *  CKO_MECHANISM, CKO_OTP_KEY, CKO_PROFILE,
-             *  CKO_VENDOR_DEFINED
+             *  CKO_DOMAIN_PARAMETERS, CKO_OTP_KEY, CKO_VENDOR_DEFINED
              */
             _ => return err_rv!(CKR_DEVICE_ERROR),
         };
@@ -1227,14 +1552,25 @@ impl ObjectFactories {
         self.get_factory(ObjectType::new(class, type_))
     }
 
+    /* Secret keys get their check value computed, or the one provided
+     * by the application verified, once the key value is known */
+    pub fn set_check_value(&self, obj: &mut Object) -> Result<()> {
+        if obj.get_attr_as_ulong(CKA_CLASS)? != CKO_SECRET_KEY {
+            return Ok(());
+        }
+        self.get_object_factory(obj)?
+            .as_secret_key_factory()?
+            .set_check_value(obj)
+    }
+
     pub fn check_sensitive(
         &self,
         obj: &Object,
-        template: &[CK_ATTRIBUTE],
+        types: &[CK_ATTRIBUTE_TYPE],
     ) -> Result<()> {
         let objtype_attrs = self.get_object_factory(obj)?.get_attributes();
-        for ck_attr in template {
-            match objtype_attrs.iter().find(|a| a.get_type() == ck_attr.type_) {
+        for typ in types {
+            match objtype_attrs.iter().find(|a| a.get_type() == *typ) {
                 None => return err_rv!(CKR_ATTRIBUTE_TYPE_INVALID),
                 Some(attr) => {
                     if attr.is(OAFlags::Sensitive) {
@@ -1304,24 +1640,13 @@ impl ObjectFactories {
                     continue;
                 }
                 Some(attr) => {
-                    let attr_val = attr.get_value();
-                    let attr_len = CK_ULONG::try_from(attr_val.len())?;
-                    if ck_attr.pValue.is_null() {
-                        ck_attr.ulValueLen = attr_len;
+                    let rv = if is_attr_array(attr.get_type()) {
+                        fill_ck_attr_array(ck_attr, &attr.to_attr_array()?)?
                     } else {
-                        if ck_attr.ulValueLen < attr_len {
-                            ck_attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
-                            result = CKR_BUFFER_TOO_SMALL;
-                        } else {
-                            ck_attr.ulValueLen = attr_len;
-                            unsafe {
-                                std::ptr::copy_nonoverlapping(
-                                    attr_val.as_ptr(),
-                                    ck_attr.pValue as *mut _,
-                                    attr_val.len(),
-                                );
-                            }
-                        }
+                        fill_ck_attr(ck_attr, attr.get_value())?
+                    };
+                    if rv != CKR_OK {
+                        result = rv;
                     }
                 }
             }
@@ -1424,12 +1749,72 @@ impl ObjectFactories {
     }
 }
 
+fn fill_ck_attr(ck_attr: &mut CK_ATTRIBUTE, val: &[u8]) -> Result<CK_RV> {
+    let len = CK_ULONG::try_from(val.len())?;
+    if ck_attr.pValue.is_null() {
+        ck_attr.ulValueLen = len;
+        return Ok(CKR_OK);
+    }
+    if ck_attr.ulValueLen < len {
+        ck_attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+        return Ok(CKR_BUFFER_TOO_SMALL);
+    }
+    ck_attr.ulValueLen = len;
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            val.as_ptr(),
+            ck_attr.pValue as *mut _,
+            val.len(),
+        );
+    }
+    Ok(CKR_OK)
+}
+
+/* Attribute arrays are returned as an array of CK_ATTRIBUTE, each
+ * element is then filled in like a top level attribute */
+fn fill_ck_attr_array(
+    ck_attr: &mut CK_ATTRIBUTE,
+    attrs: &[Attribute],
+) -> Result<CK_RV> {
+    let len = CK_ULONG::try_from(attrs.len())? * sizeof!(CK_ATTRIBUTE);
+    if ck_attr.pValue.is_null() {
+        ck_attr.ulValueLen = len;
+        return Ok(CKR_OK);
+    }
+    if ck_attr.ulValueLen < len {
+        ck_attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+        return Ok(CKR_BUFFER_TOO_SMALL);
+    }
+    ck_attr.ulValueLen = len;
+    let elements: &mut [CK_ATTRIBUTE] = unsafe {
+        std::slice::from_raw_parts_mut(
+            ck_attr.pValue as *mut CK_ATTRIBUTE,
+            attrs.len(),
+        )
+    };
+    let mut result = CKR_OK;
+    for (elem, attr) in elements.iter_mut().zip(attrs.iter()) {
+        elem.type_ = attr.get_type();
+        let rv = fill_ck_attr(elem, attr.get_value())?;
+        if rv != CKR_OK {
+            result = rv;
+        }
+    }
+    Ok(result)
+}
+
 static DATA_OBJECT_FACTORY: Lazy<Box<dyn ObjectFactory>> =
     Lazy::new(|| Box::new(DataFactory::new()));
 
 static X509_CERT_FACTORY: Lazy<Box<dyn ObjectFactory>> =
     Lazy::new(|| Box::new(X509Factory::new()));
 
+static X509_ATTR_CERT_FACTORY: Lazy<Box<dyn ObjectFactory>> =
+    Lazy::new(|| Box::new(X509AttrCertFactory::new()));
+
+static WTLS_CERT_FACTORY: Lazy<Box<dyn ObjectFactory>> =
+    Lazy::new(|| Box::new(WTLSFactory::new()));
+
 static GENERIC_SECRET_FACTORY: Lazy<Box<dyn ObjectFactory>> =
     Lazy::new(|| Box::new(GenericSecretKeyFactory::new()));
 
@@ -1444,6 +1829,14 @@ pub fn register(mechs: &mut Mechanisms, ot: &mut ObjectFactories) {
         ObjectType::new(CKO_CERTIFICATE, CKC_X_509),
         &X509_CERT_FACTORY,
     );
+    ot.add_factory(
+        ObjectType::new(CKO_CERTIFICATE, CKC_X_509_ATTR_CERT),
+        &X509_ATTR_CERT_FACTORY,
+    );
+    ot.add_factory(
+        ObjectType::new(CKO_CERTIFICATE, CKC_WTLS),
+        &WTLS_CERT_FACTORY,
+    );
     ot.add_factory(
         ObjectType::new(CKO_SECRET_KEY, CKK_GENERIC_SECRET),
         &GENERIC_SECRET_FACTORY,
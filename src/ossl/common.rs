@@ -238,6 +238,29 @@ impl EvpPkey {
         }
     }
 
+    /// Returns the raw public key exported from the key
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        let mut params: *mut OSSL_PARAM = std::ptr::null_mut();
+        let res = unsafe {
+            EVP_PKEY_todata(
+                self.ptr,
+                c_int::try_from(EVP_PKEY_PUBLIC_KEY)?,
+                &mut params,
+            )
+        };
+        if res != 1 {
+            return err_rv!(CKR_DEVICE_ERROR);
+        }
+        let pubkey = OsslParam::from_ptr(params).and_then(|p| {
+            Ok(p.get_octet_string(name_as_char(OSSL_PKEY_PARAM_PUB_KEY))?
+                .to_vec())
+        });
+        unsafe {
+            OSSL_PARAM_free(params);
+        }
+        pubkey
+    }
+
     pub fn as_ptr(&self) -> *const EVP_PKEY {
         self.ptr
     }
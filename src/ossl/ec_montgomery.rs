@@ -0,0 +1,38 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+use {super::ossl, ossl::*};
+
+static OSSL_X25519: &[u8; 7] = b"X25519\0";
+static OSSL_X448: &[u8; 5] = b"X448\0";
+
+pub fn ec_montgomery_import(obj: &mut Object) -> Result<()> {
+    ec_key_import(obj, |key| {
+        object_to_montgomery_private_key(key)?.public_key()
+    })
+}
+
+fn get_ossl_name_from_obj(key: &Object) -> Result<&'static [u8]> {
+    match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits) {
+        Ok(BITS_X25519) => Ok(OSSL_X25519),
+        Ok(BITS_X448) => Ok(OSSL_X448),
+        _ => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+    }
+}
+
+/// Convert the PKCS #11 private key object to OpenSSL EVP_PKEY
+fn object_to_montgomery_private_key(key: &Object) -> Result<EvpPkey> {
+    let mut params = OsslParam::with_capacity(1);
+    params.zeroize = true;
+    params.add_octet_string(
+        name_as_char(OSSL_PKEY_PARAM_PRIV_KEY),
+        key.get_attr_as_bytes(CKA_VALUE)?,
+    )?;
+    params.finalize();
+
+    EvpPkey::fromdata(
+        name_as_char(get_ossl_name_from_obj(key)?),
+        EVP_PKEY_PRIVATE_KEY,
+        &params,
+    )
+}
@@ -19,9 +19,90 @@ use std::borrow::Cow;
 use zeroize::Zeroize;
 
 pub fn ecc_import(obj: &mut Object) -> Result<()> {
-    bytes_attr_not_empty!(obj; CKA_EC_PARAMS);
-    bytes_attr_not_empty!(obj; CKA_VALUE);
-    Ok(())
+    ec_key_import(obj, get_ec_point_from_private_key)
+}
+
+/// Get the public point of a private key object
+fn get_ec_point_from_private_key(key: &Object) -> Result<Vec<u8>> {
+    let pkey = object_to_ecc_private_key(key)?;
+    /* Not all OpenSSL versions compute the public point when a
+     * private key is imported, in that case compute it here */
+    match pkey.public_key() {
+        Ok(p) => Ok(p),
+        Err(_) => compute_ec_point(key),
+    }
+}
+
+/// Multiply the group generator by the private scalar of the key object
+/// and return the uncompressed public point
+fn compute_ec_point(key: &Object) -> Result<Vec<u8>> {
+    let curve_name = get_curve_name_from_obj(key)?;
+    let value = key.get_attr_as_bytes(CKA_VALUE)?;
+    let value_len = c_int::try_from(value.len())?;
+    let mut params = OsslParam::with_capacity(1);
+    params.add_utf8_string(
+        name_as_char(OSSL_PKEY_PARAM_GROUP_NAME),
+        &curve_name,
+    )?;
+    params.finalize();
+
+    let form = point_conversion_form_t_POINT_CONVERSION_UNCOMPRESSED;
+    let mut point = Vec::<u8>::new();
+    let mut ok = false;
+    unsafe {
+        let group = EC_GROUP_new_from_params(
+            params.as_ptr(),
+            get_libctx(),
+            std::ptr::null(),
+        );
+        let bnctx = BN_CTX_new_ex(get_libctx());
+        let scalar = BN_bin2bn(value.as_ptr(), value_len, std::ptr::null_mut());
+        let ecpoint = if group.is_null() {
+            std::ptr::null_mut()
+        } else {
+            EC_POINT_new(group)
+        };
+        if !ecpoint.is_null()
+            && !bnctx.is_null()
+            && !scalar.is_null()
+            && EC_POINT_mul(
+                group,
+                ecpoint,
+                scalar,
+                std::ptr::null(),
+                std::ptr::null(),
+                bnctx,
+            ) == 1
+        {
+            let len = EC_POINT_point2oct(
+                group,
+                ecpoint,
+                form,
+                std::ptr::null_mut(),
+                0,
+                bnctx,
+            );
+            if len != 0 {
+                point.resize(len, 0);
+                ok = EC_POINT_point2oct(
+                    group,
+                    ecpoint,
+                    form,
+                    point.as_mut_ptr(),
+                    len,
+                    bnctx,
+                ) == len;
+            }
+        }
+        EC_POINT_free(ecpoint);
+        BN_clear_free(scalar);
+        BN_CTX_free(bnctx);
+        EC_GROUP_free(group);
+    }
+    if !ok {
+        return err_rv!(CKR_DEVICE_ERROR);
+    }
+    Ok(point)
 }
 
 /* confusingly enough, this is not EC for FIPS-level operations  */
@@ -53,27 +134,12 @@ struct EccOperation {
     sigctx: Option<ProviderSignatureCtx>,
 }
 
-fn make_bits_from_ec_params(key: &Object) -> Result<usize> {
-    let x = match key.get_attr_as_bytes(CKA_EC_PARAMS) {
-        Ok(b) => b,
-        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-    };
-    let bits = match asn1::parse_single::<ECParameters>(x) {
-        Ok(a) => match a {
-            ECParameters::OId(o) => oid_to_bits(o)?,
-            ECParameters::CurveName(c) => curve_name_to_bits(c)?,
-            _ => return err_rv!(CKR_GENERAL_ERROR),
-        },
-        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-    };
-    Ok(bits)
-}
-
 fn make_output_length_from_obj(key: &Object) -> Result<usize> {
-    let bits = match make_bits_from_ec_params(key) {
-        Ok(b) => b,
-        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-    };
+    let bits =
+        match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits) {
+            Ok(b) => b,
+            Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+        };
     Ok(2 * ((bits + 7) / 8))
 }
 
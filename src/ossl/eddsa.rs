@@ -14,11 +14,8 @@ use mechanism::*;
 
 use std::ffi::c_int;
 
-// TODO could probably reuse the ECC one as its the same?
 pub fn eddsa_import(obj: &mut Object) -> Result<()> {
-    bytes_attr_not_empty!(obj; CKA_EC_PARAMS);
-    bytes_attr_not_empty!(obj; CKA_VALUE);
-    Ok(())
+    ec_key_import(obj, |key| object_to_ecc_private_key(key)?.public_key())
 }
 
 /* confusingly enough, this is not EC for FIPS-level operations  */
@@ -63,31 +60,15 @@ static OSSL_ED25519: &[u8; 8] = b"ED25519\0";
 static OSSL_ED448: &[u8; 6] = b"ED448\0";
 
 fn get_ossl_name_from_obj(key: &Object) -> Result<&'static [u8]> {
-    match make_bits_from_ec_params(key) {
+    match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits) {
         Ok(BITS_ED25519) => Ok(OSSL_ED25519),
         Ok(BITS_ED448) => Ok(OSSL_ED448),
         _ => return err_rv!(CKR_GENERAL_ERROR),
     }
 }
 
-fn make_bits_from_ec_params(key: &Object) -> Result<usize> {
-    let x = match key.get_attr_as_bytes(CKA_EC_PARAMS) {
-        Ok(b) => b,
-        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-    };
-    let bits = match asn1::parse_single::<ECParameters>(x) {
-        Ok(a) => match a {
-            ECParameters::OId(o) => oid_to_bits(o)?,
-            ECParameters::CurveName(c) => curve_name_to_bits(c)?,
-            _ => return err_rv!(CKR_GENERAL_ERROR),
-        },
-        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-    };
-    Ok(bits)
-}
-
 fn make_output_length_from_obj(key: &Object) -> Result<usize> {
-    match make_bits_from_ec_params(key) {
+    match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits) {
         Ok(255) => Ok(64),
         Ok(448) => Ok(114),
         _ => return err_rv!(CKR_GENERAL_ERROR),
@@ -181,7 +162,7 @@ fn no_params() -> EddsaParams {
 }
 
 fn is_448_curve(key: &Object) -> Result<bool> {
-    match make_bits_from_ec_params(key) {
+    match make_bits_from_ec_params(key, oid_to_bits, curve_name_to_bits) {
         Ok(BITS_ED25519) => Ok(false),
         Ok(BITS_ED448) => Ok(true),
         _ => return err_rv!(CKR_GENERAL_ERROR),
@@ -19,6 +19,7 @@ pub const KRY_VENDOR_OFFSET: CK_ULONG = CKA_VENDOR_DEFINED + 485259;
 /* Object types */
 pub const KRO_TOKEN_DATA: CK_OBJECT_CLASS = KRY_VENDOR_OFFSET + 1;
 /* + 10 taken by pkcs11/validation_draft.rs */
+/* + 11 taken by pkcs11/trust_draft.rs */
 
 /* Attributes */
 pub const KRA_MAX_LOGIN_ATTEMPTS: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 1;
@@ -27,7 +28,23 @@ pub const KRA_FLAGS: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 3;
 pub const KRA_MANUFACTURER_ID: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 4;
 pub const KRA_MODEL: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 5;
 pub const KRA_SERIAL_NUMBER: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 6;
-/* + 10 taken by pkcs11/validation_draft.rs */
+pub const KRA_INTEGRITY_TAG: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 7;
+pub const KRA_EXPORT_POLICY: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 8;
+pub const KRA_INTEGRITY_KEY: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 9;
+/* + 10 to + 21 taken by pkcs11/validation_draft.rs */
+/* DER or PEM encoded PKCS#8 private key or SubjectPublicKeyInfo, the
+ * key object is created from it by C_CreateObject */
+pub const KRA_KEY_DATA: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 30;
+/* Passphrase of an encrypted PKCS#8 private key in KRA_KEY_DATA */
+pub const KRA_KEY_DATA_PASSPHRASE: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 31;
+/* Array of CK_PROFILE_ID of the available profiles that list a mechanism */
+pub const KRA_MECHANISM_PROFILES: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 32;
+/* CK_FLAGS of the operations the SO denied for a mechanism */
+pub const KRA_DENIED_OPERATIONS: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 33;
+/* Array of CK_MECHANISM_TYPE and CK_FLAGS pairs of the operations denied
+ * by the SO, kept with the token info */
+pub const KRA_MECHANISM_POLICY: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 34;
+/* + 40 to + 44 taken by pkcs11/trust_draft.rs */
 
 /* Errors */
 pub const KRR_TOKEN_NOT_INITIALIZED: CK_ULONG = KRY_VENDOR_OFFSET + 1;
@@ -35,4 +52,6 @@ pub const KRR_TOKEN_NOT_INITIALIZED: CK_ULONG = KRY_VENDOR_OFFSET + 1;
 pub const KRY_UNSPEC: CK_ULONG = CK_UNAVAILABLE_INFORMATION;
 
 include!("extensions.rs");
+include!("vendor.rs");
 include!("validation_draft.rs");
+include!("trust_draft.rs");
@@ -0,0 +1,27 @@
+/* /\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\ */
+
+/* Draft Trust Objects
+ * PKCS#11 3.2 will provide official numbers for the following new
+ * types, once that happens we'll remove them from here and rebuild
+ * with the official ones */
+pub type CK_TRUST = CK_ULONG;
+pub type CK_TRUST_PTR = *mut CK_TRUST;
+
+pub const CKT_TRUST_UNKNOWN: CK_TRUST = KRY_VENDOR_OFFSET + 0;
+pub const CKT_TRUSTED: CK_TRUST = KRY_VENDOR_OFFSET + 1;
+pub const CKT_TRUST_ANCHOR: CK_TRUST = KRY_VENDOR_OFFSET + 2;
+pub const CKT_NOT_TRUSTED: CK_TRUST = KRY_VENDOR_OFFSET + 3;
+pub const CKT_TRUST_MUST_VERIFY_TRUST: CK_TRUST = KRY_VENDOR_OFFSET + 4;
+
+pub const CKO_TRUST: CK_OBJECT_CLASS = KRY_VENDOR_OFFSET + 11;
+
+pub const CKA_HASH_OF_CERTIFICATE: CK_ATTRIBUTE_TYPE = KRY_VENDOR_OFFSET + 40;
+pub const CKA_PKCS_TRUST_SERVER_AUTH: CK_ATTRIBUTE_TYPE =
+    KRY_VENDOR_OFFSET + 41;
+pub const CKA_PKCS_TRUST_CLIENT_AUTH: CK_ATTRIBUTE_TYPE =
+    KRY_VENDOR_OFFSET + 42;
+pub const CKA_PKCS_TRUST_CODE_SIGNING: CK_ATTRIBUTE_TYPE =
+    KRY_VENDOR_OFFSET + 43;
+pub const CKA_PKCS_TRUST_EMAIL_PROTECTION: CK_ATTRIBUTE_TYPE =
+    KRY_VENDOR_OFFSET + 44;
+/* /\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\ */
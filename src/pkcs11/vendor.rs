@@ -0,0 +1,48 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Kryoptic specific functions */
+
+/* Export policy flags */
+pub const KRF_EXPORT_NON_EXTRACTABLE: CK_FLAGS = 0x00000001;
+
+pub type KR_C_RotateKEK = ::std::option::Option<
+    unsafe extern "C" fn(
+        hSession: CK_SESSION_HANDLE,
+        pPin: CK_UTF8CHAR_PTR,
+        ulPinLen: CK_ULONG,
+    ) -> CK_RV,
+>;
+pub type KR_C_SetExportPolicy = ::std::option::Option<
+    unsafe extern "C" fn(hSession: CK_SESSION_HANDLE, flags: CK_FLAGS) -> CK_RV,
+>;
+pub type KR_C_ExportToken = ::std::option::Option<
+    unsafe extern "C" fn(
+        hSession: CK_SESSION_HANDLE,
+        hKey: CK_OBJECT_HANDLE,
+        pPassphrase: CK_UTF8CHAR_PTR,
+        ulPassphraseLen: CK_ULONG,
+        pArchive: CK_BYTE_PTR,
+        pulArchiveLen: CK_ULONG_PTR,
+    ) -> CK_RV,
+>;
+pub type KR_C_ImportToken = ::std::option::Option<
+    unsafe extern "C" fn(
+        hSession: CK_SESSION_HANDLE,
+        hKey: CK_OBJECT_HANDLE,
+        pPassphrase: CK_UTF8CHAR_PTR,
+        ulPassphraseLen: CK_ULONG,
+        pArchive: CK_BYTE_PTR,
+        ulArchiveLen: CK_ULONG,
+    ) -> CK_RV,
+>;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KR_FUNCTION_LIST {
+    pub version: CK_VERSION,
+    pub KR_RotateKEK: KR_C_RotateKEK,
+    pub KR_SetExportPolicy: KR_C_SetExportPolicy,
+    pub KR_ExportToken: KR_C_ExportToken,
+    pub KR_ImportToken: KR_C_ImportToken,
+}
@@ -0,0 +1,750 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Per token restrictions on the available mechanisms and key sizes,
+ * configured in the policy table of a slot, for example:
+ *
+ *   [slots.policy]
+ *   baseline = "hardened"
+ *   deny = ["CKM_SHA224_RSA_PKCS"]
+ *
+ *   [slots.policy.deny_operations]
+ *   CKM_RSA_PKCS_OAEP = ["wrap"]
+ *
+ *   [slots.policy.min_key_bits]
+ *   CKK_EC = 384
+ *
+ * Setting check_key_dates refuses keys outside of their CKA_START_DATE
+ * and CKA_END_DATE window for encryption, signing, wrapping and
+ * derivation.
+ *
+ * Mechanisms that are denied are not registered with the token at all,
+ * denied operations are removed from the mechanism flags, and key size
+ * limits are reflected in the mechanism info and enforced whenever keys
+ * are created or used */
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use super::ecc;
+use super::error;
+use super::interface;
+use super::mechanism;
+use super::misc;
+use super::object;
+use super::{cast_params, err_rv, to_rv};
+
+use error::Result;
+use interface::*;
+use mechanism::{Mechanism, Mechanisms, Operation};
+use misc::CK_ULONG_SIZE;
+use object::{Object, ObjectFactory};
+
+pub mod crypto_policies;
+
+macro_rules! names {
+    ($($name:ident),* $(,)?) => {
+        &[$((stringify!($name), $name)),*]
+    };
+}
+
+const MECHANISM_NAMES: &[(&str, CK_MECHANISM_TYPE)] = names![
+    CKM_AES_CBC,
+    CKM_AES_CBC_ENCRYPT_DATA,
+    CKM_AES_CBC_PAD,
+    CKM_AES_CCM,
+    CKM_AES_CFB1,
+    CKM_AES_CFB128,
+    CKM_AES_CFB8,
+    CKM_AES_CMAC,
+    CKM_AES_CMAC_GENERAL,
+    CKM_AES_CTR,
+    CKM_AES_CTS,
+    CKM_AES_ECB,
+    CKM_AES_ECB_ENCRYPT_DATA,
+    CKM_AES_GCM,
+    CKM_AES_KEY_GEN,
+    CKM_AES_KEY_WRAP,
+    CKM_AES_KEY_WRAP_KWP,
+    CKM_AES_MAC,
+    CKM_AES_MAC_GENERAL,
+    CKM_AES_OFB,
+    CKM_ECDH1_COFACTOR_DERIVE,
+    CKM_ECDH1_DERIVE,
+    CKM_ECDSA,
+    CKM_ECDSA_SHA1,
+    CKM_ECDSA_SHA224,
+    CKM_ECDSA_SHA256,
+    CKM_ECDSA_SHA384,
+    CKM_ECDSA_SHA512,
+    CKM_ECDSA_SHA3_224,
+    CKM_ECDSA_SHA3_256,
+    CKM_ECDSA_SHA3_384,
+    CKM_ECDSA_SHA3_512,
+    CKM_EC_EDWARDS_KEY_PAIR_GEN,
+    CKM_EC_KEY_PAIR_GEN,
+    CKM_EDDSA,
+    CKM_GENERIC_SECRET_KEY_GEN,
+    CKM_HKDF_DATA,
+    CKM_HKDF_DERIVE,
+    CKM_HKDF_KEY_GEN,
+    CKM_PKCS5_PBKD2,
+    CKM_RSA_PKCS,
+    CKM_RSA_PKCS_KEY_PAIR_GEN,
+    CKM_RSA_PKCS_OAEP,
+    CKM_RSA_PKCS_PSS,
+    CKM_SHA1_KEY_DERIVATION,
+    CKM_SHA1_RSA_PKCS,
+    CKM_SHA1_RSA_PKCS_PSS,
+    CKM_SHA224,
+    CKM_SHA224_HMAC,
+    CKM_SHA224_HMAC_GENERAL,
+    CKM_SHA224_KEY_DERIVATION,
+    CKM_SHA224_KEY_GEN,
+    CKM_SHA224_RSA_PKCS,
+    CKM_SHA224_RSA_PKCS_PSS,
+    CKM_SHA256,
+    CKM_SHA256_HMAC,
+    CKM_SHA256_HMAC_GENERAL,
+    CKM_SHA256_KEY_DERIVATION,
+    CKM_SHA256_KEY_GEN,
+    CKM_SHA256_RSA_PKCS,
+    CKM_SHA256_RSA_PKCS_PSS,
+    CKM_SHA384,
+    CKM_SHA384_HMAC,
+    CKM_SHA384_HMAC_GENERAL,
+    CKM_SHA384_KEY_DERIVATION,
+    CKM_SHA384_KEY_GEN,
+    CKM_SHA384_RSA_PKCS,
+    CKM_SHA384_RSA_PKCS_PSS,
+    CKM_SHA3_224,
+    CKM_SHA3_224_HMAC,
+    CKM_SHA3_224_HMAC_GENERAL,
+    CKM_SHA3_224_KEY_DERIVATION,
+    CKM_SHA3_224_KEY_GEN,
+    CKM_SHA3_224_RSA_PKCS,
+    CKM_SHA3_224_RSA_PKCS_PSS,
+    CKM_SHA3_256,
+    CKM_SHA3_256_HMAC,
+    CKM_SHA3_256_HMAC_GENERAL,
+    CKM_SHA3_256_KEY_DERIVATION,
+    CKM_SHA3_256_KEY_GEN,
+    CKM_SHA3_256_RSA_PKCS,
+    CKM_SHA3_256_RSA_PKCS_PSS,
+    CKM_SHA3_384,
+    CKM_SHA3_384_HMAC,
+    CKM_SHA3_384_HMAC_GENERAL,
+    CKM_SHA3_384_KEY_DERIVATION,
+    CKM_SHA3_384_KEY_GEN,
+    CKM_SHA3_384_RSA_PKCS,
+    CKM_SHA3_384_RSA_PKCS_PSS,
+    CKM_SHA3_512,
+    CKM_SHA3_512_HMAC,
+    CKM_SHA3_512_HMAC_GENERAL,
+    CKM_SHA3_512_KEY_DERIVATION,
+    CKM_SHA3_512_KEY_GEN,
+    CKM_SHA3_512_RSA_PKCS,
+    CKM_SHA3_512_RSA_PKCS_PSS,
+    CKM_SHA512,
+    CKM_SHA512_HMAC,
+    CKM_SHA512_HMAC_GENERAL,
+    CKM_SHA512_KEY_DERIVATION,
+    CKM_SHA512_KEY_GEN,
+    CKM_SHA512_RSA_PKCS,
+    CKM_SHA512_RSA_PKCS_PSS,
+    CKM_SHA_1,
+    CKM_SHA_1_HMAC,
+    CKM_SHA_1_HMAC_GENERAL,
+    CKM_SHA_1_KEY_GEN,
+    CKM_SP800_108_COUNTER_KDF,
+    CKM_SP800_108_DOUBLE_PIPELINE_KDF,
+    CKM_SP800_108_FEEDBACK_KDF,
+    CKM_TLS12_KDF,
+    CKM_TLS12_KEY_AND_MAC_DERIVE,
+    CKM_TLS12_KEY_SAFE_DERIVE,
+    CKM_TLS12_MAC,
+    CKM_TLS12_MASTER_KEY_DERIVE,
+    CKM_TLS_KDF,
+    CKM_TLS_MAC,
+];
+
+const KEY_TYPE_NAMES: &[(&str, CK_KEY_TYPE)] = names![
+    CKK_AES,
+    CKK_EC,
+    CKK_EC_EDWARDS,
+    CKK_EC_MONTGOMERY,
+    CKK_GENERIC_SECRET,
+    CKK_HKDF,
+    CKK_RSA,
+    CKK_SHA224_HMAC,
+    CKK_SHA256_HMAC,
+    CKK_SHA384_HMAC,
+    CKK_SHA3_224_HMAC,
+    CKK_SHA3_256_HMAC,
+    CKK_SHA3_384_HMAC,
+    CKK_SHA3_512_HMAC,
+    CKK_SHA512_HMAC,
+    CKK_SHA_1_HMAC,
+];
+
+const OPERATION_NAMES: &[(&str, CK_FLAGS)] = &[
+    ("encrypt", CKF_ENCRYPT),
+    ("decrypt", CKF_DECRYPT),
+    ("digest", CKF_DIGEST),
+    ("sign", CKF_SIGN),
+    ("verify", CKF_VERIFY),
+    ("generate", CKF_GENERATE | CKF_GENERATE_KEY_PAIR),
+    ("wrap", CKF_WRAP),
+    ("unwrap", CKF_UNWRAP),
+    ("derive", CKF_DERIVE),
+];
+
+/* The operations that can be denied for a mechanism */
+pub const OPERATION_FLAGS: CK_FLAGS = CKF_ENCRYPT
+    | CKF_DECRYPT
+    | CKF_DIGEST
+    | CKF_SIGN
+    | CKF_VERIFY
+    | CKF_GENERATE
+    | CKF_GENERATE_KEY_PAIR
+    | CKF_WRAP
+    | CKF_UNWRAP
+    | CKF_DERIVE;
+
+const SHA1_SIGNATURES: [CK_MECHANISM_TYPE; 3] =
+    [CKM_SHA1_RSA_PKCS, CKM_SHA1_RSA_PKCS_PSS, CKM_ECDSA_SHA1];
+
+const DATED_OPERATIONS: CK_FLAGS =
+    CKF_ENCRYPT | CKF_SIGN | CKF_WRAP | CKF_DERIVE;
+
+const RSA_PKCS1_ENCRYPTION: CK_FLAGS =
+    CKF_ENCRYPT | CKF_DECRYPT | CKF_WRAP | CKF_UNWRAP;
+
+/* Names are the PKCS#11 constant names, vendor values can be given as
+ * hexadecimal numbers */
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T>
+where
+    T: TryFrom<u64>,
+{
+    for (n, v) in table {
+        if *n == name {
+            return Some(*v);
+        }
+    }
+    match name.strip_prefix("0x") {
+        Some(hex) => match u64::from_str_radix(hex, 16) {
+            Ok(v) => T::try_from(v).ok(),
+            Err(_) => None,
+        },
+        None => None,
+    }
+}
+
+fn mechanism_by_name(name: &str) -> Result<CK_MECHANISM_TYPE> {
+    match lookup(MECHANISM_NAMES, name) {
+        Some(m) => Ok(m),
+        None => err_rv!(CKR_ARGUMENTS_BAD),
+    }
+}
+
+fn key_type_by_name(name: &str) -> Result<CK_KEY_TYPE> {
+    match lookup(KEY_TYPE_NAMES, name) {
+        Some(k) => Ok(k),
+        None => err_rv!(CKR_ARGUMENTS_BAD),
+    }
+}
+
+/* The key type a mechanism operates on and whether its mechanism info
+ * expresses key sizes in bytes rather than bits */
+fn mechanism_key_type(mech: CK_MECHANISM_TYPE) -> Option<(CK_KEY_TYPE, bool)> {
+    match mech {
+        CKM_RSA_PKCS_KEY_PAIR_GEN
+        | CKM_RSA_PKCS
+        | CKM_RSA_PKCS_OAEP
+        | CKM_RSA_PKCS_PSS
+        | CKM_SHA1_RSA_PKCS
+        | CKM_SHA1_RSA_PKCS_PSS
+        | CKM_SHA224_RSA_PKCS
+        | CKM_SHA224_RSA_PKCS_PSS
+        | CKM_SHA256_RSA_PKCS
+        | CKM_SHA256_RSA_PKCS_PSS
+        | CKM_SHA384_RSA_PKCS
+        | CKM_SHA384_RSA_PKCS_PSS
+        | CKM_SHA512_RSA_PKCS
+        | CKM_SHA512_RSA_PKCS_PSS
+        | CKM_SHA3_224_RSA_PKCS
+        | CKM_SHA3_224_RSA_PKCS_PSS
+        | CKM_SHA3_256_RSA_PKCS
+        | CKM_SHA3_256_RSA_PKCS_PSS
+        | CKM_SHA3_384_RSA_PKCS
+        | CKM_SHA3_384_RSA_PKCS_PSS
+        | CKM_SHA3_512_RSA_PKCS
+        | CKM_SHA3_512_RSA_PKCS_PSS => Some((CKK_RSA, false)),
+        CKM_EC_KEY_PAIR_GEN
+        | CKM_ECDH1_DERIVE
+        | CKM_ECDH1_COFACTOR_DERIVE
+        | CKM_ECDSA
+        | CKM_ECDSA_SHA1
+        | CKM_ECDSA_SHA224
+        | CKM_ECDSA_SHA256
+        | CKM_ECDSA_SHA384
+        | CKM_ECDSA_SHA512
+        | CKM_ECDSA_SHA3_224
+        | CKM_ECDSA_SHA3_256
+        | CKM_ECDSA_SHA3_384
+        | CKM_ECDSA_SHA3_512 => Some((CKK_EC, false)),
+        CKM_AES_KEY_GEN | CKM_AES_CBC | CKM_AES_CBC_PAD | CKM_AES_CCM
+        | CKM_AES_CFB1 | CKM_AES_CFB128 | CKM_AES_CFB8 | CKM_AES_CMAC
+        | CKM_AES_CMAC_GENERAL | CKM_AES_CTR | CKM_AES_CTS | CKM_AES_ECB
+        | CKM_AES_GCM | CKM_AES_KEY_WRAP | CKM_AES_KEY_WRAP_KWP
+        | CKM_AES_MAC | CKM_AES_MAC_GENERAL | CKM_AES_OFB => {
+            Some((CKK_AES, true))
+        }
+        _ => None,
+    }
+}
+
+fn key_bits(key: &Object) -> Option<CK_ULONG> {
+    let bits = match key.get_attr_as_ulong(CKA_KEY_TYPE).ok()? {
+        CKK_RSA => match key.get_attr_as_bytes(CKA_MODULUS) {
+            Ok(m) => {
+                let skip = m.iter().take_while(|b| **b == 0).count();
+                match m.get(skip) {
+                    Some(b) => {
+                        (m.len() - skip) * 8 - b.leading_zeros() as usize
+                    }
+                    None => 0,
+                }
+            }
+            Err(_) => return key.get_attr_as_ulong(CKA_MODULUS_BITS).ok(),
+        },
+        CKK_EC => ecc::ec_key_curve_size(key).ok()?,
+        CKK_EC_EDWARDS | CKK_EC_MONTGOMERY => return None,
+        _ => match key.get_attr_as_ulong(CKA_VALUE_LEN) {
+            Ok(l) => usize::try_from(l).ok()? * 8,
+            Err(_) => key.get_attr_as_bytes(CKA_VALUE).ok()?.len() * 8,
+        },
+    };
+    CK_ULONG::try_from(bits).ok()
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub baseline: Option<String>,
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub deny_sha1_signatures: bool,
+    #[serde(default)]
+    pub deny_ecdh_without_kdf: bool,
+    #[serde(default)]
+    pub check_key_dates: bool,
+    #[serde(default)]
+    pub deny_operations: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub min_key_bits: BTreeMap<String, CK_ULONG>,
+    #[serde(default)]
+    pub max_key_bits: BTreeMap<String, CK_ULONG>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Policy {
+    allow: Option<Vec<CK_MECHANISM_TYPE>>,
+    deny: Vec<CK_MECHANISM_TYPE>,
+    deny_operations: BTreeMap<CK_MECHANISM_TYPE, CK_FLAGS>,
+    min_key_bits: BTreeMap<CK_KEY_TYPE, CK_ULONG>,
+    max_key_bits: BTreeMap<CK_KEY_TYPE, CK_ULONG>,
+    deny_sha1_signatures: bool,
+    deny_ecdh_without_kdf: bool,
+    check_key_dates: bool,
+}
+
+impl Policy {
+    /* Disables SHA-1 signatures, RSA keys shorter than 2048 bits,
+     * PKCS#1 v1.5 encryption and ECDH without a key derivation */
+    pub fn hardened() -> Policy {
+        let mut policy = Policy::default();
+        policy.deny_sha1_signatures = true;
+        policy.deny_ecdh_without_kdf = true;
+        policy.deny_operation(CKM_RSA_PKCS, RSA_PKCS1_ENCRYPTION);
+        policy.min_key_bits.insert(CKK_RSA, 2048);
+        policy
+    }
+
+    pub fn from_config(config: &PolicyConfig) -> Result<Policy> {
+        let mut policy = match &config.baseline {
+            None => Policy::default(),
+            Some(b) => match b.as_str() {
+                "none" => Policy::default(),
+                "hardened" => Policy::hardened(),
+                _ => return err_rv!(CKR_ARGUMENTS_BAD),
+            },
+        };
+        if let Some(allow) = &config.allow {
+            let mut mechs = Vec::with_capacity(allow.len());
+            for name in allow {
+                mechs.push(mechanism_by_name(name)?);
+            }
+            policy.allow = Some(mechs);
+        }
+        for name in &config.deny {
+            policy.deny.push(mechanism_by_name(name)?);
+        }
+        policy.deny_sha1_signatures |= config.deny_sha1_signatures;
+        policy.deny_ecdh_without_kdf |= config.deny_ecdh_without_kdf;
+        policy.check_key_dates |= config.check_key_dates;
+        for (name, ops) in &config.deny_operations {
+            let mech = mechanism_by_name(name)?;
+            let mut flags: CK_FLAGS = 0;
+            for op in ops {
+                flags |= match lookup(OPERATION_NAMES, op) {
+                    Some(f) => f,
+                    None => return err_rv!(CKR_ARGUMENTS_BAD),
+                };
+            }
+            policy.deny_operation(mech, flags);
+        }
+        for (name, bits) in &config.min_key_bits {
+            policy.min_key_bits.insert(key_type_by_name(name)?, *bits);
+        }
+        for (name, bits) in &config.max_key_bits {
+            policy.max_key_bits.insert(key_type_by_name(name)?, *bits);
+        }
+        Ok(policy)
+    }
+
+    pub fn deny_operation(&mut self, mech: CK_MECHANISM_TYPE, ops: CK_FLAGS) {
+        *self.deny_operations.entry(mech).or_insert(0) |= ops;
+    }
+
+    /* Combines two policies, the result is the most restrictive */
+    pub fn merge(&mut self, other: &Policy) {
+        self.allow = match (self.allow.take(), &other.allow) {
+            (Some(a), Some(b)) => {
+                Some(a.into_iter().filter(|m| b.contains(m)).collect())
+            }
+            (Some(a), None) => Some(a),
+            (None, b) => b.clone(),
+        };
+        for mech in &other.deny {
+            if !self.deny.contains(mech) {
+                self.deny.push(*mech);
+            }
+        }
+        for (mech, ops) in &other.deny_operations {
+            self.deny_operation(*mech, *ops);
+        }
+        for (key_type, bits) in &other.min_key_bits {
+            let min = self.min_key_bits.entry(*key_type).or_insert(*bits);
+            if *min < *bits {
+                *min = *bits;
+            }
+        }
+        for (key_type, bits) in &other.max_key_bits {
+            let max = self.max_key_bits.entry(*key_type).or_insert(*bits);
+            if *max > *bits {
+                *max = *bits;
+            }
+        }
+        self.deny_sha1_signatures |= other.deny_sha1_signatures;
+        self.deny_ecdh_without_kdf |= other.deny_ecdh_without_kdf;
+        self.check_key_dates |= other.check_key_dates;
+    }
+
+    fn denied_operations(&self, mech: CK_MECHANISM_TYPE) -> CK_FLAGS {
+        let mut ops = match self.deny_operations.get(&mech) {
+            Some(f) => *f,
+            None => 0,
+        };
+        if self.deny_sha1_signatures && SHA1_SIGNATURES.contains(&mech) {
+            ops |= CKF_SIGN;
+        }
+        ops
+    }
+
+    fn is_denied(&self, mech: CK_MECHANISM_TYPE) -> bool {
+        if self.deny.contains(&mech) {
+            return true;
+        }
+        match &self.allow {
+            Some(allow) => !allow.contains(&mech),
+            None => false,
+        }
+    }
+
+    /* Removes denied mechanisms and restricts the flags and key sizes
+     * advertised by the remaining ones */
+    pub fn apply(&self, mechanisms: &mut Mechanisms) {
+        for typ in mechanisms.list() {
+            if self.is_denied(typ) {
+                mechanisms.remove(typ);
+                continue;
+            }
+            let orig = match mechanisms.info(typ) {
+                Some(i) => *i,
+                None => continue,
+            };
+            let mut info = orig;
+            info.flags &= !self.denied_operations(typ);
+            if let Some((key_type, in_bytes)) = mechanism_key_type(typ) {
+                let unit = if in_bytes { 8 } else { 1 };
+                if let Some(min) = self.min_key_bits.get(&key_type) {
+                    let min = (min + unit - 1) / unit;
+                    if info.ulMinKeySize < min {
+                        info.ulMinKeySize = min;
+                    }
+                }
+                if let Some(max) = self.max_key_bits.get(&key_type) {
+                    let max = max / unit;
+                    if info.ulMaxKeySize == 0 || info.ulMaxKeySize > max {
+                        info.ulMaxKeySize = max;
+                    }
+                }
+            }
+            if info.flags != orig.flags
+                || info.ulMinKeySize != orig.ulMinKeySize
+                || info.ulMaxKeySize != orig.ulMaxKeySize
+            {
+                mechanisms.restrict(typ, info);
+            }
+        }
+    }
+
+    /* Refuses keys outside of the allowed sizes */
+    pub fn check_key(&self, key: &Object) -> Result<()> {
+        let key_type = match key.get_attr_as_ulong(CKA_KEY_TYPE) {
+            Ok(k) => k,
+            Err(_) => return Ok(()),
+        };
+        let min = self.min_key_bits.get(&key_type);
+        let max = self.max_key_bits.get(&key_type);
+        if min.is_none() && max.is_none() {
+            return Ok(());
+        }
+        let bits = match key_bits(key) {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+        if let Some(min) = min {
+            if bits < *min {
+                return err_rv!(CKR_KEY_SIZE_RANGE);
+            }
+        }
+        if let Some(max) = max {
+            if bits > *max {
+                return err_rv!(CKR_KEY_SIZE_RANGE);
+            }
+        }
+        Ok(())
+    }
+
+    /* Refuses keys outside of their validity period for operations that
+     * originate data, verification and decryption remain possible */
+    pub fn check_key_dates(&self, key: &Object, op: CK_FLAGS) -> Result<()> {
+        if !self.check_key_dates || op & DATED_OPERATIONS == 0 {
+            return Ok(());
+        }
+        let today = misc::utc_date()?;
+        if let Some(start) = key.get_attr(CKA_START_DATE) {
+            let start = start.get_value();
+            if start.len() != 0 && today.as_slice() < start.as_slice() {
+                return err_rv!(CKR_KEY_FUNCTION_NOT_PERMITTED);
+            }
+        }
+        if let Some(end) = key.get_attr(CKA_END_DATE) {
+            let end = end.get_value();
+            if end.len() != 0 && today.as_slice() > end.as_slice() {
+                return err_rv!(CKR_KEY_FUNCTION_NOT_PERMITTED);
+            }
+        }
+        Ok(())
+    }
+
+    /* Checks the requested operation and the mechanism parameters */
+    pub fn check_mechanism(
+        &self,
+        mech: &CK_MECHANISM,
+        op: CK_FLAGS,
+    ) -> Result<()> {
+        if self.is_denied(mech.mechanism)
+            || self.denied_operations(mech.mechanism) & op != 0
+        {
+            return err_rv!(CKR_MECHANISM_INVALID);
+        }
+        match mech.mechanism {
+            CKM_RSA_PKCS_PSS if self.deny_sha1_signatures && op == CKF_SIGN => {
+                let params = cast_params!(mech, CK_RSA_PKCS_PSS_PARAMS);
+                if params.hashAlg == CKM_SHA_1 {
+                    return err_rv!(CKR_MECHANISM_PARAM_INVALID);
+                }
+            }
+            CKM_ECDH1_DERIVE | CKM_ECDH1_COFACTOR_DERIVE
+                if self.deny_ecdh_without_kdf =>
+            {
+                let params = cast_params!(mech, CK_ECDH1_DERIVE_PARAMS);
+                if params.kdf == CKD_NULL {
+                    return err_rv!(CKR_MECHANISM_PARAM_INVALID);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/* The operations denied by the SO are stored as pairs of CK_ULONGs,
+ * the mechanism type followed by the denied flags */
+pub fn encode_denied_operations(
+    denied: &BTreeMap<CK_MECHANISM_TYPE, CK_FLAGS>,
+) -> Vec<u8> {
+    let mut value = Vec::<u8>::with_capacity(denied.len() * 2 * CK_ULONG_SIZE);
+    for (mech, ops) in denied {
+        value.extend_from_slice(&mech.to_ne_bytes());
+        value.extend_from_slice(&ops.to_ne_bytes());
+    }
+    value
+}
+
+pub fn decode_denied_operations(
+    value: &[u8],
+) -> Result<BTreeMap<CK_MECHANISM_TYPE, CK_FLAGS>> {
+    if value.len() % (2 * CK_ULONG_SIZE) != 0 {
+        return err_rv!(CKR_GENERAL_ERROR);
+    }
+    let mut denied = BTreeMap::<CK_MECHANISM_TYPE, CK_FLAGS>::new();
+    for pair in value.chunks(2 * CK_ULONG_SIZE) {
+        let (mech, ops) = pair.split_at(CK_ULONG_SIZE);
+        let mech = CK_ULONG::from_ne_bytes(
+            mech.try_into().map_err(|_| to_rv!(CKR_GENERAL_ERROR))?,
+        );
+        let ops = CK_ULONG::from_ne_bytes(
+            ops.try_into().map_err(|_| to_rv!(CKR_GENERAL_ERROR))?,
+        );
+        denied.insert(mech, ops);
+    }
+    Ok(denied)
+}
+
+/* Wraps a registered mechanism to report the restricted info */
+#[derive(Debug)]
+pub struct Restricted {
+    info: CK_MECHANISM_INFO,
+    mech: Box<dyn Mechanism>,
+}
+
+impl Restricted {
+    pub fn new(
+        mech: Box<dyn Mechanism>,
+        info: CK_MECHANISM_INFO,
+    ) -> Restricted {
+        Restricted {
+            info: info,
+            mech: mech,
+        }
+    }
+}
+
+impl Mechanism for Restricted {
+    fn info(&self) -> &CK_MECHANISM_INFO {
+        &self.info
+    }
+
+    fn encryption_new(
+        &self,
+        mech: &CK_MECHANISM,
+        key: &Object,
+    ) -> Result<Box<dyn mechanism::Encryption>> {
+        self.mech.encryption_new(mech, key)
+    }
+
+    fn decryption_new(
+        &self,
+        mech: &CK_MECHANISM,
+        key: &Object,
+    ) -> Result<Box<dyn mechanism::Decryption>> {
+        self.mech.decryption_new(mech, key)
+    }
+
+    fn digest_new(
+        &self,
+        mech: &CK_MECHANISM,
+    ) -> Result<Box<dyn mechanism::Digest>> {
+        self.mech.digest_new(mech)
+    }
+
+    fn mac_new(
+        &self,
+        mech: &CK_MECHANISM,
+        key: &Object,
+        op: CK_FLAGS,
+    ) -> Result<Box<dyn mechanism::Mac>> {
+        self.mech.mac_new(mech, key, op)
+    }
+
+    fn sign_new(
+        &self,
+        mech: &CK_MECHANISM,
+        key: &Object,
+    ) -> Result<Box<dyn mechanism::Sign>> {
+        self.mech.sign_new(mech, key)
+    }
+
+    fn verify_new(
+        &self,
+        mech: &CK_MECHANISM,
+        key: &Object,
+    ) -> Result<Box<dyn mechanism::Verify>> {
+        self.mech.verify_new(mech, key)
+    }
+
+    fn generate_key(
+        &self,
+        mech: &CK_MECHANISM,
+        template: &[CK_ATTRIBUTE],
+        mechanisms: &Mechanisms,
+        factories: &object::ObjectFactories,
+    ) -> Result<Object> {
+        self.mech
+            .generate_key(mech, template, mechanisms, factories)
+    }
+
+    fn generate_keypair(
+        &self,
+        mech: &CK_MECHANISM,
+        pubkey_template: &[CK_ATTRIBUTE],
+        prikey_template: &[CK_ATTRIBUTE],
+    ) -> Result<(Object, Object)> {
+        self.mech
+            .generate_keypair(mech, pubkey_template, prikey_template)
+    }
+
+    fn wrap_key(
+        &self,
+        mech: &CK_MECHANISM,
+        wrapping_key: &Object,
+        key: &Object,
+        data: &mut [u8],
+        factory: &Box<dyn ObjectFactory>,
+    ) -> Result<usize> {
+        self.mech.wrap_key(mech, wrapping_key, key, data, factory)
+    }
+
+    fn unwrap_key(
+        &self,
+        mech: &CK_MECHANISM,
+        wrapping_key: &Object,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+        factory: &Box<dyn ObjectFactory>,
+    ) -> Result<Object> {
+        self.mech
+            .unwrap_key(mech, wrapping_key, data, template, factory)
+    }
+
+    fn derive_operation(&self, mech: &CK_MECHANISM) -> Result<Operation> {
+        self.mech.derive_operation(mech)
+    }
+}
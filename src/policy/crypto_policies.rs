@@ -0,0 +1,245 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* System wide crypto-policies back-end. The back-end file is generated
+ * by update-crypto-policies and holds the flattened policy, one
+ * property per line using the crypto-policies algorithm names:
+ *
+ *   hash = SHA2-256 SHA2-384 SHA2-512 SHA3-256 SHA3-384 SHA3-512
+ *   mac = HMAC-SHA2-256 HMAC-SHA2-384 HMAC-SHA2-512
+ *   sign = ECDSA-SHA2-256 RSA-PSS-SHA2-256 RSA-SHA2-256 EDDSA-ED25519
+ *   cipher = AES-256-GCM AES-256-CBC AES-128-GCM AES-128-CBC
+ *   key_exchange = ECDHE DHE
+ *   min_rsa_size = 2048
+ *   min_ec_size = 256
+ *
+ * Algorithms missing from a list are disabled, hashes missing from the
+ * list are only disabled in signatures as digests have many other uses.
+ * Properties that are not present or not relevant to a token are
+ * ignored */
+
+use std::collections::HashMap;
+
+use super::super::err_rv;
+use super::super::error;
+use super::super::interface;
+
+use super::{Policy, RSA_PKCS1_ENCRYPTION};
+
+use error::Result;
+use interface::*;
+
+pub const DEFAULT_BACKEND_FILE: &str =
+    "/etc/crypto-policies/back-ends/kryoptic.config";
+
+/* signature names end with the name of the hash they use */
+const HASHES: &[&str] = &[
+    "SHA1", "SHA2-224", "SHA2-256", "SHA2-384", "SHA2-512", "SHA3-224",
+    "SHA3-256", "SHA3-384", "SHA3-512",
+];
+
+const MACS: &[(&str, &[CK_MECHANISM_TYPE])] = &[
+    ("HMAC-SHA1", &[CKM_SHA_1_HMAC, CKM_SHA_1_HMAC_GENERAL]),
+    ("HMAC-SHA2-224", &[CKM_SHA224_HMAC, CKM_SHA224_HMAC_GENERAL]),
+    ("HMAC-SHA2-256", &[CKM_SHA256_HMAC, CKM_SHA256_HMAC_GENERAL]),
+    ("HMAC-SHA2-384", &[CKM_SHA384_HMAC, CKM_SHA384_HMAC_GENERAL]),
+    ("HMAC-SHA2-512", &[CKM_SHA512_HMAC, CKM_SHA512_HMAC_GENERAL]),
+    (
+        "HMAC-SHA3-224",
+        &[CKM_SHA3_224_HMAC, CKM_SHA3_224_HMAC_GENERAL],
+    ),
+    (
+        "HMAC-SHA3-256",
+        &[CKM_SHA3_256_HMAC, CKM_SHA3_256_HMAC_GENERAL],
+    ),
+    (
+        "HMAC-SHA3-384",
+        &[CKM_SHA3_384_HMAC, CKM_SHA3_384_HMAC_GENERAL],
+    ),
+    (
+        "HMAC-SHA3-512",
+        &[CKM_SHA3_512_HMAC, CKM_SHA3_512_HMAC_GENERAL],
+    ),
+];
+
+/* CKM_EDDSA serves both curves, so it is listed twice and stays usable
+ * as long as either one is allowed */
+const SIGNATURES: &[(&str, &[CK_MECHANISM_TYPE])] = &[
+    ("RSA-SHA1", &[CKM_SHA1_RSA_PKCS]),
+    ("RSA-SHA2-224", &[CKM_SHA224_RSA_PKCS]),
+    ("RSA-SHA2-256", &[CKM_SHA256_RSA_PKCS]),
+    ("RSA-SHA2-384", &[CKM_SHA384_RSA_PKCS]),
+    ("RSA-SHA2-512", &[CKM_SHA512_RSA_PKCS]),
+    ("RSA-SHA3-224", &[CKM_SHA3_224_RSA_PKCS]),
+    ("RSA-SHA3-256", &[CKM_SHA3_256_RSA_PKCS]),
+    ("RSA-SHA3-384", &[CKM_SHA3_384_RSA_PKCS]),
+    ("RSA-SHA3-512", &[CKM_SHA3_512_RSA_PKCS]),
+    ("RSA-PSS-SHA1", &[CKM_SHA1_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA2-224", &[CKM_SHA224_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA2-256", &[CKM_SHA256_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA2-384", &[CKM_SHA384_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA2-512", &[CKM_SHA512_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA3-224", &[CKM_SHA3_224_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA3-256", &[CKM_SHA3_256_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA3-384", &[CKM_SHA3_384_RSA_PKCS_PSS]),
+    ("RSA-PSS-SHA3-512", &[CKM_SHA3_512_RSA_PKCS_PSS]),
+    ("ECDSA-SHA1", &[CKM_ECDSA_SHA1]),
+    ("ECDSA-SHA2-224", &[CKM_ECDSA_SHA224]),
+    ("ECDSA-SHA2-256", &[CKM_ECDSA_SHA256]),
+    ("ECDSA-SHA2-384", &[CKM_ECDSA_SHA384]),
+    ("ECDSA-SHA2-512", &[CKM_ECDSA_SHA512]),
+    ("ECDSA-SHA3-224", &[CKM_ECDSA_SHA3_224]),
+    ("ECDSA-SHA3-256", &[CKM_ECDSA_SHA3_256]),
+    ("ECDSA-SHA3-384", &[CKM_ECDSA_SHA3_384]),
+    ("ECDSA-SHA3-512", &[CKM_ECDSA_SHA3_512]),
+    ("EDDSA-ED25519", &[CKM_EDDSA]),
+    ("EDDSA-ED448", &[CKM_EDDSA]),
+];
+
+const AES_MODES: &[(&str, &[CK_MECHANISM_TYPE])] = &[
+    ("GCM", &[CKM_AES_GCM]),
+    ("CCM", &[CKM_AES_CCM]),
+    ("CTR", &[CKM_AES_CTR]),
+    ("CBC", &[CKM_AES_CBC, CKM_AES_CBC_PAD, CKM_AES_CTS]),
+    ("CFB", &[CKM_AES_CFB1, CKM_AES_CFB8, CKM_AES_CFB128]),
+    ("OFB", &[CKM_AES_OFB]),
+];
+
+const SIGN_OPERATIONS: CK_FLAGS = CKF_SIGN | CKF_VERIFY;
+const CIPHER_OPERATIONS: CK_FLAGS = CKF_ENCRYPT | CKF_DECRYPT;
+
+fn parse(text: &str) -> Result<HashMap<&str, Vec<&str>>> {
+    let mut props = HashMap::<&str, Vec<&str>>::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => return err_rv!(CKR_ARGUMENTS_BAD),
+        };
+        props.insert(key.trim(), value.split_whitespace().collect());
+    }
+    Ok(props)
+}
+
+/* Mechanisms implementing only algorithms missing from the allowed list */
+fn disallowed(
+    table: &[(&str, &[CK_MECHANISM_TYPE])],
+    allowed: &[&str],
+) -> Vec<CK_MECHANISM_TYPE> {
+    let mut mechs = Vec::<CK_MECHANISM_TYPE>::new();
+    for (name, list) in table {
+        if !allowed.contains(name) {
+            mechs.extend_from_slice(list);
+        }
+    }
+    for (name, list) in table {
+        if allowed.contains(name) {
+            mechs.retain(|m| !list.contains(m));
+        }
+    }
+    mechs
+}
+
+fn size(value: &[&str]) -> Result<CK_ULONG> {
+    match value {
+        [v] => match v.parse::<CK_ULONG>() {
+            Ok(n) => Ok(n),
+            Err(_) => err_rv!(CKR_ARGUMENTS_BAD),
+        },
+        _ => err_rv!(CKR_ARGUMENTS_BAD),
+    }
+}
+
+pub fn from_backend(text: &str) -> Result<Policy> {
+    let props = parse(text)?;
+    let mut policy = Policy::default();
+
+    if let Some(hashes) = props.get("hash") {
+        for (name, list) in SIGNATURES {
+            let denied = HASHES.iter().any(|h| {
+                !hashes.contains(h) && name.ends_with(&format!("-{}", h))
+            });
+            if denied {
+                for mech in *list {
+                    policy.deny_operation(*mech, SIGN_OPERATIONS);
+                }
+            }
+        }
+        /* SHA-1 can also be selected through the PSS parameters */
+        if !hashes.contains(&"SHA1") {
+            policy.deny_sha1_signatures = true;
+        }
+    }
+    if let Some(macs) = props.get("mac") {
+        policy.deny.extend(disallowed(MACS, macs));
+    }
+    if let Some(sigs) = props.get("sign") {
+        for mech in disallowed(SIGNATURES, sigs) {
+            policy.deny_operation(mech, SIGN_OPERATIONS);
+        }
+        /* SHA-1 can also be selected through the PSS parameters */
+        if !sigs.iter().any(|s| s.ends_with("-SHA1")) {
+            policy.deny_sha1_signatures = true;
+        }
+    }
+    if let Some(ciphers) = props.get("cipher") {
+        let mut modes = Vec::<&str>::new();
+        let mut min_bits: Option<CK_ULONG> = None;
+        for c in ciphers {
+            let mut parts = c.splitn(3, '-');
+            let (alg, bits, mode) = (parts.next(), parts.next(), parts.next());
+            if alg != Some("AES") {
+                continue;
+            }
+            let bits = match bits.map(|b| b.parse::<CK_ULONG>()) {
+                Some(Ok(b)) => b,
+                _ => continue,
+            };
+            if let Some(m) = mode {
+                modes.push(m);
+            }
+            min_bits = match min_bits {
+                Some(b) if b < bits => Some(b),
+                _ => Some(bits),
+            };
+        }
+        for mech in disallowed(AES_MODES, &modes) {
+            policy.deny_operation(mech, CIPHER_OPERATIONS);
+        }
+        if let Some(b) = min_bits {
+            policy.min_key_bits.insert(CKK_AES, b);
+        }
+    }
+    if let Some(kx) = props.get("key_exchange") {
+        /* PKCS#1 v1.5 encryption is only used for RSA key transport */
+        if !kx.contains(&"RSA") {
+            policy.deny_operation(CKM_RSA_PKCS, RSA_PKCS1_ENCRYPTION);
+        }
+        if !kx.contains(&"ECDHE") && !kx.contains(&"ECDH") {
+            policy.deny.push(CKM_ECDH1_DERIVE);
+            policy.deny.push(CKM_ECDH1_COFACTOR_DERIVE);
+        }
+    }
+    if let Some(v) = props.get("min_rsa_size") {
+        policy.min_key_bits.insert(CKK_RSA, size(v)?);
+    }
+    if let Some(v) = props.get("min_ec_size") {
+        policy.min_key_bits.insert(CKK_EC, size(v)?);
+    }
+    Ok(policy)
+}
+
+/* Returns None when the back-end file does not exist, as on systems
+ * without crypto-policies */
+pub fn load(path: &str) -> Result<Option<Policy>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(Some(from_backend(&text)?)),
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(None),
+            _ => Err(error::Error::ck_rv_from_error(CKR_GENERAL_ERROR, e)),
+        },
+    }
+}
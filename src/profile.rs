@@ -0,0 +1,147 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* CKO_PROFILE objects advertise the PKCS#11 profiles implemented by
+ * the token. Which profiles are listed depends on the mechanisms that
+ * are actually available, so the objects are regenerated whenever a
+ * policy restricts the mechanism list. */
+
+use super::attr_element;
+use super::attribute;
+use super::error;
+use super::interface;
+use super::mechanism;
+use super::object;
+
+use attribute::from_ulong;
+use error::Result;
+use interface::*;
+use mechanism::Mechanisms;
+use object::{
+    OAFlags, Object, ObjectAttr, ObjectFactories, ObjectFactory, ObjectType,
+    SynthesizedFactory,
+};
+
+use once_cell::sync::Lazy;
+
+/* Each requirement is satisfied if at least one of the listed
+ * mechanisms is available with all of the listed flags */
+type Requirement = &'static [(CK_MECHANISM_TYPE, CK_FLAGS)];
+
+const BASELINE_PROVIDER: &[Requirement] = &[
+    &[(CKM_RSA_PKCS_KEY_PAIR_GEN, CKF_GENERATE_KEY_PAIR)],
+    &[
+        (CKM_RSA_PKCS, CKF_SIGN | CKF_VERIFY),
+        (CKM_RSA_PKCS_PSS, CKF_SIGN | CKF_VERIFY),
+    ],
+    &[(CKM_EC_KEY_PAIR_GEN, CKF_GENERATE_KEY_PAIR)],
+    &[(CKM_ECDSA, CKF_SIGN | CKF_VERIFY)],
+    &[(CKM_AES_KEY_GEN, CKF_GENERATE)],
+    &[
+        (CKM_AES_GCM, CKF_ENCRYPT | CKF_DECRYPT),
+        (CKM_AES_CBC_PAD, CKF_ENCRYPT | CKF_DECRYPT),
+    ],
+    &[(CKM_SHA256, CKF_DIGEST)],
+    &[(CKM_SHA384, CKF_DIGEST)],
+    &[(CKM_SHA512, CKF_DIGEST)],
+    &[(CKM_SHA256_HMAC, CKF_SIGN | CKF_VERIFY)],
+];
+
+const EXTENDED_PROVIDER: &[Requirement] = &[
+    &[(CKM_RSA_PKCS_OAEP, CKF_ENCRYPT | CKF_DECRYPT)],
+    &[(CKM_ECDH1_DERIVE, CKF_DERIVE)],
+    &[(CKM_AES_KEY_WRAP, CKF_WRAP | CKF_UNWRAP)],
+    &[(CKM_AES_KEY_WRAP_KWP, CKF_WRAP | CKF_UNWRAP)],
+    &[(CKM_AES_CTR, CKF_ENCRYPT | CKF_DECRYPT)],
+    &[(CKM_AES_CMAC, CKF_SIGN | CKF_VERIFY)],
+    &[(CKM_SHA3_256, CKF_DIGEST)],
+    &[(CKM_HKDF_DERIVE, CKF_DERIVE)],
+    &[(CKM_PKCS5_PBKD2, CKF_GENERATE)],
+];
+
+const AUTHENTICATION_TOKEN: &[Requirement] = &[
+    &[
+        (CKM_RSA_PKCS_KEY_PAIR_GEN, CKF_GENERATE_KEY_PAIR),
+        (CKM_EC_KEY_PAIR_GEN, CKF_GENERATE_KEY_PAIR),
+    ],
+    &[
+        (CKM_RSA_PKCS, CKF_SIGN),
+        (CKM_RSA_PKCS_PSS, CKF_SIGN),
+        (CKM_ECDSA, CKF_SIGN),
+    ],
+];
+
+/* only needs certificate objects, which are always supported */
+const PUBLIC_CERTIFICATES_TOKEN: &[Requirement] = &[];
+
+fn requirements_met(mechs: &Mechanisms, reqs: &[Requirement]) -> bool {
+    reqs.iter().all(|req| {
+        req.iter().any(|(typ, flags)| match mechs.info(*typ) {
+            Some(info) => info.flags & flags == *flags,
+            None => false,
+        })
+    })
+}
+
+/* Returns the ids of the profiles the current mechanisms satisfy */
+pub fn available_profiles(mechs: &Mechanisms) -> Vec<CK_PROFILE_ID> {
+    let mut profiles = Vec::<CK_PROFILE_ID>::new();
+    let baseline = requirements_met(mechs, BASELINE_PROVIDER);
+    if baseline {
+        profiles.push(CKP_BASELINE_PROVIDER);
+        if requirements_met(mechs, EXTENDED_PROVIDER) {
+            profiles.push(CKP_EXTENDED_PROVIDER);
+        }
+    }
+    if requirements_met(mechs, AUTHENTICATION_TOKEN) {
+        profiles.push(CKP_AUTHENTICATION_TOKEN);
+    }
+    if requirements_met(mechs, PUBLIC_CERTIFICATES_TOKEN) {
+        profiles.push(CKP_PUBLIC_CERTIFICATES_TOKEN);
+    }
+    profiles
+}
+
+fn requirements(profile_id: CK_PROFILE_ID) -> &'static [Requirement] {
+    match profile_id {
+        CKP_BASELINE_PROVIDER => BASELINE_PROVIDER,
+        CKP_EXTENDED_PROVIDER => EXTENDED_PROVIDER,
+        CKP_AUTHENTICATION_TOKEN => AUTHENTICATION_TOKEN,
+        CKP_PUBLIC_CERTIFICATES_TOKEN => PUBLIC_CERTIFICATES_TOKEN,
+        _ => &[],
+    }
+}
+
+/* Returns the ids of the available profiles that list the mechanism
+ * among their requirements */
+pub fn mechanism_profiles(
+    mechs: &Mechanisms,
+    typ: CK_MECHANISM_TYPE,
+) -> Vec<CK_PROFILE_ID> {
+    available_profiles(mechs)
+        .into_iter()
+        .filter(|p| {
+            requirements(*p)
+                .iter()
+                .any(|req| req.iter().any(|(t, _)| *t == typ))
+        })
+        .collect()
+}
+
+/* Synthesizes a read-only profile object */
+pub fn profile_object(profile_id: CK_PROFILE_ID) -> Result<Object> {
+    let mut obj = object::synthesized_object(CKO_PROFILE)?;
+    obj.set_attr(from_ulong(CKA_PROFILE_ID, profile_id))?;
+    Ok(obj)
+}
+
+static PROFILE_FACTORY: Lazy<Box<dyn ObjectFactory>> = Lazy::new(|| {
+    Box::new(SynthesizedFactory::new(vec![attr_element!(
+        CKA_PROFILE_ID;
+        OAFlags::AlwaysRequired | OAFlags::NeverSettable
+        | OAFlags::Unchangeable; from_ulong; val CKP_INVALID_ID)]))
+});
+
+pub fn register(_: &mut Mechanisms, ot: &mut ObjectFactories) {
+    ot.add_factory(ObjectType::new(CKO_PROFILE, 0), &PROFILE_FACTORY);
+}
@@ -8,7 +8,7 @@ use super::kasn1;
 use super::object;
 use super::{attr_element, bytes_attr_not_empty, err_rv};
 
-use attribute::{from_bool, from_bytes, from_ulong};
+use attribute::{from_bool, from_bytes, from_ulong, CkAttrs};
 use error::Result;
 use interface::*;
 use kasn1::DerEncBigUint;
@@ -52,6 +52,7 @@ impl ObjectFactory for RSAPubFactory {
         let mut obj = self.default_object_create(template)?;
 
         rsa_import(&mut obj)?;
+        set_public_key_info(&mut obj)?;
 
         Ok(obj)
     }
@@ -59,14 +60,77 @@ impl ObjectFactory for RSAPubFactory {
     fn get_attributes(&self) -> &Vec<ObjectAttr> {
         &self.attributes
     }
+
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        PubKeyFactory::import_from_public_key_info(self, data, template)
+    }
 }
 
 impl CommonKeyFactory for RSAPubFactory {}
 
-impl PubKeyFactory for RSAPubFactory {}
+impl PubKeyFactory for RSAPubFactory {
+    fn import_from_public_key_info(
+        &self,
+        data: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let spki = match asn1::parse_single::<kasn1::SubjectPublicKeyInfo>(data)
+        {
+            Ok(k) => k,
+            Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+        };
+        if spki.get_oid() != &OID_RSA_ENCRYPTION {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let rsapub =
+            match asn1::parse_single::<RSAPublicKey>(spki.get_public_key()) {
+                Ok(k) => k,
+                Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+            };
+        let mut tmpl = CkAttrs::from(template);
+        tmpl.add_owned_slice(CKA_MODULUS, rsapub.modulus.as_nopad_bytes())?;
+        tmpl.add_owned_slice(
+            CKA_PUBLIC_EXPONENT,
+            rsapub.public_exponent.as_nopad_bytes(),
+        )?;
+        self.create(tmpl.as_slice())
+    }
+}
 
 type Version = u64;
 
+/* RFC 8017 A.1.1 */
+#[derive(asn1::Asn1Read, asn1::Asn1Write)]
+struct RSAPublicKey<'a> {
+    modulus: DerEncBigUint<'a>,
+    public_exponent: DerEncBigUint<'a>,
+}
+
+/* DER encoding of the NULL parameters of rsaEncryption */
+const RSA_ENCRYPTION_PARAMS: &[u8] = &[0x05, 0x00];
+
+fn set_public_key_info(key: &mut Object) -> Result<()> {
+    let pubkey = match asn1::write_single(&RSAPublicKey {
+        modulus: DerEncBigUint::new(key.get_attr_as_bytes(CKA_MODULUS)?)?,
+        public_exponent: DerEncBigUint::new(
+            key.get_attr_as_bytes(CKA_PUBLIC_EXPONENT)?,
+        )?,
+    }) {
+        Ok(p) => p,
+        Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    };
+    object::set_public_key_info(
+        key,
+        OID_RSA_ENCRYPTION,
+        Some(RSA_ENCRYPTION_PARAMS),
+        &pubkey,
+    )
+}
+
 #[derive(asn1::Asn1Read, asn1::Asn1Write)]
 struct OtherPrimeInfo<'a> {
     prime: DerEncBigUint<'a>,
@@ -159,6 +223,7 @@ impl ObjectFactory for RSAPrivFactory {
         let mut obj = self.default_object_create(template)?;
 
         rsa_import(&mut obj)?;
+        set_public_key_info(&mut obj)?;
 
         Ok(obj)
     }
@@ -199,8 +264,11 @@ impl PrivKeyFactory for RSAPrivFactory {
             Ok(p) => p,
             _ => return err_rv!(CKR_GENERAL_ERROR),
         };
-        let pkeyinfo =
-            kasn1::PrivateKeyInfo::new(&pkey.as_slice(), OID_RSA_ENCRYPTION)?;
+        let pkeyinfo = kasn1::PrivateKeyInfo::new(
+            &pkey.as_slice(),
+            OID_RSA_ENCRYPTION,
+            Some(RSA_ENCRYPTION_PARAMS),
+        )?;
 
         match asn1::write_single(&pkeyinfo) {
             Ok(x) => Ok(x),
@@ -235,10 +303,7 @@ impl PrivKeyFactory for RSAPrivFactory {
         if !extra.iter().all(|b| *b == 0) {
             return err_rv!(CKR_WRAPPED_KEY_INVALID);
         }
-        let pkeyinfo = match tlv.parse::<kasn1::PrivateKeyInfo>() {
-            Ok(k) => k,
-            Err(_) => return err_rv!(CKR_WRAPPED_KEY_INVALID),
-        };
+        let pkeyinfo = kasn1::PrivateKeyInfo::parse_wrapped(tlv.full_data())?;
         if pkeyinfo.get_oid() != &OID_RSA_ENCRYPTION {
             return err_rv!(CKR_WRAPPED_KEY_INVALID);
         }
@@ -297,6 +362,7 @@ impl PrivKeyFactory for RSAPrivFactory {
         ))? {
             return err_rv!(CKR_TEMPLATE_INCONSISTENT);
         }
+        set_public_key_info(&mut key)?;
 
         Ok(key)
     }
@@ -435,6 +501,8 @@ impl Mechanism for RsaPKCSMechanism {
             &mut pubkey,
             &mut privkey,
         )?;
+        set_public_key_info(&mut pubkey)?;
+        set_public_key_info(&mut privkey)?;
         object::default_key_attributes(&mut privkey, mech.mechanism)?;
         object::default_key_attributes(&mut pubkey, mech.mechanism)?;
 
@@ -1,6 +1,7 @@
 // Copyright 2023 Simo Sorce
 // See LICENSE.txt file for terms
 
+use std::collections::VecDeque;
 use std::vec::Vec;
 
 use super::error;
@@ -17,14 +18,22 @@ use token::Token;
 #[cfg(feature = "fips")]
 use super::fips;
 
+/* Results are returned in pages, storage objects are only looked at
+ * and assigned a handle when their page is requested */
 #[derive(Debug)]
 pub struct SessionSearch {
     handles: Vec<CK_OBJECT_HANDLE>,
+    uids: VecDeque<String>,
+    types: Vec<CK_ATTRIBUTE_TYPE>,
     in_use: bool,
 }
 
 impl SearchOperation for SessionSearch {
-    fn results(&mut self, max: usize) -> Result<Vec<CK_OBJECT_HANDLE>> {
+    fn results(
+        &mut self,
+        token: &mut Token,
+        max: usize,
+    ) -> Result<Vec<CK_OBJECT_HANDLE>> {
         if !self.in_use {
             return err_rv!(CKR_OPERATION_NOT_INITIALIZED);
         }
@@ -32,11 +41,20 @@ impl SearchOperation for SessionSearch {
         if max < amount {
             amount = max;
         }
-        Ok(self.handles.drain(0..amount).collect())
+        let mut results: Vec<CK_OBJECT_HANDLE> =
+            self.handles.drain(0..amount).collect();
+        if results.len() < max {
+            results.append(&mut token.search_results(
+                &mut self.uids,
+                &self.types,
+                max - results.len(),
+            )?);
+        }
+        Ok(results)
     }
 
     fn finalized(&self) -> bool {
-        self.handles.len() == 0
+        self.handles.len() == 0 && self.uids.len() == 0
     }
 }
 
@@ -201,8 +219,11 @@ impl Session {
         if !self.operation.finalized() {
             return err_rv!(CKR_OPERATION_ACTIVE);
         }
+        let (handles, uids) = token.search_objects(template)?;
         self.operation = Operation::Search(Box::new(SessionSearch {
-            handles: token.search_objects(template)?,
+            handles: handles,
+            uids: uids,
+            types: template.iter().map(|a| a.type_).collect(),
             in_use: true,
         }));
         self.login_status = OpLoginStatus::NotRequired;
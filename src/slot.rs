@@ -4,10 +4,12 @@
 use std::collections::HashMap;
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use super::config;
 use super::error;
 use super::interface;
+use super::policy::Policy;
 use super::session::Session;
-use super::token::Token;
+use super::token::{copy_sized_string, Token};
 
 use super::err_rv;
 use error::Result;
@@ -26,16 +28,38 @@ pub struct Slot {
 }
 
 impl Slot {
-    pub fn new(filename: String) -> Result<Slot> {
+    pub fn new(
+        config: &config::Slot,
+        system_policy: Option<&Policy>,
+    ) -> Result<Slot> {
+        let mut description = SLOT_DESCRIPTION;
+        if let Some(label) = &config.label {
+            copy_sized_string(label.as_bytes(), &mut description);
+        }
+        let mut token =
+            Token::with_dbtype(config.dbtype.as_deref(), config.get_dbpath())?;
+        let policy = match (system_policy, &config.policy) {
+            (None, None) => None,
+            (Some(sys), None) => Some(sys.clone()),
+            (None, Some(p)) => Some(Policy::from_config(p)?),
+            (Some(sys), Some(p)) => {
+                let mut policy = sys.clone();
+                policy.merge(&Policy::from_config(p)?);
+                Some(policy)
+            }
+        };
+        if let Some(p) = policy {
+            token.apply_policy(p)?;
+        }
         Ok(Slot {
             slot_info: CK_SLOT_INFO {
-                slotDescription: SLOT_DESCRIPTION,
+                slotDescription: description,
                 manufacturerID: MANUFACTURER_ID,
                 flags: CKF_TOKEN_PRESENT,
                 hardwareVersion: CK_VERSION { major: 0, minor: 0 },
                 firmwareVersion: CK_VERSION { major: 0, minor: 0 },
             },
-            token: RwLock::new(Token::new(filename)?),
+            token: RwLock::new(token),
             sessions: HashMap::new(),
         })
     }
@@ -45,11 +69,25 @@ impl Slot {
     }
 
     pub fn get_token_info(&self) -> CK_TOKEN_INFO {
-        let tok = self.token.read().unwrap();
+        let mut tok = self.token.write().unwrap();
+        /* on failure report the last known state */
+        let _ = tok.refresh();
         *tok.get_token_info()
     }
 
     pub fn get_token(&self) -> Result<RwLockReadGuard<'_, Token>> {
+        /* pick up changes made by other processes before handing out the
+         * token, never block here as the caller may already hold a read
+         * lock, in that case the last known state is used */
+        let stale = match self.token.read() {
+            Ok(token) => token.is_stale(),
+            Err(_) => false,
+        };
+        if stale {
+            if let Ok(mut token) = self.token.try_write() {
+                let _ = token.refresh();
+            }
+        }
         match self.token.read() {
             Ok(token) => {
                 if token.is_initialized() {
@@ -69,7 +107,9 @@ impl Slot {
         nochecks: bool,
     ) -> Result<RwLockWriteGuard<'_, Token>> {
         match self.token.write() {
-            Ok(token) => {
+            Ok(mut token) => {
+                /* on failure operate on the last known state */
+                let _ = token.refresh();
                 if nochecks {
                     Ok(token)
                 } else if token.is_initialized() {
@@ -1,15 +1,19 @@
 // Copyright 2024 Simo Sorce
 // See LICENSE.txt file for terms
 
+use super::err_rv;
 use super::error;
 use super::interface;
 use super::object;
 
 use error::Result;
-use interface::CK_ATTRIBUTE;
+use interface::{CKR_ARGUMENTS_BAD, CK_ATTRIBUTE};
 use object::Object;
 
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 
 pub trait Storage: Debug + Send + Sync {
     fn open(&mut self, filename: &String) -> Result<()>;
@@ -18,9 +22,74 @@ pub trait Storage: Debug + Send + Sync {
     fn fetch_by_uid(&self, uid: &String) -> Result<Object>;
     fn store(&mut self, uid: &String, obj: Object) -> Result<()>;
     fn search(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<Object>>;
+    /* like search but only returns the unique ids of matching objects */
+    fn search_uids(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<String>>;
     fn remove_by_uid(&mut self, uid: &String) -> Result<()>;
+    /* transactions do not nest */
+    fn begin_transaction(&mut self) -> Result<()>;
+    fn commit_transaction(&mut self) -> Result<()>;
+    fn rollback_transaction(&mut self) -> Result<()>;
+    /* changes when the storage is modified by another process */
+    fn generation(&self) -> Result<u64>;
 }
 
+/* Writes data to a temporary file which is synced before being renamed
+ * in place, so that the target is replaced atomically even on crash */
+pub fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmppath = path.as_os_str().to_os_string();
+    tmppath.push(".tmp");
+    let ret = File::create(&tmppath)
+        .and_then(|mut f| {
+            f.write_all(data)?;
+            f.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmppath, path));
+    if ret.is_err() {
+        let _ = std::fs::remove_file(&tmppath);
+        return ret;
+    }
+    /* the rename is durable only once the directory is synced */
+    sync_parent_dir(path)
+}
+
+/* Makes the creation, removal or renaming of a file durable */
+pub fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(p) if p.as_os_str().len() > 0 => p,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/* Selects the backend by name, as used in the configuration file, or
+ * by file name when no name is given. When no filename is provided we
+ * assume a memory only token that has no backing store */
+pub fn new_storage(
+    dbtype: Option<&str>,
+    filename: &str,
+) -> Result<Box<dyn Storage>> {
+    match dbtype {
+        Some("sqlite") => Ok(sqlite::sqlite()),
+        Some("json") => Ok(json::json()),
+        Some("directory") => Ok(directory::directory()),
+        Some("memory") => Ok(memory::memory()),
+        Some(_) => err_rv!(CKR_ARGUMENTS_BAD),
+        None => {
+            if filename.ends_with(".json") {
+                Ok(json::json())
+            } else if filename.ends_with(".sql") {
+                Ok(sqlite::sqlite())
+            } else if filename.ends_with("/") || Path::new(filename).is_dir() {
+                /* a directory with one file per object */
+                Ok(directory::directory())
+            } else {
+                Ok(memory::memory())
+            }
+        }
+    }
+}
+
+pub mod directory;
 pub mod json;
 pub mod memory;
 pub mod sqlite;
@@ -0,0 +1,432 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_string_pretty};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use super::super::error;
+use super::super::interface;
+use super::super::object;
+use super::super::{err_not_found, err_rv};
+
+use super::json::JsonObject;
+use super::{write_atomically, Storage};
+
+use error::Result;
+use interface::*;
+use object::Object;
+
+/* Each object is stored as <uid>.json in the token directory, the lock
+ * file serializes access between processes and holds a counter that is
+ * incremented on every change */
+const OBJECT_SUFFIX: &str = ".json";
+const LOCK_FILE: &str = "kryoptic.lock";
+/* All the changes of a transaction are first written here, the journal
+ * is removed once they have all been applied to the object files */
+const JOURNAL_FILE: &str = "kryoptic.journal";
+
+fn io_error(e: std::io::Error) -> error::Error {
+    error::Error::ck_rv_from_error(CKR_DEVICE_ERROR, e)
+}
+
+fn flock(file: &File, op: libc::c_int) -> Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), op) } != 0 {
+        return Err(io_error(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/* None marks a removal */
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    uid: String,
+    object: Option<JsonObject>,
+}
+
+#[derive(Debug)]
+pub struct DirectoryStorage {
+    dirname: String,
+    /* exclusively locked for the duration of a transaction */
+    lock: Option<File>,
+    /* changes staged in a transaction, None marks a removal */
+    pending: HashMap<String, Option<Object>>,
+    /* counter increments made by this process, they are not reported
+     * as a change of generation */
+    local_changes: u64,
+}
+
+impl DirectoryStorage {
+    fn in_transaction(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    fn object_path(&self, uid: &String) -> Result<PathBuf> {
+        /* uids become file names, never allow path separators or
+         * anything else that could escape the directory */
+        if uid.len() == 0
+            || !uid
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        Ok(Path::new(&self.dirname).join(format!("{}{}", uid, OBJECT_SUFFIX)))
+    }
+
+    /* Locks the directory, unless we already hold the lock within a
+     * transaction. The lock is released when the returned file is
+     * dropped. Shared locks on a token that does not exist yet return
+     * no lock as there is nothing to read anyway */
+    fn lock(&self, exclusive: bool) -> Result<Option<File>> {
+        if self.in_transaction() {
+            return Ok(None);
+        }
+        if exclusive {
+            std::fs::create_dir_all(&self.dirname).map_err(io_error)?;
+        }
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(exclusive)
+            .open(Path::new(&self.dirname).join(LOCK_FILE))
+        {
+            Ok(f) => f,
+            Err(e) => {
+                if !exclusive && e.kind() == std::io::ErrorKind::NotFound {
+                    return Ok(None);
+                }
+                return Err(io_error(e));
+            }
+        };
+        let op = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        flock(&file, op)?;
+        /* complete a transaction left behind by a crash or a failure,
+         * readers need to temporarily upgrade their lock for that */
+        if self.journal_path().exists() {
+            if !exclusive {
+                flock(&file, libc::LOCK_EX)?;
+            }
+            self.replay_journal(&file)?;
+            if !exclusive {
+                flock(&file, libc::LOCK_SH)?;
+            }
+        }
+        Ok(Some(file))
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        Path::new(&self.dirname).join(JOURNAL_FILE)
+    }
+
+    /* Renaming the journal in place is the commit point, a journal that
+     * was not completely written is never renamed and is ignored */
+    fn write_journal(
+        &self,
+        pending: &HashMap<String, Option<Object>>,
+    ) -> Result<()> {
+        let mut entries = Vec::<JournalEntry>::with_capacity(pending.len());
+        for (uid, obj) in pending {
+            entries.push(JournalEntry {
+                uid: uid.clone(),
+                object: match obj {
+                    Some(o) => Some(JsonObject::from_object(o)?),
+                    None => None,
+                },
+            });
+        }
+        let jstr = match to_string_pretty(&entries) {
+            Ok(j) => j,
+            Err(e) => return Err(error::Error::other_error(e)),
+        };
+        write_atomically(&self.journal_path(), jstr.as_bytes())
+            .map_err(io_error)
+    }
+
+    /* Applies all the changes in the journal, must be called with the
+     * exclusive lock held. Replaying a journal more than once is safe */
+    fn replay_journal(&self, lock: &File) -> Result<()> {
+        let data = match std::fs::read(self.journal_path()) {
+            Ok(d) => d,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    return Ok(());
+                }
+                return Err(io_error(e));
+            }
+        };
+        for entry in from_slice::<Vec<JournalEntry>>(&data)? {
+            match entry.object {
+                Some(o) => {
+                    let (id, obj) = o.to_object()?;
+                    if id != entry.uid {
+                        return err_rv!(CKR_DEVICE_ERROR);
+                    }
+                    self.write_object(&entry.uid, &obj)?
+                }
+                None => self.delete_object(&entry.uid)?,
+            }
+        }
+        self.sync_dir()?;
+        std::fs::remove_file(self.journal_path()).map_err(io_error)?;
+        self.sync_dir()?;
+        Self::write_generation(lock)
+    }
+
+    fn read_generation(mut file: &File) -> Result<u64> {
+        let mut data = String::new();
+        file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        file.read_to_string(&mut data).map_err(io_error)?;
+        if data.len() == 0 {
+            return Ok(0);
+        }
+        match data.trim().parse::<u64>() {
+            Ok(g) => Ok(g),
+            Err(_) => err_rv!(CKR_DEVICE_ERROR),
+        }
+    }
+
+    fn write_generation(mut file: &File) -> Result<()> {
+        let generation = Self::read_generation(file)?.wrapping_add(1);
+        file.set_len(0).map_err(io_error)?;
+        file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        file.write_all(generation.to_string().as_bytes())
+            .map_err(io_error)?;
+        file.sync_all().map_err(io_error)
+    }
+
+    fn bump_generation(&mut self, file: &File) -> Result<()> {
+        Self::write_generation(file)?;
+        self.local_changes = self.local_changes.wrapping_add(1);
+        Ok(())
+    }
+
+    fn read_object(&self, uid: &String) -> Result<Object> {
+        if let Some(p) = self.pending.get(uid) {
+            return match p {
+                Some(o) => Ok(o.clone()),
+                None => err_not_found!(uid.clone()),
+            };
+        }
+        let data = match std::fs::read(self.object_path(uid)?) {
+            Ok(d) => d,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    return err_not_found!(uid.clone());
+                }
+                return Err(io_error(e));
+            }
+        };
+        let (id, obj) = from_slice::<JsonObject>(&data)?.to_object()?;
+        /* a file renamed by hand must not impersonate another object */
+        if id != *uid {
+            return err_rv!(CKR_DEVICE_ERROR);
+        }
+        Ok(obj)
+    }
+
+    /* objects are always replaced atomically */
+    fn write_object(&self, uid: &String, obj: &Object) -> Result<()> {
+        let path = self.object_path(uid)?;
+        let jstr = match to_string_pretty(&JsonObject::from_object(obj)?) {
+            Ok(j) => j,
+            Err(e) => return Err(error::Error::other_error(e)),
+        };
+        write_atomically(&path, jstr.as_bytes()).map_err(io_error)
+    }
+
+    fn delete_object(&self, uid: &String) -> Result<()> {
+        match std::fs::remove_file(self.object_path(uid)?) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(io_error(e))
+                }
+            }
+        }
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        File::open(&self.dirname)
+            .and_then(|d| d.sync_all())
+            .map_err(io_error)
+    }
+
+    /* uids of all objects, including the ones staged in a transaction */
+    fn list_uids(&self) -> Result<Vec<String>> {
+        let mut uids = Vec::<String>::new();
+        let entries = match std::fs::read_dir(&self.dirname) {
+            Ok(e) => e,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    return Ok(uids);
+                }
+                return Err(io_error(e));
+            }
+        };
+        for entry in entries {
+            let name = entry.map_err(io_error)?.file_name();
+            let name = match name.to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            if let Some(uid) = name.strip_suffix(OBJECT_SUFFIX) {
+                if !self.pending.contains_key(uid) {
+                    uids.push(uid.to_string());
+                }
+            }
+        }
+        for (uid, obj) in &self.pending {
+            if obj.is_some() {
+                uids.push(uid.clone());
+            }
+        }
+        Ok(uids)
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn open(&mut self, filename: &String) -> Result<()> {
+        self.dirname = filename.clone();
+        let _lock = self.lock(false)?;
+        if self.list_uids()?.len() == 0 {
+            return err_rv!(CKR_CRYPTOKI_NOT_INITIALIZED);
+        }
+        Ok(())
+    }
+    fn reinit(&mut self) -> Result<()> {
+        if self.in_transaction() {
+            for uid in self.list_uids()? {
+                self.pending.insert(uid, None);
+            }
+            return Ok(());
+        }
+        let lock = self.lock(true)?;
+        for uid in self.list_uids()? {
+            self.delete_object(&uid)?;
+        }
+        self.sync_dir()?;
+        match lock {
+            Some(l) => self.bump_generation(&l),
+            None => Ok(()),
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn fetch_by_uid(&self, uid: &String) -> Result<Object> {
+        let _lock = self.lock(false)?;
+        self.read_object(uid)
+    }
+    fn store(&mut self, uid: &String, obj: Object) -> Result<()> {
+        /* make sure the uid is usable before staging anything */
+        self.object_path(uid)?;
+        if self.in_transaction() {
+            self.pending.insert(uid.clone(), Some(obj));
+            return Ok(());
+        }
+        let lock = self.lock(true)?;
+        self.write_object(uid, &obj)?;
+        match lock {
+            Some(l) => self.bump_generation(&l),
+            None => Ok(()),
+        }
+    }
+    fn search(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<Object>> {
+        let _lock = self.lock(false)?;
+        let mut result = Vec::<Object>::new();
+        for uid in self.list_uids()? {
+            let obj = self.read_object(&uid)?;
+            if obj.match_template(template) {
+                result.push(obj);
+            }
+        }
+        Ok(result)
+    }
+    fn search_uids(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<String>> {
+        let _lock = self.lock(false)?;
+        let mut result = Vec::<String>::new();
+        for uid in self.list_uids()? {
+            if self.read_object(&uid)?.match_template(template) {
+                result.push(uid);
+            }
+        }
+        Ok(result)
+    }
+    fn remove_by_uid(&mut self, uid: &String) -> Result<()> {
+        self.object_path(uid)?;
+        if self.in_transaction() {
+            self.pending.insert(uid.clone(), None);
+            return Ok(());
+        }
+        let lock = self.lock(true)?;
+        self.delete_object(uid)?;
+        self.sync_dir()?;
+        match lock {
+            Some(l) => self.bump_generation(&l),
+            None => Ok(()),
+        }
+    }
+    fn begin_transaction(&mut self) -> Result<()> {
+        if self.in_transaction() {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        self.lock = self.lock(true)?;
+        self.pending.clear();
+        Ok(())
+    }
+    /* Once the journal is in place the transaction is committed, if
+     * applying it fails midway it is completed the next time the token
+     * is locked, by this or any other process, so that is not an error */
+    fn commit_transaction(&mut self) -> Result<()> {
+        let lock = match self.lock.take() {
+            Some(l) => l,
+            None => return err_rv!(CKR_GENERAL_ERROR),
+        };
+        let pending = std::mem::take(&mut self.pending);
+        if pending.len() == 0 {
+            return Ok(());
+        }
+        self.write_journal(&pending)?;
+        if self.replay_journal(&lock).is_ok() {
+            self.local_changes = self.local_changes.wrapping_add(1);
+        }
+        Ok(())
+    }
+    fn rollback_transaction(&mut self) -> Result<()> {
+        if self.lock.take().is_none() {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        self.pending.clear();
+        Ok(())
+    }
+    fn generation(&self) -> Result<u64> {
+        let generation = match &self.lock {
+            Some(l) => Self::read_generation(l)?,
+            None => match self.lock(false)? {
+                Some(l) => Self::read_generation(&l)?,
+                None => 0,
+            },
+        };
+        Ok(generation.wrapping_sub(self.local_changes))
+    }
+}
+
+pub fn directory() -> Box<dyn Storage> {
+    Box::new(DirectoryStorage {
+        dirname: String::from(""),
+        lock: None,
+        pending: HashMap::new(),
+        local_changes: 0,
+    })
+}
@@ -3,7 +3,13 @@
 
 use data_encoding::BASE64;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_reader, to_string_pretty, Map, Number, Value};
+use serde_json::{
+    from_reader, from_slice, to_string_pretty, Map, Number, Value,
+};
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
 
 use super::super::attribute;
 use super::super::err_rv;
@@ -12,35 +18,59 @@ use super::super::interface;
 use super::super::object;
 
 use super::memory;
-use super::Storage;
+use super::{sync_parent_dir, write_atomically, Storage};
 
 use attribute::{AttrType, Attribute};
 use error::Result;
 use interface::*;
 use object::Object;
 
-fn to_json_value(a: &Attribute) -> Value {
-    match a.get_attrtype() {
-        AttrType::BoolType => match a.to_bool() {
-            Ok(b) => Value::Bool(b),
-            Err(_) => Value::Null,
-        },
-        AttrType::NumType => match a.to_ulong() {
-            Ok(l) => Value::Number(Number::from(l)),
-            Err(_) => Value::Null,
-        },
-        AttrType::StringType => match a.to_string() {
-            Ok(s) => Value::String(s),
-            Err(_) => Value::String(BASE64.encode(a.get_value())),
-        },
-        AttrType::BytesType => Value::String(BASE64.encode(a.get_value())),
-        AttrType::DateType => match a.to_date_string() {
-            Ok(d) => Value::String(d),
-            Err(_) => Value::String(String::new()),
-        },
+/* attributes holding arrays of CK_ULONG values, these are stored as
+ * lists of numbers instead of opaque native endian blobs */
+const ULONG_ARRAY_ATTRS: [CK_ATTRIBUTE_TYPE; 1] = [CKA_ALLOWED_MECHANISMS];
+
+fn ulong_array_to_json_value(v: &Vec<u8>) -> Option<Value> {
+    let size = std::mem::size_of::<CK_ULONG>();
+    if v.len() % size != 0 {
+        return None;
+    }
+    let mut arr = Vec::<Value>::with_capacity(v.len() / size);
+    for chunk in v.chunks(size) {
+        let mut b = [0u8; std::mem::size_of::<CK_ULONG>()];
+        b.copy_from_slice(chunk);
+        arr.push(Value::Number(Number::from(CK_ULONG::from_ne_bytes(b))));
+    }
+    Some(Value::Array(arr))
+}
+
+/* values that can not be represented in a way to_object() reads back
+ * unchanged are refused instead of being silently altered */
+fn to_json_value(a: &Attribute) -> Result<Value> {
+    Ok(match a.get_attrtype() {
+        AttrType::BoolType => Value::Bool(a.to_bool()?),
+        AttrType::NumType => Value::Number(Number::from(a.to_ulong()?)),
+        AttrType::StringType => Value::String(a.to_string()?),
+        AttrType::BytesType => {
+            if ULONG_ARRAY_ATTRS.contains(&a.get_type()) {
+                if let Some(v) = ulong_array_to_json_value(a.get_value()) {
+                    return Ok(v);
+                }
+            }
+            Value::String(BASE64.encode(a.get_value()))
+        }
+        AttrType::DateType => {
+            if a.get_value().len() == 0 {
+                /* special case for default empty value */
+                Value::String(String::new())
+            } else {
+                let d = a.to_date_string()?;
+                attribute::string_to_ck_date(&d)?;
+                Value::String(d)
+            }
+        }
         AttrType::IgnoreType => Value::Null,
         AttrType::DenyType => Value::Null,
-    }
+    })
 }
 
 fn uninit(e: std::io::Error) -> error::Error {
@@ -57,14 +87,103 @@ pub struct JsonObject {
 }
 
 impl JsonObject {
-    pub fn from_object(o: &Object) -> JsonObject {
+    pub fn from_object(o: &Object) -> Result<JsonObject> {
         let mut jo = JsonObject {
             attributes: Map::new(),
         };
-        for a in o.get_attributes() {
-            jo.attributes.insert(a.name(), to_json_value(a));
+        /* insert in name order so the output does not depend on how
+         * the attributes were added to the object */
+        let mut attrs: Vec<&Attribute> = o.get_attributes().iter().collect();
+        attrs.sort_by_cached_key(|a| a.name());
+        for a in attrs {
+            jo.attributes.insert(a.name(), to_json_value(a)?);
+        }
+        Ok(jo)
+    }
+
+    pub fn to_object(&self) -> Result<(String, Object)> {
+        let mut obj = Object::new();
+        let mut uid: Option<String> = None;
+        for (key, val) in &self.attributes {
+            let (id, atype) = attribute::attr_name_to_id_type(key)?;
+            let attr = match atype {
+                AttrType::BoolType => match val.as_bool() {
+                    Some(b) => attribute::from_bool(id, b),
+                    None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+                },
+                AttrType::NumType => match val.as_u64() {
+                    Some(n) => attribute::from_ulong(id, n),
+                    None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+                },
+                AttrType::StringType => match val.as_str() {
+                    Some(s) => attribute::from_string(id, s.to_string()),
+                    None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+                },
+                AttrType::BytesType => match val {
+                    Value::String(s) => {
+                        let len = match BASE64.decode_len(s.len()) {
+                            Ok(l) => l,
+                            Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+                        };
+                        let mut v = vec![0; len];
+                        match BASE64.decode_mut(s.as_bytes(), &mut v) {
+                            Ok(l) => {
+                                attribute::from_bytes(id, v[0..l].to_vec())
+                            }
+                            Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+                        }
+                    }
+                    Value::Array(arr) => {
+                        if !ULONG_ARRAY_ATTRS.contains(&id) {
+                            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+                        }
+                        let mut v = Vec::<u8>::with_capacity(
+                            arr.len() * std::mem::size_of::<CK_ULONG>(),
+                        );
+                        for n in arr {
+                            match n.as_u64() {
+                                Some(n) => v.extend_from_slice(
+                                    &CK_ULONG::try_from(n)?.to_ne_bytes(),
+                                ),
+                                None => {
+                                    return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID)
+                                }
+                            }
+                        }
+                        attribute::from_bytes(id, v)
+                    }
+                    _ => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+                },
+                AttrType::DateType => match val.as_str() {
+                    Some(s) => {
+                        if s.len() == 0 {
+                            /* special case for default empty value */
+                            attribute::from_date_bytes(id, Vec::new())
+                        } else {
+                            attribute::from_date(
+                                id,
+                                attribute::string_to_ck_date(&s)?,
+                            )
+                        }
+                    }
+                    None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+                },
+                AttrType::DenyType => continue,
+                AttrType::IgnoreType => continue,
+            };
+
+            obj.set_attr(attr)?;
+            if key == "CKA_UNIQUE_ID" {
+                uid = match val.as_str() {
+                    Some(s) => Some(s.to_string()),
+                    None => return err_rv!(CKR_DEVICE_ERROR),
+                }
+            }
+        }
+        match uid {
+            Some(u) => Ok((u, obj)),
+            None => err_rv!(CKR_DEVICE_ERROR),
         }
-        jo
     }
 }
 
@@ -83,75 +202,20 @@ impl JsonToken {
 
     pub fn prime_cache(&self, cache: &mut Box<dyn Storage>) -> Result<()> {
         for jo in &self.objects {
-            let mut obj = Object::new();
-            let mut uid: Option<String> = None;
-            for (key, val) in &jo.attributes {
-                let (id, atype) = attribute::attr_name_to_id_type(key)?;
-                let attr = match atype {
-                    AttrType::BoolType => match val.as_bool() {
-                        Some(b) => attribute::from_bool(id, b),
-                        None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
-                    },
-                    AttrType::NumType => match val.as_u64() {
-                        Some(n) => attribute::from_ulong(id, n),
-                        None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
-                    },
-                    AttrType::StringType => match val.as_str() {
-                        Some(s) => attribute::from_string(id, s.to_string()),
-                        None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
-                    },
-                    AttrType::BytesType => match val.as_str() {
-                        Some(s) => {
-                            let len = match BASE64.decode_len(s.len()) {
-                                Ok(l) => l,
-                                Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-                            };
-                            let mut v = vec![0; len];
-                            match BASE64.decode_mut(s.as_bytes(), &mut v) {
-                                Ok(l) => {
-                                    attribute::from_bytes(id, v[0..l].to_vec())
-                                }
-                                Err(_) => return err_rv!(CKR_GENERAL_ERROR),
-                            }
-                        }
-                        None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
-                    },
-                    AttrType::DateType => match val.as_str() {
-                        Some(s) => {
-                            if s.len() == 0 {
-                                /* special case for default empty value */
-                                attribute::from_date_bytes(id, Vec::new())
-                            } else {
-                                attribute::from_date(
-                                    id,
-                                    attribute::string_to_ck_date(&s)?,
-                                )
-                            }
-                        }
-                        None => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
-                    },
-                    AttrType::DenyType => continue,
-                    AttrType::IgnoreType => continue,
-                };
-
-                obj.set_attr(attr)?;
-                if key == "CKA_UNIQUE_ID" {
-                    uid = match val.as_str() {
-                        Some(s) => Some(s.to_string()),
-                        None => return err_rv!(CKR_DEVICE_ERROR),
-                    }
-                }
-            }
-            match uid {
-                Some(u) => cache.store(&u, obj)?,
-                None => return err_rv!(CKR_DEVICE_ERROR),
-            }
+            let (uid, obj) = jo.to_object()?;
+            cache.store(&uid, obj)?;
         }
         Ok(())
     }
 
-    pub fn from_cache(cache: &mut Box<dyn Storage>) -> JsonToken {
-        let objs = cache.search(&[]).unwrap();
+    /* objects are always saved in the same order, so token files can
+     * be compared, numeric uids sort before uuids and in numeric order */
+    pub fn from_cache(cache: &mut Box<dyn Storage>) -> Result<JsonToken> {
+        let mut objs = cache.search(&[])?;
+        objs.sort_by_cached_key(|o| {
+            let uid = o.get_attr_as_string(CKA_UNIQUE_ID).unwrap_or_default();
+            (uid.len(), uid)
+        });
         let mut jt = JsonToken {
             objects: Vec::with_capacity(objs.len()),
         };
@@ -159,10 +223,28 @@ impl JsonToken {
             if !o.is_token() {
                 continue;
             }
-            jt.objects.push(JsonObject::from_object(&o));
+            jt.objects.push(JsonObject::from_object(&o)?);
         }
 
-        jt
+        Ok(jt)
+    }
+
+    pub fn from_objects(objs: &[Object]) -> Result<JsonToken> {
+        let mut jt = JsonToken {
+            objects: Vec::with_capacity(objs.len()),
+        };
+        for o in objs {
+            jt.objects.push(JsonObject::from_object(o)?);
+        }
+        Ok(jt)
+    }
+
+    pub fn to_objects(&self) -> Result<Vec<(String, Object)>> {
+        let mut objs = Vec::with_capacity(self.objects.len());
+        for jo in &self.objects {
+            objs.push(jo.to_object()?);
+        }
+        Ok(objs)
     }
 
     pub fn save(&self, filename: &str) -> Result<()> {
@@ -170,52 +252,257 @@ impl JsonToken {
             Ok(j) => j,
             Err(e) => return Err(error::Error::other_error(e)),
         };
-        match std::fs::write(filename, jstr) {
+        match write_atomically(std::path::Path::new(filename), jstr.as_bytes())
+        {
             Ok(_) => Ok(()),
             Err(e) => Err(error::Error::other_error(e)),
         }
     }
 }
 
+/* A change to a single object, None marks a removal */
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonChange {
+    uid: String,
+    object: Option<JsonObject>,
+}
+
+/* Changes are appended to this file, each flush adds a single line with
+ * all its changes, a line that was not completely written is ignored.
+ * Once the log holds more changes than there are objects it is folded
+ * into the token file, replaying the log on top of the folded file is
+ * harmless as the last change of each object matches the file */
+const LOG_SUFFIX: &str = ".log";
+
 #[derive(Debug)]
 pub struct JsonStorage {
     filename: String,
     cache: Box<dyn Storage>,
+    transaction: bool,
+    /* uids of the objects changed since the last flush */
+    changes: HashSet<String>,
+    /* the whole file needs to be written on the next flush */
+    rewrite: bool,
+    /* number of changes and length of the valid data in the log */
+    logged: usize,
+    log_len: u64,
+}
+
+impl JsonStorage {
+    fn log_path(&self) -> PathBuf {
+        let mut path = self.filename.clone();
+        path.push_str(LOG_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    fn replay_log(&mut self) -> Result<()> {
+        let data = match std::fs::read(self.log_path()) {
+            Ok(d) => d,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    return Ok(());
+                }
+                return Err(error::Error::other_error(e));
+            }
+        };
+        let mut start = 0;
+        while let Some(len) = data[start..].iter().position(|c| *c == b'\n') {
+            let end = start + len + 1;
+            let changes = match from_slice::<Vec<JsonChange>>(&data[start..end])
+            {
+                Ok(c) => c,
+                Err(e) => {
+                    /* only the last line may have been cut short */
+                    if end < data.len() {
+                        return Err(e.into());
+                    }
+                    break;
+                }
+            };
+            for c in &changes {
+                match &c.object {
+                    Some(o) => {
+                        let (id, obj) = o.to_object()?;
+                        if id != c.uid {
+                            return err_rv!(CKR_DEVICE_ERROR);
+                        }
+                        self.cache.store(&c.uid, obj)?;
+                    }
+                    /* the object may already be gone */
+                    None => {
+                        let _ = self.cache.remove_by_uid(&c.uid);
+                    }
+                }
+            }
+            self.logged += changes.len();
+            start = end;
+        }
+        self.log_len = u64::try_from(start)?;
+        Ok(())
+    }
+
+    fn append_log(&mut self, changes: &Vec<JsonChange>) -> Result<()> {
+        let mut line = serde_json::to_string(changes)?;
+        line.push('\n');
+        let path = self.log_path();
+        let mut file =
+            OpenOptions::new().write(true).create(true).open(&path)?;
+        /* drop anything left behind by an interrupted append */
+        file.set_len(self.log_len)?;
+        file.seek(SeekFrom::Start(self.log_len))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        if self.log_len == 0 {
+            sync_parent_dir(&path)?;
+        }
+        self.log_len += u64::try_from(line.len())?;
+        self.logged += changes.len();
+        Ok(())
+    }
+
+    /* writes out the whole token and removes the log */
+    fn save_all(&mut self) -> Result<()> {
+        let token = JsonToken::from_cache(&mut self.cache)?;
+        token.save(&self.filename)?;
+        match std::fs::remove_file(self.log_path()) {
+            Ok(()) => (),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(error::Error::other_error(e));
+                }
+            }
+        }
+        self.logged = 0;
+        self.log_len = 0;
+        Ok(())
+    }
 }
 
 impl Storage for JsonStorage {
     fn open(&mut self, filename: &String) -> Result<()> {
         self.filename = filename.clone();
         let token = JsonToken::load(&self.filename)?;
-        token.prime_cache(&mut self.cache)
+        token.prime_cache(&mut self.cache)?;
+        self.replay_log()
     }
     fn reinit(&mut self) -> Result<()> {
-        // TODO
-        Ok(())
+        /* the removals need to be logged as well, or replaying the log
+         * after a crash would bring the objects back */
+        for uid in self.cache.search_uids(&[])? {
+            self.changes.insert(uid);
+        }
+        self.cache.reinit()?;
+        self.rewrite = true;
+        if self.transaction {
+            return Ok(());
+        }
+        self.flush()
     }
     fn flush(&mut self) -> Result<()> {
-        let token = JsonToken::from_cache(&mut self.cache);
-        token.save(&self.filename)
+        if self.changes.len() == 0 && !self.rewrite {
+            return Ok(());
+        }
+        let exists = std::path::Path::new(&self.filename).exists();
+        if exists && self.changes.len() > 0 {
+            let mut uids: Vec<&String> = self.changes.iter().collect();
+            uids.sort();
+            let mut changes = Vec::<JsonChange>::with_capacity(uids.len());
+            for uid in uids {
+                changes.push(JsonChange {
+                    uid: uid.clone(),
+                    object: match self.cache.fetch_by_uid(uid) {
+                        Ok(o) => Some(JsonObject::from_object(&o)?),
+                        Err(_) => None,
+                    },
+                });
+            }
+            self.append_log(&changes)?;
+        }
+        /* a log without the token file it applies to would be replayed
+         * on top of the wrong objects, so it is never kept */
+        if !exists
+            || self.rewrite
+            || self.logged > self.cache.search_uids(&[])?.len()
+        {
+            self.save_all()?;
+        }
+        self.changes.clear();
+        self.rewrite = false;
+        Ok(())
     }
     fn fetch_by_uid(&self, uid: &String) -> Result<Object> {
         self.cache.fetch_by_uid(uid)
     }
     fn store(&mut self, uid: &String, obj: Object) -> Result<()> {
+        /* an object that can not be saved must not reach the cache, or
+         * it would make every following flush fail */
+        JsonObject::from_object(&obj)?;
         self.cache.store(uid, obj)?;
+        self.changes.insert(uid.clone());
+        if self.transaction {
+            return Ok(());
+        }
         self.flush()
     }
     fn search(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<Object>> {
         self.cache.search(template)
     }
+    fn search_uids(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<String>> {
+        self.cache.search_uids(template)
+    }
     fn remove_by_uid(&mut self, uid: &String) -> Result<()> {
         self.cache.remove_by_uid(uid)?;
+        self.changes.insert(uid.clone());
+        if self.transaction {
+            return Ok(());
+        }
         self.flush()
     }
+    fn begin_transaction(&mut self) -> Result<()> {
+        self.cache.begin_transaction()?;
+        self.transaction = true;
+        Ok(())
+    }
+    /* the uids changed by a failed or rolled back transaction are kept,
+     * saving their current state again is harmless */
+    fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction = false;
+        match self.flush() {
+            Ok(()) => self.cache.commit_transaction(),
+            Err(e) => {
+                let _ = self.cache.rollback_transaction();
+                Err(e)
+            }
+        }
+    }
+    fn rollback_transaction(&mut self) -> Result<()> {
+        self.transaction = false;
+        self.cache.rollback_transaction()
+    }
+    fn generation(&self) -> Result<u64> {
+        /* the file is only read at open, external changes are
+         * never picked up */
+        Ok(0)
+    }
+}
+
+/* leave a single file behind */
+impl Drop for JsonStorage {
+    fn drop(&mut self) {
+        if self.logged > 0 {
+            let _ = self.save_all();
+        }
+    }
 }
 
 pub fn json() -> Box<dyn Storage> {
     Box::new(JsonStorage {
         filename: String::from(""),
         cache: memory::memory(),
+        transaction: false,
+        changes: HashSet::new(),
+        rewrite: false,
+        logged: 0,
+        log_len: 0,
     })
 }
@@ -18,6 +18,7 @@ use std::fmt::Debug;
 #[derive(Debug)]
 struct MemoryStorage {
     objects: HashMap<String, Object>,
+    backup: Option<HashMap<String, Object>>,
 }
 
 impl Storage for MemoryStorage {
@@ -50,14 +51,49 @@ impl Storage for MemoryStorage {
         }
         Ok(ret)
     }
+    fn search_uids(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<String>> {
+        let mut ret = Vec::<String>::new();
+        for (uid, o) in self.objects.iter() {
+            if o.match_template(template) {
+                ret.push(uid.clone());
+            }
+        }
+        Ok(ret)
+    }
     fn remove_by_uid(&mut self, uid: &String) -> Result<()> {
         self.objects.remove(uid);
         Ok(())
     }
+    fn begin_transaction(&mut self) -> Result<()> {
+        if self.backup.is_some() {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        self.backup = Some(self.objects.clone());
+        Ok(())
+    }
+    fn commit_transaction(&mut self) -> Result<()> {
+        match self.backup.take() {
+            Some(_) => Ok(()),
+            None => err_rv!(CKR_GENERAL_ERROR),
+        }
+    }
+    fn rollback_transaction(&mut self) -> Result<()> {
+        match self.backup.take() {
+            Some(objects) => {
+                self.objects = objects;
+                Ok(())
+            }
+            None => err_rv!(CKR_GENERAL_ERROR),
+        }
+    }
+    fn generation(&self) -> Result<u64> {
+        Ok(0)
+    }
 }
 
 pub fn memory() -> Box<dyn Storage> {
     Box::new(MemoryStorage {
         objects: HashMap::new(),
+        backup: None,
     })
 }
@@ -1,8 +1,12 @@
 // Copyright 2024 Simo Sorce
 // See LICENSE.txt file for terms
 
-use rusqlite::{params, types::Value, Connection, Rows, Transaction};
+use rusqlite::{
+    params, types::Value, Connection, OptionalExtension, Rows,
+    TransactionBehavior,
+};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use super::super::attribute;
 use super::super::error;
@@ -30,9 +34,29 @@ const IS_DB_INITIALIZED: &str =
 const DROP_DB_TABLE: &str = "DROP TABLE objects";
 const CREATE_DB_TABLE: &str = "CREATE TABLE objects (id int NOT NULL, attr int NOT NULL, val blob, UNIQUE (id, attr))";
 
+/* schema versioning, databases without a meta table are version 0 */
+const HAS_META_TABLE: &str =
+    "SELECT count(*) FROM sqlite_master WHERE type='table' AND name='meta'";
+const DROP_META_TABLE: &str = "DROP TABLE IF EXISTS meta";
+const CREATE_META_TABLE: &str = "CREATE TABLE IF NOT EXISTS meta (name text NOT NULL PRIMARY KEY, value int NOT NULL)";
+const GET_SCHEMA_VERSION: &str =
+    "SELECT value FROM meta WHERE name = 'schema_version'";
+const SET_SCHEMA_VERSION: &str =
+    "INSERT OR REPLACE INTO meta VALUES ('schema_version', ?)";
+
+/* each entry upgrades the schema from version N to N + 1, entries
+ * must never be changed once released, only new ones appended */
+const MIGRATIONS: [&str; 1] = [
+    /* 0 -> 1: index attribute values to speed up searches */
+    "CREATE INDEX IF NOT EXISTS objects_attr_val ON objects (attr, val)",
+];
+const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
 /* search by filter constants */
 const SEARCH_ALL: &str = "SELECT * FROM objects";
 const SEARCH_NEST: &str = " WHERE id IN ( ";
+const SEARCH_UIDS: &str = "SELECT val FROM objects WHERE attr = ?";
+const SEARCH_UIDS_NEST: &str = " AND id IN ( ";
 const SEARCH_OBJ_ID: &str = "SELECT id FROM objects WHERE attr = ? AND val = ?";
 const SEARCH_CONCAT: &str = " INTERSECT ";
 const SEARCH_CLOSE: &str = " )";
@@ -43,6 +67,15 @@ const UPDATE_ATTR: &str = "INSERT OR REPLACE INTO objects VALUES (?, ?, ?)";
 const DELETE_OBJ: &str = "DELETE FROM objects WHERE id = ?";
 const MAX_ID: &str = "SELECT IFNULL(MAX(id), 0) FROM objects";
 
+/* explicit transactions, single operations use savepoints so they
+ * can nest within these */
+const BEGIN_TRANSACTION: &str = "BEGIN IMMEDIATE TRANSACTION";
+const COMMIT_TRANSACTION: &str = "COMMIT TRANSACTION";
+const ROLLBACK_TRANSACTION: &str = "ROLLBACK TRANSACTION";
+
+/* how long to wait for other processes holding the database lock */
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub struct SqliteStorage {
     filename: String,
@@ -64,12 +97,65 @@ impl SqliteStorage {
 
     fn db_reset(&mut self) -> Result<()> {
         let mut conn = self.conn.lock().unwrap();
-        let mut tx = conn.transaction().map_err(bad_storage)?;
+        let mut tx = conn.savepoint().map_err(bad_storage)?;
         tx.set_drop_behavior(rusqlite::DropBehavior::Rollback);
         /* the drop can fail when files are empty (new) */
         let _ = tx.execute(DROP_DB_TABLE, params![]);
+        tx.execute(DROP_META_TABLE, params![])
+            .map_err(bad_storage)?;
         tx.execute(CREATE_DB_TABLE, params![])
             .map_err(bad_storage)?;
+        Self::apply_migrations(&tx, 0)?;
+        tx.commit().map_err(bad_storage)
+    }
+
+    fn schema_version(conn: &Connection) -> Result<usize> {
+        let has_meta: i64 = conn
+            .query_row(HAS_META_TABLE, [], |row| row.get(0))
+            .map_err(bad_storage)?;
+        if has_meta == 0 {
+            return Ok(0);
+        }
+        let version: Option<i64> = conn
+            .query_row(GET_SCHEMA_VERSION, [], |row| row.get(0))
+            .optional()
+            .map_err(bad_storage)?;
+        match version {
+            Some(v) => Ok(usize::try_from(v)?),
+            None => Ok(0),
+        }
+    }
+
+    fn apply_migrations(conn: &Connection, from: usize) -> Result<()> {
+        conn.execute(CREATE_META_TABLE, params![])
+            .map_err(bad_storage)?;
+        for migration in &MIGRATIONS[from..] {
+            conn.execute_batch(migration).map_err(bad_storage)?;
+        }
+        conn.execute(SET_SCHEMA_VERSION, params![SCHEMA_VERSION as i64])
+            .map_err(bad_storage)?;
+        Ok(())
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let version = Self::schema_version(&conn)?;
+        if version > SCHEMA_VERSION {
+            /* created by a newer version we can't safely handle */
+            return err_rv!(CKR_TOKEN_NOT_RECOGNIZED);
+        }
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        /* take the write lock and check again, another process may
+         * have upgraded the schema in the meanwhile */
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(bad_storage)?;
+        let version = Self::schema_version(&tx)?;
+        if version < SCHEMA_VERSION {
+            Self::apply_migrations(&tx, version)?;
+        }
         tx.commit().map_err(bad_storage)
     }
 
@@ -150,17 +236,19 @@ impl SqliteStorage {
         }
     }
 
-    fn search_with_filter(
-        conn: &Connection,
+    /* appends one indexed subquery per attribute in the template,
+     * the intersection yields the ids of all matching objects */
+    fn add_filter(
+        search_query: &mut String,
+        search_params: &mut Vec<Value>,
+        nest: &str,
         template: &[CK_ATTRIBUTE],
-    ) -> Result<Vec<Object>> {
-        let mut search_query = String::from(SEARCH_ALL);
+    ) -> Result<()> {
         let mut subqcount = 0;
-        let mut search_params = Vec::<Value>::with_capacity(template.len() * 2);
         for a in template {
             /* add subqueries */
             if subqcount == 0 {
-                search_query.push_str(SEARCH_NEST);
+                search_query.push_str(nest);
             } else {
                 search_query.push_str(SEARCH_CONCAT);
             }
@@ -172,7 +260,9 @@ impl SqliteStorage {
                     AttrType::BoolType => Value::from(a.to_bool()?),
                     AttrType::NumType => Self::num_to_val(a.to_ulong()?)?,
                     AttrType::StringType => Value::from(a.to_string()?),
-                    AttrType::BytesType => Value::from(a.to_buf()?),
+                    AttrType::BytesType => {
+                        Value::from(a.to_attribute()?.to_bytes()?.clone())
+                    }
                     AttrType::DateType => {
                         Value::from(a.to_attribute()?.to_date_string()?)
                     }
@@ -186,6 +276,21 @@ impl SqliteStorage {
         if subqcount > 0 {
             search_query.push_str(SEARCH_CLOSE);
         }
+        Ok(())
+    }
+
+    fn search_with_filter(
+        conn: &Connection,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Vec<Object>> {
+        let mut search_query = String::from(SEARCH_ALL);
+        let mut search_params = Vec::<Value>::with_capacity(template.len() * 2);
+        Self::add_filter(
+            &mut search_query,
+            &mut search_params,
+            SEARCH_NEST,
+            template,
+        )?;
         /* finally make sure results return ordered by id,
          * this simplifies conversion to actual Objects */
         search_query.push_str(SEARCH_ORDER);
@@ -197,11 +302,34 @@ impl SqliteStorage {
         Ok(Self::rows_to_objects(rows)?)
     }
 
-    fn store_object(
-        tx: &mut Transaction,
-        uid: &String,
-        obj: &Object,
-    ) -> Result<()> {
+    fn search_uids_with_filter(
+        conn: &Connection,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Vec<String>> {
+        let mut search_query = String::from(SEARCH_UIDS);
+        let mut search_params =
+            Vec::<Value>::with_capacity(template.len() * 2 + 1);
+        search_params.push(Value::from(u32::try_from(CKA_UNIQUE_ID)?));
+        Self::add_filter(
+            &mut search_query,
+            &mut search_params,
+            SEARCH_UIDS_NEST,
+            template,
+        )?;
+        search_query.push_str(SEARCH_ORDER);
+
+        let mut stmt = conn.prepare(&search_query).map_err(bad_code)?;
+        let mut rows = stmt
+            .query(rusqlite::params_from_iter(search_params))
+            .map_err(bad_code)?;
+        let mut uids = Vec::<String>::new();
+        while let Some(row) = rows.next().map_err(bad_storage)? {
+            uids.push(row.get(0).map_err(bad_storage)?);
+        }
+        Ok(uids)
+    }
+
+    fn store_object(tx: &Connection, uid: &String, obj: &Object) -> Result<()> {
         let objid = match Self::delete_object(tx, uid)? {
             0 => {
                 /* find new id to use for new object */
@@ -234,7 +362,7 @@ impl SqliteStorage {
         Ok(())
     }
 
-    fn delete_object(tx: &mut Transaction, uid: &String) -> Result<i32> {
+    fn delete_object(tx: &Connection, uid: &String) -> Result<i32> {
         let mut stmt = tx.prepare(SEARCH_OBJ_ID).map_err(bad_storage)?;
         let objid = match stmt
             .query_row(params![CKA_UNIQUE_ID, uid], |row| row.get(0))
@@ -289,7 +417,19 @@ impl Storage for SqliteStorage {
             Ok(c) => Arc::new(Mutex::from(c)),
             Err(_) => return err_rv!(CKR_TOKEN_NOT_PRESENT),
         };
-        self.is_initialized()
+        let conn = self.conn.lock().unwrap();
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(bad_storage)?;
+        /* WAL lets readers in other processes proceed while we write,
+         * the returned mode is ignored as some filesystems can't do WAL
+         * and sqlite falls back to the previous journal mode */
+        let _: String = conn
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+                row.get(0)
+            })
+            .map_err(bad_storage)?;
+        drop(conn);
+        self.is_initialized()?;
+        self.migrate()
     }
     fn reinit(&mut self) -> Result<()> {
         self.db_reset()
@@ -303,9 +443,9 @@ impl Storage for SqliteStorage {
     }
     fn store(&mut self, uid: &String, obj: Object) -> Result<()> {
         let mut conn = self.conn.lock().unwrap();
-        let mut tx = conn.transaction().map_err(bad_storage)?;
+        let mut tx = conn.savepoint().map_err(bad_storage)?;
         tx.set_drop_behavior(rusqlite::DropBehavior::Rollback);
-        Self::store_object(&mut tx, uid, &obj)?;
+        Self::store_object(&tx, uid, &obj)?;
         tx.commit().map_err(bad_storage)
     }
     fn search(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<Object>> {
@@ -320,13 +460,47 @@ impl Storage for SqliteStorage {
         }
         Ok(result)
     }
+    fn search_uids(&self, template: &[CK_ATTRIBUTE]) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        Self::search_uids_with_filter(&conn, template)
+    }
     fn remove_by_uid(&mut self, uid: &String) -> Result<()> {
         let mut conn = self.conn.lock().unwrap();
-        let mut tx = conn.transaction().map_err(bad_storage)?;
+        let mut tx = conn.savepoint().map_err(bad_storage)?;
         tx.set_drop_behavior(rusqlite::DropBehavior::Rollback);
-        Self::delete_object(&mut tx, &uid)?;
+        Self::delete_object(&tx, &uid)?;
         tx.commit().map_err(bad_storage)
     }
+    fn begin_transaction(&mut self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        if !conn.is_autocommit() {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        conn.execute_batch(BEGIN_TRANSACTION).map_err(bad_storage)
+    }
+    fn commit_transaction(&mut self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        if conn.is_autocommit() {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        conn.execute_batch(COMMIT_TRANSACTION).map_err(bad_storage)
+    }
+    fn rollback_transaction(&mut self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        if conn.is_autocommit() {
+            return err_rv!(CKR_GENERAL_ERROR);
+        }
+        conn.execute_batch(ROLLBACK_TRANSACTION)
+            .map_err(bad_storage)
+    }
+    fn generation(&self) -> Result<u64> {
+        /* data_version only changes when other connections commit */
+        let conn = self.conn.lock().unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "data_version", |row| row.get(0))
+            .map_err(bad_storage)?;
+        Ok(u64::try_from(version)?)
+    }
 }
 
 pub fn sqlite() -> Box<dyn Storage> {
@@ -157,3 +157,25 @@ fn test_set_attr() {
 
     testtokn.finalize();
 }
+
+#[test]
+#[parallel]
+fn test_legacy_attr_array() {
+    /* older versions stored templates as a copy of the CK_ATTRIBUTE
+     * array they were given */
+    let class = CKO_SECRET_KEY;
+    let template =
+        make_ptrs_template(&[(CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE)]);
+    let raw = unsafe {
+        std::slice::from_raw_parts(
+            template.as_ptr() as *const u8,
+            template.len() * std::mem::size_of::<CK_ATTRIBUTE>(),
+        )
+    };
+    let attr = attribute::from_bytes(CKA_WRAP_TEMPLATE, raw.to_vec());
+    assert_eq!(attr.to_attr_array().unwrap().len(), 0);
+
+    /* anything else that does not parse is still an error */
+    let attr = attribute::from_bytes(CKA_WRAP_TEMPLATE, vec![1u8; 5]);
+    assert!(attr.to_attr_array().is_err());
+}
@@ -0,0 +1,332 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+use super::tests;
+use tests::*;
+
+use serial_test::parallel;
+use std::io::Write;
+
+fn test_transactions(
+    name: &str,
+    new_storage: fn() -> Box<dyn storage::Storage>,
+) {
+    remove_token_files(name);
+    let filename = name.to_string();
+    let mut store = new_storage();
+    /* opening a non existing token fails but sets up the storage */
+    let _ = store.open(&filename);
+    store.reinit().unwrap();
+
+    let uid = "100".to_string();
+    let mut obj = object::Object::new();
+    obj.set_attr(attribute::from_string(CKA_UNIQUE_ID, uid.clone()))
+        .unwrap();
+    obj.set_attr(attribute::from_bool(CKA_TOKEN, true)).unwrap();
+    obj.set_attr(attribute::from_ulong(CKA_CLASS, CKO_DATA))
+        .unwrap();
+
+    /* nothing is left behind on rollback */
+    store.begin_transaction().unwrap();
+    store.store(&uid, obj.clone()).unwrap();
+    assert!(store.fetch_by_uid(&uid).is_ok());
+    /* transactions do not nest */
+    assert!(store.begin_transaction().is_err());
+    store.rollback_transaction().unwrap();
+    assert!(store.fetch_by_uid(&uid).is_err());
+
+    /* everything is there after a commit */
+    store.begin_transaction().unwrap();
+    store.store(&uid, obj).unwrap();
+    store.commit_transaction().unwrap();
+    assert!(store.fetch_by_uid(&uid).is_ok());
+    drop(store);
+
+    let mut store = new_storage();
+    store.open(&filename).unwrap();
+    assert!(store.fetch_by_uid(&uid).is_ok());
+    drop(store);
+
+    remove_token_files(name);
+}
+
+#[test]
+#[parallel]
+fn test_transactions_json() {
+    test_transactions("test_transactions.json", storage::json::json);
+}
+
+#[test]
+#[parallel]
+fn test_transactions_sql() {
+    test_transactions("test_transactions.sql", storage::sqlite::sqlite);
+}
+
+#[test]
+#[parallel]
+fn test_transactions_dir() {
+    test_transactions("test_transactions.d/", storage::directory::directory);
+}
+
+#[test]
+#[parallel]
+fn test_dir_commit_failure() {
+    let name = "test_dir_commit_failure.d/";
+    remove_token_files(name);
+    let filename = name.to_string();
+    let mut store = storage::directory::directory();
+    let _ = store.open(&filename);
+    store.reinit().unwrap();
+
+    let uids = ["200".to_string(), "201".to_string()];
+    /* a directory in the way makes writing the second object fail */
+    let blocker = format!("{}201.json", name);
+    std::fs::create_dir_all(&blocker).unwrap();
+
+    store.begin_transaction().unwrap();
+    for uid in &uids {
+        let mut obj = object::Object::new();
+        obj.set_attr(attribute::from_string(CKA_UNIQUE_ID, uid.clone()))
+            .unwrap();
+        obj.set_attr(attribute::from_bool(CKA_TOKEN, true)).unwrap();
+        obj.set_attr(attribute::from_ulong(CKA_CLASS, CKO_DATA))
+            .unwrap();
+        store.store(uid, obj).unwrap();
+    }
+    /* the journal is durable, so the commit succeeds */
+    store.commit_transaction().unwrap();
+    drop(store);
+
+    /* the whole transaction is applied at the next open */
+    std::fs::remove_dir(&blocker).unwrap();
+    let mut store = storage::directory::directory();
+    store.open(&filename).unwrap();
+    for uid in &uids {
+        assert!(store.fetch_by_uid(uid).is_ok());
+    }
+    assert!(!std::path::Path::new(name).join("kryoptic.journal").exists());
+    drop(store);
+
+    remove_token_files(name);
+}
+
+#[test]
+#[parallel]
+fn test_json_log() {
+    let name = "test_json_log.json";
+    let logname = format!("{}.log", name);
+    remove_token_files(name);
+    let filename = name.to_string();
+    let mut store = storage::json::json();
+    let _ = store.open(&filename);
+    store
+        .store(&"9".to_string(), roundtrip_object("9"))
+        .unwrap();
+
+    /* later changes only go to the log */
+    store
+        .store(&"10".to_string(), roundtrip_object("10"))
+        .unwrap();
+    let data = std::fs::read_to_string(name).unwrap();
+    assert!(!data.contains("key 10"));
+    let log = std::fs::read_to_string(&logname).unwrap();
+    assert!(log.contains("key 10"));
+    assert!(!log.contains("key 9"));
+
+    /* the log is replayed at open, a line cut short is ignored */
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&logname)
+        .unwrap();
+    file.write_all(b"[{\"uid\":\"11\",").unwrap();
+    drop(file);
+    let mut other = storage::json::json();
+    other.open(&filename).unwrap();
+    assert!(other.fetch_by_uid(&"10".to_string()).is_ok());
+    assert!(other.fetch_by_uid(&"11".to_string()).is_err());
+    drop(other);
+
+    /* the log is folded into the file when closing */
+    assert!(!std::path::Path::new(&logname).exists());
+    let data = std::fs::read_to_string(name).unwrap();
+    assert!(data.contains("key 10"));
+    drop(store);
+
+    remove_token_files(name);
+}
+
+#[test]
+#[parallel]
+fn test_dir_generation() {
+    let name = "test_dir_generation.d/";
+    remove_token_files(name);
+    let filename = name.to_string();
+    let mut store = storage::directory::directory();
+    let _ = store.open(&filename);
+    store.reinit().unwrap();
+
+    let uid = "300".to_string();
+    let mut obj = object::Object::new();
+    obj.set_attr(attribute::from_string(CKA_UNIQUE_ID, uid.clone()))
+        .unwrap();
+    obj.set_attr(attribute::from_bool(CKA_TOKEN, true)).unwrap();
+    obj.set_attr(attribute::from_ulong(CKA_CLASS, CKO_DATA))
+        .unwrap();
+
+    /* our own changes do not change the generation */
+    let generation = store.generation().unwrap();
+    store.store(&uid, obj.clone()).unwrap();
+    store.begin_transaction().unwrap();
+    store.store(&uid, obj.clone()).unwrap();
+    store.commit_transaction().unwrap();
+    store.remove_by_uid(&uid).unwrap();
+    assert_eq!(store.generation().unwrap(), generation);
+
+    /* changes made through another handle do */
+    let mut other = storage::directory::directory();
+    let _ = other.open(&filename);
+    other.store(&uid, obj).unwrap();
+    assert_ne!(store.generation().unwrap(), generation);
+    drop(other);
+    drop(store);
+
+    remove_token_files(name);
+}
+
+#[test]
+#[parallel]
+fn test_sqlite_migrations() {
+    let name = "test_sqlite_migrations.sql";
+    remove_token_files(name);
+    let filename = name.to_string();
+
+    /* a database as created before schema versioning was introduced */
+    let conn = rusqlite::Connection::open(name).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE objects (id int NOT NULL, attr int NOT NULL, \
+         val blob, UNIQUE (id, attr))",
+    )
+    .unwrap();
+    drop(conn);
+
+    let mut store = storage::sqlite::sqlite();
+    store.open(&filename).unwrap();
+    drop(store);
+
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let version: i64 = conn
+        .query_row(
+            "SELECT value FROM meta WHERE name = 'schema_version'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(version, 1);
+    let index: i64 = conn
+        .query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'index' \
+             AND name = 'objects_attr_val'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(index, 1);
+
+    /* refuse databases from the future */
+    conn.execute(
+        "UPDATE meta SET value = 1000 WHERE name = 'schema_version'",
+        [],
+    )
+    .unwrap();
+    drop(conn);
+
+    let mut store = storage::sqlite::sqlite();
+    let err = store.open(&filename).unwrap_err();
+    assert_eq!(err.rv(), CKR_TOKEN_NOT_RECOGNIZED);
+    drop(store);
+
+    remove_token_files(name);
+}
+
+fn roundtrip_object(uid: &str) -> object::Object {
+    let mut obj = object::Object::new();
+    obj.set_attr(attribute::from_string(CKA_UNIQUE_ID, uid.to_string()))
+        .unwrap();
+    obj.set_attr(attribute::from_bool(CKA_TOKEN, true)).unwrap();
+    obj.set_attr(attribute::from_ulong(CKA_CLASS, CKO_SECRET_KEY))
+        .unwrap();
+    obj.set_attr(attribute::from_string(CKA_LABEL, format!("key {}", uid)))
+        .unwrap();
+    obj.set_attr(attribute::from_bytes(CKA_ID, vec![0, 1, 2, 0xff]))
+        .unwrap();
+    obj.set_attr(attribute::from_date(
+        CKA_START_DATE,
+        CK_DATE {
+            year: *b"2024",
+            month: *b"02",
+            day: *b"29",
+        },
+    ))
+    .unwrap();
+    obj.set_attr(attribute::from_date_bytes(CKA_END_DATE, Vec::new()))
+        .unwrap();
+    let mut mechs = Vec::<u8>::new();
+    for m in [CKM_AES_GCM, CKM_AES_KEY_WRAP_KWP] {
+        mechs.extend_from_slice(&m.to_ne_bytes());
+    }
+    obj.set_attr(attribute::from_bytes(CKA_ALLOWED_MECHANISMS, mechs))
+        .unwrap();
+    obj
+}
+
+#[test]
+#[parallel]
+fn test_json_roundtrip() {
+    let names = ["test_json_roundtrip1.json", "test_json_roundtrip2.json"];
+    let uids = ["9", "10", "100"];
+
+    /* store the same objects in different orders */
+    for (idx, name) in names.iter().enumerate() {
+        remove_token_files(name);
+        let mut store = storage::json::json();
+        let _ = store.open(&name.to_string());
+        let mut order = uids.to_vec();
+        if idx == 1 {
+            order.reverse();
+        }
+        for uid in order {
+            store
+                .store(&uid.to_string(), roundtrip_object(uid))
+                .unwrap();
+        }
+    }
+
+    /* output is stable and ulong arrays are readable */
+    let data = std::fs::read_to_string(names[0]).unwrap();
+    assert_eq!(data, std::fs::read_to_string(names[1]).unwrap());
+    assert!(data.contains("\"CKA_ALLOWED_MECHANISMS\": ["));
+
+    /* all attributes survive a reload unchanged */
+    let mut store = storage::json::json();
+    store.open(&names[0].to_string()).unwrap();
+    for uid in uids {
+        let orig = roundtrip_object(uid);
+        let obj = store.fetch_by_uid(&uid.to_string()).unwrap();
+        assert_eq!(obj.get_attributes().len(), orig.get_attributes().len());
+        for a in orig.get_attributes() {
+            let b = obj.get_attr(a.get_type()).unwrap();
+            assert_eq!(b.get_value(), a.get_value());
+        }
+    }
+
+    /* values that would not be read back unchanged are refused */
+    let mut obj = roundtrip_object("101");
+    obj.set_attr(attribute::from_date_bytes(CKA_END_DATE, vec![b'2'; 3]))
+        .unwrap();
+    assert!(store.store(&"101".to_string(), obj).is_err());
+    drop(store);
+
+    for name in names {
+        remove_token_files(name);
+    }
+}
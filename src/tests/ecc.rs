@@ -222,3 +222,114 @@ fn test_ecc_operations() {
 
     testtokn.finalize();
 }
+
+#[test]
+#[parallel]
+fn test_ecc_unwrap_curves() {
+    let mut testtokn =
+        TestToken::initialized("test_ecc_unwrap_curves.sql", None);
+    let session = testtokn.get_session(true);
+
+    /* login */
+    testtokn.login();
+
+    let wrapkey = ret_or_panic!(generate_key(
+        session,
+        CKM_AES_KEY_GEN,
+        std::ptr::null_mut(),
+        0,
+        &[(CKA_VALUE_LEN, 16)],
+        &[],
+        &[(CKA_WRAP, true), (CKA_UNWRAP, true)],
+    ));
+
+    for (params, siglen) in [
+        ("06082A8648CE3D030107", 64), /* secp256r1 */
+        ("06052B81040023", 132),      /* secp521r1 */
+    ] {
+        let ec_params = hex::decode(params).expect("Failed to decode hex");
+        let (pubkey, prikey) = ret_or_panic!(generate_key_pair(
+            session,
+            CKM_EC_KEY_PAIR_GEN,
+            &[(CKA_CLASS, CKO_PUBLIC_KEY), (CKA_KEY_TYPE, CKK_EC)],
+            &[(CKA_EC_PARAMS, ec_params.as_slice())],
+            &[(CKA_VERIFY, true)],
+            &[(CKA_CLASS, CKO_PRIVATE_KEY), (CKA_KEY_TYPE, CKK_EC)],
+            &[],
+            &[(CKA_SIGN, true), (CKA_EXTRACTABLE, true)],
+        ));
+
+        let mut mechanism: CK_MECHANISM = CK_MECHANISM {
+            mechanism: CKM_AES_ECB,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut wrapped = vec![0u8; 65536];
+        let mut wrapped_len = wrapped.len() as CK_ULONG;
+        let ret = fn_wrap_key(
+            session,
+            &mut mechanism,
+            wrapkey,
+            prikey,
+            wrapped.as_mut_ptr(),
+            &mut wrapped_len,
+        );
+        assert_eq!(ret, CKR_OK);
+
+        let mut template = make_attr_template(
+            &[(CKA_CLASS, CKO_PRIVATE_KEY), (CKA_KEY_TYPE, CKK_EC)],
+            &[],
+            &[(CKA_TOKEN, false), (CKA_SIGN, true)],
+        );
+        let mut prikey2 = CK_INVALID_HANDLE;
+        let ret = fn_unwrap_key(
+            session,
+            &mut mechanism,
+            wrapkey,
+            wrapped.as_mut_ptr(),
+            wrapped_len,
+            template.as_mut_ptr(),
+            template.len() as CK_ULONG,
+            &mut prikey2,
+        );
+        assert_eq!(ret, CKR_OK);
+
+        /* the unwrapped key must be on the same curve */
+        assert_eq!(
+            check_attributes(
+                session,
+                prikey2,
+                &[],
+                &[(CKA_EC_PARAMS, ec_params.as_slice())],
+                &[],
+            ),
+            None
+        );
+
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_ECDSA_SHA256,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let data = "plaintext";
+        let sig = ret_or_panic!(sig_gen(
+            session,
+            prikey2,
+            data.as_bytes(),
+            &mechanism
+        ));
+        assert_eq!(sig.len(), siglen);
+        assert_eq!(
+            CKR_OK,
+            sig_verify(
+                session,
+                pubkey,
+                data.as_bytes(),
+                sig.as_slice(),
+                &mechanism
+            )
+        );
+    }
+
+    testtokn.finalize();
+}
@@ -287,3 +287,109 @@ fn test_re_init_token_json() {
 fn test_re_init_token_sql() {
     test_re_init_token_common("test_reinit_token.sql")
 }
+
+#[test]
+#[serial]
+fn test_config_file_slots() {
+    let mut testtokn = TestToken::new("test_config_slots.sql", true);
+    testtokn.setup_db(None);
+    let other_db = "test_config_slots_other.json";
+    let other_slot = testtokn.get_slot() + 100;
+
+    let conffile = "test_config_slots.conf";
+    std::fs::write(
+        conffile,
+        format!(
+            "[[slots]]\n\
+             slot = {}\n\
+             dbtype = \"sqlite\"\n\
+             dbpath = \"test_config_slots.sql\"\n\
+             \n\
+             [[slots]]\n\
+             slot = {}\n\
+             label = \"Second Slot\"\n\
+             dbpath = \"{}\"\n",
+            testtokn.get_slot(),
+            other_slot,
+            other_db
+        ),
+    )
+    .unwrap();
+
+    /* all configured slots are added at once */
+    let mut args = testtokn.make_empty_init_args();
+    args.pReserved =
+        CString::new(conffile).unwrap().into_raw() as *mut std::ffi::c_void;
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    let mut count: CK_ULONG = 0;
+    let ret = fn_get_slot_list(CK_FALSE, std::ptr::null_mut(), &mut count);
+    assert_eq!(ret, CKR_OK);
+    let mut slots = vec![0 as CK_SLOT_ID; count as usize];
+    let ret = fn_get_slot_list(CK_FALSE, slots.as_mut_ptr(), &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert!(slots.contains(&testtokn.get_slot()));
+    assert!(slots.contains(&other_slot));
+
+    let mut slot_info = CK_SLOT_INFO::default();
+    let ret = fn_get_slot_info(other_slot, &mut slot_info);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(&slot_info.slotDescription[..12], b"Second Slot ");
+
+    /* the database with the test objects is usable */
+    testtokn.get_session(false);
+    testtokn.login();
+    testtokn.logout();
+    testtokn.close_session();
+
+    /* the same configuration can not be loaded twice */
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_CRYPTOKI_ALREADY_INITIALIZED);
+
+    /* a configuration with an invalid slot adds none of its slots */
+    let partial = format!(
+        "[[slots]]\n\
+         slot = {}\n\
+         dbtype = \"memory\"\n\
+         \n\
+         [[slots]]\n\
+         slot = {}\n\
+         dbtype = \"nosuchdb\"\n",
+        other_slot + 1,
+        other_slot + 2
+    );
+    std::fs::write(conffile, &partial).unwrap();
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_ARGUMENTS_BAD);
+    let ret = fn_get_slot_info(other_slot + 1, &mut slot_info);
+    assert_eq!(ret, CKR_SLOT_ID_INVALID);
+    let ret = fn_get_slot_info(other_slot, &mut slot_info);
+    assert_eq!(ret, CKR_OK);
+
+    let ret = fn_finalize(std::ptr::null_mut() as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    /* nor leaves the library initialized */
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_ARGUMENTS_BAD);
+    let ret = fn_get_slot_list(CK_FALSE, std::ptr::null_mut(), &mut count);
+    assert_eq!(ret, CKR_CRYPTOKI_NOT_INITIALIZED);
+
+    /* duplicated slot numbers are rejected */
+    std::fs::write(conffile, "[[slots]]\nslot = 1\n\n[[slots]]\nslot = 1\n")
+        .unwrap();
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_ARGUMENTS_BAD);
+
+    std::fs::remove_file(conffile).unwrap();
+    std::fs::remove_file(other_db).unwrap_or(());
+
+    let mut args = testtokn.make_init_args();
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    testtokn.finalize();
+}
@@ -146,3 +146,60 @@ fn test_login_json() {
 fn test_login_sql() {
     test_login("test_login.sql");
 }
+
+#[test]
+#[parallel]
+fn test_login_dir() {
+    test_login("test_login.d/");
+}
+
+#[test]
+#[parallel]
+fn test_login_multiprocess() {
+    let name = "test_login_multiprocess.sql";
+    let mut testtokn = TestToken::initialized(name, None);
+    let session = testtokn.get_session(false);
+
+    let pin_flags_mask =
+        CKF_USER_PIN_LOCKED | CKF_USER_PIN_FINAL_TRY | CKF_USER_PIN_COUNT_LOW;
+
+    /* another instance on the same database, as another process would */
+    let mut other = Token::new(name.to_string()).unwrap();
+
+    let bad_pin = "87654321".as_bytes().to_vec();
+    for _ in 0..7 {
+        assert_eq!(other.login(CKU_USER, &bad_pin), CKR_PIN_INCORRECT);
+    }
+
+    /* changes made elsewhere are reflected in the token info */
+    let mut token_info = CK_TOKEN_INFO::default();
+    let ret = fn_get_token_info(testtokn.get_slot(), &mut token_info);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(token_info.flags & pin_flags_mask, CKF_USER_PIN_COUNT_LOW);
+
+    /* and failed attempts from both add up */
+    let pin = "87654321";
+    let ret = fn_login(
+        session,
+        CKU_USER,
+        pin.as_ptr() as *mut _,
+        pin.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_PIN_INCORRECT);
+    assert_eq!(other.login(CKU_USER, &bad_pin), CKR_PIN_INCORRECT);
+
+    let ret = fn_get_token_info(testtokn.get_slot(), &mut token_info);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(token_info.flags & pin_flags_mask, CKF_USER_PIN_FINAL_TRY);
+
+    /* a successful login elsewhere resets the counter */
+    let user_pin = USER_PIN.as_bytes().to_vec();
+    assert_eq!(other.login(CKU_USER, &user_pin), CKR_OK);
+
+    let ret = fn_get_token_info(testtokn.get_slot(), &mut token_info);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(token_info.flags & pin_flags_mask, 0);
+
+    drop(other);
+    testtokn.finalize();
+}
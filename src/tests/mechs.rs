@@ -32,3 +32,375 @@ fn test_get_mechs() {
 
     testtokn.finalize();
 }
+
+#[test]
+#[parallel]
+fn test_token_policy() {
+    let config = config::Config::from_toml(
+        "[[slots]]\n\
+         [slots.policy]\n\
+         baseline = \"hardened\"\n\
+         deny = [\"CKM_SHA224\"]\n\
+         [slots.policy.min_key_bits]\n\
+         CKK_AES = 192\n",
+    )
+    .unwrap();
+    let policy =
+        policy::Policy::from_config(config.slots[0].policy.as_ref().unwrap())
+            .unwrap();
+
+    /* memory only token */
+    let mut token = Token::new(String::new()).unwrap();
+    assert!(token.get_mechs_list().contains(&CKM_SHA224));
+    token.apply_policy(policy).unwrap();
+    assert!(!token.get_mechs_list().contains(&CKM_SHA224));
+    assert!(token.get_mechs_list().contains(&CKM_SHA256));
+
+    let info = token.get_mech_info(CKM_RSA_PKCS_KEY_PAIR_GEN).unwrap();
+    assert_eq!(info.ulMinKeySize, 2048);
+    let info = token.get_mech_info(CKM_RSA_PKCS).unwrap();
+    assert_eq!(info.flags & (CKF_ENCRYPT | CKF_DECRYPT), 0);
+    assert_eq!(info.flags & CKF_SIGN, CKF_SIGN);
+    let info = token.get_mech_info(CKM_SHA1_RSA_PKCS).unwrap();
+    assert_eq!(info.flags & CKF_SIGN, 0);
+    assert_eq!(info.flags & CKF_VERIFY, CKF_VERIFY);
+    let info = token.get_mech_info(CKM_AES_KEY_GEN).unwrap();
+    assert_eq!(info.ulMinKeySize, 24);
+
+    /* raw ECDH is refused, ECDH with a KDF is fine */
+    let mut params = CK_ECDH1_DERIVE_PARAMS {
+        kdf: CKD_NULL,
+        ulSharedDataLen: 0,
+        pSharedData: std::ptr::null_mut(),
+        ulPublicDataLen: 0,
+        pPublicData: std::ptr::null_mut(),
+    };
+    let mechanism = CK_MECHANISM {
+        mechanism: CKM_ECDH1_DERIVE,
+        pParameter: &mut params as *mut _ as CK_VOID_PTR,
+        ulParameterLen: sizeof!(CK_ECDH1_DERIVE_PARAMS),
+    };
+    let err = token
+        .get_policy()
+        .check_mechanism(&mechanism, CKF_DERIVE)
+        .unwrap_err();
+    assert_eq!(err.rv(), CKR_MECHANISM_PARAM_INVALID);
+    params.kdf = CKD_SHA256_KDF;
+    token
+        .get_policy()
+        .check_mechanism(&mechanism, CKF_DERIVE)
+        .unwrap();
+
+    /* short keys can't be created */
+    let class = CKO_SECRET_KEY;
+    let key_type = CKK_AES;
+    let short = [0x55u8; 16];
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_KEY_TYPE, void_ptr!(&key_type), CK_ULONG_SIZE),
+        (CKA_VALUE, void_ptr!(short.as_ptr()), short.len()),
+    ]);
+    let err = token.create_object(1, template.as_slice()).unwrap_err();
+    assert_eq!(err.rv(), CKR_KEY_SIZE_RANGE);
+    let long = [0x55u8; 32];
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_KEY_TYPE, void_ptr!(&key_type), CK_ULONG_SIZE),
+        (CKA_VALUE, void_ptr!(long.as_ptr()), long.len()),
+    ]);
+    token.create_object(1, template.as_slice()).unwrap();
+}
+
+#[test]
+#[parallel]
+fn test_crypto_policies_backend() {
+    let policy = policy::crypto_policies::from_backend(
+        "# sample back-end\n\
+         hash = SHA2-256 SHA2-384 SHA2-512\n\
+         mac = HMAC-SHA2-256 HMAC-SHA2-512\n\
+         sign = RSA-PSS-SHA2-256 ECDSA-SHA2-256 ECDSA-SHA2-224 \
+         EDDSA-ED25519\n\
+         cipher = AES-256-GCM AES-256-CBC\n\
+         key_exchange = ECDHE\n\
+         min_rsa_size = 3072\n\
+         unknown_property = ignored\n",
+    )
+    .unwrap();
+
+    let mut token = Token::new(String::new()).unwrap();
+    token.apply_policy(policy).unwrap();
+    let mechs = token.get_mechs_list();
+    /* digests stay available, only signatures are restricted */
+    assert!(mechs.contains(&CKM_SHA_1));
+    assert!(mechs.contains(&CKM_SHA224));
+    assert!(mechs.contains(&CKM_SHA256));
+    assert!(!mechs.contains(&CKM_SHA384_HMAC));
+    assert!(mechs.contains(&CKM_SHA512_HMAC));
+
+    let info = token.get_mech_info(CKM_RSA_PKCS_KEY_PAIR_GEN).unwrap();
+    assert_eq!(info.ulMinKeySize, 3072);
+    let info = token.get_mech_info(CKM_SHA256_RSA_PKCS).unwrap();
+    assert_eq!(info.flags & (CKF_SIGN | CKF_VERIFY), 0);
+    let info = token.get_mech_info(CKM_SHA256_RSA_PKCS_PSS).unwrap();
+    assert_eq!(info.flags & CKF_SIGN, CKF_SIGN);
+    let info = token.get_mech_info(CKM_ECDSA_SHA224).unwrap();
+    assert_eq!(info.flags & (CKF_SIGN | CKF_VERIFY), 0);
+    let info = token.get_mech_info(CKM_ECDSA_SHA256).unwrap();
+    assert_eq!(info.flags & CKF_SIGN, CKF_SIGN);
+    let info = token.get_mech_info(CKM_RSA_PKCS).unwrap();
+    assert_eq!(info.flags & (CKF_ENCRYPT | CKF_DECRYPT), 0);
+    let info = token.get_mech_info(CKM_AES_CTR).unwrap();
+    assert_eq!(info.flags & (CKF_ENCRYPT | CKF_DECRYPT), 0);
+    let info = token.get_mech_info(CKM_AES_GCM).unwrap();
+    assert_eq!(info.flags & CKF_ENCRYPT, CKF_ENCRYPT);
+    let info = token.get_mech_info(CKM_AES_KEY_GEN).unwrap();
+    assert_eq!(info.ulMinKeySize, 32);
+    assert!(token.get_mechs_list().contains(&CKM_ECDH1_DERIVE));
+
+    /* malformed lines are refused */
+    let err =
+        policy::crypto_policies::from_backend("hash SHA2-256\n").unwrap_err();
+    assert_eq!(err.rv(), CKR_ARGUMENTS_BAD);
+
+    /* an empty path disables the system policy */
+    let config = config::Config::from_toml(
+        "crypto_policies = \"\"\n\
+         [[slots]]\n",
+    )
+    .unwrap();
+    assert!(config.get_system_policy().unwrap().is_none());
+
+    /* as does an empty KRYOPTIC_CRYPTO_POLICIES for legacy strings */
+    no_system_policy();
+    let config = config::Config::from_init_string("test.sql:1").unwrap();
+    assert!(config.get_system_policy().unwrap().is_none());
+}
+
+#[test]
+#[parallel]
+fn test_key_dates_policy() {
+    let config = config::Config::from_toml(
+        "[[slots]]\n\
+         [slots.policy]\n\
+         check_key_dates = true\n",
+    )
+    .unwrap();
+    let policy =
+        policy::Policy::from_config(config.slots[0].policy.as_ref().unwrap())
+            .unwrap();
+
+    let mut token = Token::new(String::new()).unwrap();
+    token.apply_policy(policy).unwrap();
+
+    let class = CKO_SECRET_KEY;
+    let key_type = CKK_AES;
+    let value = [0x55u8; 16];
+    let past = *b"20000101";
+    let future = *b"99991231";
+
+    /* expired key */
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_KEY_TYPE, void_ptr!(&key_type), CK_ULONG_SIZE),
+        (CKA_VALUE, void_ptr!(value.as_ptr()), value.len()),
+        (CKA_END_DATE, void_ptr!(past.as_ptr()), past.len()),
+    ]);
+    let handle = token.create_object(1, template.as_slice()).unwrap();
+    let key = token.get_object_by_handle(handle).unwrap();
+    let err = token
+        .get_policy()
+        .check_key_dates(&key, CKF_ENCRYPT)
+        .unwrap_err();
+    assert_eq!(err.rv(), CKR_KEY_FUNCTION_NOT_PERMITTED);
+    token
+        .get_policy()
+        .check_key_dates(&key, CKF_DECRYPT)
+        .unwrap();
+
+    /* not yet valid key */
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_KEY_TYPE, void_ptr!(&key_type), CK_ULONG_SIZE),
+        (CKA_VALUE, void_ptr!(value.as_ptr()), value.len()),
+        (CKA_START_DATE, void_ptr!(future.as_ptr()), future.len()),
+    ]);
+    let handle = token.create_object(1, template.as_slice()).unwrap();
+    let key = token.get_object_by_handle(handle).unwrap();
+    let err = token
+        .get_policy()
+        .check_key_dates(&key, CKF_SIGN)
+        .unwrap_err();
+    assert_eq!(err.rv(), CKR_KEY_FUNCTION_NOT_PERMITTED);
+    token
+        .get_policy()
+        .check_key_dates(&key, CKF_VERIFY)
+        .unwrap();
+
+    /* valid key */
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_KEY_TYPE, void_ptr!(&key_type), CK_ULONG_SIZE),
+        (CKA_VALUE, void_ptr!(value.as_ptr()), value.len()),
+        (CKA_START_DATE, void_ptr!(past.as_ptr()), past.len()),
+        (CKA_END_DATE, void_ptr!(future.as_ptr()), future.len()),
+    ]);
+    let handle = token.create_object(1, template.as_slice()).unwrap();
+    let key = token.get_object_by_handle(handle).unwrap();
+    token.get_policy().check_key_dates(&key, CKF_WRAP).unwrap();
+}
+
+#[test]
+#[parallel]
+fn test_key_dates_operations() {
+    let dbname = "test_key_dates_operations.sql";
+    let mut testtokn = TestToken::new(dbname, false);
+    testtokn.setup_db(None);
+
+    let conffile = "test_key_dates_operations.conf";
+    std::fs::write(
+        conffile,
+        format!(
+            "[[slots]]\n\
+             slot = {}\n\
+             dbpath = \"{}\"\n\
+             \n\
+             [slots.policy]\n\
+             check_key_dates = true\n",
+            testtokn.get_slot(),
+            dbname
+        ),
+    )
+    .unwrap();
+    let mut args = testtokn.make_empty_init_args();
+    args.pReserved =
+        CString::new(conffile).unwrap().into_raw() as *mut std::ffi::c_void;
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+    std::fs::remove_file(conffile).unwrap();
+
+    let session = testtokn.get_session(true);
+    testtokn.login();
+
+    let value = [0x55u8; 16];
+    let past = "20000101".as_bytes();
+    let future = "99991231".as_bytes();
+
+    /* expired key, only decryption is still allowed */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_SECRET_KEY,
+        &[(CKA_KEY_TYPE, CKK_AES)],
+        &[(CKA_VALUE, value.as_slice()), (CKA_END_DATE, past)],
+        &[(CKA_ENCRYPT, true), (CKA_DECRYPT, true)],
+    ));
+    let mut mechanism = CK_MECHANISM {
+        mechanism: CKM_AES_ECB,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    };
+    let ret = fn_encrypt_init(session, &mut mechanism, handle);
+    assert_eq!(ret, CKR_KEY_FUNCTION_NOT_PERMITTED);
+    let ret = fn_decrypt_init(session, &mut mechanism, handle);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_decrypt_init(session, std::ptr::null_mut(), handle);
+    assert_eq!(ret, CKR_OK);
+
+    /* not yet valid key, only verification is allowed */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_SECRET_KEY,
+        &[(CKA_KEY_TYPE, CKK_GENERIC_SECRET)],
+        &[(CKA_VALUE, value.as_slice()), (CKA_START_DATE, future)],
+        &[(CKA_SIGN, true), (CKA_VERIFY, true)],
+    ));
+    let mut mechanism = CK_MECHANISM {
+        mechanism: CKM_SHA256_HMAC,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    };
+    let ret = fn_sign_init(session, &mut mechanism, handle);
+    assert_eq!(ret, CKR_KEY_FUNCTION_NOT_PERMITTED);
+    let ret = fn_verify_init(session, &mut mechanism, handle);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_verify_init(session, std::ptr::null_mut(), handle);
+    assert_eq!(ret, CKR_OK);
+
+    /* the same key within its validity period */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_SECRET_KEY,
+        &[(CKA_KEY_TYPE, CKK_GENERIC_SECRET)],
+        &[
+            (CKA_VALUE, value.as_slice()),
+            (CKA_START_DATE, past),
+            (CKA_END_DATE, future)
+        ],
+        &[(CKA_SIGN, true)],
+    ));
+    let ret = fn_sign_init(session, &mut mechanism, handle);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_sign_init(session, std::ptr::null_mut(), handle);
+    assert_eq!(ret, CKR_OK);
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_profiles_policy() {
+    let config = config::Config::from_toml(
+        "[[slots]]\n\
+         [slots.policy]\n\
+         deny = [\"CKM_ECDSA\", \"CKM_HKDF_DERIVE\"]\n",
+    )
+    .unwrap();
+    let policy =
+        policy::Policy::from_config(config.slots[0].policy.as_ref().unwrap())
+            .unwrap();
+
+    let class = CKO_PROFILE;
+    let template =
+        make_ptrs_template(&[(CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE)]);
+    let mut token = Token::new(String::new()).unwrap();
+    let (handles, _) = token.search_objects(template.as_slice()).unwrap();
+    assert_eq!(handles.len(), 4);
+
+    /* profiles that need the denied mechanisms are withdrawn */
+    token.apply_policy(policy).unwrap();
+    let (handles, _) = token.search_objects(template.as_slice()).unwrap();
+    let mut profiles = Vec::<CK_PROFILE_ID>::new();
+    for handle in handles {
+        let obj = token.get_object_by_handle(handle).unwrap();
+        profiles.push(obj.get_attr_as_ulong(CKA_PROFILE_ID).unwrap());
+    }
+    profiles.sort();
+    assert_eq!(
+        profiles,
+        vec![CKP_AUTHENTICATION_TOKEN, CKP_PUBLIC_CERTIFICATES_TOKEN]
+    );
+
+    /* so are the objects of the denied mechanisms */
+    let class = CKO_MECHANISM;
+    let mech = CKM_ECDSA;
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_MECHANISM_TYPE, void_ptr!(&mech), CK_ULONG_SIZE),
+    ]);
+    let (handles, _) = token.search_objects(template.as_slice()).unwrap();
+    assert_eq!(handles.len(), 0);
+
+    /* and the remaining ones only reference the available profiles */
+    let mech = CKM_RSA_PKCS;
+    let template = make_ptrs_template(&[
+        (CKA_CLASS, void_ptr!(&class), CK_ULONG_SIZE),
+        (CKA_MECHANISM_TYPE, void_ptr!(&mech), CK_ULONG_SIZE),
+    ]);
+    let (handles, _) = token.search_objects(template.as_slice()).unwrap();
+    assert_eq!(handles.len(), 1);
+    let obj = token.get_object_by_handle(handles[0]).unwrap();
+    assert_eq!(
+        obj.get_attr_as_bytes(KRA_MECHANISM_PROFILES).unwrap(),
+        &CKP_AUTHENTICATION_TOKEN.to_ne_bytes().to_vec()
+    );
+}
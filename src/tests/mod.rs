@@ -22,6 +22,15 @@ static FINI: RwLock<u64> = RwLock::new(0);
 static SYNC: RwLock<u64> = RwLock::new(0);
 
 static INIT: Once = Once::new();
+
+/* tests must not pick up the crypto-policies of the host */
+static NO_SYSTEM_POLICY: Once = Once::new();
+fn no_system_policy() {
+    NO_SYSTEM_POLICY.call_once(|| {
+        std::env::set_var(config::CRYPTO_POLICIES_ENV, "");
+    });
+}
+
 fn test_finalizer() -> Option<RwLockWriteGuard<'static, u64>> {
     let mut winner: Option<RwLockWriteGuard<u64>> = None;
     INIT.call_once(|| {
@@ -30,6 +39,16 @@ fn test_finalizer() -> Option<RwLockWriteGuard<'static, u64>> {
     winner
 }
 
+/* sqlite databases in WAL mode may leave journal files behind,
+ * directory tokens are removed with all their contents */
+fn remove_token_files(filename: &str) {
+    std::fs::remove_dir_all(filename).unwrap_or(());
+    std::fs::remove_file(filename).unwrap_or(());
+    std::fs::remove_file(format!("{}-wal", filename)).unwrap_or(());
+    std::fs::remove_file(format!("{}-shm", filename)).unwrap_or(());
+    std::fs::remove_file(format!("{}.log", filename)).unwrap_or(());
+}
+
 struct Slots {
     id: u64,
 }
@@ -48,6 +67,7 @@ struct TestToken<'a> {
 
 impl TestToken<'_> {
     fn new<'a>(filename: &'a str, encrypted: bool) -> TestToken {
+        no_system_policy();
         let mut slots = SLOTS.write().unwrap();
         slots.id += 1;
         while check_test_slot_busy(slots.id) {
@@ -72,7 +92,7 @@ impl TestToken<'_> {
             None => basic,
         };
         /* remove the target filename if any */
-        std::fs::remove_file(self.filename).unwrap_or(());
+        remove_token_files(self.filename);
 
         let so_pin = SO_PIN.as_bytes().to_vec();
         let user_pin = USER_PIN.as_bytes().to_vec();
@@ -144,7 +164,7 @@ impl TestToken<'_> {
             /* winner finalized and completed the tests */
             self.finalize = None;
         }
-        std::fs::remove_file(self.filename).unwrap_or(());
+        remove_token_files(self.filename);
     }
 
     fn initialized<'a>(
@@ -251,3 +271,5 @@ mod mac_vectors;
 mod aes_kw_vectors;
 
 mod tls;
+
+mod backends;
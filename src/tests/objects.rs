@@ -6,6 +6,30 @@ use tests::*;
 
 use serial_test::parallel;
 
+/* self-signed P-256 certificate for CN=Kryoptic Test */
+const TEST_CERT: &str =
+    "308201843082012ba00302010202141027a42c6c738e2d339071759d159783\
+     10b43ea3300a06082a8648ce3d04030230183116301406035504030c0d4b72\
+     796f707469632054657374301e170d3236313031383137353333305a170d33\
+     36313031353137353333305a30183116301406035504030c0d4b72796f7074\
+     696320546573743059301306072a8648ce3d020106082a8648ce3d03010703\
+     420004318dfb984ff42f0f4805ad0783f4ebbf6bfa5d46e8a44b17632f093a\
+     c188280b6268abc9dc960e3ac74062e0e4f4b9ef3b41d255a39007c48b60e4\
+     e4acc14b60a3533051301d0603551d0e041604140d64cc4a7672945a5e8721\
+     354fc2e0b4dd10bf6c301f0603551d230418301680140d64cc4a7672945a5e\
+     8721354fc2e0b4dd10bf6c300f0603551d130101ff040530030101ff300a06\
+     082a8648ce3d040302034700304402205bc037e7dbed16a023767124d939b8\
+     6400f207eded83314ccfdfe1709bc1817c02205d85d86bb77320fad9b6c939\
+     7ebb5defea67f2d79e856fab0146b447b6ffd316";
+
+const TEST_ATTR_CERT: &str =
+    "3081a1307d0201013017a115a4133011310f300d06035504030c06486f6c64\
+     6572a0173015a4133011310f300d06035504030c06497373756572300d0609\
+     2a864886f70d01010b050002012a3022180f32303234303130313030303030\
+     305a180f32303334303130313030303030305a3010300e060355044831070c\
+     0561646d696e300d06092a864886f70d01010b050003110000010203040506\
+     0708090a0b0c0d0e0f";
+
 #[test]
 #[parallel]
 fn test_copy_objects() {
@@ -112,15 +136,12 @@ fn test_create_objects() {
         &bool_values,
     ));
 
+    let cert = hex::decode(TEST_CERT).expect("Failed to decode cert");
     let _ = ret_or_panic!(import_object(
         session,
         CKO_CERTIFICATE,
         &[(CKA_CERTIFICATE_TYPE, CKC_X_509)],
-        &[
-            (CKA_CHECK_VALUE, "ignored".as_bytes()),
-            (CKA_SUBJECT, "subject".as_bytes()),
-            (CKA_VALUE, "value".as_bytes())
-        ],
+        &[(CKA_VALUE, cert.as_slice())],
         &[(CKA_TOKEN, true), (CKA_TRUSTED, false)],
     ));
 
@@ -216,3 +237,829 @@ fn test_create_objects() {
 
     testtokn.finalize();
 }
+
+#[test]
+#[parallel]
+fn test_x509_certificate() {
+    let mut testtokn =
+        TestToken::initialized("test_x509_certificate.sql", None);
+    let session = testtokn.get_session(true);
+    testtokn.login();
+
+    let cert = hex::decode(TEST_CERT).expect("Failed to decode cert");
+    let name =
+        hex::decode("30183116301406035504030c0d4b72796f707469632054657374")
+            .expect("Failed to decode name");
+    let serial = hex::decode("02141027a42c6c738e2d339071759d15978310b43ea3")
+        .expect("Failed to decode serial");
+    let key_hash = hex::decode("0d64cc4a7672945a5e8721354fc2e0b4dd10bf6c")
+        .expect("Failed to decode key hash");
+    let check_value =
+        hex::decode("18d235").expect("Failed to decode check value");
+    let spki = hex::decode(
+        "3059301306072a8648ce3d020106082a8648ce3d03010703420004318dfb\
+         984ff42f0f4805ad0783f4ebbf6bfa5d46e8a44b17632f093ac188280b6268\
+         abc9dc960e3ac74062e0e4f4b9ef3b41d255a39007c48b60e4e4acc14b60",
+    )
+    .expect("Failed to decode public key info");
+
+    /* attributes are filled in from the certificate */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_CERTIFICATE,
+        &[(CKA_CERTIFICATE_TYPE, CKC_X_509)],
+        &[(CKA_VALUE, cert.as_slice())],
+        &[],
+    ));
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[],
+        &[
+            (CKA_SUBJECT, name.as_slice()),
+            (CKA_ISSUER, name.as_slice()),
+            (CKA_SERIAL_NUMBER, serial.as_slice()),
+            (CKA_HASH_OF_SUBJECT_PUBLIC_KEY, key_hash.as_slice()),
+            (CKA_CHECK_VALUE, check_value.as_slice()),
+            (CKA_PUBLIC_KEY_INFO, spki.as_slice()),
+        ],
+        &[],
+    ) {
+        panic!("{}", err);
+    }
+
+    /* only the identifying attributes can be changed */
+    let new_id = "new id".as_bytes();
+    let mut template =
+        make_ptrs_template(&[(CKA_ID, void_ptr!(new_id.as_ptr()), 6)]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    for t in [CKA_SUBJECT, CKA_VALUE, CKA_CHECK_VALUE] {
+        template[0].type_ = t;
+        let ret =
+            fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+        assert_eq!(ret, CKR_ATTRIBUTE_READ_ONLY);
+    }
+
+    /* matching values are accepted */
+    let _ = ret_or_panic!(import_object(
+        session,
+        CKO_CERTIFICATE,
+        &[(CKA_CERTIFICATE_TYPE, CKC_X_509)],
+        &[
+            (CKA_VALUE, cert.as_slice()),
+            (CKA_SUBJECT, name.as_slice()),
+            (CKA_SERIAL_NUMBER, serial.as_slice()),
+            (CKA_CHECK_VALUE, check_value.as_slice()),
+        ],
+        &[],
+    ));
+
+    /* mismatches are not */
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_CERTIFICATE,
+            &[(CKA_CERTIFICATE_TYPE, CKC_X_509)],
+            &[
+                (CKA_VALUE, cert.as_slice()),
+                (CKA_SUBJECT, "subject".as_bytes()),
+            ],
+            &[],
+        ),
+        CKR_ATTRIBUTE_VALUE_INVALID
+    );
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_CERTIFICATE,
+            &[(CKA_CERTIFICATE_TYPE, CKC_X_509)],
+            &[
+                (CKA_VALUE, cert.as_slice()),
+                (CKA_CHECK_VALUE, "bad".as_bytes()),
+            ],
+            &[],
+        ),
+        CKR_ATTRIBUTE_VALUE_INVALID
+    );
+
+    /* the value must be a DER encoded certificate */
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_CERTIFICATE,
+            &[(CKA_CERTIFICATE_TYPE, CKC_X_509)],
+            &[
+                (CKA_VALUE, "value".as_bytes()),
+                (CKA_SUBJECT, "subject".as_bytes()),
+            ],
+            &[],
+        ),
+        CKR_ATTRIBUTE_VALUE_INVALID
+    );
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_other_certificates() {
+    let mut testtokn =
+        TestToken::initialized("test_other_certificates.sql", None);
+    let session = testtokn.get_session(true);
+    testtokn.login();
+
+    /* the attributes of attribute certificates come from the value */
+    let cert = hex::decode(TEST_ATTR_CERT).expect("Failed to decode attr cert");
+    let holder =
+        hex::decode("3017a115a4133011310f300d06035504030c06486f6c646572")
+            .expect("Failed to decode holder");
+    let issuer =
+        hex::decode("a0173015a4133011310f300d06035504030c06497373756572")
+            .expect("Failed to decode issuer");
+    let serial = hex::decode("02012a").expect("Failed to decode serial");
+    let types = hex::decode("30050603550448").expect("Failed to decode");
+    let check_value =
+        hex::decode("15645a").expect("Failed to decode check value");
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_CERTIFICATE,
+        &[(CKA_CERTIFICATE_TYPE, CKC_X_509_ATTR_CERT)],
+        &[(CKA_VALUE, cert.as_slice())],
+        &[],
+    ));
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[],
+        &[
+            (CKA_OWNER, holder.as_slice()),
+            (CKA_AC_ISSUER, issuer.as_slice()),
+            (CKA_SERIAL_NUMBER, serial.as_slice()),
+            (CKA_ATTR_TYPES, types.as_slice()),
+            (CKA_CHECK_VALUE, check_value.as_slice()),
+        ],
+        &[],
+    ) {
+        panic!("{}", err);
+    }
+    let new_serial = "serial".as_bytes();
+    let mut template = make_ptrs_template(&[(
+        CKA_SERIAL_NUMBER,
+        void_ptr!(new_serial.as_ptr()),
+        6,
+    )]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    for t in [CKA_OWNER, CKA_VALUE, CKA_CHECK_VALUE] {
+        template[0].type_ = t;
+        let ret =
+            fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+        assert_eq!(ret, CKR_ATTRIBUTE_READ_ONLY);
+    }
+
+    /* matching values are accepted */
+    let _ = ret_or_panic!(import_object(
+        session,
+        CKO_CERTIFICATE,
+        &[(CKA_CERTIFICATE_TYPE, CKC_X_509_ATTR_CERT)],
+        &[
+            (CKA_OWNER, holder.as_slice()),
+            (CKA_AC_ISSUER, issuer.as_slice()),
+            (CKA_ATTR_TYPES, types.as_slice()),
+            (CKA_VALUE, cert.as_slice()),
+        ],
+        &[],
+    ));
+
+    /* mismatches are not */
+    for t in [CKA_OWNER, CKA_AC_ISSUER, CKA_ATTR_TYPES] {
+        err_or_panic!(
+            import_object(
+                session,
+                CKO_CERTIFICATE,
+                &[(CKA_CERTIFICATE_TYPE, CKC_X_509_ATTR_CERT)],
+                &[(t, "other".as_bytes()), (CKA_VALUE, cert.as_slice())],
+                &[],
+            ),
+            CKR_ATTRIBUTE_VALUE_INVALID
+        );
+    }
+
+    /* the value must be a DER encoded attribute certificate */
+    for value in ["attribute certificate".as_bytes(), "".as_bytes()] {
+        err_or_panic!(
+            import_object(
+                session,
+                CKO_CERTIFICATE,
+                &[(CKA_CERTIFICATE_TYPE, CKC_X_509_ATTR_CERT)],
+                &[(CKA_OWNER, holder.as_slice()), (CKA_VALUE, value)],
+                &[],
+            ),
+            CKR_ATTRIBUTE_VALUE_INVALID
+        );
+    }
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_CERTIFICATE,
+            &[(CKA_CERTIFICATE_TYPE, CKC_X_509_ATTR_CERT)],
+            &[(CKA_OWNER, holder.as_slice())],
+            &[],
+        ),
+        CKR_TEMPLATE_INCOMPLETE
+    );
+
+    /* WTLS certificates need a subject */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_CERTIFICATE,
+        &[(CKA_CERTIFICATE_TYPE, CKC_WTLS)],
+        &[
+            (CKA_SUBJECT, "subject".as_bytes()),
+            (CKA_VALUE, "wtls certificate".as_bytes()),
+        ],
+        &[],
+    ));
+    let check_value =
+        hex::decode("8e0637").expect("Failed to decode check value");
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[],
+        &[
+            (CKA_ISSUER, "".as_bytes()),
+            (CKA_CHECK_VALUE, check_value.as_slice()),
+        ],
+        &[],
+    ) {
+        panic!("{}", err);
+    }
+    let issuer = "issuer".as_bytes();
+    let mut template =
+        make_ptrs_template(&[(CKA_ISSUER, void_ptr!(issuer.as_ptr()), 6)]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    for t in [CKA_SUBJECT, CKA_VALUE, CKA_URL] {
+        template[0].type_ = t;
+        let ret =
+            fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+        assert_eq!(ret, CKR_ATTRIBUTE_READ_ONLY);
+    }
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_CERTIFICATE,
+            &[(CKA_CERTIFICATE_TYPE, CKC_WTLS)],
+            &[(CKA_VALUE, "wtls certificate".as_bytes())],
+            &[],
+        ),
+        CKR_TEMPLATE_INCOMPLETE
+    );
+
+    /* or a URL and the hash of the public key to find it */
+    let _ = ret_or_panic!(import_object(
+        session,
+        CKO_CERTIFICATE,
+        &[(CKA_CERTIFICATE_TYPE, CKC_WTLS)],
+        &[
+            (CKA_SUBJECT, "subject".as_bytes()),
+            (CKA_VALUE, "".as_bytes()),
+            (CKA_URL, "https://example.com/cert".as_bytes()),
+            (CKA_HASH_OF_SUBJECT_PUBLIC_KEY, "hash".as_bytes()),
+        ],
+        &[],
+    ));
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_CERTIFICATE,
+            &[(CKA_CERTIFICATE_TYPE, CKC_WTLS)],
+            &[
+                (CKA_SUBJECT, "subject".as_bytes()),
+                (CKA_VALUE, "".as_bytes()),
+                (CKA_URL, "https://example.com/cert".as_bytes()),
+            ],
+            &[],
+        ),
+        CKR_ATTRIBUTE_VALUE_INVALID
+    );
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_trust_objects() {
+    let mut testtokn = TestToken::initialized("test_trust_objects.sql", None);
+    let session = testtokn.get_session(true);
+    testtokn.login();
+
+    let name =
+        hex::decode("30183116301406035504030c0d4b72796f707469632054657374")
+            .expect("Failed to decode name");
+    let serial = hex::decode("02141027a42c6c738e2d339071759d15978310b43ea3")
+        .expect("Failed to decode serial");
+    let cert_hash = [0x5au8; 32];
+
+    /* users can record that a certificate is not trusted */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_TRUST,
+        &[
+            (CKA_NAME_HASH_ALGORITHM, CKM_SHA256),
+            (CKA_PKCS_TRUST_EMAIL_PROTECTION, CKT_NOT_TRUSTED),
+        ],
+        &[(CKA_HASH_OF_CERTIFICATE, cert_hash.as_slice())],
+        &[(CKA_TOKEN, true)],
+    ));
+
+    /* but only the SO can vouch for one */
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_TRUST,
+            &[(CKA_PKCS_TRUST_SERVER_AUTH, CKT_TRUST_ANCHOR)],
+            &[
+                (CKA_ISSUER, name.as_slice()),
+                (CKA_SERIAL_NUMBER, serial.as_slice()),
+            ],
+            &[(CKA_TOKEN, true)],
+        ),
+        CKR_ATTRIBUTE_READ_ONLY
+    );
+    /* or change the trust levels */
+    let mut template = make_attr_template(
+        &[(CKA_PKCS_TRUST_EMAIL_PROTECTION, CKT_TRUST_UNKNOWN)],
+        &[],
+        &[],
+    );
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_ATTRIBUTE_READ_ONLY);
+
+    testtokn.logout();
+    let pin = "12345678";
+    let ret = fn_login(
+        session,
+        CKU_SO,
+        pin.as_ptr() as *mut _,
+        pin.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    let mut template =
+        make_attr_template(&[(CKA_PKCS_TRUST_CLIENT_AUTH, 7)], &[], &[]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_ATTRIBUTE_VALUE_INVALID);
+
+    /* linked by issuer and serial number */
+    let handle = ret_or_panic!(import_object(
+        session,
+        CKO_TRUST,
+        &[(CKA_PKCS_TRUST_SERVER_AUTH, CKT_TRUST_ANCHOR)],
+        &[
+            (CKA_ISSUER, name.as_slice()),
+            (CKA_SERIAL_NUMBER, serial.as_slice()),
+        ],
+        &[(CKA_TOKEN, true)],
+    ));
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[
+            (CKA_PKCS_TRUST_SERVER_AUTH, CKT_TRUST_ANCHOR),
+            (CKA_PKCS_TRUST_CLIENT_AUTH, CKT_TRUST_UNKNOWN),
+            (CKA_PKCS_TRUST_CODE_SIGNING, CKT_TRUST_UNKNOWN),
+            (CKA_PKCS_TRUST_EMAIL_PROTECTION, CKT_TRUST_UNKNOWN),
+        ],
+        &[],
+        &[],
+    ) {
+        panic!("{}", err);
+    }
+
+    /* trust stores look them up by certificate */
+    let mut template = make_attr_template(
+        &[(CKA_CLASS, CKO_TRUST)],
+        &[(CKA_HASH_OF_CERTIFICATE, cert_hash.as_slice())],
+        &[],
+    );
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 2);
+    assert_eq!(ret, CKR_OK);
+    let mut handles = [CK_INVALID_HANDLE; 2];
+    let mut count: CK_ULONG = 0;
+    let ret = fn_find_objects(session, handles.as_mut_ptr(), 2, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    /* the certificate must be referenced */
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_TRUST,
+            &[(CKA_PKCS_TRUST_SERVER_AUTH, CKT_TRUSTED)],
+            &[(CKA_ISSUER, name.as_slice())],
+            &[],
+        ),
+        CKR_TEMPLATE_INCOMPLETE
+    );
+    /* the hash must match the hash algorithm */
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_TRUST,
+            &[],
+            &[(CKA_HASH_OF_CERTIFICATE, cert_hash.as_slice())],
+            &[],
+        ),
+        CKR_ATTRIBUTE_VALUE_INVALID
+    );
+    /* and trust levels must be known */
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_TRUST,
+            &[(CKA_PKCS_TRUST_CODE_SIGNING, 7)],
+            &[
+                (CKA_ISSUER, name.as_slice()),
+                (CKA_SERIAL_NUMBER, serial.as_slice()),
+            ],
+            &[],
+        ),
+        CKR_ATTRIBUTE_VALUE_INVALID
+    );
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_search_paging() {
+    let mut testtokn = TestToken::initialized("test_search_paging.sql", None);
+    let session = testtokn.get_session(false);
+
+    /* skip the objects the token synthesizes in memory */
+    let mut template = make_attr_template(&[], &[], &[(CKA_TOKEN, true)]);
+    let mut handles = [CK_INVALID_HANDLE; 4];
+    let mut count: CK_ULONG = 0;
+
+    /* only public objects are found when not logged in */
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    for idx in 0..2 {
+        let ret = fn_find_objects(session, &mut handles[idx], 1, &mut count);
+        assert_eq!(ret, CKR_OK);
+        assert_eq!(count, 1);
+    }
+    let ret = fn_find_objects(session, &mut handles[2], 1, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 0);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+    assert_ne!(handles[0], handles[1]);
+
+    /* results come in pages of at most the requested size */
+    testtokn.login();
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_find_objects(session, handles.as_mut_ptr(), 3, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 3);
+    let ret = fn_find_objects(session, &mut handles[3], 3, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects(session, handles.as_mut_ptr(), 3, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 0);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    /* handles are stable across searches */
+    let template = make_attr_template(
+        &[(CKA_CLASS, CKO_PRIVATE_KEY)],
+        &[(CKA_LABEL, "Test EC Key".as_bytes())],
+        &[],
+    );
+    let ret = fn_find_objects_init(session, template.as_ptr() as *mut _, 2);
+    assert_eq!(ret, CKR_OK);
+    let mut handle: CK_ULONG = CK_INVALID_HANDLE;
+    let ret = fn_find_objects(session, &mut handle, 1, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+    assert!(handles.contains(&handle));
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_profile_objects() {
+    let mut testtokn = TestToken::initialized("test_profile_objects.sql", None);
+    let session = testtokn.get_session(false);
+
+    /* profiles are public and available without login */
+    let mut template =
+        make_attr_template(&[(CKA_CLASS, CKO_PROFILE)], &[], &[]);
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    let mut handles = [CK_INVALID_HANDLE; 8];
+    let mut count: CK_ULONG = 0;
+    let ret = fn_find_objects(session, handles.as_mut_ptr(), 8, &mut count);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    let mut profiles = Vec::<CK_PROFILE_ID>::new();
+    for handle in &handles[..count as usize] {
+        let mut profile_id: CK_PROFILE_ID = CKP_INVALID_ID;
+        let mut template = vec![make_attribute!(
+            CKA_PROFILE_ID,
+            &mut profile_id as *mut _,
+            CK_ULONG_SIZE
+        )];
+        let ret =
+            fn_get_attribute_value(session, *handle, template.as_mut_ptr(), 1);
+        assert_eq!(ret, CKR_OK);
+        profiles.push(profile_id);
+    }
+    assert!(profiles.contains(&CKP_BASELINE_PROVIDER));
+    assert!(profiles.contains(&CKP_EXTENDED_PROVIDER));
+    assert!(profiles.contains(&CKP_AUTHENTICATION_TOKEN));
+    assert!(profiles.contains(&CKP_PUBLIC_CERTIFICATES_TOKEN));
+
+    /* profile objects are read only */
+    let ret = fn_destroy_object(session, handles[0]);
+    assert_eq!(ret, CKR_ACTION_PROHIBITED);
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_PROFILE,
+            &[(CKA_PROFILE_ID, CKP_BASELINE_PROVIDER)],
+            &[],
+            &[],
+        ),
+        CKR_ACTION_PROHIBITED
+    );
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_mechanism_objects() {
+    let mut testtokn =
+        TestToken::initialized("test_mechanism_objects.sql", None);
+    let session = testtokn.get_session(false);
+
+    let mut template = make_attr_template(
+        &[(CKA_CLASS, CKO_MECHANISM), (CKA_MECHANISM_TYPE, CKM_SHA256)],
+        &[],
+        &[],
+    );
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 2);
+    assert_eq!(ret, CKR_OK);
+    let mut handles = [CK_INVALID_HANDLE; 2];
+    let mut count: CK_ULONG = 0;
+    let ret = fn_find_objects(session, handles.as_mut_ptr(), 2, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    /* SHA256 is required by the baseline provider profile */
+    let mut profiles = [CKP_INVALID_ID; 4];
+    let mut template = vec![make_attribute!(
+        KRA_MECHANISM_PROFILES,
+        profiles.as_mut_ptr(),
+        profiles.len() * CK_ULONG_SIZE
+    )];
+    let ret =
+        fn_get_attribute_value(session, handles[0], template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(template[0].ulValueLen as usize, CK_ULONG_SIZE);
+    assert_eq!(profiles[0], CKP_BASELINE_PROVIDER);
+
+    /* mechanism objects are read only */
+    let ret = fn_destroy_object(session, handles[0]);
+    assert_eq!(ret, CKR_ACTION_PROHIBITED);
+    let mut template =
+        make_attr_template(&[], &[(CKA_LABEL, "sha256".as_bytes())], &[]);
+    let ret = fn_set_attribute_value(
+        session,
+        handles[0],
+        template.as_mut_ptr(),
+        template.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_ACTION_PROHIBITED);
+
+    /* and found by searches that do not name their class */
+    let mut template =
+        make_attr_template(&[(CKA_MECHANISM_TYPE, CKM_SHA256)], &[], &[]);
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_find_objects(session, handles.as_mut_ptr(), 2, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+    err_or_panic!(
+        import_object(
+            session,
+            CKO_MECHANISM,
+            &[(CKA_MECHANISM_TYPE, CKM_SHA256)],
+            &[],
+            &[],
+        ),
+        CKR_ACTION_PROHIBITED
+    );
+
+    /* only the SO can deny operations of a mechanism */
+    let handle = handles[0];
+    let session = testtokn.get_session(true);
+    let mut template =
+        make_attr_template(&[(KRA_DENIED_OPERATIONS, CKF_DIGEST)], &[], &[]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_USER_NOT_LOGGED_IN);
+    let pin = "12345678";
+    let ret = fn_login(
+        session,
+        CKU_SO,
+        pin.as_ptr() as *mut _,
+        pin.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    let mut invalid =
+        make_attr_template(&[(KRA_DENIED_OPERATIONS, CKF_HW)], &[], &[]);
+    let ret = fn_set_attribute_value(session, handle, invalid.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_ATTRIBUTE_VALUE_INVALID);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[(KRA_DENIED_OPERATIONS, CKF_DIGEST)],
+        &[],
+        &[],
+    ) {
+        panic!("{}", err);
+    }
+    let mut mechanism: CK_MECHANISM = CK_MECHANISM {
+        mechanism: CKM_SHA256,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    };
+    let ret = fn_digest_init(session, &mut mechanism);
+    assert_eq!(ret, CKR_MECHANISM_INVALID);
+
+    /* the denial is stored with the token */
+    let other = Token::new("test_mechanism_objects.sql".to_string()).unwrap();
+    let info = other.get_mech_info(CKM_SHA256).unwrap();
+    assert_eq!(info.flags & CKF_DIGEST, 0);
+
+    /* and can be lifted again */
+    let mut template =
+        make_attr_template(&[(KRA_DENIED_OPERATIONS, 0)], &[], &[]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_digest_init(session, &mut mechanism);
+    assert_eq!(ret, CKR_OK);
+    let mut data = *b"data";
+    let mut digest = [0u8; 32];
+    let mut digest_len: CK_ULONG = digest.len() as CK_ULONG;
+    let ret = fn_digest(
+        session,
+        data.as_mut_ptr(),
+        data.len() as CK_ULONG,
+        digest.as_mut_ptr(),
+        &mut digest_len,
+    );
+    assert_eq!(ret, CKR_OK);
+
+    testtokn.finalize();
+}
+
+fn find_hw_feature(
+    session: CK_SESSION_HANDLE,
+    feature: CK_HW_FEATURE_TYPE,
+) -> CK_OBJECT_HANDLE {
+    let mut template = make_attr_template(
+        &[(CKA_CLASS, CKO_HW_FEATURE), (CKA_HW_FEATURE_TYPE, feature)],
+        &[],
+        &[],
+    );
+    let ret = fn_find_objects_init(session, template.as_mut_ptr(), 2);
+    assert_eq!(ret, CKR_OK);
+    let mut handle = CK_INVALID_HANDLE;
+    let mut count: CK_ULONG = 0;
+    let ret = fn_find_objects(session, &mut handle, 1, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+    handle
+}
+
+fn read_counter(session: CK_SESSION_HANDLE, handle: CK_OBJECT_HANDLE) -> u64 {
+    let mut value = [0u8; 8];
+    let mut template =
+        vec![make_attribute!(CKA_VALUE, value.as_mut_ptr(), value.len())];
+    let ret = fn_get_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(template[0].ulValueLen, 8);
+    u64::from_be_bytes(value)
+}
+
+fn init_token(slot: CK_SLOT_ID) {
+    let pin = "12345678";
+    let ret = fn_init_token(
+        slot,
+        pin.as_ptr() as *mut _,
+        pin.len() as CK_ULONG,
+        std::ptr::null_mut(),
+    );
+    assert_eq!(ret, CKR_OK);
+}
+
+#[test]
+#[parallel]
+fn test_hw_features() {
+    let mut testtokn = TestToken::initialized("test_hw_features.sql", None);
+    let session = testtokn.get_session(true);
+
+    /* the token has a clock */
+    let mut token_info = CK_TOKEN_INFO::default();
+    let ret = fn_get_token_info(testtokn.get_slot(), &mut token_info);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(token_info.flags & CKF_CLOCK_ON_TOKEN, CKF_CLOCK_ON_TOKEN);
+    assert_ne!(token_info.utcTime, *b"0000000000000000");
+    let handle = find_hw_feature(session, CKH_CLOCK);
+    let mut time = [0u8; 16];
+    let mut template =
+        vec![make_attribute!(CKA_VALUE, time.as_mut_ptr(), time.len())];
+    let ret = fn_get_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    assert!(time.iter().all(|c| c.is_ascii_digit()));
+    assert!(time[..4] >= token_info.utcTime[..4]);
+
+    /* every read increments the counter */
+    let handle = find_hw_feature(session, CKH_MONOTONIC_COUNTER);
+    let first = read_counter(session, handle);
+    assert_eq!(read_counter(session, handle), first + 1);
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[],
+        &[],
+        &[(CKA_RESET_ON_INIT, false), (CKA_HAS_RESET, false)],
+    ) {
+        panic!("{}", err);
+    }
+
+    /* only the SO can ask for a reset */
+    let mut template =
+        make_attr_template(&[], &[], &[(CKA_RESET_ON_INIT, true)]);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_USER_NOT_LOGGED_IN);
+
+    /* the counter survives token initialization */
+    testtokn.close_session();
+    init_token(testtokn.get_slot());
+    let session = testtokn.get_session(true);
+    let handle = find_hw_feature(session, CKH_MONOTONIC_COUNTER);
+    assert_eq!(read_counter(session, handle), first + 2);
+
+    /* unless the SO requested a reset */
+    let pin = "12345678";
+    let ret = fn_login(
+        session,
+        CKU_SO,
+        pin.as_ptr() as *mut _,
+        pin.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_set_attribute_value(session, handle, template.as_mut_ptr(), 1);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_logout(session);
+    assert_eq!(ret, CKR_OK);
+    testtokn.close_session();
+    init_token(testtokn.get_slot());
+    let session = testtokn.get_session(true);
+    let handle = find_hw_feature(session, CKH_MONOTONIC_COUNTER);
+    assert_eq!(read_counter(session, handle), 1);
+    if let Some(err) = check_attributes(
+        session,
+        handle,
+        &[],
+        &[],
+        &[(CKA_RESET_ON_INIT, false), (CKA_HAS_RESET, true)],
+    ) {
+        panic!("{}", err);
+    }
+
+    testtokn.finalize();
+}
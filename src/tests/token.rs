@@ -306,3 +306,748 @@ fn test_interface_invalid_version() {
     );
     assert_eq!(result, CKR_ARGUMENTS_BAD);
 }
+
+#[test]
+#[parallel]
+fn test_token_integrity() {
+    let name = "test_token_integrity.sql";
+    let mut testtokn = TestToken::new(name, true);
+    testtokn.setup_db(None);
+
+    let mut args = testtokn.make_init_args();
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    let session = testtokn.get_session(false);
+    testtokn.login();
+
+    let mut handle: CK_ULONG = CK_INVALID_HANDLE;
+    let template =
+        make_attr_template(&[], &[(CKA_UNIQUE_ID, "13".as_bytes())], &[]);
+    let ret = fn_find_objects_init(session, template.as_ptr() as *mut _, 1);
+    assert_eq!(ret, CKR_OK);
+    let mut count: CK_ULONG = 0;
+    let ret = fn_find_objects(session, &mut handle, 1, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    let mut extractable: CK_BBOOL = CK_TRUE;
+    let mut template = vec![make_attribute!(
+        CKA_EXTRACTABLE,
+        &mut extractable as *mut _,
+        CK_BBOOL_SIZE
+    )];
+    let ret = fn_get_attribute_value(
+        session,
+        handle,
+        template.as_mut_ptr(),
+        template.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(extractable, CK_FALSE);
+
+    /* tamper with a non sensitive attribute directly in the database */
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let _ = conn
+        .execute(
+            "UPDATE objects SET val = 1 WHERE attr = ?1 AND id IN \
+             (SELECT id FROM objects WHERE attr = ?2 AND val = ?3)",
+            rusqlite::params![CKA_EXTRACTABLE, CKA_UNIQUE_ID, "13"],
+        )
+        .unwrap();
+    drop(conn);
+
+    let ret = fn_get_attribute_value(
+        session,
+        handle,
+        template.as_mut_ptr(),
+        template.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_DEVICE_ERROR);
+
+    /* a key made to look public with its tag removed is still rejected */
+    let mut handle: CK_ULONG = CK_INVALID_HANDLE;
+    let search =
+        make_attr_template(&[], &[(CKA_UNIQUE_ID, "11".as_bytes())], &[]);
+    let ret = fn_find_objects_init(session, search.as_ptr() as *mut _, 1);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_find_objects(session, &mut handle, 1, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let _ = conn
+        .execute(
+            "UPDATE objects SET val = 0 WHERE attr = ?1 AND id IN \
+             (SELECT id FROM objects WHERE attr = ?2 AND val = ?3)",
+            rusqlite::params![CKA_PRIVATE, CKA_UNIQUE_ID, "11"],
+        )
+        .unwrap();
+    let _ = conn
+        .execute(
+            "DELETE FROM objects WHERE attr = ?1 AND id IN \
+             (SELECT id FROM objects WHERE attr = ?2 AND val = ?3)",
+            rusqlite::params![KRA_INTEGRITY_TAG, CKA_UNIQUE_ID, "11"],
+        )
+        .unwrap();
+    drop(conn);
+
+    let ret = fn_get_attribute_value(
+        session,
+        handle,
+        template.as_mut_ptr(),
+        template.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_DEVICE_ERROR);
+
+    /* all objects are tagged, so an untagged public key is rejected too */
+    let mut handle: CK_ULONG = CK_INVALID_HANDLE;
+    let search =
+        make_attr_template(&[], &[(CKA_UNIQUE_ID, "10".as_bytes())], &[]);
+    let ret = fn_find_objects_init(session, search.as_ptr() as *mut _, 1);
+    assert_eq!(ret, CKR_OK);
+    let ret = fn_find_objects(session, &mut handle, 1, &mut count);
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(count, 1);
+    let ret = fn_find_objects_final(session);
+    assert_eq!(ret, CKR_OK);
+
+    let mut token: CK_BBOOL = CK_FALSE;
+    let mut template = vec![make_attribute!(
+        CKA_TOKEN,
+        &mut token as *mut _,
+        CK_BBOOL_SIZE
+    )];
+    let ret = fn_get_attribute_value(
+        session,
+        handle,
+        template.as_mut_ptr(),
+        template.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    assert_eq!(token, CK_TRUE);
+
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let _ = conn
+        .execute(
+            "DELETE FROM objects WHERE attr = ?1 AND id IN \
+             (SELECT id FROM objects WHERE attr = ?2 AND val = ?3)",
+            rusqlite::params![KRA_INTEGRITY_TAG, CKA_UNIQUE_ID, "10"],
+        )
+        .unwrap();
+    drop(conn);
+
+    let ret = fn_get_attribute_value(
+        session,
+        handle,
+        template.as_mut_ptr(),
+        template.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_DEVICE_ERROR);
+
+    /* the token info is tagged as well, and checked at login */
+    testtokn.logout();
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let _ = conn
+        .execute(
+            "UPDATE objects SET val = 0 WHERE attr = ?1 AND id IN \
+             (SELECT id FROM objects WHERE attr = ?2 AND val = ?3)",
+            rusqlite::params![KRA_FLAGS, CKA_UNIQUE_ID, "2"],
+        )
+        .unwrap();
+    drop(conn);
+
+    let ret = fn_login(
+        session,
+        CKU_USER,
+        USER_PIN.as_ptr() as *mut _,
+        USER_PIN.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_DEVICE_ERROR);
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_token_rotate_kek() {
+    let mut testtokn = TestToken::new("test_token_rotate_kek.sql", true);
+    testtokn.setup_db(None);
+
+    let mut args = testtokn.make_init_args();
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    let mut piface: *mut CK_INTERFACE = std::ptr::null_mut();
+    let mut version = CK_VERSION { major: 1, minor: 0 };
+    let ret = C_GetInterface(
+        "Kryoptic Vendor v1\0".as_ptr() as CK_UTF8CHAR_PTR,
+        &mut version,
+        &mut piface,
+        0,
+    );
+    assert_eq!(ret, CKR_OK);
+    let list: KR_FUNCTION_LIST =
+        unsafe { *((*piface).pFunctionList as *const KR_FUNCTION_LIST) };
+    let rotate_kek = list.KR_RotateKEK.unwrap();
+
+    let session = testtokn.get_session(true);
+    let pin = "12345678";
+
+    /* not logged in */
+    let ret = unsafe {
+        rotate_kek(session, pin.as_ptr() as *mut _, pin.len() as CK_ULONG)
+    };
+    assert_eq!(ret, CKR_USER_NOT_LOGGED_IN);
+
+    testtokn.login();
+
+    let mechanism: CK_MECHANISM = CK_MECHANISM {
+        mechanism: CKM_ECDSA_SHA256,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    };
+    let data = "plaintext";
+    let pri_handle =
+        ret_or_panic!(get_test_key_handle(session, "\x02", CKO_PRIVATE_KEY));
+    let pub_handle =
+        ret_or_panic!(get_test_key_handle(session, "\x02", CKO_PUBLIC_KEY));
+
+    /* wrong pin */
+    let bad = "87654321";
+    let ret = unsafe {
+        rotate_kek(session, bad.as_ptr() as *mut _, bad.len() as CK_ULONG)
+    };
+    assert_eq!(ret, CKR_PIN_INCORRECT);
+
+    let ret = unsafe {
+        rotate_kek(session, pin.as_ptr() as *mut _, pin.len() as CK_ULONG)
+    };
+    assert_eq!(ret, CKR_OK);
+
+    /* keys are still usable with the new KEK */
+    let signature = ret_or_panic!(sig_gen(
+        session,
+        pri_handle,
+        data.as_bytes(),
+        &mechanism
+    ));
+    let ret = sig_verify(
+        session,
+        pub_handle,
+        data.as_bytes(),
+        signature.as_slice(),
+        &mechanism,
+    );
+    assert_eq!(ret, CKR_OK);
+
+    /* and after a new login */
+    testtokn.logout();
+    testtokn.login();
+    let signature = ret_or_panic!(sig_gen(
+        session,
+        pri_handle,
+        data.as_bytes(),
+        &mechanism
+    ));
+    let ret = sig_verify(
+        session,
+        pub_handle,
+        data.as_bytes(),
+        signature.as_slice(),
+        &mechanism,
+    );
+    assert_eq!(ret, CKR_OK);
+
+    testtokn.finalize();
+}
+
+fn object_by_uid(token: &mut Token, uid: &str) -> object::Object {
+    let template = make_ptrs_template(&[(
+        CKA_UNIQUE_ID,
+        uid.as_ptr() as *mut _,
+        uid.len(),
+    )]);
+    let (_, mut uids) = token.search_objects(template.as_slice()).unwrap();
+    let handles = token.search_results(&mut uids, &[], 1).unwrap();
+    assert_eq!(handles.len(), 1);
+    token.get_object_by_handle(handles[0]).unwrap()
+}
+
+#[test]
+#[parallel]
+fn test_token_rotate_kek_resume() {
+    let name = "test_token_rotate_resume.sql";
+    let copy = "test_token_rotate_resume.copy.sql";
+    let mut testtokn = TestToken::new(name, true);
+    testtokn.setup_db(None);
+    remove_token_files(copy);
+    std::fs::copy(name, copy).unwrap();
+
+    let mut args = testtokn.make_init_args();
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    let mut piface: *mut CK_INTERFACE = std::ptr::null_mut();
+    let mut version = CK_VERSION { major: 1, minor: 0 };
+    let ret = C_GetInterface(
+        "Kryoptic Vendor v1\0".as_ptr() as CK_UTF8CHAR_PTR,
+        &mut version,
+        &mut piface,
+        0,
+    );
+    assert_eq!(ret, CKR_OK);
+    let list: KR_FUNCTION_LIST =
+        unsafe { *((*piface).pFunctionList as *const KR_FUNCTION_LIST) };
+    let rotate_kek = list.KR_RotateKEK.unwrap();
+
+    /* the SO can rotate as well, given the user PIN */
+    let session = testtokn.get_session(true);
+    let so_pin = "12345678";
+    let ret = fn_login(
+        session,
+        CKU_SO,
+        so_pin.as_ptr() as *mut _,
+        so_pin.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    let pin = "12345678";
+    let ret = unsafe {
+        rotate_kek(session, pin.as_ptr() as *mut _, pin.len() as CK_ULONG)
+    };
+    assert_eq!(ret, CKR_OK);
+    testtokn.logout();
+
+    /* recreate in the copy the state left by a rotation interrupted
+     * after the new KEK was stored and a single object converted */
+    let conn = rusqlite::Connection::open(copy).unwrap();
+    conn.execute("ATTACH DATABASE ?1 AS rotated", rusqlite::params![name])
+        .unwrap();
+    let newid: i32 = conn
+        .query_row("SELECT MAX(id) + 1 FROM objects", [], |r| r.get(0))
+        .unwrap();
+    let _ = conn
+        .execute(
+            "INSERT INTO objects SELECT ?1, attr, \
+             CASE WHEN attr = ?2 THEN '3' ELSE val END \
+             FROM rotated.objects WHERE id = \
+             (SELECT id FROM rotated.objects WHERE attr = ?2 AND val = '1')",
+            rusqlite::params![newid, CKA_UNIQUE_ID],
+        )
+        .unwrap();
+    let oldid: i32 = conn
+        .query_row(
+            "SELECT id FROM objects WHERE attr = ?1 AND val = ?2",
+            rusqlite::params![CKA_UNIQUE_ID, "13"],
+            |r| r.get(0),
+        )
+        .unwrap();
+    let _ = conn
+        .execute(
+            "DELETE FROM objects WHERE id = ?1",
+            rusqlite::params![oldid],
+        )
+        .unwrap();
+    let _ = conn
+        .execute(
+            "INSERT INTO objects SELECT ?1, attr, val \
+             FROM rotated.objects WHERE id = \
+             (SELECT id FROM rotated.objects WHERE attr = ?2 AND val = ?3)",
+            rusqlite::params![oldid, CKA_UNIQUE_ID, "13"],
+        )
+        .unwrap();
+    drop(conn);
+
+    /* the next login completes the rotation */
+    let user_pin = USER_PIN.as_bytes().to_vec();
+    let mut token = Token::new(copy.to_string()).unwrap();
+    assert_eq!(token.login(CKU_USER, &user_pin), CKR_OK);
+    let _ = object_by_uid(&mut token, "11");
+    let _ = object_by_uid(&mut token, "13");
+
+    let conn = rusqlite::Connection::open(copy).unwrap();
+    let pending: i32 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM objects WHERE attr = ?1 AND val = '3'",
+            rusqlite::params![CKA_UNIQUE_ID],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(pending, 0);
+    drop(conn);
+
+    /* and the user PIN now unwraps the new KEK */
+    assert_eq!(token.logout(), CKR_OK);
+    assert_eq!(token.login(CKU_USER, &user_pin), CKR_OK);
+    let _ = object_by_uid(&mut token, "11");
+    let _ = object_by_uid(&mut token, "13");
+    drop(token);
+    remove_token_files(copy);
+
+    /* the rotated token itself is usable by the user */
+    testtokn.login();
+    let mechanism: CK_MECHANISM = CK_MECHANISM {
+        mechanism: CKM_ECDSA_SHA256,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    };
+    let data = "plaintext";
+    let pri_handle =
+        ret_or_panic!(get_test_key_handle(session, "\x02", CKO_PRIVATE_KEY));
+    let signature = ret_or_panic!(sig_gen(
+        session,
+        pri_handle,
+        data.as_bytes(),
+        &mechanism
+    ));
+    assert_ne!(signature.len(), 0);
+
+    testtokn.finalize();
+}
+
+#[test]
+#[parallel]
+fn test_token_export_import() {
+    let name = "test_token_export.sql";
+    let mut testtokn = TestToken::new(name, true);
+    testtokn.setup_db(None);
+
+    let mut args = testtokn.make_init_args();
+    let args_ptr = &mut args as *mut CK_C_INITIALIZE_ARGS;
+    let ret = fn_initialize(args_ptr as *mut std::ffi::c_void);
+    assert_eq!(ret, CKR_OK);
+
+    let mut piface: *mut CK_INTERFACE = std::ptr::null_mut();
+    let mut version = CK_VERSION { major: 1, minor: 0 };
+    let ret = C_GetInterface(
+        "Kryoptic Vendor v1\0".as_ptr() as CK_UTF8CHAR_PTR,
+        &mut version,
+        &mut piface,
+        0,
+    );
+    assert_eq!(ret, CKR_OK);
+    let list: KR_FUNCTION_LIST =
+        unsafe { *((*piface).pFunctionList as *const KR_FUNCTION_LIST) };
+    let export_token = list.KR_ExportToken.unwrap();
+    let set_export_policy = list.KR_SetExportPolicy.unwrap();
+
+    let session = testtokn.get_session(true);
+    let pass = "backup passphrase";
+    let mut archive_len: CK_ULONG = 0;
+
+    /* not logged in */
+    let ret = unsafe {
+        export_token(
+            session,
+            CK_INVALID_HANDLE,
+            pass.as_ptr() as *mut _,
+            pass.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut archive_len,
+        )
+    };
+    assert_eq!(ret, CKR_USER_NOT_LOGGED_IN);
+
+    /* the test private keys are not extractable */
+    testtokn.login();
+    let ret = unsafe {
+        export_token(
+            session,
+            CK_INVALID_HANDLE,
+            pass.as_ptr() as *mut _,
+            pass.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut archive_len,
+        )
+    };
+    assert_eq!(ret, CKR_KEY_UNEXTRACTABLE);
+
+    /* the policy can not be relaxed by editing the database */
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let _ = conn
+        .execute(
+            "INSERT INTO objects SELECT id, ?1, ?2 FROM objects \
+             WHERE attr = ?3 AND val = ?4",
+            rusqlite::params![
+                KRA_EXPORT_POLICY,
+                KRF_EXPORT_NON_EXTRACTABLE,
+                CKA_UNIQUE_ID,
+                "2"
+            ],
+        )
+        .unwrap();
+    drop(conn);
+    let ret = unsafe {
+        export_token(
+            session,
+            CK_INVALID_HANDLE,
+            pass.as_ptr() as *mut _,
+            pass.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut archive_len,
+        )
+    };
+    assert_eq!(ret, CKR_DEVICE_ERROR);
+    let conn = rusqlite::Connection::open(name).unwrap();
+    let _ = conn
+        .execute(
+            "DELETE FROM objects WHERE attr = ?1",
+            rusqlite::params![KRA_EXPORT_POLICY],
+        )
+        .unwrap();
+    drop(conn);
+
+    /* only the SO can change the policy */
+    let ret = unsafe { set_export_policy(session, KRF_EXPORT_NON_EXTRACTABLE) };
+    assert_eq!(ret, CKR_USER_NOT_LOGGED_IN);
+    testtokn.logout();
+    let ret = fn_login(
+        session,
+        CKU_SO,
+        SO_PIN.as_ptr() as *mut _,
+        SO_PIN.len() as CK_ULONG,
+    );
+    assert_eq!(ret, CKR_OK);
+    let ret = unsafe { set_export_policy(session, KRF_EXPORT_NON_EXTRACTABLE) };
+    assert_eq!(ret, CKR_OK);
+    testtokn.logout();
+    testtokn.login();
+
+    let ret = unsafe {
+        export_token(
+            session,
+            CK_INVALID_HANDLE,
+            pass.as_ptr() as *mut _,
+            pass.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut archive_len,
+        )
+    };
+    assert_eq!(ret, CKR_OK);
+    let mut archive = vec![0u8; archive_len as usize];
+    let ret = unsafe {
+        export_token(
+            session,
+            CK_INVALID_HANDLE,
+            pass.as_ptr() as *mut _,
+            pass.len() as CK_ULONG,
+            archive.as_mut_ptr(),
+            &mut archive_len,
+        )
+    };
+    assert_eq!(ret, CKR_OK);
+    archive.resize(archive_len as usize, 0);
+
+    /* import into a fresh token with its own KEK */
+    let fresh = "test_token_import.sql";
+    remove_token_files(fresh);
+    let so_pin = SO_PIN.as_bytes().to_vec();
+    let user_pin = USER_PIN.as_bytes().to_vec();
+    let mut label = TOKEN_LABEL.as_bytes().to_vec();
+    label.resize(32, 0x20);
+    let mut token = Token::new(fresh.to_string()).unwrap();
+    token.use_encryption(true);
+    token.initialize(&so_pin, &label).unwrap();
+    token.set_pin(CKU_USER, &user_pin, &vec![0u8; 0]).unwrap();
+    assert_eq!(token.login(CKU_USER, &user_pin), CKR_OK);
+
+    let wrong = "wrong passphrase".as_bytes().to_vec();
+    let err = token.import_archive(None, &wrong, &archive).unwrap_err();
+    assert_eq!(err.rv(), CKR_ENCRYPTED_DATA_INVALID);
+
+    let mut tampered = archive.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    let passphrase = pass.as_bytes().to_vec();
+    let err = token
+        .import_archive(None, &passphrase, &tampered)
+        .unwrap_err();
+    assert_eq!(err.rv(), CKR_ENCRYPTED_DATA_INVALID);
+
+    /* excessive work factors in the header are refused upfront */
+    let header = crate::archive::ArchiveHeader::new(
+        crate::archive::ArchiveKdf::Pbkdf2 {
+            salt: "salt".to_string(),
+            iterations: u32::MAX,
+        },
+    );
+    let err =
+        crate::archive::ArchiveHeader::parse(&header.to_bytes()).unwrap_err();
+    assert_eq!(err.rv(), CKR_ENCRYPTED_DATA_INVALID);
+
+    token.import_archive(None, &passphrase, &archive).unwrap();
+
+    /* only fresh tokens can be restored */
+    let err = token
+        .import_archive(None, &passphrase, &archive)
+        .unwrap_err();
+    assert_eq!(err.rv(), CKR_FUNCTION_REJECTED);
+    drop(token);
+
+    /* the restored keys are readable with the new token KEK */
+    let mut source = Token::new(name.to_string()).unwrap();
+    assert_eq!(source.login(CKU_USER, &user_pin), CKR_OK);
+    let mut token = Token::new(fresh.to_string()).unwrap();
+    assert_eq!(token.login(CKU_USER, &user_pin), CKR_OK);
+    let (_, uids) = token.search_objects(&[]).unwrap();
+    assert_eq!(uids.len(), 4);
+    let orig = object_by_uid(&mut source, "13");
+    let copy = object_by_uid(&mut token, "13");
+    assert_eq!(
+        orig.get_attr_as_bytes(CKA_VALUE).unwrap(),
+        copy.get_attr_as_bytes(CKA_VALUE).unwrap()
+    );
+    let orig = object_by_uid(&mut source, "11");
+    let copy = object_by_uid(&mut token, "11");
+    assert_eq!(
+        orig.get_attr_as_bytes(CKA_PRIVATE_EXPONENT).unwrap(),
+        copy.get_attr_as_bytes(CKA_PRIVATE_EXPONENT).unwrap()
+    );
+    drop(source);
+    drop(token);
+    remove_token_files(fresh);
+
+    testtokn.finalize();
+}
+
+const MIGRATION_AES_KEY: [u8; 16] = [
+    0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b,
+    0x4c, 0x4d, 0x4e, 0x4f,
+];
+
+fn migration_target(target: &str) -> Vec<u8> {
+    remove_token_files(target);
+    let user_pin = USER_PIN.as_bytes().to_vec();
+    let mut label = TOKEN_LABEL.as_bytes().to_vec();
+    label.resize(32, 0x20);
+    let mut token = Token::new(target.to_string()).unwrap();
+    token
+        .initialize(&SO_PIN.as_bytes().to_vec(), &label)
+        .unwrap();
+    token.set_pin(CKU_USER, &user_pin, &vec![0u8; 0]).unwrap();
+    user_pin
+}
+
+fn object_by_label(token: &mut Token, label: &str) -> object::Object {
+    let template = make_ptrs_template(&[(
+        CKA_LABEL,
+        label.as_ptr() as *mut _,
+        label.len(),
+    )]);
+    let (_, mut uids) = token.search_objects(template.as_slice()).unwrap();
+    let handles = token.search_results(&mut uids, &[], 2).unwrap();
+    assert_eq!(handles.len(), 1);
+    token.get_object_by_handle(handles[0]).unwrap()
+}
+
+/* The source databases in testdata/migration are written by
+ * testdata/migration/generate.py */
+
+#[test]
+#[parallel]
+fn test_migrate_softhsm() {
+    let srcdir =
+        "testdata/migration/softhsm/4c3a9b6e-2f1d-4e8a-9b5c-7d6e5f4a3b2c";
+    let target = "test_migrate_softhsm.sql";
+    let user_pin = migration_target(target);
+
+    /* wrong source PIN */
+    let ret = migration::migrate(
+        migration::Source::SoftHsm,
+        srcdir,
+        b"4321",
+        target,
+        &user_pin,
+    );
+    assert!(ret.is_err());
+
+    let report = migration::migrate(
+        migration::Source::SoftHsm,
+        srcdir,
+        b"1234",
+        target,
+        &user_pin,
+    )
+    .unwrap();
+    assert_eq!(report.imported, 2);
+    /* only the wrap and unwrap templates can't be converted */
+    assert_eq!(report.problems.len(), 2);
+
+    let mut token = Token::new(target.to_string()).unwrap();
+    assert_eq!(token.login(CKU_USER, &user_pin), CKR_OK);
+    let key = object_by_label(&mut token, "softhsm key");
+    assert_eq!(
+        key.get_attr_as_bytes(CKA_VALUE).unwrap(),
+        &MIGRATION_AES_KEY.to_vec()
+    );
+    assert_eq!(key.get_attr_as_bytes(CKA_ID).unwrap(), &vec![1u8, 2]);
+    assert_eq!(key.get_attr_as_ulong(CKA_KEY_TYPE).unwrap(), CKK_AES);
+    assert!(key.get_attr_as_bool(CKA_LOCAL).unwrap());
+    assert!(key.get_attr_as_bool(CKA_TOKEN).unwrap());
+    let data = object_by_label(&mut token, "softhsm data");
+    assert_eq!(
+        data.get_attr_as_bytes(CKA_VALUE).unwrap(),
+        &"public data".as_bytes().to_vec()
+    );
+    drop(token);
+
+    remove_token_files(target);
+}
+
+#[test]
+#[parallel]
+fn test_migrate_nss() {
+    let srcdir = "sql:testdata/migration/nss";
+    let target = "test_migrate_nss.sql";
+    let user_pin = migration_target(target);
+
+    /* wrong source password */
+    let ret = migration::migrate(
+        migration::Source::Nss,
+        srcdir,
+        b"wrong password",
+        target,
+        &user_pin,
+    );
+    assert!(ret.is_err());
+
+    /* the key value and the password check are PBES2 encrypted with
+     * NSS's truncated 14 byte AES IVs */
+    let report = migration::migrate(
+        migration::Source::Nss,
+        srcdir,
+        b"nss password",
+        target,
+        &user_pin,
+    )
+    .unwrap();
+    assert_eq!(report.problems, Vec::<String>::new());
+    assert_eq!(report.imported, 2);
+
+    let mut token = Token::new(target.to_string()).unwrap();
+    assert_eq!(token.login(CKU_USER, &user_pin), CKR_OK);
+    let key = object_by_label(&mut token, "nss key");
+    assert_eq!(
+        key.get_attr_as_bytes(CKA_VALUE).unwrap(),
+        &MIGRATION_AES_KEY.to_vec()
+    );
+    assert_eq!(key.get_attr_as_ulong(CKA_VALUE_LEN).unwrap(), 16);
+    assert_eq!(key.get_attr_as_bytes(CKA_ID).unwrap(), &vec![3u8, 4]);
+    assert!(key.get_attr_as_bool(CKA_SENSITIVE).unwrap());
+    assert!(key.get_attr_as_bool(CKA_LOCAL).unwrap());
+    let data = object_by_label(&mut token, "nss data");
+    assert_eq!(
+        data.get_attr_as_bytes(CKA_VALUE).unwrap(),
+        &"public data".as_bytes().to_vec()
+    );
+    drop(token);
+
+    remove_token_files(target);
+}
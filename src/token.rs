@@ -2,11 +2,14 @@
 // See LICENSE.txt file for terms
 
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::vec::Vec;
 
 use super::aes;
+use super::archive;
 use super::attribute;
+#[cfg(not(feature = "fips"))]
+use super::ec_montgomery;
 use super::ecc;
 #[cfg(not(feature = "fips"))]
 use super::eddsa;
@@ -14,24 +17,35 @@ use super::error;
 use super::hash;
 use super::hkdf;
 use super::hmac;
+use super::hw_feature;
 use super::interface;
+use super::kasn1;
 use super::mechanism;
+use super::misc;
 use super::object;
 use super::pbkdf2;
+use super::policy;
+use super::profile;
 use super::rsa;
 use super::sp800_108;
 use super::sshkdf;
 use super::storage;
 use super::tlskdf;
+use super::trust;
 
 use super::{err_rv, get_random_data, sizeof, to_rv};
+use archive::{ArchiveHeader, ArchiveKdf};
 use error::Result;
 use interface::*;
 use mechanism::Mechanisms;
 use object::{Object, ObjectFactories};
+use policy::Policy;
+use storage::json::JsonToken;
 use storage::Storage;
 
+use constant_time_eq::constant_time_eq;
 use hex;
+use zeroize::Zeroize;
 
 #[cfg(feature = "fips")]
 use super::fips;
@@ -51,11 +65,29 @@ const TOKEN_MODEL: &str = "v1";
 const SO_PIN_UID: &str = "0";
 const USER_PIN_UID: &str = "1";
 const TOKEN_INFO_UID: &str = "2";
+const KEK_ROTATION_UID: &str = "3";
+const MONOTONIC_COUNTER_UID: &str = "4";
 
 const MAX_LOGIN_ATTEMPTS: CK_ULONG = 10;
 
+/* flags derived from the PIN objects rather than stored */
+const PIN_FLAGS: CK_FLAGS = CKF_USER_PIN_INITIALIZED
+    | CKF_USER_PIN_COUNT_LOW
+    | CKF_USER_PIN_FINAL_TRY
+    | CKF_USER_PIN_LOCKED
+    | CKF_USER_PIN_TO_BE_CHANGED
+    | CKF_SO_PIN_COUNT_LOW
+    | CKF_SO_PIN_FINAL_TRY
+    | CKF_SO_PIN_LOCKED
+    | CKF_SO_PIN_TO_BE_CHANGED;
+
 const USER_PIN_IV: &str = "USRPIN IV UNWRAP";
 const USER_PIN_AAD: &str = "USRPIN AUTH_DATA";
+/* A KEK wrapped with this AAD means all objects carry integrity tags,
+ * made with the integrity key wrapped next to it in the PIN objects */
+const USER_PIN_TAGGED_AAD: &str = "USRPIN AUTH_DATA TAGGED";
+const INTEGRITY_KEY_IV: &str = "INTKEY IV UNWRAP";
+const INTEGRITY_KEY_AAD: &str = "INTKEY AUTH_DATA";
 const DEFPIN_SALT: &str = "DEFAULT SALT DATA"; /* at least 16 bytes for FIPS */
 const DEFPIN_ITER: usize = 1000;
 const DEFAULT_IV_SIZE: usize = 12; /* 96 bits as required by FIPS for AES GCM */
@@ -70,7 +102,21 @@ fn default_password() -> Vec<u8> {
     vec![0u8; 0]
 }
 
-fn copy_sized_string(s: &[u8], d: &mut [u8]) {
+/* objects with small numeric uids are internal token objects */
+fn is_internal_uid(uid: &str) -> bool {
+    match uid.parse::<usize>() {
+        Ok(n) => n < 10,
+        Err(_) => false,
+    }
+}
+
+/* the token info carries the export policy, so unlike the other
+ * internal objects it is tagged like user objects */
+fn is_tagged_uid(uid: &str) -> bool {
+    !is_internal_uid(uid) || uid == TOKEN_INFO_UID
+}
+
+pub fn copy_sized_string(s: &[u8], d: &mut [u8]) {
     let mut slen = s.len();
     match s.last() {
         None => return,
@@ -140,30 +186,61 @@ impl Handles {
     }
 }
 
+/* registers all mechanisms and factories */
+fn register_all(mechanisms: &mut Mechanisms, factories: &mut ObjectFactories) {
+    object::register(mechanisms, factories);
+    aes::register(mechanisms, factories);
+    rsa::register(mechanisms, factories);
+    ecc::register(mechanisms, factories);
+    #[cfg(not(feature = "fips"))]
+    eddsa::register(mechanisms, factories);
+    #[cfg(not(feature = "fips"))]
+    ec_montgomery::register(mechanisms, factories);
+    hash::register(mechanisms, factories);
+    hmac::register(mechanisms, factories);
+    hkdf::register(mechanisms, factories);
+    pbkdf2::register(mechanisms, factories);
+    sp800_108::register(mechanisms, factories);
+    sshkdf::register(mechanisms, factories);
+    tlskdf::register(mechanisms, factories);
+    trust::register(mechanisms, factories);
+    profile::register(mechanisms, factories);
+    mechanism::register(mechanisms, factories);
+    hw_feature::register(mechanisms, factories);
+
+    #[cfg(feature = "fips")]
+    fips::register(mechanisms, factories);
+}
+
 #[derive(Debug)]
 pub struct Token {
     info: CK_TOKEN_INFO,
     filename: String,
     object_factories: ObjectFactories,
     mechanisms: Mechanisms,
+    policy: Policy,
+    config_policy: Policy,
+    denied_operations: BTreeMap<CK_MECHANISM_TYPE, CK_FLAGS>,
     storage: Box<dyn Storage>,
     session_objects: HashMap<CK_OBJECT_HANDLE, Object>,
     handles: Handles,
     kek: Option<Object>,
+    integrity_key: Option<Object>,
     so_logged_in: bool,
+    in_transaction: bool,
+    generation: u64,
 }
 
 impl Token {
     pub fn new(filename: String) -> Result<Token> {
-        /* when no filename is provided we assume a memory only
-         * token that has no backing store */
-        let store = if filename.ends_with(".json") {
-            storage::json::json()
-        } else if filename.ends_with(".sql") {
-            storage::sqlite::sqlite()
-        } else {
-            storage::memory::memory()
-        };
+        Token::with_dbtype(None, filename)
+    }
+
+    pub fn with_dbtype(
+        dbtype: Option<&str>,
+        filename: String,
+    ) -> Result<Token> {
+        let store = storage::new_storage(dbtype, &filename)?;
 
         let mut token: Token = Token {
             info: CK_TOKEN_INFO {
@@ -189,11 +266,17 @@ impl Token {
             filename: filename,
             object_factories: ObjectFactories::new(),
             mechanisms: Mechanisms::new(),
+            policy: Policy::default(),
+            config_policy: Policy::default(),
+            denied_operations: BTreeMap::new(),
             storage: store,
             session_objects: HashMap::new(),
             handles: Handles::new(),
             kek: None,
+            integrity_key: None,
             so_logged_in: false,
+            in_transaction: false,
+            generation: 0,
         };
 
         /* default strings */
@@ -204,27 +287,14 @@ impl Token {
         );
         copy_sized_string(TOKEN_MODEL.as_bytes(), &mut token.info.model);
 
-        /* register mechanisms and factories */
-        object::register(&mut token.mechanisms, &mut token.object_factories);
-        aes::register(&mut token.mechanisms, &mut token.object_factories);
-        rsa::register(&mut token.mechanisms, &mut token.object_factories);
-        ecc::register(&mut token.mechanisms, &mut token.object_factories);
-        #[cfg(not(feature = "fips"))]
-        eddsa::register(&mut token.mechanisms, &mut token.object_factories);
-        hash::register(&mut token.mechanisms, &mut token.object_factories);
-        hmac::register(&mut token.mechanisms, &mut token.object_factories);
-        hkdf::register(&mut token.mechanisms, &mut token.object_factories);
-        pbkdf2::register(&mut token.mechanisms, &mut token.object_factories);
-        sp800_108::register(&mut token.mechanisms, &mut token.object_factories);
-        sshkdf::register(&mut token.mechanisms, &mut token.object_factories);
-        tlskdf::register(&mut token.mechanisms, &mut token.object_factories);
-
-        #[cfg(feature = "fips")]
-        fips::register(&mut token.mechanisms, &mut token.object_factories);
+        /* the mechanisms are registered by rebuild_mechanisms() once
+         * the operations denied by the SO are loaded */
+        register_all(&mut Mechanisms::new(), &mut token.object_factories);
 
         if token.filename.len() > 0 {
             match token.storage.open(&token.filename) {
                 Ok(()) => {
+                    token.generation = token.storage.generation()?;
                     token.load_token_info()?;
                     token.info.flags |= CKF_TOKEN_INITIALIZED;
                     #[cfg(not(test))]
@@ -252,6 +322,11 @@ impl Token {
         #[cfg(feature = "fips")]
         fips::token_init(&mut token)?;
 
+        token.rebuild_mechanisms();
+        token.refresh_profiles()?;
+        token.refresh_mechanism_objects()?;
+        token.refresh_hw_features()?;
+
         Ok(token)
     }
 
@@ -312,6 +387,11 @@ impl Token {
         self.info.flags = obj
             .get_attr_as_ulong(KRA_FLAGS)
             .map_err(|_| to_rv!(CKR_TOKEN_NOT_RECOGNIZED))?;
+        if let Some(a) = obj.get_attr(KRA_MECHANISM_POLICY) {
+            self.denied_operations =
+                policy::decode_denied_operations(a.get_value())
+                    .map_err(|_| to_rv!(CKR_TOKEN_NOT_RECOGNIZED))?;
+        }
 
         Ok(())
     }
@@ -346,6 +426,7 @@ impl Token {
         ))?;
         obj.set_attr(attribute::from_ulong(KRA_FLAGS, self.info.flags))?;
 
+        self.tag_object(&uid, &mut obj)?;
         self.storage.store(&uid, obj)?;
         return Ok(());
     }
@@ -370,11 +451,13 @@ impl Token {
         Ok(obj)
     }
 
+    /* The wrapped integrity key is left untouched when none is given */
     fn store_pin_object(
         &mut self,
         uid: String,
         label: String,
         wrapped: Vec<u8>,
+        wrapped_ik: Option<Vec<u8>>,
     ) -> Result<()> {
         match self.storage.fetch_by_uid(&uid) {
             Ok(o) => {
@@ -382,6 +465,9 @@ impl Token {
                 obj.set_attr(attribute::from_string(CKA_LABEL, label))?;
                 obj.set_attr(attribute::from_bytes(CKA_VALUE, wrapped))?;
                 obj.set_attr(attribute::from_ulong(KRA_LOGIN_ATTEMPTS, 0))?;
+                if let Some(ik) = wrapped_ik {
+                    obj.set_attr(attribute::from_bytes(KRA_INTEGRITY_KEY, ik))?;
+                }
                 self.storage.store(&uid, obj)?;
             }
             Err(_) => {
@@ -403,6 +489,9 @@ impl Token {
                     MAX_LOGIN_ATTEMPTS,
                 ))?;
                 obj.set_attr(attribute::from_ulong(KRA_LOGIN_ATTEMPTS, 0))?;
+                if let Some(ik) = wrapped_ik {
+                    obj.set_attr(attribute::from_bytes(KRA_INTEGRITY_KEY, ik))?;
+                }
 
                 self.storage.store(&uid, obj)?;
             }
@@ -464,21 +553,41 @@ impl Token {
         )
     }
 
-    fn wrapping_params(&self) -> CK_GCM_PARAMS {
+    fn wrapping_params(
+        &self,
+        iv: &'static str,
+        aad: &'static str,
+    ) -> CK_GCM_PARAMS {
         CK_GCM_PARAMS {
-            pIv: USER_PIN_IV.as_ptr() as *mut CK_BYTE,
-            ulIvLen: USER_PIN_IV.len() as CK_ULONG,
-            ulIvBits: (USER_PIN_IV.len() * 8) as CK_ULONG,
-            pAAD: USER_PIN_AAD.as_ptr() as *mut CK_BYTE,
-            ulAADLen: USER_PIN_AAD.len() as CK_ULONG,
+            pIv: iv.as_ptr() as *mut CK_BYTE,
+            ulIvLen: iv.len() as CK_ULONG,
+            ulIvBits: (iv.len() * 8) as CK_ULONG,
+            pAAD: aad.as_ptr() as *mut CK_BYTE,
+            ulAADLen: aad.len() as CK_ULONG,
             ulTagBits: 64 as CK_ULONG,
         }
     }
 
-    fn wrap_kek(
+    fn wrap_kek(&mut self, wrapper: &Object, kek: Object) -> Result<Vec<u8>> {
+        self.wrap_token_key(wrapper, kek, USER_PIN_IV, USER_PIN_TAGGED_AAD)
+    }
+
+    /* the integrity key is wrapped with its own IV, as the user PIN
+     * key also wraps the KEK */
+    fn wrap_integrity_key(
+        &mut self,
+        wrapper: &Object,
+        ik: Object,
+    ) -> Result<Vec<u8>> {
+        self.wrap_token_key(wrapper, ik, INTEGRITY_KEY_IV, INTEGRITY_KEY_AAD)
+    }
+
+    fn wrap_token_key(
         &mut self,
         wrapper: &Object,
         mut kek: Object,
+        iv: &'static str,
+        aad: &'static str,
     ) -> Result<Vec<u8>> {
         let vlen = kek.get_attr_as_ulong(CKA_VALUE_LEN)?;
         let bs = aes::AES_BLOCK_SIZE;
@@ -490,7 +599,8 @@ impl Token {
         let outlen = aes.wrap_key(
             &CK_MECHANISM {
                 mechanism: CKM_AES_GCM,
-                pParameter: &self.wrapping_params() as *const _ as *mut _,
+                pParameter: &self.wrapping_params(iv, aad) as *const _
+                    as *mut _,
                 ulParameterLen: sizeof!(CK_GCM_PARAMS),
             },
             wrapper,
@@ -502,7 +612,42 @@ impl Token {
         Ok(buf)
     }
 
-    fn unwrap_kek(&self, wrapper: &Object, wrapped: &[u8]) -> Result<Object> {
+    fn unwrap_kek(
+        &self,
+        wrapper: &Object,
+        wrapped: &[u8],
+        aad: &'static str,
+    ) -> Result<Object> {
+        self.unwrap_token_key(wrapper, wrapped, USER_PIN_IV, aad)
+    }
+
+    fn unwrap_integrity_key(
+        &self,
+        wrapper: &Object,
+        obj: &Object,
+    ) -> Result<Object> {
+        let wrapped = match obj.get_attr(KRA_INTEGRITY_KEY) {
+            Some(a) => a.get_value(),
+            None => return err_rv!(CKR_DEVICE_ERROR),
+        };
+        match self.unwrap_token_key(
+            wrapper,
+            wrapped.as_slice(),
+            INTEGRITY_KEY_IV,
+            INTEGRITY_KEY_AAD,
+        ) {
+            Ok(k) => Ok(k),
+            Err(_) => err_rv!(CKR_DEVICE_ERROR),
+        }
+    }
+
+    fn unwrap_token_key(
+        &self,
+        wrapper: &Object,
+        wrapped: &[u8],
+        iv: &'static str,
+        aad: &'static str,
+    ) -> Result<Object> {
         let class = CKO_SECRET_KEY;
         let keytyp = CKK_AES;
         let keylen = aes::MAX_AES_SIZE_BYTES as CK_ULONG;
@@ -517,7 +662,8 @@ impl Token {
         Ok(aes.unwrap_key(
             &CK_MECHANISM {
                 mechanism: CKM_AES_GCM,
-                pParameter: &self.wrapping_params() as *const _ as *mut _,
+                pParameter: &self.wrapping_params(iv, aad) as *const _
+                    as *mut _,
                 ulParameterLen: sizeof!(CK_GCM_PARAMS),
             },
             wrapper,
@@ -537,37 +683,27 @@ impl Token {
         };
         let max = obj.get_attr_as_ulong(KRA_MAX_LOGIN_ATTEMPTS)?;
         let attempts = obj.get_attr_as_ulong(KRA_LOGIN_ATTEMPTS)?;
-        match max - attempts {
-            0 => {
-                if is_so {
-                    self.info.flags |= CKF_SO_PIN_LOCKED;
-                } else {
-                    self.info.flags |= CKF_USER_PIN_LOCKED;
-                }
-            }
-            1 => {
-                if is_so {
-                    self.info.flags |= CKF_SO_PIN_FINAL_TRY;
-                } else {
-                    self.info.flags |= CKF_USER_PIN_FINAL_TRY;
-                }
-            }
-            2 | 3 => {
-                if is_so {
-                    self.info.flags |= CKF_SO_PIN_COUNT_LOW;
-                } else {
-                    self.info.flags |= CKF_USER_PIN_COUNT_LOW;
-                }
-            }
-            _ => {
-                if attempts == 0 {
-                    self.info.flags &= if is_so {
-                        !(CKF_SO_PIN_FINAL_TRY | CKF_SO_PIN_COUNT_LOW)
-                    } else {
-                        !(CKF_USER_PIN_FINAL_TRY | CKF_USER_PIN_COUNT_LOW)
-                    }
-                }
-            }
+        let (locked, final_try, count_low) = if is_so {
+            (
+                CKF_SO_PIN_LOCKED,
+                CKF_SO_PIN_FINAL_TRY,
+                CKF_SO_PIN_COUNT_LOW,
+            )
+        } else {
+            (
+                CKF_USER_PIN_LOCKED,
+                CKF_USER_PIN_FINAL_TRY,
+                CKF_USER_PIN_COUNT_LOW,
+            )
+        };
+        /* the counter may have been changed by another process in
+         * either direction, so always recompute all flags */
+        self.info.flags &= !(locked | final_try | count_low);
+        match max.saturating_sub(attempts) {
+            0 => self.info.flags |= locked,
+            1 => self.info.flags |= final_try,
+            2 | 3 => self.info.flags |= count_low,
+            _ => (),
         }
         Ok(())
     }
@@ -590,16 +726,20 @@ impl Token {
         Ok(())
     }
 
-    fn reset_user_pin(&mut self) -> Result<()> {
+    fn generate_kek(&mut self) -> Result<Object> {
         let class = CKO_SECRET_KEY;
         let keytyp = CKK_AES;
         let keylen = aes::MAX_AES_SIZE_BYTES as CK_ULONG;
-        let mut template = attribute::CkAttrs::with_capacity(3);
+        let truebool: CK_BBOOL = CK_TRUE;
+        /* a new KEK is used right away when rotating */
+        let mut template = attribute::CkAttrs::with_capacity(5);
         template.add_ulong(CKA_CLASS, &class);
         template.add_ulong(CKA_KEY_TYPE, &keytyp);
         template.add_ulong(CKA_VALUE_LEN, &keylen);
+        template.add_bool(CKA_ENCRYPT, &truebool);
+        template.add_bool(CKA_DECRYPT, &truebool);
         let aes = self.mechanisms.get(CKM_AES_KEY_GEN)?;
-        let kek = aes.generate_key(
+        aes.generate_key(
             &CK_MECHANISM {
                 mechanism: CKM_AES_KEY_GEN,
                 pParameter: std::ptr::null_mut(),
@@ -608,19 +748,51 @@ impl Token {
             template.as_slice(),
             &self.mechanisms,
             &self.object_factories,
-        )?;
+        )
+    }
+
+    fn reset_user_pin(&mut self) -> Result<()> {
+        let kek = self.generate_kek()?;
+        /* any pending rotation is for a KEK we are discarding */
+        let _ = self.storage.remove_by_uid(&KEK_ROTATION_UID.to_string());
         /* the default pin is the null pin
          * Except in FIPS mode where OpenSSL refuses empty passwords */
         let key =
             self.pin_to_key(&default_password(), DEFPIN_SALT, DEFPIN_ITER)?;
-        let wrapped = self.wrap_kek(&key, kek)?;
+        /* Without the integrity key, which the SO lacks on tokens tagged
+         * by an upgrade, the KEK is wrapped as a legacy one and objects
+         * are tagged again with a new integrity key at the next login */
+        let (wrapped, wrapped_ik) = match self.integrity_key.clone() {
+            Some(ik) => (
+                self.wrap_kek(&key, kek)?,
+                Some(self.wrap_integrity_key(&key, ik)?),
+            ),
+            None => (
+                self.wrap_token_key(&key, kek, USER_PIN_IV, USER_PIN_AAD)?,
+                None,
+            ),
+        };
         self.store_pin_object(
             USER_PIN_UID.to_string(),
             format!("{}:{}", DEFPIN_SALT, DEFPIN_ITER),
             wrapped,
+            wrapped_ik,
         )
     }
 
+    /* The SO PIN object stores the derived key itself to check the
+     * PIN, so the integrity key is wrapped with a key derived with a
+     * different salt */
+    fn so_integrity_wrapper(
+        &mut self,
+        pin: &Vec<u8>,
+        salt: &str,
+        iterations: usize,
+    ) -> Result<Object> {
+        let salt = format!("{} INTEGRITY", salt);
+        self.pin_to_key(pin, salt.as_str(), iterations)
+    }
+
     fn random_pin_salt(&self) -> Result<String> {
         let mut data = [0u8; 8];
         get_random_data(&mut data)?;
@@ -653,7 +825,7 @@ impl Token {
                      * which will make all existing secrets unreadable */
                     self.reset_user_pin()?;
                 }
-                let kek = if old.len() == 0 {
+                let (kek, ik) = if old.len() == 0 {
                     /* In FIPS mode OpenSSL's PBKDF2 does not accept empty
                      * passwords, so we replace it for a default password
                      * during initialization */
@@ -664,10 +836,12 @@ impl Token {
                 let salt = self.random_pin_salt()?;
                 let key = self.pin_to_key(pin, salt.as_str(), DEFPIN_ITER)?;
                 let wrapped = self.wrap_kek(&key, kek)?;
+                let wrapped_ik = self.wrap_integrity_key(&key, ik)?;
                 self.store_pin_object(
                     USER_PIN_UID.to_string(),
                     format!("{}:{}", salt, DEFPIN_ITER),
                     wrapped,
+                    Some(wrapped_ik),
                 )?;
 
                 if old.len() != 0 {
@@ -675,13 +849,15 @@ impl Token {
                 }
             }
             CKU_SO => {
-                if self.is_initialized() {
+                let ik = if self.is_initialized() {
                     /* When the token is not yet initialized, the set_pin
                      * operation is used to set the initial SO PIN, so we
                      * can't check the old one in that case as we'd fail.
                      */
-                    self.check_so_login(old)?;
-                }
+                    self.check_so_login(old)?
+                } else {
+                    self.integrity_key.clone()
+                };
                 let salt = if pin.len() != 0 {
                     self.random_pin_salt()?
                 } else {
@@ -690,12 +866,24 @@ impl Token {
                 let derived =
                     self.pin_to_key(pin, salt.as_str(), DEFPIN_ITER)?;
                 let value = derived.get_attr_as_bytes(CKA_VALUE)?;
+                let wrapped_ik = match ik {
+                    Some(ik) => {
+                        let wrapper = self.so_integrity_wrapper(
+                            pin,
+                            salt.as_str(),
+                            DEFPIN_ITER,
+                        )?;
+                        Some(self.wrap_integrity_key(&wrapper, ik)?)
+                    }
+                    None => None,
+                };
                 /* TODO: should we store a copy of the kek with
                  * the so token for recovery reasons ? */
                 self.store_pin_object(
                     SO_PIN_UID.to_string(),
                     format!("{}:{}", salt, DEFPIN_ITER),
                     value.clone(),
+                    wrapped_ik,
                 )?;
             }
             _ => return err_rv!(CKR_GENERAL_ERROR),
@@ -704,29 +892,44 @@ impl Token {
     }
 
     pub fn initialize(&mut self, pin: &Vec<u8>, label: &Vec<u8>) -> Result<()> {
+        /* the monotonic counter survives reinitialization */
+        let mut counter = None;
         if self.is_initialized() {
             self.check_so_login(pin)?;
+            counter = self.stored_counter()?;
         };
 
-        /* this inits from scratch or deletes and reinits an existing db */
-        self.storage.reinit()?;
-
         self.handles = Handles::new();
         self.session_objects.clear();
         self.so_logged_in = false;
         self.kek = None;
+        self.integrity_key = None;
 
         /* mark uninitialized otherwise set_pin() will fail trying to verify
          * the SO PIN from storage (which has just been obliterated) */
         self.info.flags &= !CKF_TOKEN_INITIALIZED;
 
-        /* Add SO PIN */
-        self.set_pin(CKU_SO, pin, &vec![0u8; 0])?;
-        /* Generate KEK and store with empty User PIN */
-        self.reset_user_pin()?;
+        let ret = self.transaction(|tok| {
+            /* this inits from scratch or deletes and reinits an existing db */
+            tok.storage.reinit()?;
 
-        copy_sized_string(label.as_slice(), &mut self.info.label);
-        self.store_token_info()?;
+            if let Some(mut obj) = counter {
+                hw_feature::init_counter(&mut obj)?;
+                tok.storage.store(&MONOTONIC_COUNTER_UID.to_string(), obj)?;
+            }
+
+            /* Generate the integrity key shared by the SO and the User */
+            tok.integrity_key = Some(tok.generate_kek()?);
+            /* Add SO PIN */
+            tok.set_pin(CKU_SO, pin, &vec![0u8; 0])?;
+            /* Generate KEK and store with empty User PIN */
+            tok.reset_user_pin()?;
+
+            copy_sized_string(label.as_slice(), &mut tok.info.label);
+            tok.store_token_info()
+        });
+        self.integrity_key = None;
+        ret?;
 
         self.init_pin_flags()?;
 
@@ -735,27 +938,50 @@ impl Token {
             return err_rv!(CKR_GENERAL_ERROR);
         }
 
+        /* the denied operations were stored with the old token info */
+        self.denied_operations.clear();
+        self.rebuild_mechanisms();
+        self.refresh_profiles()?;
+        self.refresh_mechanism_objects()?;
+
         self.info.flags |= CKF_TOKEN_INITIALIZED;
 
+        self.refresh_hw_features()?;
+
         Ok(())
     }
 
+    /* The counter is updated against the currently stored value within
+     * a transaction, so that concurrent failed attempts from different
+     * processes are never lost */
     fn update_pin_attempts(
         &mut self,
-        uid: String,
-        attempts: CK_ULONG,
-    ) -> Result<()> {
-        let mut obj = self.storage.fetch_by_uid(&uid)?.clone();
-        obj.set_attr(attribute::from_ulong(KRA_LOGIN_ATTEMPTS, attempts))?;
-        self.storage.store(&uid, obj)
+        uid: &str,
+        success: bool,
+    ) -> Result<Object> {
+        self.transaction(|tok| {
+            let mut obj = tok.fetch_pin_object(uid)?;
+            let stored = obj.get_attr_as_ulong(KRA_LOGIN_ATTEMPTS)?;
+            let attempts = if success { 0 } else { stored + 1 };
+            if attempts != stored {
+                obj.set_attr(attribute::from_ulong(
+                    KRA_LOGIN_ATTEMPTS,
+                    attempts,
+                ))?;
+                tok.storage.store(&uid.to_string(), obj.clone())?;
+            }
+            Ok(obj)
+        })
     }
 
-    fn check_so_login(&mut self, pin: &Vec<u8>) -> Result<()> {
-        let mut obj = self.fetch_pin_object(SO_PIN_UID)?;
+    /* Returns the integrity key, if the token has a copy for the SO */
+    fn check_so_login(&mut self, pin: &Vec<u8>) -> Result<Option<Object>> {
+        let obj = self.fetch_pin_object(SO_PIN_UID)?;
 
         let stored_attempts = obj.get_attr_as_ulong(KRA_LOGIN_ATTEMPTS)?;
         let max = obj.get_attr_as_ulong(KRA_MAX_LOGIN_ATTEMPTS)?;
         if stored_attempts >= max {
+            self.update_pin_flags(&obj)?;
             return err_rv!(CKR_PIN_LOCKED);
         }
 
@@ -766,24 +992,19 @@ impl Token {
         let stored_value = obj.get_attr_as_bytes(CKA_VALUE)?;
         let value = key.get_attr_as_bytes(CKA_VALUE)?;
 
-        let mut attempts = stored_attempts;
-        if value == stored_value {
-            attempts = 0;
-        } else {
-            attempts += 1;
-        }
-
-        /* Store attempts back to token */
-        if stored_attempts != attempts {
-            let _ = self.update_pin_attempts(SO_PIN_UID.to_string(), attempts);
+        let success = value == stored_value;
 
-            /* set token info */
-            obj.set_attr(attribute::from_ulong(KRA_LOGIN_ATTEMPTS, attempts))?;
-            self.update_pin_flags(&obj)?;
-        }
+        /* Store attempts back to token and set token info */
+        let obj = self.update_pin_attempts(SO_PIN_UID, success)?;
+        self.update_pin_flags(&obj)?;
 
-        if attempts == 0 {
-            return Ok(());
+        if success {
+            if obj.get_attr(KRA_INTEGRITY_KEY).is_none() {
+                return Ok(None);
+            }
+            let wrapper =
+                self.so_integrity_wrapper(pin, salt.as_str(), iterations)?;
+            return Ok(Some(self.unwrap_integrity_key(&wrapper, &obj)?));
         }
         if self.info.flags & CKF_SO_PIN_LOCKED != 0 {
             return err_rv!(CKR_PIN_LOCKED);
@@ -791,12 +1012,14 @@ impl Token {
         return err_rv!(CKR_PIN_INCORRECT);
     }
 
-    fn check_user_login(&mut self, pin: &Vec<u8>) -> Result<Object> {
-        let mut obj = self.fetch_pin_object(USER_PIN_UID)?;
+    /* Returns the KEK and the integrity key */
+    fn check_user_login(&mut self, pin: &Vec<u8>) -> Result<(Object, Object)> {
+        let obj = self.fetch_pin_object(USER_PIN_UID)?;
 
         let stored_attempts = obj.get_attr_as_ulong(KRA_LOGIN_ATTEMPTS)?;
         let max = obj.get_attr_as_ulong(KRA_MAX_LOGIN_ATTEMPTS)?;
         if stored_attempts >= max {
+            self.update_pin_flags(&obj)?;
             return err_rv!(CKR_PIN_LOCKED);
         }
 
@@ -804,32 +1027,46 @@ impl Token {
         let (salt, iterations) = self.parse_pin_label(label.as_str())?;
         let key = self.pin_to_key(pin, salt.as_str(), iterations)?;
 
-        let mut attempts = stored_attempts;
-        let kek = match self
-            .unwrap_kek(&key, obj.get_attr_as_bytes(CKA_VALUE)?.as_slice())
-        {
-            Ok(k) => {
-                attempts = 0;
-                Some(k)
-            }
+        let mut legacy = false;
+        let wrapped = obj.get_attr_as_bytes(CKA_VALUE)?.clone();
+        let kek = match self.unwrap_kek(
+            &key,
+            wrapped.as_slice(),
+            USER_PIN_TAGGED_AAD,
+        ) {
+            Ok(k) => Some(k),
             Err(_) => {
-                attempts += 1;
-                None
+                match self.unwrap_kek(&key, wrapped.as_slice(), USER_PIN_AAD) {
+                    Ok(k) => {
+                        legacy = true;
+                        Some(k)
+                    }
+                    Err(_) => None,
+                }
             }
         };
 
-        /* Store attempts back to token */
-        if stored_attempts != attempts {
-            let _ =
-                self.update_pin_attempts(USER_PIN_UID.to_string(), attempts);
-
-            /* set token info */
-            obj.set_attr(attribute::from_ulong(KRA_LOGIN_ATTEMPTS, attempts))?;
-            self.update_pin_flags(&obj)?;
-        }
-
-        if attempts == 0 {
-            return Ok(kek.unwrap());
+        /* Store attempts back to token and set token info */
+        let obj = self.update_pin_attempts(USER_PIN_UID, kek.is_some())?;
+        self.update_pin_flags(&obj)?;
+
+        if let Some(mut kek) = kek {
+            let ik = if legacy {
+                self.upgrade_integrity_tags(&key, &kek, label.clone())?
+            } else {
+                let ik = self.unwrap_integrity_key(&key, &obj)?;
+                self.check_token_info(&ik)?;
+                ik
+            };
+            if let Some((newkek, wrapped)) = self.pending_kek(&key)? {
+                /* complete a previously interrupted rotation */
+                self.finish_kek_rotation(&kek, &newkek, &ik, wrapped, label)?;
+                if self.kek.is_some() {
+                    self.kek = Some(newkek.clone());
+                }
+                kek = newkek;
+            }
+            return Ok((kek, ik));
         }
         if self.info.flags & CKF_USER_PIN_LOCKED != 0 {
             return err_rv!(CKR_PIN_LOCKED);
@@ -837,110 +1074,557 @@ impl Token {
         return err_rv!(CKR_PIN_INCORRECT);
     }
 
-    pub fn is_logged_in(&self, user_type: CK_USER_TYPE) -> bool {
-        if user_type != CKU_SO && !self.is_login_required() {
-            return true;
+    /* Tokens created before integrity tags were introduced have a KEK
+     * wrapped with the legacy AAD. A new integrity key is generated
+     * and all objects are tagged first, and only then the KEK is
+     * rewrapped, so an interrupted upgrade is simply performed again
+     * at the next login. The SO gets no copy of the integrity key, as
+     * its PIN is not known here */
+    fn upgrade_integrity_tags(
+        &mut self,
+        key: &Object,
+        kek: &Object,
+        label: String,
+    ) -> Result<Object> {
+        let ik = self.generate_kek()?;
+        let wrapped = self.wrap_kek(key, kek.clone())?;
+        let wrapped_ik = self.wrap_integrity_key(key, ik.clone())?;
+        self.transaction(|tok| {
+            let saved = tok.integrity_key.replace(ik.clone());
+            let ret = tok.tag_all_objects();
+            tok.integrity_key = saved;
+            ret?;
+            tok.store_pin_object(
+                USER_PIN_UID.to_string(),
+                label,
+                wrapped,
+                Some(wrapped_ik),
+            )
+        })?;
+        Ok(ik)
+    }
+
+    fn tag_all_objects(&mut self) -> Result<()> {
+        if !self.integrity_enforced() {
+            return Ok(());
         }
-        match user_type {
-            KRY_UNSPEC => self.so_logged_in || self.kek.is_some(),
-            CKU_SO => self.so_logged_in,
-            CKU_USER => self.kek.is_some(),
-            _ => false,
+        for mut obj in self.storage.search(&[])? {
+            let uid = obj.get_attr_as_string(CKA_UNIQUE_ID)?;
+            if !is_tagged_uid(&uid) {
+                continue;
+            }
+            self.tag_object(&uid, &mut obj)?;
+            self.storage.store(&uid, obj)?;
         }
+        Ok(())
     }
 
-    pub fn login(&mut self, user_type: CK_USER_TYPE, pin: &Vec<u8>) -> CK_RV {
-        if !self.is_login_required() {
-            return CKR_OK;
+    /* The token info is only used after a login, so its tag is checked
+     * there rather than when it is loaded */
+    fn check_token_info(&self, ik: &Object) -> Result<()> {
+        if self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED == 0 {
+            return Ok(());
         }
-        match user_type {
-            CKU_SO => {
-                if self.so_logged_in {
-                    return CKR_USER_ALREADY_LOGGED_IN;
-                }
-                if self.kek.is_some() {
-                    return CKR_USER_ANOTHER_ALREADY_LOGGED_IN;
-                }
-                match self.check_so_login(pin) {
-                    Ok(()) => {
-                        self.so_logged_in = true;
-                        CKR_OK
-                    }
-                    Err(e) => e.rv(),
+        let uid = TOKEN_INFO_UID.to_string();
+        let mut obj = match self.storage.fetch_by_uid(&uid) {
+            Ok(o) => o,
+            Err(e) => {
+                if e.attr_not_found() {
+                    return Ok(());
+                } else {
+                    return Err(e);
                 }
             }
-            CKU_USER => {
-                if self.kek.is_some() {
-                    return CKR_USER_ALREADY_LOGGED_IN;
-                }
-                if self.so_logged_in {
-                    return CKR_USER_ANOTHER_ALREADY_LOGGED_IN;
-                }
-                match self.check_user_login(pin) {
-                    Ok(kek) => {
-                        self.kek = Some(kek);
-                        CKR_OK
-                    }
-                    Err(e) => e.rv(),
+        };
+        self.verify_integrity_tag(ik, &uid, &mut obj)
+    }
+
+    fn pending_kek(&self, key: &Object) -> Result<Option<(Object, Vec<u8>)>> {
+        let obj = match self.storage.fetch_by_uid(&KEK_ROTATION_UID.to_string())
+        {
+            Ok(o) => o,
+            Err(e) => {
+                if e.attr_not_found() {
+                    return Ok(None);
+                } else {
+                    return Err(e);
                 }
             }
-            CKU_CONTEXT_SPECIFIC => match self.check_user_login(pin) {
-                Ok(_) => CKR_OK,
-                Err(e) => e.rv(),
-            },
-            _ => CKR_USER_TYPE_INVALID,
-        }
+        };
+        let wrapped = obj.get_attr_as_bytes(CKA_VALUE)?.clone();
+        let kek =
+            match self.unwrap_kek(key, wrapped.as_slice(), USER_PIN_TAGGED_AAD)
+            {
+                Ok(k) => k,
+                Err(_) => return err_rv!(CKR_DEVICE_ERROR),
+            };
+        Ok(Some((kek, wrapped)))
     }
 
-    pub fn logout(&mut self) -> CK_RV {
-        let mut ret = CKR_USER_NOT_LOGGED_IN;
-        if !self.is_login_required() {
-            ret = CKR_OK;
-        }
-        if self.kek.is_some() {
-            self.kek = None;
-            ret = CKR_OK;
-        }
-        if self.so_logged_in {
-            self.so_logged_in = false;
-            ret = CKR_OK;
+    fn reencrypt_objects(
+        &mut self,
+        oldkek: &Object,
+        newkek: &Object,
+    ) -> Result<()> {
+        if self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED == 0 {
+            return Ok(());
         }
-        if ret != CKR_OK {
-            return ret;
+        for raw in self.storage.search(&[])? {
+            let uid = raw.get_attr_as_string(CKA_UNIQUE_ID)?;
+            if is_internal_uid(&uid) {
+                continue;
+            }
+            let mut obj = raw;
+            self.check_integrity_tag(&uid, &mut obj)?;
+            /* skip objects already converted by an interrupted rotation */
+            self.kek = Some(newkek.clone());
+            if self.decrypt_object(&uid, &mut obj.clone()).is_ok() {
+                continue;
+            }
+            self.kek = Some(oldkek.clone());
+            self.decrypt_object(&uid, &mut obj)?;
+            self.kek = Some(newkek.clone());
+            self.object_to_storage(obj, true)?;
         }
-
-        self.clear_private_session_objects();
-
-        CKR_OK
+        Ok(())
     }
 
-    pub fn save(&mut self) -> Result<()> {
-        self.storage.flush()
+    /* Objects are converted one at a time and those already encrypted
+     * with the new KEK are skipped, the user KEK is replaced only once
+     * all objects have been converted, so the rotation can be resumed
+     * at any point from the pending KEK */
+    fn finish_kek_rotation(
+        &mut self,
+        oldkek: &Object,
+        newkek: &Object,
+        ik: &Object,
+        wrapped: Vec<u8>,
+        label: String,
+    ) -> Result<()> {
+        self.transaction(|tok| {
+            let saved = tok.kek.take();
+            let saved_ik = tok.integrity_key.replace(ik.clone());
+            let ret = tok.reencrypt_objects(oldkek, newkek);
+            tok.kek = saved;
+            tok.integrity_key = saved_ik;
+            ret?;
+            tok.store_pin_object(
+                USER_PIN_UID.to_string(),
+                label,
+                wrapped,
+                None,
+            )?;
+            tok.storage.remove_by_uid(&KEK_ROTATION_UID.to_string())
+        })
     }
 
-    fn clear_private_session_objects(&mut self) {
-        let mut priv_handles = Vec::<CK_OBJECT_HANDLE>::new();
-        for (handle, obj) in self.session_objects.iter() {
-            if obj.is_private() {
-                priv_handles.push(*handle);
-            }
+    /* The KEK can only be unwrapped with the user PIN, so the SO can
+     * trigger a rotation too but has to provide the user PIN, and the
+     * SO session does not gain access to the new KEK */
+    pub fn rotate_kek(&mut self, pin: &Vec<u8>) -> Result<()> {
+        let user_logged_in = self.kek.is_some();
+        if !user_logged_in && !self.so_logged_in {
+            return err_rv!(CKR_USER_NOT_LOGGED_IN);
+        }
+        let (kek, ik) = self.check_user_login(pin)?;
+        if user_logged_in {
+            self.kek = Some(kek.clone());
         }
 
-        /* remove all private session objects */
-        for handle in priv_handles {
-            let _ = self.session_objects.remove(&handle);
-            self.handles.remove(handle);
+        let label = self
+            .fetch_pin_object(USER_PIN_UID)?
+            .get_attr_as_string(CKA_LABEL)?;
+        let (salt, iterations) = self.parse_pin_label(label.as_str())?;
+        let key = self.pin_to_key(pin, salt.as_str(), iterations)?;
+        let newkek = self.generate_kek()?;
+        let wrapped = self.wrap_kek(&key, newkek.clone())?;
+        /* store the new KEK first so an interrupted rotation
+         * can be completed at the next login */
+        self.store_pin_object(
+            KEK_ROTATION_UID.to_string(),
+            label.clone(),
+            wrapped.clone(),
+            None,
+        )?;
+        self.finish_kek_rotation(&kek, &newkek, &ik, wrapped, label)?;
+        if user_logged_in {
+            self.kek = Some(newkek);
         }
+        Ok(())
     }
 
-    fn clear_session_objects(&mut self, session: CK_SESSION_HANDLE) {
-        /* intentionally matches only valid handles, as we use invalid
-         * handles in some places to preserve special internal objects
-         * like validation objects in FIPS mode */
-        let mut handles: Vec<CK_OBJECT_HANDLE> = Vec::new();
-        for (_, obj) in self.session_objects.iter() {
-            if obj.get_session() == session {
-                handles.push(obj.get_handle());
+    /* The export policy is kept with the token info, so it can only be
+     * relaxed by the SO and is reset when the token is reinitialized.
+     * The token info is tagged, so the policy is checked against its
+     * tag when used, as another process may have changed it */
+    fn export_policy(&self) -> Result<CK_FLAGS> {
+        let uid = TOKEN_INFO_UID.to_string();
+        match self.storage.fetch_by_uid(&uid).and_then(|mut o| {
+            self.check_integrity_tag(&uid, &mut o)?;
+            o.get_attr_as_ulong(KRA_EXPORT_POLICY)
+        }) {
+            Ok(p) => Ok(p),
+            Err(e) => {
+                if e.attr_not_found() {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    pub fn set_export_policy(&mut self, flags: CK_FLAGS) -> Result<()> {
+        if !self.so_logged_in {
+            return err_rv!(CKR_USER_NOT_LOGGED_IN);
+        }
+        if flags & !KRF_EXPORT_NON_EXTRACTABLE != 0 {
+            return err_rv!(CKR_ARGUMENTS_BAD);
+        }
+        self.store_token_info_attr(attribute::from_ulong(
+            KRA_EXPORT_POLICY,
+            flags,
+        ))
+    }
+
+    /* Sets an attribute of the token info and tags it again */
+    fn store_token_info_attr(
+        &mut self,
+        attr: attribute::Attribute,
+    ) -> Result<()> {
+        let uid = TOKEN_INFO_UID.to_string();
+        self.transaction(|tok| {
+            let mut obj = match tok.storage.fetch_by_uid(&uid) {
+                Ok(o) => o,
+                Err(e) => {
+                    if !e.attr_not_found() {
+                        return Err(e);
+                    }
+                    tok.store_token_info()?;
+                    tok.storage.fetch_by_uid(&uid)?
+                }
+            };
+            tok.check_integrity_tag(&uid, &mut obj)?;
+            obj.set_attr(attr)?;
+            tok.tag_object(&uid, &mut obj)?;
+            tok.storage.store(&uid, obj)
+        })
+    }
+
+    fn archive_key(
+        &mut self,
+        kdf: &ArchiveKdf,
+        key: Option<&Object>,
+        passphrase: &Vec<u8>,
+        op: CK_ATTRIBUTE_TYPE,
+    ) -> Result<Object> {
+        let mut wrapper = match (kdf, key) {
+            (ArchiveKdf::Key, Some(k)) => {
+                k.check_key_ops(CKO_SECRET_KEY, CKK_AES, op)?;
+                k.clone()
+            }
+            (ArchiveKdf::Pbkdf2 { salt, iterations }, None) => self
+                .pin_to_key(passphrase, salt.as_str(), *iterations as usize)?,
+            _ => return err_rv!(CKR_ARGUMENTS_BAD),
+        };
+        /* the archive is bulk data and not a single key, so it goes
+         * through the encryption interface once op has been checked */
+        wrapper.set_attr(attribute::from_bool(CKA_ENCRYPT, true))?;
+        wrapper.set_attr(attribute::from_bool(CKA_DECRYPT, true))?;
+        wrapper.set_zeroize();
+        Ok(wrapper)
+    }
+
+    /* Exports all token objects, decrypted from the token KEK and
+     * encrypted with AES-KWP under the given key or passphrase */
+    pub fn export_archive(
+        &mut self,
+        key: Option<&Object>,
+        passphrase: &Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        if !self.is_logged_in(CKU_USER) {
+            return err_rv!(CKR_USER_NOT_LOGGED_IN);
+        }
+        let unextractable =
+            self.export_policy()? & KRF_EXPORT_NON_EXTRACTABLE != 0;
+        let mut objs = Vec::<Object>::new();
+        for uid in self.storage.search_uids(&[])? {
+            if is_internal_uid(&uid) {
+                continue;
+            }
+            let mut obj = self.object_from_storage(&uid, true)?;
+            obj.set_zeroize();
+            match obj.get_attr_as_ulong(CKA_CLASS)? {
+                CKO_SECRET_KEY | CKO_PRIVATE_KEY => {
+                    if !obj.is_extractable() && !unextractable {
+                        return err_rv!(CKR_KEY_UNEXTRACTABLE);
+                    }
+                }
+                _ => (),
+            }
+            objs.push(obj);
+        }
+
+        let header = ArchiveHeader::new(match key {
+            Some(_) => ArchiveKdf::Key,
+            None => ArchiveKdf::Pbkdf2 {
+                salt: self.random_pin_salt()?,
+                iterations: archive::ARCHIVE_KDF_ITER,
+            },
+        });
+        let wrapper =
+            self.archive_key(&header.kdf, key, passphrase, CKA_WRAP)?;
+        let hdr = header.to_bytes();
+        let mut json = serde_json::to_vec(&JsonToken::from_objects(&objs)?)?;
+        let mut plain = hdr.clone();
+        plain.extend_from_slice(json.as_slice());
+        json.zeroize();
+
+        let aes = self.mechanisms.get(CKM_AES_KEY_WRAP_KWP)?;
+        let mut op = aes.encryption_new(
+            &CK_MECHANISM {
+                mechanism: CKM_AES_KEY_WRAP_KWP,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            },
+            &wrapper,
+        )?;
+        let clen = op.encryption_len(plain.len(), false)?;
+        let mut data = vec![0u8; hdr.len() + clen];
+        data[..hdr.len()].copy_from_slice(&hdr);
+        let ret = op.encrypt(plain.as_slice(), &mut data[hdr.len()..]);
+        plain.zeroize();
+        data.resize(hdr.len() + ret?, 0);
+        Ok(data)
+    }
+
+    /* Imports an archive in a token that holds no objects yet, all
+     * objects are stored encrypted with this token's own KEK */
+    pub fn import_archive(
+        &mut self,
+        key: Option<&Object>,
+        passphrase: &Vec<u8>,
+        data: &[u8],
+    ) -> Result<()> {
+        if !self.is_logged_in(CKU_USER) {
+            return err_rv!(CKR_USER_NOT_LOGGED_IN);
+        }
+        let (header, hlen) = ArchiveHeader::parse(data)?;
+        let wrapper =
+            self.archive_key(&header.kdf, key, passphrase, CKA_UNWRAP)?;
+        let cipher = &data[hlen..];
+        if cipher.len() < 16 {
+            return err_rv!(CKR_ENCRYPTED_DATA_LEN_RANGE);
+        }
+        let aes = self.mechanisms.get(CKM_AES_KEY_WRAP_KWP)?;
+        let mut op = aes.decryption_new(
+            &CK_MECHANISM {
+                mechanism: CKM_AES_KEY_WRAP_KWP,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            },
+            &wrapper,
+        )?;
+        let mut plain = vec![0u8; op.decryption_len(cipher.len(), false)?];
+        let ret = match op.decrypt(cipher, plain.as_mut_slice()) {
+            Ok(outlen) => {
+                /* the clear text header is authenticated by its copy */
+                if outlen < hlen || plain[..hlen] != data[..hlen] {
+                    err_rv!(CKR_ENCRYPTED_DATA_INVALID)
+                } else {
+                    serde_json::from_slice::<JsonToken>(&plain[hlen..outlen])
+                        .map_err(|_| to_rv!(CKR_ENCRYPTED_DATA_INVALID))
+                }
+            }
+            Err(_) => err_rv!(CKR_ENCRYPTED_DATA_INVALID),
+        };
+        plain.zeroize();
+        let objs = ret?.to_objects()?;
+
+        self.transaction(|tok| {
+            for uid in tok.storage.search_uids(&[])? {
+                if !is_internal_uid(&uid) {
+                    return err_rv!(CKR_FUNCTION_REJECTED);
+                }
+            }
+            for (uid, mut obj) in objs {
+                obj.set_zeroize();
+                if is_internal_uid(&uid) || !obj.is_token() {
+                    return err_rv!(CKR_ENCRYPTED_DATA_INVALID);
+                }
+                tok.object_to_storage(obj, true)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn is_logged_in(&self, user_type: CK_USER_TYPE) -> bool {
+        if user_type != CKU_SO && !self.is_login_required() {
+            return true;
+        }
+        match user_type {
+            KRY_UNSPEC => self.so_logged_in || self.kek.is_some(),
+            CKU_SO => self.so_logged_in,
+            CKU_USER => self.kek.is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn login(&mut self, user_type: CK_USER_TYPE, pin: &Vec<u8>) -> CK_RV {
+        if !self.is_login_required() {
+            return CKR_OK;
+        }
+        match user_type {
+            CKU_SO => {
+                if self.so_logged_in {
+                    return CKR_USER_ALREADY_LOGGED_IN;
+                }
+                if self.kek.is_some() {
+                    return CKR_USER_ANOTHER_ALREADY_LOGGED_IN;
+                }
+                match self.check_so_login(pin) {
+                    Ok(ik) => {
+                        self.so_logged_in = true;
+                        self.integrity_key = ik;
+                        CKR_OK
+                    }
+                    Err(e) => e.rv(),
+                }
+            }
+            CKU_USER => {
+                if self.kek.is_some() {
+                    return CKR_USER_ALREADY_LOGGED_IN;
+                }
+                if self.so_logged_in {
+                    return CKR_USER_ANOTHER_ALREADY_LOGGED_IN;
+                }
+                match self.check_user_login(pin) {
+                    Ok((kek, ik)) => {
+                        self.kek = Some(kek);
+                        self.integrity_key = Some(ik);
+                        CKR_OK
+                    }
+                    Err(e) => e.rv(),
+                }
+            }
+            CKU_CONTEXT_SPECIFIC => match self.check_user_login(pin) {
+                Ok(_) => CKR_OK,
+                Err(e) => e.rv(),
+            },
+            _ => CKR_USER_TYPE_INVALID,
+        }
+    }
+
+    pub fn logout(&mut self) -> CK_RV {
+        let mut ret = CKR_USER_NOT_LOGGED_IN;
+        if !self.is_login_required() {
+            ret = CKR_OK;
+        }
+        if self.kek.is_some() {
+            self.kek = None;
+            ret = CKR_OK;
+        }
+        if self.so_logged_in {
+            self.so_logged_in = false;
+            ret = CKR_OK;
+        }
+        if ret != CKR_OK {
+            return ret;
+        }
+        self.integrity_key = None;
+
+        self.clear_private_session_objects();
+
+        CKR_OK
+    }
+
+    /* Runs f within a storage transaction, committing only if it
+     * succeeds, nested calls join the outer transaction */
+    fn transaction<R, F>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Token) -> Result<R>,
+    {
+        if self.in_transaction {
+            return f(self);
+        }
+        self.storage.begin_transaction()?;
+        self.in_transaction = true;
+        let ret = f(self);
+        self.in_transaction = false;
+        match ret {
+            Ok(r) => match self.storage.commit_transaction() {
+                Ok(()) => Ok(r),
+                Err(e) => {
+                    let _ = self.storage.rollback_transaction();
+                    Err(e)
+                }
+            },
+            Err(e) => {
+                let _ = self.storage.rollback_transaction();
+                Err(e)
+            }
+        }
+    }
+
+    /* Whether the storage was modified by another process since we
+     * last looked at it */
+    pub fn is_stale(&self) -> bool {
+        if self.filename.len() == 0 || !self.is_initialized() {
+            return false;
+        }
+        match self.storage.generation() {
+            Ok(generation) => generation != self.generation,
+            Err(_) => false,
+        }
+    }
+
+    /* Reloads the cached token info and PIN flags if the storage was
+     * modified by another process since we last looked at it */
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.filename.len() == 0 || !self.is_initialized() {
+            return Ok(());
+        }
+        let generation = self.storage.generation()?;
+        if generation == self.generation {
+            return Ok(());
+        }
+        /* whether encryption is in use is decided at open time */
+        let restore_key = self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED;
+        self.load_token_info()?;
+        self.info.flags &= !(CKF_RESTORE_KEY_NOT_NEEDED | PIN_FLAGS);
+        self.info.flags |= CKF_TOKEN_INITIALIZED | restore_key;
+        self.init_pin_flags()?;
+        self.generation = generation;
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        self.storage.flush()
+    }
+
+    fn clear_private_session_objects(&mut self) {
+        let mut priv_handles = Vec::<CK_OBJECT_HANDLE>::new();
+        for (handle, obj) in self.session_objects.iter() {
+            if obj.is_private() {
+                priv_handles.push(*handle);
+            }
+        }
+
+        /* remove all private session objects */
+        for handle in priv_handles {
+            let _ = self.session_objects.remove(&handle);
+            self.handles.remove(handle);
+        }
+    }
+
+    fn clear_session_objects(&mut self, session: CK_SESSION_HANDLE) {
+        /* intentionally matches only valid handles, as we use invalid
+         * handles in some places to preserve special internal objects
+         * like validation objects in FIPS mode */
+        let mut handles: Vec<CK_OBJECT_HANDLE> = Vec::new();
+        for (_, obj) in self.session_objects.iter() {
+            if obj.get_session() == session {
+                handles.push(obj.get_handle());
             }
         }
 
@@ -967,30 +1651,155 @@ impl Token {
     }
 
     fn encrypt_value(&self, uid: &String, val: &Vec<u8>) -> Result<Vec<u8>> {
-        if let Some(ref kek) = self.kek {
-            let mut iv = [0u8; DEFAULT_IV_SIZE];
-            get_random_data(&mut iv)?;
-            let mut params = self.encryption_params(&iv, uid.as_bytes());
-            let mech: CK_MECHANISM = CK_MECHANISM {
-                mechanism: CKM_AES_GCM,
-                pParameter: &mut params as *mut interface::CK_GCM_PARAMS
-                    as *mut _,
-                ulParameterLen: sizeof!(CK_GCM_PARAMS),
+        match self.kek {
+            Some(ref kek) => self.encrypt_with(kek, uid, val),
+            None => err_rv!(CKR_GENERAL_ERROR),
+        }
+    }
+
+    fn encrypt_with(
+        &self,
+        key: &Object,
+        uid: &String,
+        val: &Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut iv = [0u8; DEFAULT_IV_SIZE];
+        get_random_data(&mut iv)?;
+        let mut params = self.encryption_params(&iv, uid.as_bytes());
+        let mech: CK_MECHANISM = CK_MECHANISM {
+            mechanism: CKM_AES_GCM,
+            pParameter: &mut params as *mut interface::CK_GCM_PARAMS as *mut _,
+            ulParameterLen: sizeof!(CK_GCM_PARAMS),
+        };
+        let aes = self.mechanisms.get(CKM_AES_GCM)?;
+        let mut op = aes.encryption_new(&mech, key)?;
+        let clen = op.encryption_len(val.len(), false)?;
+        let mut encval = vec![0u8; iv.len() + clen];
+        encval[..iv.len()].copy_from_slice(&iv);
+        let outlen =
+            op.encrypt(val.as_slice(), &mut encval.as_mut_slice()[iv.len()..])?;
+        encval.resize(iv.len() + outlen, 0);
+        Ok(encval)
+    }
+
+    fn integrity_enforced(&self) -> bool {
+        self.integrity_key.is_some()
+            && self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED != 0
+    }
+
+    /* The integrity key wrapped for the User records that the token
+     * enforces integrity, it can't be dropped without the KEK wrapped
+     * with the tagged AAD failing the next login */
+    fn integrity_protected(&self) -> Result<bool> {
+        match self.storage.fetch_by_uid(&USER_PIN_UID.to_string()) {
+            Ok(o) => Ok(o.get_attr(KRA_INTEGRITY_KEY).is_some()),
+            Err(e) => {
+                if e.attr_not_found() {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /* The digest covers the canonical serialization of all the stored
+     * attributes sorted by type, sensitive values are covered in their
+     * encrypted form */
+    fn integrity_digest(&self, obj: &Object) -> Result<Vec<u8>> {
+        let mut attrs: Vec<&attribute::Attribute> = obj
+            .get_attributes()
+            .iter()
+            .filter(|a| a.get_type() != KRA_INTEGRITY_TAG)
+            .collect();
+        attrs.sort_by_key(|a| a.get_type());
+        let mut data = Vec::<u8>::new();
+        for a in attrs {
+            let val = match a.get_attrtype() {
+                attribute::AttrType::BoolType => vec![u8::from(a.to_bool()?)],
+                attribute::AttrType::DenyType
+                | attribute::AttrType::IgnoreType => continue,
+                _ => a.get_value().clone(),
             };
-            let aes = self.mechanisms.get(CKM_AES_GCM)?;
-            let mut op = aes.encryption_new(&mech, &kek)?;
-            let clen = op.encryption_len(val.len(), false)?;
-            let mut encval = vec![0u8; iv.len() + clen];
-            encval[..iv.len()].copy_from_slice(&iv);
-            let outlen = op.encrypt(
-                val.as_slice(),
-                &mut encval.as_mut_slice()[iv.len()..],
-            )?;
-            encval.resize(iv.len() + outlen, 0);
-            return Ok(encval);
-        } else {
-            return err_rv!(CKR_GENERAL_ERROR);
+            data.extend_from_slice(&(a.get_type() as u64).to_be_bytes());
+            data.extend_from_slice(&(val.len() as u64).to_be_bytes());
+            data.extend_from_slice(val.as_slice());
         }
+        let sha = self.mechanisms.get(CKM_SHA256)?;
+        let mut op = sha.digest_new(&CK_MECHANISM {
+            mechanism: CKM_SHA256,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        })?;
+        let mut digest = vec![0u8; op.digest_len()?];
+        op.digest(data.as_slice(), digest.as_mut_slice())?;
+        Ok(digest)
+    }
+
+    /* Replaces any tag of an object about to be stored with a new one,
+     * objects can only be left untagged on tokens never upgraded */
+    fn tag_object(&self, uid: &String, obj: &mut Object) -> Result<()> {
+        obj.del_attr(KRA_INTEGRITY_TAG);
+        if self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED == 0
+            || !is_tagged_uid(uid)
+        {
+            return Ok(());
+        }
+        match self.integrity_key {
+            Some(ref ik) => {
+                let digest = self.integrity_digest(obj)?;
+                let tag = self.encrypt_with(ik, uid, &digest)?;
+                obj.set_attr(attribute::from_bytes(KRA_INTEGRITY_TAG, tag))
+            }
+            None => {
+                if self.integrity_protected()? {
+                    /* an untagged object would be rejected later */
+                    return err_rv!(CKR_USER_NOT_LOGGED_IN);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn check_integrity_tag(
+        &self,
+        uid: &String,
+        obj: &mut Object,
+    ) -> Result<()> {
+        if self.integrity_enforced() && is_tagged_uid(uid) {
+            if let Some(ref ik) = self.integrity_key {
+                return self.verify_integrity_tag(ik, uid, obj);
+            }
+        }
+        /* the tag is a storage artifact, never hand it out */
+        obj.del_attr(KRA_INTEGRITY_TAG);
+        Ok(())
+    }
+
+    /* Once integrity is enforced, untagged objects are rejected like
+     * tampered ones, whatever their attributes claim */
+    fn verify_integrity_tag(
+        &self,
+        ik: &Object,
+        uid: &String,
+        obj: &mut Object,
+    ) -> Result<()> {
+        let tag = match obj.get_attr(KRA_INTEGRITY_TAG) {
+            Some(a) => a.get_value().clone(),
+            None => return err_rv!(CKR_DEVICE_ERROR),
+        };
+        obj.del_attr(KRA_INTEGRITY_TAG);
+        if tag.len() <= DEFAULT_IV_SIZE {
+            return err_rv!(CKR_DEVICE_ERROR);
+        }
+        let digest = match self.decrypt_with(ik, uid, &tag) {
+            Ok(d) => d,
+            Err(_) => return err_rv!(CKR_DEVICE_ERROR),
+        };
+        if !constant_time_eq(&digest, &self.integrity_digest(obj)?) {
+            return err_rv!(CKR_DEVICE_ERROR);
+        }
+        Ok(())
     }
 
     fn object_to_storage(
@@ -1009,47 +1818,60 @@ impl Token {
                 obj.set_attr(attribute::from_bytes(typ, encval))?;
             }
         }
+        self.tag_object(&uid, &mut obj)?;
         self.storage.store(&uid, obj)
     }
 
     fn decrypt_value(&self, uid: &String, val: &Vec<u8>) -> Result<Vec<u8>> {
-        if let Some(ref kek) = self.kek {
-            let mut params = self.encryption_params(
-                &val.as_slice()[..DEFAULT_IV_SIZE],
-                uid.as_bytes(),
-            );
-            let mech: CK_MECHANISM = CK_MECHANISM {
-                mechanism: CKM_AES_GCM,
-                pParameter: &mut params as *mut interface::CK_GCM_PARAMS
-                    as *mut _,
-                ulParameterLen: sizeof!(CK_GCM_PARAMS),
-            };
-            let aes = self.mechanisms.get(CKM_AES_GCM)?;
-            let mut op = aes.decryption_new(&mech, &kek)?;
-            let mut plain =
-                vec![
-                    0u8;
-                    op.decryption_len(val.len() - DEFAULT_IV_SIZE, false)?
-                ];
-            let outlen = op.decrypt(
-                &val.as_slice()[DEFAULT_IV_SIZE..],
-                plain.as_mut_slice(),
-            )?;
-            plain.resize(outlen, 0);
-            return Ok(plain);
-        } else {
-            return err_rv!(CKR_GENERAL_ERROR);
+        match self.kek {
+            Some(ref kek) => self.decrypt_with(kek, uid, val),
+            None => err_rv!(CKR_GENERAL_ERROR),
         }
     }
 
+    fn decrypt_with(
+        &self,
+        key: &Object,
+        uid: &String,
+        val: &Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut params = self.encryption_params(
+            &val.as_slice()[..DEFAULT_IV_SIZE],
+            uid.as_bytes(),
+        );
+        let mech: CK_MECHANISM = CK_MECHANISM {
+            mechanism: CKM_AES_GCM,
+            pParameter: &mut params as *mut interface::CK_GCM_PARAMS as *mut _,
+            ulParameterLen: sizeof!(CK_GCM_PARAMS),
+        };
+        let aes = self.mechanisms.get(CKM_AES_GCM)?;
+        let mut op = aes.decryption_new(&mech, key)?;
+        let mut plain =
+            vec![0u8; op.decryption_len(val.len() - DEFAULT_IV_SIZE, false)?];
+        let outlen = op.decrypt(
+            &val.as_slice()[DEFAULT_IV_SIZE..],
+            plain.as_mut_slice(),
+        )?;
+        plain.resize(outlen, 0);
+        Ok(plain)
+    }
+
     fn object_from_storage(
         &self,
         uid: &String,
         decrypt: bool,
     ) -> Result<Object> {
         let mut obj = self.storage.fetch_by_uid(uid)?;
-        if decrypt && self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED != 0 {
-            let ats = self.object_factories.get_sensitive_attrs(&obj)?;
+        self.check_integrity_tag(uid, &mut obj)?;
+        if decrypt {
+            self.decrypt_object(uid, &mut obj)?;
+        }
+        Ok(obj)
+    }
+
+    fn decrypt_object(&self, uid: &String, obj: &mut Object) -> Result<()> {
+        if self.info.flags & CKF_RESTORE_KEY_NOT_NEEDED != 0 {
+            let ats = self.object_factories.get_sensitive_attrs(obj)?;
             for typ in ats {
                 let encval = obj.get_attr_as_bytes(typ)?;
                 let plain = self.decrypt_value(uid, encval)?;
@@ -1058,7 +1880,7 @@ impl Token {
                 obj.set_attr(attribute::from_bytes(typ, plain))?;
             }
         }
-        Ok(obj)
+        Ok(())
     }
 
     pub fn get_object_by_handle(
@@ -1090,6 +1912,9 @@ impl Token {
         s_handle: CK_SESSION_HANDLE,
         mut obj: Object,
     ) -> Result<CK_OBJECT_HANDLE> {
+        self.policy.check_key(&obj)?;
+        trust::check_creation(&obj, self.so_logged_in)?;
+        self.object_factories.set_check_value(&mut obj)?;
         let uid = obj.get_attr_as_string(CKA_UNIQUE_ID)?;
         let is_token = obj.is_token();
         if is_token {
@@ -1103,22 +1928,207 @@ impl Token {
         obj.set_handle(handle);
         self.handles.insert(handle, uid.clone());
         if obj.is_token() {
-            self.object_to_storage(obj, true)?;
+            if let Err(e) = self.object_to_storage(obj, true) {
+                self.handles.remove(handle);
+                return Err(e);
+            }
         } else {
             self.session_objects.insert(handle, obj);
         }
         Ok(handle)
     }
 
+    /* Inserts all objects or none */
+    pub fn insert_objects(
+        &mut self,
+        s_handle: CK_SESSION_HANDLE,
+        objs: Vec<Object>,
+    ) -> Result<Vec<CK_OBJECT_HANDLE>> {
+        let mut inserted = Vec::<CK_OBJECT_HANDLE>::with_capacity(objs.len());
+        let ret = self.transaction(|tok| {
+            for obj in objs {
+                inserted.push(tok.insert_object(s_handle, obj)?);
+            }
+            Ok(())
+        });
+        if let Err(e) = ret {
+            /* storage has been rolled back, drop handles and
+             * session objects as well */
+            for h in inserted {
+                let _ = self.session_objects.remove(&h);
+                self.handles.remove(h);
+            }
+            return Err(e);
+        }
+        Ok(inserted)
+    }
+
     pub fn create_object(
         &mut self,
         s_handle: CK_SESSION_HANDLE,
         template: &[CK_ATTRIBUTE],
     ) -> Result<CK_OBJECT_HANDLE> {
-        let object = self.object_factories.create(template)?;
+        let object = if template.iter().any(|a| a.type_ == KRA_KEY_DATA) {
+            self.import_key_data(template)?
+        } else {
+            self.object_factories.create(template)?
+        };
         self.insert_object(s_handle, object)
     }
 
+    /* Decrypts a PBES2 encrypted PKCS#8 private key */
+    fn decrypt_private_key_info(
+        &self,
+        epki: &kasn1::EncryptedPrivateKeyInfo,
+        passphrase: &[u8],
+    ) -> Result<Vec<u8>> {
+        let pbes2 = epki.get_pbes2()?;
+        /* the parameters come with the imported data, bound the work
+         * as done for archives */
+        if pbes2.iterations == 0
+            || pbes2.iterations > u64::from(archive::ARCHIVE_KDF_MAX_ITER)
+        {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let params = CK_PKCS5_PBKD2_PARAMS2 {
+            saltSource: CKZ_DATA_SPECIFIED,
+            pSaltSourceData: pbes2.salt.as_ptr() as *const _ as *mut _,
+            ulSaltSourceDataLen: pbes2.salt.len() as CK_ULONG,
+            iterations: pbes2.iterations as CK_ULONG,
+            prf: pbes2.prf,
+            pPrfData: std::ptr::null_mut(),
+            ulPrfDataLen: 0,
+            pPassword: passphrase.as_ptr() as *const _ as *mut _,
+            ulPasswordLen: passphrase.len() as CK_ULONG,
+        };
+        let class = CKO_SECRET_KEY;
+        let keytyp = CKK_AES;
+        let keylen = pbes2.key_len as CK_ULONG;
+        let truebool: CK_BBOOL = CK_TRUE;
+        let mut template = attribute::CkAttrs::with_capacity(4);
+        template.add_ulong(CKA_CLASS, &class);
+        template.add_ulong(CKA_KEY_TYPE, &keytyp);
+        template.add_ulong(CKA_VALUE_LEN, &keylen);
+        template.add_bool(CKA_DECRYPT, &truebool);
+        let pbkdf2 = self.mechanisms.get(CKM_PKCS5_PBKD2)?;
+        let mut key = pbkdf2.generate_key(
+            &CK_MECHANISM {
+                mechanism: CKM_PKCS5_PBKD2,
+                pParameter: &params as *const _ as *mut _,
+                ulParameterLen: sizeof!(CK_PKCS5_PBKD2_PARAMS2),
+            },
+            template.as_slice(),
+            &self.mechanisms,
+            &self.object_factories,
+        )?;
+        key.set_zeroize();
+
+        let aes = self.mechanisms.get(CKM_AES_CBC_PAD)?;
+        let mut op = aes.decryption_new(
+            &CK_MECHANISM {
+                mechanism: CKM_AES_CBC_PAD,
+                pParameter: pbes2.iv.as_ptr() as *const _ as *mut _,
+                ulParameterLen: pbes2.iv.len() as CK_ULONG,
+            },
+            &key,
+        )?;
+        let cipher = epki.get_encrypted_data();
+        let mut plain = vec![0u8; op.decryption_len(cipher.len(), false)?];
+        match op.decrypt(cipher, plain.as_mut_slice()) {
+            Ok(outlen) => {
+                plain.resize(outlen, 0);
+                Ok(plain)
+            }
+            Err(_) => {
+                /* most likely a wrong passphrase */
+                plain.zeroize();
+                err_rv!(CKR_ATTRIBUTE_VALUE_INVALID)
+            }
+        }
+    }
+
+    fn key_data_to_object(
+        &self,
+        der: &[u8],
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Object> {
+        let (class, oid) =
+            match asn1::parse_single::<kasn1::PrivateKeyInfo>(der) {
+                Ok(pki) => (CKO_PRIVATE_KEY, pki.get_oid().clone()),
+                Err(_) => {
+                    match asn1::parse_single::<kasn1::SubjectPublicKeyInfo>(der)
+                    {
+                        Ok(spki) => (CKO_PUBLIC_KEY, spki.get_oid().clone()),
+                        Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+                    }
+                }
+            };
+        let key_type = kasn1::key_type(&oid)?;
+        let mut tmpl = attribute::CkAttrs::from(template);
+        for (typ, val) in [(CKA_CLASS, class), (CKA_KEY_TYPE, key_type)] {
+            match tmpl.remove_ulong(typ)? {
+                Some(v) if v != val => {
+                    return err_rv!(CKR_TEMPLATE_INCONSISTENT)
+                }
+                _ => tmpl.add_owned_ulong(typ, val)?,
+            }
+        }
+        let factory = self
+            .object_factories
+            .get_factory(object::ObjectType::new(class, key_type))?;
+        if class == CKO_PUBLIC_KEY {
+            return factory.import_from_public_key_info(der, tmpl.as_slice());
+        }
+        match factory.import_from_wrapped(der.to_vec(), tmpl.as_slice()) {
+            Err(e) if e.rv() == CKR_WRAPPED_KEY_INVALID => {
+                err_rv!(CKR_ATTRIBUTE_VALUE_INVALID)
+            }
+            r => r,
+        }
+    }
+
+    /* Creates a key object from the PKCS#8 or SubjectPublicKeyInfo
+     * structure in KRA_KEY_DATA, the other attributes of the template
+     * are applied to the new object */
+    fn import_key_data(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        let mut data: Option<Vec<u8>> = None;
+        let mut passphrase: Option<Vec<u8>> = None;
+        let mut attrs = Vec::<CK_ATTRIBUTE>::with_capacity(template.len());
+        for a in template {
+            match a.type_ {
+                KRA_KEY_DATA => data = Some(a.to_buf()?),
+                KRA_KEY_DATA_PASSPHRASE => passphrase = Some(a.to_buf()?),
+                _ => attrs.push(*a),
+            }
+        }
+        let mut der = match data {
+            Some(mut d) => {
+                let der = kasn1::pem_to_der(&d);
+                d.zeroize();
+                der?
+            }
+            None => return err_rv!(CKR_TEMPLATE_INCOMPLETE),
+        };
+        let plain =
+            match asn1::parse_single::<kasn1::EncryptedPrivateKeyInfo>(&der) {
+                Ok(epki) => match &passphrase {
+                    Some(p) => Some(self.decrypt_private_key_info(&epki, p)),
+                    None => Some(err_rv!(CKR_TEMPLATE_INCOMPLETE)),
+                },
+                Err(_) => None,
+            };
+        if let Some(mut p) = passphrase {
+            p.zeroize();
+        }
+        if let Some(p) = plain {
+            der.zeroize();
+            der = p?;
+        }
+        let ret = self.key_data_to_object(&der, attrs.as_slice());
+        der.zeroize();
+        ret
+    }
+
     pub fn destroy_object(&mut self, o_handle: CK_OBJECT_HANDLE) -> Result<()> {
         match self.session_objects.get(&o_handle) {
             Some(obj) => {
@@ -1143,7 +2153,12 @@ impl Token {
         Ok(())
     }
 
-    pub fn get_token_info(&self) -> &CK_TOKEN_INFO {
+    pub fn get_token_info(&mut self) -> &CK_TOKEN_INFO {
+        /* on failure the last known time is reported */
+        if let Ok(t) = misc::utc_time() {
+            self.info.utcTime = t;
+        }
+        self.info.flags |= CKF_CLOCK_ON_TOKEN;
         &self.info
     }
 
@@ -1153,7 +2168,11 @@ impl Token {
         template: &mut [CK_ATTRIBUTE],
     ) -> Result<()> {
         let is_logged = self.is_logged_in(KRY_UNSPEC);
-        let obj = match self.handles.get(o_handle) {
+        let current = match self.hw_feature_type(o_handle)? {
+            Some(feature) => self.hw_feature_attrs(feature, template)?,
+            None => Vec::new(),
+        };
+        let mut obj = match self.handles.get(o_handle) {
             Some(uid) => {
                 if let Some(o) = self.session_objects.get(&o_handle) {
                     Cow::Borrowed(o)
@@ -1167,6 +2186,9 @@ impl Token {
             /* do not reveal if the object exists or not */
             return err_rv!(CKR_OBJECT_HANDLE_INVALID);
         }
+        for attr in current {
+            obj.to_mut().set_attr(attr)?;
+        }
         self.object_factories.get_object_attributes(&obj, template)
     }
 
@@ -1175,11 +2197,19 @@ impl Token {
         o_handle: CK_OBJECT_HANDLE,
         template: &mut [CK_ATTRIBUTE],
     ) -> Result<()> {
+        if self.hw_feature_type(o_handle)? == Some(CKH_MONOTONIC_COUNTER) {
+            return self.set_counter_attrs(template);
+        }
+        if let Some(typ) = self.mechanism_type(o_handle)? {
+            return self.set_mechanism_attrs(typ, template);
+        }
         let uid = match self.handles.get(o_handle) {
             Some(u) => u,
             None => return err_rv!(CKR_OBJECT_HANDLE_INVALID),
         };
+        let so_logged_in = self.so_logged_in;
         if let Some(mut obj) = self.session_objects.get_mut(&o_handle) {
+            trust::check_changes(&obj, template, so_logged_in)?;
             return self
                 .object_factories
                 .set_object_attributes(&mut obj, template);
@@ -1187,6 +2217,7 @@ impl Token {
             /* no need to decrypt because Sensitive attributes
              * cannot be changed via this function */
             let mut obj = self.object_from_storage(uid, false)?;
+            trust::check_changes(&obj, template, so_logged_in)?;
             self.object_factories
                 .set_object_attributes(&mut obj, template)?;
             self.object_to_storage(obj, false)
@@ -1252,17 +2283,21 @@ impl Token {
         self.insert_object(s_handle, newobj)
     }
 
+    /* Session objects are matched right away, while from storage only
+     * the unique ids of matching objects are collected, these are
+     * turned into handles lazily by search_results() */
     pub fn search_objects(
         &mut self,
         template: &[CK_ATTRIBUTE],
-    ) -> Result<Vec<CK_OBJECT_HANDLE>> {
+    ) -> Result<(Vec<CK_OBJECT_HANDLE>, VecDeque<String>)> {
         let mut handles = Vec::<CK_OBJECT_HANDLE>::new();
-        let is_logged_in = self.is_logged_in(KRY_UNSPEC);
+        let types: Vec<CK_ATTRIBUTE_TYPE> =
+            template.iter().map(|a| a.type_).collect();
 
         /* First add internal session objects */
         for (_, o) in &self.session_objects {
             if o.is_sensitive() {
-                match self.object_factories.check_sensitive(o, template) {
+                match self.object_factories.check_sensitive(o, &types) {
                     Err(_) => continue,
                     Ok(()) => (),
                 }
@@ -1272,39 +2307,60 @@ impl Token {
             }
         }
 
-        /* Then search storage */
-        let ret = self.storage.search(template)?;
-        for o in ret {
-            if !is_logged_in && o.is_private() {
-                continue;
-            }
+        /* Then search storage, letting it filter out private objects */
+        let private = CK_FALSE;
+        let mut tmpl = attribute::CkAttrs::from(template);
+        if !self.is_logged_in(KRY_UNSPEC) {
+            tmpl.add_bool(CKA_PRIVATE, &private);
+        }
+        let mut uids = self.storage.search_uids(tmpl.as_slice())?;
 
-            if o.is_sensitive() {
-                match self.object_factories.check_sensitive(&o, template) {
-                    Err(_) => continue,
-                    Ok(()) => (),
-                }
-            }
+        /* do not return internal objects */
+        uids.retain(|uid| !is_internal_uid(uid));
+        Ok((handles, VecDeque::from(uids)))
+    }
 
-            let uid = match o.get_attr_as_string(CKA_UNIQUE_ID) {
-                Ok(s) => s,
-                Err(_) => return err_rv!(CKR_GENERAL_ERROR),
+    /* Returns handles for up to max of the uids found by search_objects(),
+     * objects are fetched only if needed to check the template does not
+     * reference sensitive attributes and are never decrypted */
+    pub fn search_results(
+        &mut self,
+        uids: &mut VecDeque<String>,
+        types: &[CK_ATTRIBUTE_TYPE],
+        max: usize,
+    ) -> Result<Vec<CK_OBJECT_HANDLE>> {
+        let mut handles = Vec::<CK_OBJECT_HANDLE>::new();
+        while handles.len() < max {
+            let uid = match uids.pop_front() {
+                Some(u) => u,
+                None => break,
             };
+            if types.len() > 0 {
+                let obj = match self.storage.fetch_by_uid(&uid) {
+                    Ok(o) => o,
+                    Err(e) => {
+                        /* removed since the search was started */
+                        if e.attr_not_found() {
+                            continue;
+                        }
+                        return Err(e);
+                    }
+                };
+                if obj.is_sensitive() {
+                    match self.object_factories.check_sensitive(&obj, types) {
+                        Err(_) => continue,
+                        Ok(()) => (),
+                    }
+                }
+            }
             let handle = match self.handles.get_by_uid(&uid) {
                 Some(h) => *h,
                 None => {
                     let h = self.handles.next();
-                    self.handles.insert(h, uid.clone());
+                    self.handles.insert(h, uid);
                     h
                 }
             };
-
-            /* do not return internal objects */
-            if let Ok(numuid) = uid.parse::<usize>() {
-                if numuid < 10 {
-                    continue;
-                }
-            }
             handles.push(handle);
         }
         Ok(handles)
@@ -1314,6 +2370,273 @@ impl Token {
         &self.mechanisms
     }
 
+    /* the policy can only further restrict the registered mechanisms */
+    pub fn apply_policy(&mut self, policy: Policy) -> Result<()> {
+        self.config_policy = policy;
+        self.rebuild_mechanisms();
+        self.refresh_profiles()?;
+        self.refresh_mechanism_objects()
+    }
+
+    /* Registers the mechanisms afresh and restricts them with the
+     * configured policy and the operations denied by the SO, so that
+     * lifting a denial makes the operations available again */
+    fn rebuild_mechanisms(&mut self) {
+        let mut policy = self.config_policy.clone();
+        for (typ, ops) in &self.denied_operations {
+            policy.deny_operation(*typ, *ops);
+        }
+        let mut mechanisms = Mechanisms::new();
+        register_all(&mut mechanisms, &mut ObjectFactories::new());
+        policy.apply(&mut mechanisms);
+        self.mechanisms = mechanisms;
+        self.policy = policy;
+    }
+
+    /* Drops the objects of a class that the token synthesizes */
+    fn remove_synthesized_objects(
+        &mut self,
+        class: CK_OBJECT_CLASS,
+    ) -> Result<()> {
+        let mut handles = Vec::<CK_OBJECT_HANDLE>::new();
+        for (handle, obj) in self.session_objects.iter() {
+            if obj.get_attr_as_ulong(CKA_CLASS)? == class {
+                handles.push(*handle);
+            }
+        }
+        for handle in handles {
+            let _ = self.session_objects.remove(&handle);
+            self.handles.remove(handle);
+        }
+        Ok(())
+    }
+
+    /* Replaces the profile objects with the ones matching the
+     * currently available mechanisms */
+    fn refresh_profiles(&mut self) -> Result<()> {
+        self.remove_synthesized_objects(CKO_PROFILE)?;
+        for profile_id in profile::available_profiles(&self.mechanisms) {
+            let obj = profile::profile_object(profile_id)?;
+            /* invalid session handle, like other internal objects */
+            let _ = self.insert_object(CK_INVALID_HANDLE, obj)?;
+        }
+        Ok(())
+    }
+
+    /* Replaces the mechanism objects with one for each of the
+     * currently available mechanisms */
+    fn refresh_mechanism_objects(&mut self) -> Result<()> {
+        self.remove_synthesized_objects(CKO_MECHANISM)?;
+        for typ in self.mechanisms.list() {
+            let profiles = profile::mechanism_profiles(&self.mechanisms, typ);
+            let denied = match self.denied_operations.get(&typ) {
+                Some(ops) => *ops,
+                None => 0,
+            };
+            let obj = mechanism::mechanism_object(typ, &profiles, denied)?;
+            let _ = self.insert_object(CK_INVALID_HANDLE, obj)?;
+        }
+        Ok(())
+    }
+
+    /* Denials never remove mechanisms, so the existing objects are
+     * updated and keep their handles */
+    fn update_mechanism_objects(&mut self) -> Result<()> {
+        for (_, obj) in self.session_objects.iter_mut() {
+            if obj.get_attr_as_ulong(CKA_CLASS)? != CKO_MECHANISM {
+                continue;
+            }
+            let typ = obj.get_attr_as_ulong(CKA_MECHANISM_TYPE)?;
+            let profiles = profile::mechanism_profiles(&self.mechanisms, typ);
+            let denied = match self.denied_operations.get(&typ) {
+                Some(ops) => *ops,
+                None => 0,
+            };
+            mechanism::update_mechanism_object(obj, &profiles, denied)?;
+        }
+        Ok(())
+    }
+
+    /* The counter needs storage, so it is only available once the
+     * token is initialized */
+    fn refresh_hw_features(&mut self) -> Result<()> {
+        self.remove_synthesized_objects(CKO_HW_FEATURE)?;
+        let clock = hw_feature::clock_object()?;
+        let _ = self.insert_object(CK_INVALID_HANDLE, clock)?;
+        if self.is_initialized() {
+            let counter = hw_feature::counter_object()?;
+            let _ = self.insert_object(CK_INVALID_HANDLE, counter)?;
+        }
+        Ok(())
+    }
+
+    fn hw_feature_type(
+        &self,
+        o_handle: CK_OBJECT_HANDLE,
+    ) -> Result<Option<CK_HW_FEATURE_TYPE>> {
+        match self.session_objects.get(&o_handle) {
+            Some(o) => match o.get_attr_as_ulong(CKA_CLASS)? {
+                CKO_HW_FEATURE => {
+                    Ok(Some(o.get_attr_as_ulong(CKA_HW_FEATURE_TYPE)?))
+                }
+                _ => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn mechanism_type(
+        &self,
+        o_handle: CK_OBJECT_HANDLE,
+    ) -> Result<Option<CK_MECHANISM_TYPE>> {
+        match self.session_objects.get(&o_handle) {
+            Some(o) => match o.get_attr_as_ulong(CKA_CLASS)? {
+                CKO_MECHANISM => {
+                    Ok(Some(o.get_attr_as_ulong(CKA_MECHANISM_TYPE)?))
+                }
+                _ => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /* Only the SO can deny operations of a mechanism, the denials are
+     * kept with the token info and restrict the mechanism on top of the
+     * configured policy, the object is otherwise read only */
+    fn set_mechanism_attrs(
+        &mut self,
+        typ: CK_MECHANISM_TYPE,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<()> {
+        let mut denied: Option<CK_FLAGS> = None;
+        for a in template {
+            if a.type_ != KRA_DENIED_OPERATIONS {
+                return err_rv!(CKR_ACTION_PROHIBITED);
+            }
+            denied = Some(a.to_ulong()?);
+        }
+        if !self.so_logged_in {
+            return err_rv!(CKR_USER_NOT_LOGGED_IN);
+        }
+        let denied = match denied {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        if denied & !policy::OPERATION_FLAGS != 0 {
+            return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+        }
+        let mut denied_operations = self.denied_operations.clone();
+        if denied == 0 {
+            denied_operations.remove(&typ);
+        } else {
+            denied_operations.insert(typ, denied);
+        }
+        self.store_token_info_attr(attribute::from_bytes(
+            KRA_MECHANISM_POLICY,
+            policy::encode_denied_operations(&denied_operations),
+        ))?;
+        self.denied_operations = denied_operations;
+        self.rebuild_mechanisms();
+        self.refresh_profiles()?;
+        self.update_mechanism_objects()
+    }
+
+    fn stored_counter(&self) -> Result<Option<Object>> {
+        match self
+            .storage
+            .fetch_by_uid(&MONOTONIC_COUNTER_UID.to_string())
+        {
+            Ok(o) => Ok(Some(o)),
+            Err(e) => {
+                if e.attr_not_found() {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /* The counter is created the first time it is needed */
+    fn fetch_counter(&self) -> Result<Object> {
+        match self.stored_counter()? {
+            Some(o) => Ok(o),
+            None => hw_feature::new_stored_counter(MONOTONIC_COUNTER_UID),
+        }
+    }
+
+    /* Increments the stored counter and returns its new state */
+    fn next_counter(&mut self) -> Result<Object> {
+        self.transaction(|tok| {
+            let mut obj = tok.fetch_counter()?;
+            hw_feature::increment_counter(&mut obj)?;
+            tok.storage
+                .store(&MONOTONIC_COUNTER_UID.to_string(), obj.clone())?;
+            Ok(obj)
+        })
+    }
+
+    /* Current values of a hardware feature, the counter is incremented
+     * only when its value is actually read */
+    fn hw_feature_attrs(
+        &mut self,
+        feature: CK_HW_FEATURE_TYPE,
+        template: &[CK_ATTRIBUTE],
+    ) -> Result<Vec<attribute::Attribute>> {
+        match feature {
+            CKH_CLOCK => Ok(vec![attribute::from_bytes(
+                CKA_VALUE,
+                misc::utc_time()?.to_vec(),
+            )]),
+            CKH_MONOTONIC_COUNTER => {
+                let read = template
+                    .iter()
+                    .any(|a| a.type_ == CKA_VALUE && !a.pValue.is_null());
+                let counter = if read {
+                    self.next_counter()?
+                } else {
+                    self.fetch_counter()?
+                };
+                let mut attrs = Vec::<attribute::Attribute>::new();
+                for typ in [CKA_VALUE, CKA_RESET_ON_INIT, CKA_HAS_RESET] {
+                    if let Some(a) = counter.get_attr(typ) {
+                        attrs.push(a.clone());
+                    }
+                }
+                Ok(attrs)
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /* Only the SO can request that the next C_InitToken resets the
+     * monotonic counter, nothing else can be changed */
+    fn set_counter_attrs(&mut self, template: &[CK_ATTRIBUTE]) -> Result<()> {
+        let mut reset: Option<bool> = None;
+        for a in template {
+            if a.type_ != CKA_RESET_ON_INIT {
+                return err_rv!(CKR_ATTRIBUTE_READ_ONLY);
+            }
+            reset = Some(a.to_bool()?);
+        }
+        if !self.so_logged_in {
+            return err_rv!(CKR_USER_NOT_LOGGED_IN);
+        }
+        let reset = match reset {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        self.transaction(|tok| {
+            let mut obj = tok.fetch_counter()?;
+            obj.set_attr(attribute::from_bool(CKA_RESET_ON_INIT, reset))?;
+            tok.storage.store(&MONOTONIC_COUNTER_UID.to_string(), obj)
+        })
+    }
+
+    pub fn get_policy(&self) -> &Policy {
+        &self.policy
+    }
+
     pub fn get_object_factories(&self) -> &ObjectFactories {
         &self.object_factories
     }
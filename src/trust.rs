@@ -0,0 +1,170 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* CKO_TRUST objects record how far a certificate is trusted for each
+ * purpose. A trust object references its certificate either by issuer
+ * and serial number or by the hash of the certificate value, so that
+ * trust stores can find it with a regular object search. */
+
+use super::attribute;
+use super::error;
+use super::hash;
+use super::interface;
+use super::mechanism;
+use super::object;
+use super::{attr_element, err_rv};
+
+use attribute::{from_bytes, from_ulong};
+use error::Result;
+use interface::*;
+use mechanism::Mechanisms;
+use object::{
+    OAFlags, Object, ObjectAttr, ObjectFactories, ObjectFactory, ObjectType,
+};
+
+use once_cell::sync::Lazy;
+use std::fmt::Debug;
+
+const TRUST_PURPOSES: [CK_ATTRIBUTE_TYPE; 4] = [
+    CKA_PKCS_TRUST_SERVER_AUTH,
+    CKA_PKCS_TRUST_CLIENT_AUTH,
+    CKA_PKCS_TRUST_CODE_SIGNING,
+    CKA_PKCS_TRUST_EMAIL_PROTECTION,
+];
+
+fn check_level(level: CK_TRUST) -> Result<()> {
+    match level {
+        CKT_TRUST_UNKNOWN
+        | CKT_TRUSTED
+        | CKT_TRUST_ANCHOR
+        | CKT_NOT_TRUSTED
+        | CKT_TRUST_MUST_VERIFY_TRUST => Ok(()),
+        _ => err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+    }
+}
+
+/* Trust objects that vouch for a certificate can only be stored by the
+ * SO, like CKA_TRUSTED on certificates, while users can still record
+ * that a certificate is not trusted */
+pub fn check_creation(obj: &Object, so_logged_in: bool) -> Result<()> {
+    if obj.get_attr_as_ulong(CKA_CLASS)? != CKO_TRUST {
+        return Ok(());
+    }
+    for purpose in TRUST_PURPOSES {
+        let level = obj.get_attr_as_ulong(purpose)?;
+        check_level(level)?;
+        match level {
+            CKT_TRUST_UNKNOWN | CKT_NOT_TRUSTED => (),
+            _ => {
+                if !so_logged_in {
+                    return err_rv!(CKR_ATTRIBUTE_READ_ONLY);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/* Only the SO can change how far a certificate is trusted */
+pub fn check_changes(
+    obj: &Object,
+    template: &[CK_ATTRIBUTE],
+    so_logged_in: bool,
+) -> Result<()> {
+    if obj.get_attr_as_ulong(CKA_CLASS)? != CKO_TRUST {
+        return Ok(());
+    }
+    for attr in template {
+        if TRUST_PURPOSES.contains(&attr.type_) {
+            if !so_logged_in {
+                return err_rv!(CKR_ATTRIBUTE_READ_ONLY);
+            }
+            check_level(attr.to_ulong()?)?;
+        }
+    }
+    Ok(())
+}
+
+fn not_empty(obj: &Object, t: CK_ATTRIBUTE_TYPE) -> bool {
+    match obj.get_attr_as_bytes(t) {
+        Ok(v) => v.len() > 0,
+        Err(_) => false,
+    }
+}
+
+#[derive(Debug)]
+struct TrustFactory {
+    attributes: Vec<ObjectAttr>,
+}
+
+impl TrustFactory {
+    fn new() -> TrustFactory {
+        let mut data: TrustFactory = TrustFactory {
+            attributes: Vec::new(),
+        };
+        data.attributes.append(&mut data.init_common_object_attrs());
+        data.attributes
+            .append(&mut data.init_common_storage_attrs());
+        data.attributes.push(attr_element!(
+                CKA_ISSUER; OAFlags::Defval | OAFlags::Unchangeable;
+                from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(
+                CKA_SERIAL_NUMBER; OAFlags::Defval | OAFlags::Unchangeable;
+                from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(
+                CKA_HASH_OF_CERTIFICATE;
+                OAFlags::Defval | OAFlags::Unchangeable;
+                from_bytes; val Vec::new()));
+        data.attributes.push(attr_element!(
+                CKA_NAME_HASH_ALGORITHM;
+                OAFlags::Defval | OAFlags::Unchangeable;
+                from_ulong; val CKM_SHA_1));
+        for purpose in TRUST_PURPOSES {
+            data.attributes.push(attr_element!(
+                    purpose; OAFlags::Defval; from_ulong;
+                    val CKT_TRUST_UNKNOWN));
+        }
+        data
+    }
+}
+
+impl ObjectFactory for TrustFactory {
+    fn create(&self, template: &[CK_ATTRIBUTE]) -> Result<Object> {
+        let obj = self.default_object_create(template)?;
+
+        /* the certificate must be identifiable */
+        let by_serial =
+            not_empty(&obj, CKA_ISSUER) && not_empty(&obj, CKA_SERIAL_NUMBER);
+        let by_hash = not_empty(&obj, CKA_HASH_OF_CERTIFICATE);
+        if !by_serial && !by_hash {
+            return err_rv!(CKR_TEMPLATE_INCOMPLETE);
+        }
+        if by_hash {
+            let hash = obj.get_attr_as_ulong(CKA_NAME_HASH_ALGORITHM)?;
+            let len = match hash::internal_hash_op(hash) {
+                Ok(op) => op.digest_len()?,
+                Err(_) => return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID),
+            };
+            if obj.get_attr_as_bytes(CKA_HASH_OF_CERTIFICATE)?.len() != len {
+                return err_rv!(CKR_ATTRIBUTE_VALUE_INVALID);
+            }
+        }
+
+        for purpose in TRUST_PURPOSES {
+            check_level(obj.get_attr_as_ulong(purpose)?)?;
+        }
+
+        Ok(obj)
+    }
+
+    fn get_attributes(&self) -> &Vec<ObjectAttr> {
+        &self.attributes
+    }
+}
+
+static TRUST_FACTORY: Lazy<Box<dyn ObjectFactory>> =
+    Lazy::new(|| Box::new(TrustFactory::new()));
+
+pub fn register(_: &mut Mechanisms, ot: &mut ObjectFactories) {
+    ot.add_factory(ObjectType::new(CKO_TRUST, 0), &TRUST_FACTORY);
+}
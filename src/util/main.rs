@@ -0,0 +1,940 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Administrative tool, the token is selected through the same
+ * KRYOPTIC_CONF configuration used by the pkcs11 module */
+use std::env;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use kryoptic_pkcs11::interface::*;
+use kryoptic_pkcs11::kasn1;
+use kryoptic_pkcs11::{C_GetFunctionList, C_GetInterface};
+
+mod secret;
+use secret::read_secret;
+
+type Result<T> = std::result::Result<T, String>;
+
+macro_rules! call {
+    ($list:expr, $func:ident($($args:expr),*)) => {
+        match $list.$func {
+            Some(f) => unsafe { f($($args),*) },
+            None => CKR_FUNCTION_NOT_SUPPORTED,
+        }
+    };
+}
+
+fn check(rv: CK_RV, what: &str) -> Result<()> {
+    if rv != CKR_OK {
+        return Err(format!("{} failed: 0x{:x}", what, rv));
+    }
+    Ok(())
+}
+
+fn read_new_secret(prompt: &str) -> Result<Vec<u8>> {
+    let secret = read_secret(prompt)?;
+    if read_secret("Confirm")? != secret {
+        return Err(String::from("Entries do not match"));
+    }
+    Ok(secret)
+}
+
+/* Fixed size PKCS#11 strings are padded with blanks */
+fn padded(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).trim_end().to_string()
+}
+
+fn class_name(class: CK_OBJECT_CLASS) -> String {
+    match class {
+        CKO_DATA => String::from("data"),
+        CKO_CERTIFICATE => String::from("certificate"),
+        CKO_PUBLIC_KEY => String::from("public key"),
+        CKO_PRIVATE_KEY => String::from("private key"),
+        CKO_SECRET_KEY => String::from("secret key"),
+        CKO_HW_FEATURE => String::from("hardware feature"),
+        CKO_MECHANISM => String::from("mechanism"),
+        CKO_PROFILE => String::from("profile"),
+        CKO_TRUST => String::from("trust"),
+        CKO_VALIDATION => String::from("validation"),
+        _ => format!("0x{:x}", class),
+    }
+}
+
+fn key_type_name(key_type: CK_KEY_TYPE) -> String {
+    match key_type {
+        CKK_RSA => String::from("RSA"),
+        CKK_EC => String::from("EC"),
+        CKK_EC_EDWARDS => String::from("EC Edwards"),
+        CKK_EC_MONTGOMERY => String::from("EC Montgomery"),
+        CKK_AES => String::from("AES"),
+        CKK_GENERIC_SECRET => String::from("generic secret"),
+        _ => format!("0x{:x}", key_type),
+    }
+}
+
+enum Kind {
+    Ulong,
+    Bool,
+    Text,
+    Bytes,
+}
+
+const SHOWN_ATTRS: [(CK_ATTRIBUTE_TYPE, &str, Kind); 24] = [
+    (CKA_UNIQUE_ID, "unique id", Kind::Text),
+    (CKA_KEY_TYPE, "key type", Kind::Ulong),
+    (CKA_CERTIFICATE_TYPE, "certificate type", Kind::Ulong),
+    (CKA_LABEL, "label", Kind::Text),
+    (CKA_ID, "id", Kind::Bytes),
+    (CKA_TOKEN, "token", Kind::Bool),
+    (CKA_PRIVATE, "private", Kind::Bool),
+    (CKA_MODIFIABLE, "modifiable", Kind::Bool),
+    (CKA_SENSITIVE, "sensitive", Kind::Bool),
+    (CKA_EXTRACTABLE, "extractable", Kind::Bool),
+    (CKA_LOCAL, "local", Kind::Bool),
+    (CKA_ENCRYPT, "encrypt", Kind::Bool),
+    (CKA_DECRYPT, "decrypt", Kind::Bool),
+    (CKA_SIGN, "sign", Kind::Bool),
+    (CKA_VERIFY, "verify", Kind::Bool),
+    (CKA_WRAP, "wrap", Kind::Bool),
+    (CKA_UNWRAP, "unwrap", Kind::Bool),
+    (CKA_DERIVE, "derive", Kind::Bool),
+    (CKA_VALUE_LEN, "value length", Kind::Ulong),
+    (CKA_MODULUS_BITS, "modulus bits", Kind::Ulong),
+    (CKA_EC_PARAMS, "ec params", Kind::Bytes),
+    (CKA_SUBJECT, "subject", Kind::Bytes),
+    (CKA_SERIAL_NUMBER, "serial number", Kind::Bytes),
+    (CKA_VALIDATION_MODULE_ID, "module id", Kind::Text),
+];
+
+const MECH_FLAGS: [(CK_FLAGS, &str); 12] = [
+    (CKF_ENCRYPT, "encrypt"),
+    (CKF_DECRYPT, "decrypt"),
+    (CKF_DIGEST, "digest"),
+    (CKF_SIGN, "sign"),
+    (CKF_VERIFY, "verify"),
+    (CKF_GENERATE, "generate"),
+    (CKF_GENERATE_KEY_PAIR, "generate_key_pair"),
+    (CKF_WRAP, "wrap"),
+    (CKF_UNWRAP, "unwrap"),
+    (CKF_DERIVE, "derive"),
+    (CKF_SIGN_RECOVER, "sign_recover"),
+    (CKF_VERIFY_RECOVER, "verify_recover"),
+];
+
+const EC_P256: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const EC_P384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+const EC_P521: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x23];
+const EC_ED25519: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+const EC_ED448: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x71];
+
+/* Owns the values a CK_ATTRIBUTE array points to */
+#[derive(Default)]
+struct Template {
+    attrs: Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)>,
+}
+
+impl Template {
+    fn ulong(mut self, typ: CK_ATTRIBUTE_TYPE, val: CK_ULONG) -> Self {
+        self.attrs.push((typ, val.to_ne_bytes().to_vec()));
+        self
+    }
+
+    fn bool(mut self, typ: CK_ATTRIBUTE_TYPE, val: bool) -> Self {
+        let b = if val { CK_TRUE } else { CK_FALSE };
+        self.attrs.push((typ, vec![b]));
+        self
+    }
+
+    fn bytes(mut self, typ: CK_ATTRIBUTE_TYPE, val: &[u8]) -> Self {
+        self.attrs.push((typ, val.to_vec()));
+        self
+    }
+
+    fn label(self, label: Option<&str>) -> Self {
+        match label {
+            Some(l) => self.bytes(CKA_LABEL, l.as_bytes()),
+            None => self,
+        }
+    }
+
+    fn as_ck(&self) -> Vec<CK_ATTRIBUTE> {
+        self.attrs
+            .iter()
+            .map(|(t, v)| CK_ATTRIBUTE {
+                type_: *t,
+                pValue: v.as_ptr() as *mut _,
+                ulValueLen: v.len() as CK_ULONG,
+            })
+            .collect()
+    }
+}
+
+/* The token fills the certificate attributes from the value and decodes
+ * PKCS#8 and SubjectPublicKeyInfo keys passed in KRA_KEY_DATA */
+fn import_template(der: &[u8]) -> Result<(Template, &'static str)> {
+    if kasn1::Certificate::parse(der).is_ok() {
+        let tmpl = Template::default()
+            .ulong(CKA_CLASS, CKO_CERTIFICATE)
+            .ulong(CKA_CERTIFICATE_TYPE, CKC_X_509)
+            .bool(CKA_TOKEN, true)
+            .bytes(CKA_VALUE, der);
+        return Ok((tmpl, "certificate"));
+    }
+    if let Ok(pki) = asn1::parse_single::<kasn1::PrivateKeyInfo>(der) {
+        let key_type = kasn1::key_type(pki.get_oid())
+            .map_err(|_| String::from("Unsupported key algorithm"))?;
+        let tmpl = Template::default()
+            .ulong(CKA_CLASS, CKO_PRIVATE_KEY)
+            .bool(CKA_TOKEN, true)
+            .bool(CKA_PRIVATE, true)
+            .bool(CKA_SENSITIVE, true)
+            .bool(CKA_SIGN, key_type != CKK_EC_MONTGOMERY)
+            .bool(
+                CKA_DERIVE,
+                key_type == CKK_EC || key_type == CKK_EC_MONTGOMERY,
+            )
+            .bool(CKA_DECRYPT, key_type == CKK_RSA)
+            .bytes(KRA_KEY_DATA, der);
+        return Ok((tmpl, "private key"));
+    }
+    if let Ok(spki) = asn1::parse_single::<kasn1::SubjectPublicKeyInfo>(der) {
+        if kasn1::key_type(spki.get_oid()).is_err() {
+            return Err(String::from("Unsupported key algorithm"));
+        }
+        let tmpl = Template::default()
+            .ulong(CKA_CLASS, CKO_PUBLIC_KEY)
+            .bool(CKA_TOKEN, true)
+            .bool(CKA_VERIFY, true)
+            .bytes(KRA_KEY_DATA, der);
+        return Ok((tmpl, "public key"));
+    }
+    Err(String::from(
+        "Not a certificate, PKCS#8 private key or public key",
+    ))
+}
+
+struct Util {
+    fnlist: CK_FUNCTION_LIST,
+    kry: KR_FUNCTION_LIST,
+    session: CK_SESSION_HANDLE,
+}
+
+impl Util {
+    fn new() -> Result<Util> {
+        let mut plist: *mut CK_FUNCTION_LIST = std::ptr::null_mut();
+        check(C_GetFunctionList(&mut plist), "C_GetFunctionList")?;
+        let mut piface: *mut CK_INTERFACE = std::ptr::null_mut();
+        let mut version = CK_VERSION { major: 1, minor: 0 };
+        check(
+            C_GetInterface(
+                "Kryoptic Vendor v1\0".as_ptr() as CK_UTF8CHAR_PTR,
+                &mut version,
+                &mut piface,
+                0,
+            ),
+            "C_GetInterface",
+        )?;
+        let util = unsafe {
+            Util {
+                fnlist: *plist,
+                kry: *((*piface).pFunctionList as *const KR_FUNCTION_LIST),
+                session: CK_INVALID_HANDLE,
+            }
+        };
+        check(
+            call!(util.fnlist, C_Initialize(std::ptr::null_mut())),
+            "C_Initialize",
+        )?;
+        Ok(util)
+    }
+
+    fn open_session(&mut self, slot: CK_SLOT_ID) -> Result<()> {
+        check(
+            call!(
+                self.fnlist,
+                C_OpenSession(
+                    slot,
+                    CKF_SERIAL_SESSION | CKF_RW_SESSION,
+                    std::ptr::null_mut(),
+                    None,
+                    &mut self.session
+                )
+            ),
+            "C_OpenSession",
+        )
+    }
+
+    fn login_with(&self, user_type: CK_USER_TYPE, pin: &[u8]) -> Result<()> {
+        check(
+            call!(
+                self.fnlist,
+                C_Login(
+                    self.session,
+                    user_type,
+                    pin.as_ptr() as *mut _,
+                    pin.len() as CK_ULONG
+                )
+            ),
+            "C_Login",
+        )
+    }
+
+    fn login(&self, user_type: CK_USER_TYPE) -> Result<()> {
+        let prompt = match user_type {
+            CKU_SO => "SO PIN",
+            _ => "User PIN",
+        };
+        self.login_with(user_type, &read_secret(prompt)?)
+    }
+
+    fn list_slots(&self) -> Result<()> {
+        let mut count: CK_ULONG = 0;
+        check(
+            call!(
+                self.fnlist,
+                C_GetSlotList(CK_FALSE, std::ptr::null_mut(), &mut count)
+            ),
+            "C_GetSlotList",
+        )?;
+        let mut slots = vec![0 as CK_SLOT_ID; count as usize];
+        check(
+            call!(
+                self.fnlist,
+                C_GetSlotList(CK_FALSE, slots.as_mut_ptr(), &mut count)
+            ),
+            "C_GetSlotList",
+        )?;
+        slots.resize(count as usize, 0);
+        for slot in slots {
+            let mut sinfo = CK_SLOT_INFO::default();
+            check(
+                call!(self.fnlist, C_GetSlotInfo(slot, &mut sinfo)),
+                "C_GetSlotInfo",
+            )?;
+            println!("Slot {}: {}", slot, padded(&sinfo.slotDescription));
+            let mut tinfo = CK_TOKEN_INFO::default();
+            check(
+                call!(self.fnlist, C_GetTokenInfo(slot, &mut tinfo)),
+                "C_GetTokenInfo",
+            )?;
+            println!("  label:         {}", padded(&tinfo.label));
+            println!("  manufacturer:  {}", padded(&tinfo.manufacturerID));
+            println!("  model:         {}", padded(&tinfo.model));
+            println!("  serial:        {}", padded(&tinfo.serialNumber));
+            println!(
+                "  initialized:   {}",
+                tinfo.flags & CKF_TOKEN_INITIALIZED != 0
+            );
+            println!(
+                "  user pin set:  {}",
+                tinfo.flags & CKF_USER_PIN_INITIALIZED != 0
+            );
+        }
+        Ok(())
+    }
+
+    fn init_token(&self, slot: CK_SLOT_ID, label: &str) -> Result<()> {
+        let mut padlabel = label.as_bytes().to_vec();
+        if padlabel.len() > 32 {
+            return Err(String::from("Labels are limited to 32 bytes"));
+        }
+        padlabel.resize(32, b' ');
+        let pin = read_new_secret("New SO PIN")?;
+        check(
+            call!(
+                self.fnlist,
+                C_InitToken(
+                    slot,
+                    pin.as_ptr() as *mut _,
+                    pin.len() as CK_ULONG,
+                    padlabel.as_mut_ptr()
+                )
+            ),
+            "C_InitToken",
+        )
+    }
+
+    fn init_pin(&self) -> Result<()> {
+        self.login(CKU_SO)?;
+        let pin = read_new_secret("New User PIN")?;
+        check(
+            call!(
+                self.fnlist,
+                C_InitPIN(
+                    self.session,
+                    pin.as_ptr() as *mut _,
+                    pin.len() as CK_ULONG
+                )
+            ),
+            "C_InitPIN",
+        )
+    }
+
+    /* C_SetPIN changes the PIN of the logged in SO, or the user PIN */
+    fn set_pin(&self, who: &str) -> Result<()> {
+        let old = match who {
+            "so" => {
+                let pin = read_secret("Current SO PIN")?;
+                self.login_with(CKU_SO, &pin)?;
+                pin
+            }
+            "user" => read_secret("Current User PIN")?,
+            _ => return Err(format!("Unknown user type: {}", who)),
+        };
+        let new = read_new_secret("New PIN")?;
+        check(
+            call!(
+                self.fnlist,
+                C_SetPIN(
+                    self.session,
+                    old.as_ptr() as *mut _,
+                    old.len() as CK_ULONG,
+                    new.as_ptr() as *mut _,
+                    new.len() as CK_ULONG
+                )
+            ),
+            "C_SetPIN",
+        )
+    }
+
+    fn list_mechs(&self, slot: CK_SLOT_ID) -> Result<()> {
+        let mut count: CK_ULONG = 0;
+        check(
+            call!(
+                self.fnlist,
+                C_GetMechanismList(slot, std::ptr::null_mut(), &mut count)
+            ),
+            "C_GetMechanismList",
+        )?;
+        let mut mechs = vec![0 as CK_MECHANISM_TYPE; count as usize];
+        check(
+            call!(
+                self.fnlist,
+                C_GetMechanismList(slot, mechs.as_mut_ptr(), &mut count)
+            ),
+            "C_GetMechanismList",
+        )?;
+        mechs.resize(count as usize, 0);
+        mechs.sort();
+        for mech in mechs {
+            let mut info = CK_MECHANISM_INFO::default();
+            check(
+                call!(self.fnlist, C_GetMechanismInfo(slot, mech, &mut info)),
+                "C_GetMechanismInfo",
+            )?;
+            let flags: Vec<&str> = MECH_FLAGS
+                .iter()
+                .filter(|(f, _)| info.flags & f != 0)
+                .map(|(_, n)| *n)
+                .collect();
+            println!(
+                "0x{:08x}  keysize {}-{}  {}",
+                mech,
+                info.ulMinKeySize,
+                info.ulMaxKeySize,
+                flags.join(",")
+            );
+        }
+        Ok(())
+    }
+
+    fn find(&self, template: &Template) -> Result<Vec<CK_OBJECT_HANDLE>> {
+        let mut attrs = template.as_ck();
+        check(
+            call!(
+                self.fnlist,
+                C_FindObjectsInit(
+                    self.session,
+                    attrs.as_mut_ptr(),
+                    attrs.len() as CK_ULONG
+                )
+            ),
+            "C_FindObjectsInit",
+        )?;
+        let mut handles = Vec::<CK_OBJECT_HANDLE>::new();
+        loop {
+            let mut batch = [CK_INVALID_HANDLE; 64];
+            let mut count: CK_ULONG = 0;
+            let rv = call!(
+                self.fnlist,
+                C_FindObjects(
+                    self.session,
+                    batch.as_mut_ptr(),
+                    batch.len() as CK_ULONG,
+                    &mut count
+                )
+            );
+            if rv != CKR_OK {
+                let _ = call!(self.fnlist, C_FindObjectsFinal(self.session));
+                return check(rv, "C_FindObjects").map(|_| handles);
+            }
+            if count == 0 {
+                break;
+            }
+            handles.extend_from_slice(&batch[..count as usize]);
+        }
+        check(
+            call!(self.fnlist, C_FindObjectsFinal(self.session)),
+            "C_FindObjectsFinal",
+        )?;
+        Ok(handles)
+    }
+
+    /* Returns None for attributes the object does not have or that
+     * can't be revealed */
+    fn get_attr(
+        &self,
+        handle: CK_OBJECT_HANDLE,
+        typ: CK_ATTRIBUTE_TYPE,
+    ) -> Option<Vec<u8>> {
+        let mut attr = CK_ATTRIBUTE {
+            type_: typ,
+            pValue: std::ptr::null_mut(),
+            ulValueLen: 0,
+        };
+        let rv = call!(
+            self.fnlist,
+            C_GetAttributeValue(self.session, handle, &mut attr, 1)
+        );
+        if rv != CKR_OK || attr.ulValueLen == CK_UNAVAILABLE_INFORMATION {
+            return None;
+        }
+        let mut value = vec![0u8; attr.ulValueLen as usize];
+        attr.pValue = value.as_mut_ptr() as *mut _;
+        let rv = call!(
+            self.fnlist,
+            C_GetAttributeValue(self.session, handle, &mut attr, 1)
+        );
+        if rv != CKR_OK {
+            return None;
+        }
+        value.resize(attr.ulValueLen as usize, 0);
+        Some(value)
+    }
+
+    fn get_ulong(
+        &self,
+        handle: CK_OBJECT_HANDLE,
+        typ: CK_ATTRIBUTE_TYPE,
+    ) -> Option<CK_ULONG> {
+        match self.get_attr(handle, typ) {
+            Some(v) => Some(CK_ULONG::from_ne_bytes(v.try_into().ok()?)),
+            None => None,
+        }
+    }
+
+    fn list_objects(&self) -> Result<()> {
+        self.login(CKU_USER)?;
+        for handle in self.find(&Template::default())? {
+            let class = match self.get_ulong(handle, CKA_CLASS) {
+                Some(c) => class_name(c),
+                None => String::from("unknown"),
+            };
+            println!("Object {}: {}", handle, class);
+            for (typ, name, kind) in &SHOWN_ATTRS {
+                let value = match self.get_attr(handle, *typ) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let text = match kind {
+                    Kind::Ulong
+                        if value.len() == std::mem::size_of::<CK_ULONG>() =>
+                    {
+                        let val = CK_ULONG::from_ne_bytes(
+                            value.as_slice().try_into().unwrap(),
+                        );
+                        match *typ {
+                            CKA_KEY_TYPE => key_type_name(val),
+                            _ => val.to_string(),
+                        }
+                    }
+                    Kind::Bool if value.len() == 1 => {
+                        (value[0] != CK_FALSE).to_string()
+                    }
+                    Kind::Text => String::from_utf8_lossy(&value).to_string(),
+                    _ => hex::encode(&value),
+                };
+                println!("  {:<17} {}", format!("{}:", name), text);
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&self, template: &Template) -> Result<CK_OBJECT_HANDLE> {
+        let mut attrs = template.as_ck();
+        let mut handle = CK_INVALID_HANDLE;
+        check(
+            call!(
+                self.fnlist,
+                C_CreateObject(
+                    self.session,
+                    attrs.as_mut_ptr(),
+                    attrs.len() as CK_ULONG,
+                    &mut handle
+                )
+            ),
+            "C_CreateObject",
+        )?;
+        Ok(handle)
+    }
+
+    fn import(&self, path: &str, label: Option<&str>) -> Result<()> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let items = kasn1::pem_to_der_all(&data)
+            .map_err(|_| format!("{}: Invalid PEM data", path))?;
+        self.login(CKU_USER)?;
+        for der in &items {
+            let (tmpl, what) =
+                import_template(der).map_err(|e| format!("{}: {}", path, e))?;
+            let handle = self.create(&tmpl.label(label))?;
+            println!("Imported {} as object {}", what, handle);
+        }
+        Ok(())
+    }
+
+    fn generate(&self, kind: &str, label: Option<&str>) -> Result<()> {
+        self.login(CKU_USER)?;
+        let (mechanism, public) = match kind {
+            "aes128" | "aes192" | "aes256" => (CKM_AES_KEY_GEN, None),
+            "rsa2048" | "rsa3072" | "rsa4096" => (
+                CKM_RSA_PKCS_KEY_PAIR_GEN,
+                Some(
+                    Template::default()
+                        .ulong(CKA_MODULUS_BITS, kind[3..].parse().unwrap())
+                        .bytes(CKA_PUBLIC_EXPONENT, &[0x01, 0x00, 0x01])
+                        .bool(CKA_ENCRYPT, true),
+                ),
+            ),
+            "p256" | "p384" | "p521" => (
+                CKM_EC_KEY_PAIR_GEN,
+                Some(Template::default().bytes(
+                    CKA_EC_PARAMS,
+                    match kind {
+                        "p256" => EC_P256,
+                        "p384" => EC_P384,
+                        _ => EC_P521,
+                    },
+                )),
+            ),
+            "ed25519" | "ed448" => (
+                CKM_EC_EDWARDS_KEY_PAIR_GEN,
+                Some(Template::default().bytes(
+                    CKA_EC_PARAMS,
+                    match kind {
+                        "ed25519" => EC_ED25519,
+                        _ => EC_ED448,
+                    },
+                )),
+            ),
+            _ => return Err(format!("Unknown key type: {}", kind)),
+        };
+        let mut mech = CK_MECHANISM {
+            mechanism: mechanism,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let private = Template::default()
+            .bool(CKA_TOKEN, true)
+            .bool(CKA_PRIVATE, true)
+            .bool(CKA_SENSITIVE, true)
+            .label(label);
+        let public = match public {
+            None => {
+                let bits: CK_ULONG = kind[3..].parse().unwrap();
+                let mut attrs = private
+                    .ulong(CKA_VALUE_LEN, bits / 8)
+                    .bool(CKA_ENCRYPT, true)
+                    .bool(CKA_DECRYPT, true)
+                    .bool(CKA_WRAP, true)
+                    .bool(CKA_UNWRAP, true)
+                    .as_ck();
+                let mut handle = CK_INVALID_HANDLE;
+                check(
+                    call!(
+                        self.fnlist,
+                        C_GenerateKey(
+                            self.session,
+                            &mut mech,
+                            attrs.as_mut_ptr(),
+                            attrs.len() as CK_ULONG,
+                            &mut handle
+                        )
+                    ),
+                    "C_GenerateKey",
+                )?;
+                println!("Generated secret key as object {}", handle);
+                return Ok(());
+            }
+            Some(p) => p,
+        };
+        let mut pubattrs = public
+            .bool(CKA_TOKEN, true)
+            .bool(CKA_VERIFY, true)
+            .label(label)
+            .as_ck();
+        let mut privattrs = private
+            .bool(CKA_SIGN, true)
+            .bool(CKA_DECRYPT, mechanism == CKM_RSA_PKCS_KEY_PAIR_GEN)
+            .bool(CKA_DERIVE, mechanism == CKM_EC_KEY_PAIR_GEN)
+            .as_ck();
+        let mut pubkey = CK_INVALID_HANDLE;
+        let mut privkey = CK_INVALID_HANDLE;
+        check(
+            call!(
+                self.fnlist,
+                C_GenerateKeyPair(
+                    self.session,
+                    &mut mech,
+                    pubattrs.as_mut_ptr(),
+                    pubattrs.len() as CK_ULONG,
+                    privattrs.as_mut_ptr(),
+                    privattrs.len() as CK_ULONG,
+                    &mut pubkey,
+                    &mut privkey
+                )
+            ),
+            "C_GenerateKeyPair",
+        )?;
+        println!(
+            "Generated public key as object {} and private key as object {}",
+            pubkey, privkey
+        );
+        Ok(())
+    }
+
+    fn delete(&self, uid: &str) -> Result<()> {
+        self.login(CKU_USER)?;
+        let handles = self
+            .find(&Template::default().bytes(CKA_UNIQUE_ID, uid.as_bytes()))?;
+        if handles.len() == 0 {
+            return Err(format!("No object with unique id {}", uid));
+        }
+        for handle in handles {
+            check(
+                call!(self.fnlist, C_DestroyObject(self.session, handle)),
+                "C_DestroyObject",
+            )?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "fips")]
+    fn fips_status(&self) -> Result<()> {
+        let mut piface: *mut CK_INTERFACE = std::ptr::null_mut();
+        let mut version = CK_VERSION { major: 0, minor: 1 };
+        check(
+            C_GetInterface(
+                "Kryoptic Validation v1\0".as_ptr() as CK_UTF8CHAR_PTR,
+                &mut version,
+                &mut piface,
+                0,
+            ),
+            "C_GetInterface",
+        )?;
+        let val = unsafe {
+            *((*piface).pFunctionList
+                as *const kryoptic_pkcs11::CK_FUNCTION_LIST_VAL)
+        };
+        println!("FIPS mode: enabled");
+        let validations =
+            self.find(&Template::default().ulong(CKA_CLASS, CKO_VALIDATION))?;
+        for handle in validations {
+            if let Some(id) = self.get_attr(handle, CKA_VALIDATION_MODULE_ID) {
+                println!("  module:        {}", String::from_utf8_lossy(&id));
+            }
+            if let Some(l) = self.get_ulong(handle, CKA_VALIDATION_LEVEL) {
+                println!("  level:         {}", l);
+            }
+            if let Some(f) = self.get_ulong(handle, CKA_VALIDATION_FLAG) {
+                println!("  flag:          0x{:x}", f);
+            }
+        }
+        let mut flags: CK_FLAGS = 0;
+        check(
+            call!(
+                val,
+                C_GetSessionValidationFlags(
+                    self.session,
+                    CKS_LAST_VALIDATION_OK,
+                    &mut flags
+                )
+            ),
+            "C_GetSessionValidationFlags",
+        )?;
+        println!("  last operation validation flags: 0x{:x}", flags);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fips"))]
+    fn fips_status(&self) -> Result<()> {
+        println!("FIPS mode: disabled");
+        Ok(())
+    }
+
+    fn export(&self, path: &str) -> Result<()> {
+        self.login(CKU_USER)?;
+        let pass = read_new_secret("Archive passphrase")?;
+        let mut len: CK_ULONG = 0;
+        check(
+            call!(
+                self.kry,
+                KR_ExportToken(
+                    self.session,
+                    CK_INVALID_HANDLE,
+                    pass.as_ptr() as *mut _,
+                    pass.len() as CK_ULONG,
+                    std::ptr::null_mut(),
+                    &mut len
+                )
+            ),
+            "KR_ExportToken",
+        )?;
+        let mut archive = vec![0u8; len as usize];
+        check(
+            call!(
+                self.kry,
+                KR_ExportToken(
+                    self.session,
+                    CK_INVALID_HANDLE,
+                    pass.as_ptr() as *mut _,
+                    pass.len() as CK_ULONG,
+                    archive.as_mut_ptr(),
+                    &mut len
+                )
+            ),
+            "KR_ExportToken",
+        )?;
+        archive.resize(len as usize, 0);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        file.write_all(&archive)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn import_archive(&self, path: &str) -> Result<()> {
+        let archive =
+            std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.login(CKU_USER)?;
+        let pass = read_secret("Archive passphrase")?;
+        check(
+            call!(
+                self.kry,
+                KR_ImportToken(
+                    self.session,
+                    CK_INVALID_HANDLE,
+                    pass.as_ptr() as *mut _,
+                    pass.len() as CK_ULONG,
+                    archive.as_ptr() as *mut _,
+                    archive.len() as CK_ULONG
+                )
+            ),
+            "KR_ImportToken",
+        )
+    }
+
+    fn export_policy(&self, policy: &str) -> Result<()> {
+        let flags = match policy {
+            "default" => 0,
+            "non-extractable" => KRF_EXPORT_NON_EXTRACTABLE,
+            _ => return Err(format!("Unknown export policy: {}", policy)),
+        };
+        self.login(CKU_SO)?;
+        check(
+            call!(self.kry, KR_SetExportPolicy(self.session, flags)),
+            "KR_SetExportPolicy",
+        )
+    }
+}
+
+impl Drop for Util {
+    fn drop(&mut self) {
+        if self.session != CK_INVALID_HANDLE {
+            let _ = call!(self.fnlist, C_Logout(self.session));
+            let _ = call!(self.fnlist, C_CloseSession(self.session));
+        }
+        let _ = call!(self.fnlist, C_Finalize(std::ptr::null_mut()));
+    }
+}
+
+fn usage(name: &str) -> ! {
+    eprintln!("usage: {} <command> [slot] [args]", name);
+    eprintln!("commands:");
+    eprintln!("  list-slots                      show slots and tokens");
+    eprintln!("  init <slot> <label>             initialize, sets the SO PIN");
+    eprintln!("  init-pin <slot>                 set the User PIN as SO");
+    eprintln!("  set-pin <slot> <user|so>        change a PIN");
+    eprintln!("  list-mechs <slot>               show supported mechanisms");
+    eprintln!("  list-objects <slot>             show objects and attributes");
+    eprintln!("  import <slot> <file> [label]    import PEM or DER keys and");
+    eprintln!("                                  certificates");
+    eprintln!("  generate <slot> <type> [label]  generate a key of type");
+    eprintln!("                                  aes128, aes192, aes256,");
+    eprintln!("                                  rsa2048, rsa3072, rsa4096,");
+    eprintln!("                                  p256, p384, p521, ed25519");
+    eprintln!("                                  or ed448");
+    eprintln!("  delete <slot> <unique-id>       delete an object");
+    eprintln!("  fips <slot>                     show FIPS indicators");
+    eprintln!("  export <slot> <archive>         back up all token objects");
+    eprintln!("  import-archive <slot> <archive> restore a backup in a fresh");
+    eprintln!("                                  token");
+    eprintln!("  export-policy <slot> <default|non-extractable>");
+    eprintln!("                                  set exportable keys");
+    std::process::exit(1);
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let mut util = Util::new()?;
+    if args[1] == "list-slots" {
+        return util.list_slots();
+    }
+    if args.len() < 3 {
+        usage(&args[0]);
+    }
+    let slot = match args[2].parse::<CK_SLOT_ID>() {
+        Ok(s) => s,
+        Err(_) => return Err(format!("Invalid slot: {}", args[2])),
+    };
+    let arg = |n: usize| match args.get(n) {
+        Some(a) => a.as_str(),
+        None => usage(&args[0]),
+    };
+    let label = args.get(4).map(|l| l.as_str());
+    /* tokens can't be initialized while sessions are open */
+    if args[1] == "init" {
+        return util.init_token(slot, arg(3));
+    }
+    util.open_session(slot)?;
+    match args[1].as_str() {
+        "init-pin" => util.init_pin(),
+        "set-pin" => util.set_pin(arg(3)),
+        "list-mechs" => util.list_mechs(slot),
+        "list-objects" => util.list_objects(),
+        "import" => util.import(arg(3), label),
+        "generate" => util.generate(arg(3), label),
+        "delete" => util.delete(arg(3)),
+        "fips" => util.fips_status(),
+        "export" => util.export(arg(3)),
+        "import-archive" => util.import_archive(arg(3)),
+        "export-policy" => util.export_policy(arg(3)),
+        _ => usage(&args[0]),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 5 {
+        usage(&args[0]);
+    }
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright 2024 Simo Sorce
+// See LICENSE.txt file for terms
+
+/* Shared with the other command line tools */
+use std::io::{BufRead, Write};
+
+/* Reads a line from stdin, with echo disabled when it is a terminal */
+pub fn read_secret(prompt: &str) -> Result<Vec<u8>, String> {
+    eprint!("{}: ", prompt);
+    let _ = std::io::stderr().flush();
+    let fd = libc::STDIN_FILENO;
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    let tty = unsafe { libc::tcgetattr(fd, &mut term) } == 0;
+    if tty {
+        let mut noecho = term;
+        noecho.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &noecho) };
+    }
+    let mut line = String::new();
+    let ret = std::io::stdin().lock().read_line(&mut line);
+    if tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        eprintln!();
+    }
+    match ret {
+        Ok(_) => Ok(line
+            .trim_end_matches(|c: char| c == '\r' || c == '\n')
+            .as_bytes()
+            .to_vec()),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,397 @@
+#!/usr/bin/env python3
+# Copyright 2024 Simo Sorce
+# See LICENSE.txt file for terms
+
+# Writes the SoftHSMv2 and NSS token databases used by the migration
+# tests. The files follow the on-disk formats of SoftHSMv2 2.6 (file
+# backend) and NSS softokn (sqlite backend), the encryption is done with
+# pyca/cryptography so that it is independent of the code being tested.
+#
+# SoftHSM token: softhsm/4c3a9b6e-2f1d-4e8a-9b5c-7d6e5f4a3b2c, PIN "1234"
+# NSS database:  nss/, password "nss password"
+
+import hashlib
+import os
+import shutil
+import sqlite3
+import struct
+
+from cryptography.hazmat.primitives import hashes, padding
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.pbkdf2 import PBKDF2HMAC
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+CKO_DATA = 0
+CKO_SECRET_KEY = 4
+CKK_AES = 0x1F
+CKM_AES_KEY_GEN = 0x1080
+
+CKA_CLASS = 0x0
+CKA_TOKEN = 0x1
+CKA_PRIVATE = 0x2
+CKA_LABEL = 0x3
+CKA_APPLICATION = 0x10
+CKA_VALUE = 0x11
+CKA_OBJECT_ID = 0x12
+CKA_TRUSTED = 0x86
+CKA_CHECK_VALUE = 0x90
+CKA_KEY_TYPE = 0x100
+CKA_ID = 0x102
+CKA_SENSITIVE = 0x103
+CKA_ENCRYPT = 0x104
+CKA_DECRYPT = 0x105
+CKA_WRAP = 0x106
+CKA_UNWRAP = 0x107
+CKA_SIGN = 0x108
+CKA_VERIFY = 0x10A
+CKA_DERIVE = 0x10C
+CKA_START_DATE = 0x110
+CKA_END_DATE = 0x111
+CKA_VALUE_LEN = 0x161
+CKA_EXTRACTABLE = 0x162
+CKA_LOCAL = 0x163
+CKA_NEVER_EXTRACTABLE = 0x164
+CKA_ALWAYS_SENSITIVE = 0x165
+CKA_KEY_GEN_MECHANISM = 0x166
+CKA_MODIFIABLE = 0x170
+CKA_COPYABLE = 0x171
+CKA_DESTROYABLE = 0x172
+CKA_WRAP_WITH_TRUSTED = 0x210
+CKA_WRAP_TEMPLATE = 0x40000211
+CKA_UNWRAP_TEMPLATE = 0x40000212
+CKA_ALLOWED_MECHANISMS = 0x40000600
+
+AES_KEY = bytes(range(0x40, 0x50))
+
+
+def aes_cbc(key, iv, data):
+    padder = padding.PKCS7(128).padder()
+    data = padder.update(data) + padder.finalize()
+    enc = Cipher(algorithms.AES(key), modes.CBC(iv)).encryptor()
+    return enc.update(data) + enc.finalize()
+
+
+def aes_kcv(key):
+    enc = Cipher(algorithms.AES(key), modes.ECB()).encryptor()
+    return (enc.update(bytes(16)) + enc.finalize())[:3]
+
+
+# SoftHSMv2: OSAttributes.h and ObjectFile.cpp
+
+CKA_VENDOR_SOFTHSM = 0x80000000 + 0x5348
+CKA_OS_TOKENLABEL = CKA_VENDOR_SOFTHSM + 1
+CKA_OS_TOKENSERIAL = CKA_VENDOR_SOFTHSM + 2
+CKA_OS_TOKENFLAGS = CKA_VENDOR_SOFTHSM + 3
+CKA_OS_SOPIN = CKA_VENDOR_SOFTHSM + 4
+CKA_OS_USERPIN = CKA_VENDOR_SOFTHSM + 5
+
+BOOLEAN_ATTR = 1
+ULONG_ATTR = 2
+BYTESTR_ATTR = 3
+ATTRMAP_ATTR = 4
+MECHSET_ATTR = 5
+
+
+def ulong(v):
+    return struct.pack(">Q", v)
+
+
+def object_file(attrs):
+    # generation counter, then the attributes in std::map order
+    data = ulong(1)
+    for typ in sorted(attrs):
+        kind, val = attrs[typ]
+        data += ulong(typ) + ulong(kind)
+        if kind == BOOLEAN_ATTR:
+            data += bytes([1 if val else 0])
+        elif kind == ULONG_ATTR:
+            data += ulong(val)
+        elif kind == BYTESTR_ATTR or kind == ATTRMAP_ATTR:
+            data += ulong(len(val)) + val
+        elif kind == MECHSET_ATTR:
+            data += ulong(len(val)) + b"".join(ulong(m) for m in val)
+    return data
+
+
+def softhsm_pin_blob(pin, token_key):
+    # SecureDataManager: salt || IV || AES-256-CBC("RJR" || key) with
+    # the RFC 4880 iterated and salted S2K of the PIN
+    salt = os.urandom(8)
+    iters = 1500 + salt[-1]
+    h = hashlib.sha256(salt + pin).digest()
+    for _ in range(iters - 1):
+        h = hashlib.sha256(h).digest()
+    iv = os.urandom(16)
+    return salt + iv + aes_cbc(h, iv, b"RJR" + token_key)
+
+
+def softhsm(path):
+    shutil.rmtree(path, ignore_errors=True)
+    os.makedirs(path)
+    token_key = os.urandom(32)
+
+    def enc(data):
+        iv = os.urandom(16)
+        return iv + aes_cbc(token_key, iv, data)
+
+    def write(name, attrs):
+        with open(os.path.join(path, name + ".object"), "wb") as f:
+            f.write(object_file(attrs))
+        open(os.path.join(path, name + ".lock"), "wb").close()
+
+    write(
+        "token",
+        {
+            CKA_OS_TOKENLABEL: (BYTESTR_ATTR, b"softhsm token".ljust(32)),
+            CKA_OS_TOKENSERIAL: (BYTESTR_ATTR, b"b1d2a9e46c0f7a35"),
+            CKA_OS_TOKENFLAGS: (ULONG_ATTR, 0x0000042D),
+            CKA_OS_SOPIN: (BYTESTR_ATTR, softhsm_pin_blob(b"so pin", token_key)),
+            CKA_OS_USERPIN: (BYTESTR_ATTR, softhsm_pin_blob(b"1234", token_key)),
+        },
+    )
+
+    write(
+        "1e0b6d12-54f3-4f55-8c0e-0b8e2a9d7c61",
+        {
+            CKA_CLASS: (ULONG_ATTR, CKO_DATA),
+            CKA_TOKEN: (BOOLEAN_ATTR, True),
+            CKA_PRIVATE: (BOOLEAN_ATTR, False),
+            CKA_MODIFIABLE: (BOOLEAN_ATTR, True),
+            CKA_LABEL: (BYTESTR_ATTR, b"softhsm data"),
+            CKA_COPYABLE: (BOOLEAN_ATTR, True),
+            CKA_DESTROYABLE: (BOOLEAN_ATTR, True),
+            CKA_APPLICATION: (BYTESTR_ATTR, b"migration test"),
+            CKA_OBJECT_ID: (BYTESTR_ATTR, b""),
+            CKA_VALUE: (BYTESTR_ATTR, b"public data"),
+        },
+    )
+
+    # private objects have every byte string encrypted, empty ones too
+    write(
+        "9f4c8e2a-3b7d-4a61-a5e0-6c2d1f8b9e47",
+        {
+            CKA_CLASS: (ULONG_ATTR, CKO_SECRET_KEY),
+            CKA_TOKEN: (BOOLEAN_ATTR, True),
+            CKA_PRIVATE: (BOOLEAN_ATTR, True),
+            CKA_MODIFIABLE: (BOOLEAN_ATTR, True),
+            CKA_LABEL: (BYTESTR_ATTR, enc(b"softhsm key")),
+            CKA_COPYABLE: (BOOLEAN_ATTR, True),
+            CKA_DESTROYABLE: (BOOLEAN_ATTR, True),
+            CKA_KEY_TYPE: (ULONG_ATTR, CKK_AES),
+            CKA_ID: (BYTESTR_ATTR, enc(b"\x01\x02")),
+            CKA_START_DATE: (BYTESTR_ATTR, enc(b"")),
+            CKA_END_DATE: (BYTESTR_ATTR, enc(b"")),
+            CKA_DERIVE: (BOOLEAN_ATTR, False),
+            CKA_LOCAL: (BOOLEAN_ATTR, True),
+            CKA_KEY_GEN_MECHANISM: (ULONG_ATTR, CKM_AES_KEY_GEN),
+            CKA_ALLOWED_MECHANISMS: (MECHSET_ATTR, []),
+            CKA_SENSITIVE: (BOOLEAN_ATTR, True),
+            CKA_ENCRYPT: (BOOLEAN_ATTR, True),
+            CKA_DECRYPT: (BOOLEAN_ATTR, True),
+            CKA_SIGN: (BOOLEAN_ATTR, False),
+            CKA_VERIFY: (BOOLEAN_ATTR, False),
+            CKA_WRAP: (BOOLEAN_ATTR, False),
+            CKA_UNWRAP: (BOOLEAN_ATTR, False),
+            CKA_EXTRACTABLE: (BOOLEAN_ATTR, False),
+            CKA_ALWAYS_SENSITIVE: (BOOLEAN_ATTR, True),
+            CKA_NEVER_EXTRACTABLE: (BOOLEAN_ATTR, True),
+            CKA_CHECK_VALUE: (BYTESTR_ATTR, enc(aes_kcv(AES_KEY))),
+            CKA_WRAP_WITH_TRUSTED: (BOOLEAN_ATTR, False),
+            CKA_TRUSTED: (BOOLEAN_ATTR, False),
+            CKA_WRAP_TEMPLATE: (ATTRMAP_ATTR, b""),
+            CKA_UNWRAP_TEMPLATE: (ATTRMAP_ATTR, b""),
+            CKA_VALUE: (BYTESTR_ATTR, enc(AES_KEY)),
+            CKA_VALUE_LEN: (ULONG_ATTR, len(AES_KEY)),
+        },
+    )
+
+
+# NSS softokn: sdb.c, sftkdb.c and sftkpwd.c
+
+NSS_COLUMNS = [
+    0x0, 0x1, 0x2, 0x3, 0x10, 0x11, 0x12, 0x80, 0x81, 0x82, 0x83, 0x84,
+    0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x8B, 0x90, 0x100, 0x101, 0x102,
+    0x103, 0x104, 0x105, 0x106, 0x107, 0x108, 0x109, 0x10A, 0x10B, 0x10C,
+    0x110, 0x111, 0x120, 0x121, 0x122, 0x123, 0x124, 0x125, 0x126, 0x127,
+    0x128, 0x129, 0x130, 0x131, 0x132, 0x133, 0x134, 0x160, 0x161, 0x162,
+    0x163, 0x164, 0x165, 0x166, 0x170, 0x180, 0x181, 0x200, 0x201, 0x202,
+    0x210, 0x300, 0x301, 0x302, 0x400, 0x401, 0x402, 0x403, 0x404, 0x405,
+    0x406, 0x480, 0x481, 0x482, 0x500, 0x501, 0x502, 0x503, 0x40000211,
+    0x40000212, 0x80000001, 0xCE534351, 0xCE534352, 0xCE534353,
+    0xCE534354, 0xCE534355, 0xCE534356, 0xCE534357, 0xCE534358,
+    0xCE534364, 0xCE534365, 0xCE534366, 0xCE534367, 0xCE534368,
+    0xCE534369, 0xCE534373, 0xCE534374, 0xCE536351, 0xCE536352,
+    0xCE536353, 0xCE536354, 0xCE536355, 0xCE536356, 0xCE536357,
+    0xCE536358, 0xCE536359, 0xCE53635A, 0xCE53635B, 0xCE53635C,
+    0xCE53635D, 0xCE53635E, 0xCE53635F, 0xCE536360, 0xCE5363B4,
+    0xCE5363B5, 0xD5A0DB00,
+]
+
+NSS_NULL = b"\xa5\x00\xa5"
+
+
+def der(tag, content):
+    n = len(content)
+    if n < 0x80:
+        length = bytes([n])
+    else:
+        b = n.to_bytes((n.bit_length() + 7) // 8, "big")
+        length = bytes([0x80 | len(b)]) + b
+    return bytes([tag]) + length + content
+
+
+def der_seq(*items):
+    return der(0x30, b"".join(items))
+
+
+def der_int(v):
+    b = v.to_bytes(v.bit_length() // 8 + 1, "big")
+    return der(0x02, b)
+
+
+def der_oid(dotted):
+    arcs = [int(a) for a in dotted.split(".")]
+    out = bytes([arcs[0] * 40 + arcs[1]])
+    for a in arcs[2:]:
+        enc = [a & 0x7F]
+        a >>= 7
+        while a:
+            enc.insert(0, 0x80 | (a & 0x7F))
+            a >>= 7
+        out += bytes(enc)
+    return der(0x06, out)
+
+
+def nss_encrypt(password, data):
+    # PBES2 with PBKDF2-HMAC-SHA256 and AES-256-CBC; NSS encodes only
+    # the last 14 bytes of the IV, the first two are the DER header of
+    # the IV octet string (04 0e)
+    salt = os.urandom(32)
+    iterations = 10000
+    key = PBKDF2HMAC(hashes.SHA256(), 32, salt, iterations).derive(password)
+    iv14 = os.urandom(14)
+    enc = aes_cbc(key, b"\x04\x0e" + iv14, data)
+    kdf = der_seq(
+        der_oid("1.2.840.113549.1.5.12"),
+        der_seq(
+            der(0x04, salt),
+            der_int(iterations),
+            der_int(32),
+            der_seq(der_oid("1.2.840.113549.2.9"), der(0x05, b"")),
+        ),
+    )
+    cipher = der_seq(der_oid("2.16.840.1.101.3.4.1.42"), der(0x04, iv14))
+    alg = der_seq(der_oid("1.2.840.113549.1.5.13"), der_seq(kdf, cipher))
+    return der_seq(alg, der(0x04, enc))
+
+
+def nss_ulong(v):
+    return struct.pack(">I", v)
+
+
+def nss_table(conn, table, rows):
+    cols = ", ".join("a%x" % c for c in NSS_COLUMNS)
+    conn.execute(
+        "CREATE TABLE %s (id PRIMARY KEY UNIQUE ON CONFLICT ABORT, %s)"
+        % (table, cols)
+    )
+    conn.execute("CREATE INDEX issuer ON %s (a81)" % table)
+    conn.execute("CREATE INDEX subject ON %s (a101)" % table)
+    conn.execute("CREATE INDEX label ON %s (a3)" % table)
+    conn.execute("CREATE INDEX ckaid ON %s (a102)" % table)
+    for oid, attrs in rows:
+        names = ["id"] + ["a%x" % t for t in attrs]
+        marks = ", ".join("?" for _ in names)
+        conn.execute(
+            "INSERT INTO %s (%s) VALUES (%s)" % (table, ", ".join(names), marks),
+            [oid] + [sqlite3.Binary(v) for v in attrs.values()],
+        )
+
+
+def nss(path):
+    shutil.rmtree(path, ignore_errors=True)
+    os.makedirs(path)
+
+    global_salt = os.urandom(20)
+    password = hashlib.sha1(global_salt + b"nss password").digest()
+
+    conn = sqlite3.connect(os.path.join(path, "key4.db"))
+    conn.execute(
+        "CREATE TABLE metaData (id PRIMARY KEY UNIQUE ON CONFLICT REPLACE, "
+        "item1, item2)"
+    )
+    conn.execute(
+        "INSERT INTO metaData VALUES ('password', ?, ?)",
+        (global_salt, nss_encrypt(password, b"password-check")),
+    )
+    oid = 0x3A5F1C27
+    conn.execute(
+        "INSERT INTO metaData VALUES (?, ?, ?)",
+        ("sig_key_%08x_%08x" % (oid, CKA_VALUE), os.urandom(20), os.urandom(80)),
+    )
+    nss_table(
+        conn,
+        "nssPrivate",
+        [
+            (
+                oid,
+                {
+                    CKA_CLASS: nss_ulong(CKO_SECRET_KEY),
+                    CKA_TOKEN: b"\x01",
+                    CKA_PRIVATE: b"\x01",
+                    CKA_LABEL: b"nss key",
+                    CKA_VALUE: nss_encrypt(password, AES_KEY),
+                    CKA_KEY_TYPE: nss_ulong(CKK_AES),
+                    CKA_ID: b"\x03\x04",
+                    CKA_SENSITIVE: b"\x01",
+                    CKA_ENCRYPT: b"\x01",
+                    CKA_DECRYPT: b"\x01",
+                    CKA_WRAP: b"\x00",
+                    CKA_UNWRAP: b"\x00",
+                    CKA_SIGN: b"\x00",
+                    CKA_VERIFY: b"\x00",
+                    CKA_DERIVE: b"\x00",
+                    CKA_START_DATE: NSS_NULL,
+                    CKA_END_DATE: NSS_NULL,
+                    CKA_VALUE_LEN: nss_ulong(len(AES_KEY)),
+                    CKA_EXTRACTABLE: b"\x00",
+                    CKA_LOCAL: b"\x01",
+                    CKA_NEVER_EXTRACTABLE: b"\x01",
+                    CKA_ALWAYS_SENSITIVE: b"\x01",
+                    CKA_KEY_GEN_MECHANISM: nss_ulong(CKM_AES_KEY_GEN),
+                    CKA_MODIFIABLE: b"\x01",
+                },
+            )
+        ],
+    )
+    conn.commit()
+    conn.close()
+
+    conn = sqlite3.connect(os.path.join(path, "cert9.db"))
+    nss_table(
+        conn,
+        "nssPublic",
+        [
+            (
+                0x1D2E3F40,
+                {
+                    CKA_CLASS: nss_ulong(CKO_DATA),
+                    CKA_TOKEN: b"\x01",
+                    CKA_PRIVATE: b"\x00",
+                    CKA_LABEL: b"nss data",
+                    CKA_APPLICATION: b"migration test",
+                    CKA_VALUE: b"public data",
+                    CKA_OBJECT_ID: NSS_NULL,
+                    CKA_MODIFIABLE: b"\x01",
+                },
+            )
+        ],
+    )
+    conn.commit()
+    conn.close()
+
+
+if __name__ == "__main__":
+    softhsm(os.path.join(HERE, "softhsm", "4c3a9b6e-2f1d-4e8a-9b5c-7d6e5f4a3b2c"))
+    nss(os.path.join(HERE, "nss"))